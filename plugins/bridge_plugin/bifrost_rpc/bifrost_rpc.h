@@ -30,6 +30,84 @@ extern "C" {
 #endif
 
 // bifrost rpc api
+// every rpc_result below is owned by the caller and must be given back by rpc_result_free
+void rpc_result_free(eosio::rpc_result *result);
+
+eosio::rpc_result *init_relay_config(const char *config);
+
+eosio::rpc_result *check_signer_balance(const char *urls, const char *signer);
+
+eosio::rpc_result *relay_status();
+
+typedef void (*cross_out_callback)(const eosio::cross_out_request_ffi *);
+
+void register_cross_out_callback(cross_out_callback callback);
+
+eosio::rpc_result *subscribe_cross_out_requests(const char *urls);
+
+void unsubscribe_cross_out_requests();
+
+// memo carries the bifrost request id like "redeem:42"
+eosio::rpc_result *match_redeem_transfer(const char *memo, const eosio::transaction_id_type trx_id);
+
+eosio::rpc_result *redeem_requests_status();
+
+// confirm on bifrost the requests whose redeem transfers are proven
+eosio::rpc_result *confirm_redeem_requests(const char *urls, const char *signer);
+
+eosio::rpc_result *parse_transfer_memo(const char *memo);
+
+// transfer with invalid memo goes to refund queue
+eosio::rpc_result *validate_transfer_memo(
+   const char                                   *memo,
+   const eosio::account_name                    contract,
+   const eosio::account_name                    from,
+   const char                                   *quantity,
+   const eosio::transaction_id_type             trx_id
+);
+
+eosio::rpc_result *refund_queue();
+
+// refunds for the host to send back to EOS with memo "refund:<trx_id>", handed over again if not settled in a few minutes
+eosio::rpc_result *take_refunds();
+
+// a refund transfer from crossaccount is applied in block_num, returns whether it refunds a queued transfer
+bool observe_refund_transfer(
+   const char                                   *memo,
+   const eosio::account_name                    contract,
+   const eosio::account_name                    to,
+   const char                                   *quantity,
+   const eosio::transaction_id_type             trx_id,
+   uint32_t                                     block_num
+);
+
+// transactions of an irreversible block, refunds among them are settled. returns how many are settled
+uint32_t settle_refunds(uint32_t block_num, const eosio::transaction_id_type *trx_ids, size_t trx_ids_size);
+
+// quantity is asset string like "43.0000 EOS"
+eosio::rpc_result *classify_transfer(const eosio::account_name contract, const char *quantity);
+
+// whether any token of the contract is in asset registry
+bool is_asset_contract(const eosio::account_name contract);
+
+// abi cache is bounded, an evicted abi should be registered again
+bool is_contract_abi_cached(const eosio::account_name contract, uint32_t abi_sequence);
+
+eosio::rpc_result *register_contract_abi(
+   const eosio::account_name                    contract,
+   uint32_t                                     abi_sequence,
+   const char                                   *abi_json
+);
+
+// act_receipt is optional, the latest registered abi is used if it's nullptr
+eosio::rpc_result *decode_action_data(
+   const eosio::action_ffi                      *act_ffi,
+   const eosio::action_receipt_ffi              *act_receipt
+);
+
+// whether the action should be relayed, by rules from relay config
+eosio::rule_match match_action_rules(const eosio::action_ffi *act_ffi, const eosio::account_name receiver);
+
 eosio::rpc_result *change_schedule(
    const char                                   *urls,
    const char                                   *signer,
@@ -42,6 +120,59 @@ eosio::rpc_result *change_schedule(
    size_t                                       ids_list_size
 );
 
+// like change_schedule, each argument is a buffer of fc::raw::pack output and its size:
+// producer_authority_schedule, incremental_merkle, vector<signed_block_header>, vector<vector<block_id_type>>
+eosio::rpc_result *change_schedule_packed(
+   const char                                   *urls,
+   const char                                   *signer,
+   const eosio::digest_type                     legacy_schedule_hash,
+   const char                                   *schedule,
+   size_t                                       schedule_size,
+   const char                                   *imcre_merkle,
+   size_t                                       imcre_merkle_size,
+   const char                                   *blocks,
+   size_t                                       blocks_size,
+   const char                                   *ids_list,
+   size_t                                       ids_list_size
+);
+
+// same as change_schedule, but arguments are ffi structs instead of json
+eosio::rpc_result *change_schedule_v2(
+   const char                                   *urls,
+   const char                                   *signer,
+   const eosio::digest_type                     legacy_schedule_hash,
+   const eosio::producer_authority_schedule_ffi *schedule,
+   const eosio::incremental_merkle_ffi          *imcre_merkle,
+   const eosio::signed_block_header_ffi         *blocks_ffi,
+   size_t                                       blocks_ffi_size,
+   const eosio::block_id_type_list              *ids_list,
+   size_t                                       ids_list_size
+);
+
+eosio::rpc_result *change_schedule_dry_run(
+   const char                                   *urls,
+   const char                                   *signer,
+   const eosio::digest_type                     legacy_schedule_hash,
+   const char                                   *schedule,
+   const char                                   *imcre_merkle,
+   const char                                   *blocks_ffi,
+   size_t                                       blocks_ffi_size,
+   const char                                   *ids_list,
+   size_t                                       ids_list_size
+);
+
+eosio::rpc_result *estimate_change_schedule_fee(
+   const char                                   *urls,
+   const char                                   *signer,
+   const eosio::digest_type                     legacy_schedule_hash,
+   const char                                   *schedule,
+   const char                                   *imcre_merkle,
+   const char                                   *blocks_ffi,
+   size_t                                       blocks_ffi_size,
+   const char                                   *ids_list,
+   size_t                                       ids_list_size
+);
+
 eosio::rpc_result *prove_action(
    const char                                   *urls,
    const char                                   *signer,
@@ -56,6 +187,165 @@ eosio::rpc_result *prove_action(
    const eosio::transaction_id_type             trx_id
 );
 
+// results of each proof are in msg as json: [{"index", "success", "code", "msg"}]
+eosio::rpc_result *prove_actions_batch(
+   const char                                   *urls,
+   const char                                   *signer,
+   const eosio::prove_action_ffi                *items,
+   size_t                                       items_size
+);
+
+eosio::rpc_result *prove_action_dry_run(
+   const char                                   *urls,
+   const char                                   *signer,
+   const eosio::action_ffi                      *act_ffi,
+   const eosio::incremental_merkle_ffi          *imcre_merkle,
+   const eosio::action_receipt_ffi              *act_receipt,
+   const eosio::block_id_type_list              *action_merkle_paths,
+   const eosio::signed_block_header_ffi         *blocks_ffi,
+   size_t                                       blocks_ffi_size,
+   const eosio::block_id_type_list              *ids_list,
+   size_t                                       ids_list_size,
+   const eosio::transaction_id_type             trx_id
+);
+
+// schedule is optional producer_authority_schedule json, pass nullptr to only read bifrost storage
+eosio::rpc_result *query_producer_schedule(
+   const char                                   *urls,
+   const char                                   *schedule
+);
+
+eosio::rpc_result *is_action_proven(
+   const char                                   *urls,
+   const eosio::action_receipt_ffi              *act_receipt
+);
+
+eosio::rpc_result *estimate_prove_action_fee(
+   const char                                   *urls,
+   const char                                   *signer,
+   const eosio::action_ffi                      *act_ffi,
+   const eosio::incremental_merkle_ffi          *imcre_merkle,
+   const eosio::action_receipt_ffi              *act_receipt,
+   const eosio::block_id_type_list              *action_merkle_paths,
+   const eosio::signed_block_header_ffi         *blocks_ffi,
+   size_t                                       blocks_ffi_size,
+   const eosio::block_id_type_list              *ids_list,
+   size_t                                       ids_list_size,
+   const eosio::transaction_id_type             trx_id
+);
+
+// submissions are queued and sent by process_submission_queue, in order of block number
+// and schedule version. an action proof waits until bifrost has the schedule it depends on,
+// a schedule change waits until bifrost's stored schedule matches its legacy_schedule_hash.
+eosio::rpc_result *enqueue_change_schedule(
+   const eosio::digest_type                     legacy_schedule_hash,
+   const char                                   *schedule,
+   const char                                   *imcre_merkle,
+   const char                                   *blocks_ffi,
+   size_t                                       blocks_ffi_size,
+   const char                                   *ids_list,
+   size_t                                       ids_list_size
+);
+
+eosio::rpc_result *enqueue_prove_action(
+   const eosio::action_ffi                      *act_ffi,
+   const eosio::incremental_merkle_ffi          *imcre_merkle,
+   const eosio::action_receipt_ffi              *act_receipt,
+   const eosio::block_id_type_list              *action_merkle_paths,
+   const eosio::signed_block_header_ffi         *blocks_ffi,
+   size_t                                       blocks_ffi_size,
+   const eosio::block_id_type_list              *ids_list,
+   size_t                                       ids_list_size,
+   const eosio::transaction_id_type             trx_id
+);
+
+// irreversible blocks shared by pending proofs, blocks before the floor (the oldest pending
+// proof) are dropped. floor 0 means nothing is pending. false if the block can't be cached.
+bool cache_block(
+   uint32_t                                     block_num,
+   const eosio::block_id_type                   id,
+   const eosio::signed_block_header_ffi         *header,
+   const eosio::incremental_merkle_ffi          *imcre_merkle
+);
+
+bool set_block_cache_floor(uint32_t block_num);
+
+// whether every block needed to prove block_num is cached
+bool block_cache_ready(uint32_t block_num);
+
+// first block needed to prove block_num that is not cached, 0 once ready
+uint32_t block_cache_next_missing(uint32_t block_num);
+
+// cache a block read back from block log, false unless its previous block is cached
+bool rebuild_cached_block(
+   uint32_t                                     block_num,
+   const eosio::block_id_type                   id,
+   const eosio::signed_block_header_ffi         *header
+);
+
+eosio::rpc_result *block_cache_status();
+
+// like enqueue_change_schedule and enqueue_prove_action, headers, ids and blockroot merkle are from block cache
+eosio::rpc_result *enqueue_change_schedule_cached(
+   const eosio::digest_type                     legacy_schedule_hash,
+   const eosio::producer_authority_schedule_ffi *schedule,
+   uint32_t                                     block_num
+);
+
+eosio::rpc_result *enqueue_prove_action_cached(
+   const eosio::action_ffi                      *act_ffi,
+   const eosio::action_receipt_ffi              *act_receipt,
+   const eosio::block_id_type_list              *action_merkle_paths,
+   const eosio::transaction_id_type             trx_id,
+   uint32_t                                     block_num
+);
+
+// results are in msg as json: [{"id", "kind", "block_num", "schedule_version", "success", "code", "msg"}]
+eosio::rpc_result *process_submission_queue(
+   const char                                   *urls,
+   const char                                   *signer
+);
+
+// missing schedule versions are reported in "gaps" of the status json
+eosio::rpc_result *submission_queue_status();
+
+// how many submissions are waiting, without building the status json
+uint64_t submission_queue_pending();
+
+// crash-safe relay state, every put and remove is on disk once it returns
+typedef void (*store_entry_callback)(void *ctx, const char *key, size_t key_size, const char *value, size_t value_size);
+
+eosio::rpc_result *open_relay_store(const char *path);
+
+eosio::rpc_result *relay_store_put(
+   const char                                   *tree,
+   const char                                   *key,
+   size_t                                       key_size,
+   const char                                   *value,
+   size_t                                       value_size
+);
+
+eosio::rpc_result *relay_store_remove(
+   const char                                   *tree,
+   const char                                   *key,
+   size_t                                       key_size
+);
+
+// callback is called once for each entry in key order, msg is the number of entries
+eosio::rpc_result *relay_store_load(
+   const char                                   *tree,
+   store_entry_callback                         callback,
+   void                                         *ctx
+);
+
+eosio::rpc_result *close_relay_store();
+
+// every extrinsic is journaled in the outbox of relay store. resolve extrinsics whose outcome
+// is unknown by bifrost tx pool and recent blocks, msg is {"in_pool", "finalized", "lost", "pruned"}
+eosio::rpc_result *reconcile_outbox(const char *urls);
+
+eosio::rpc_result *outbox_entries();
+
 #ifdef __cplusplus
 }
 #endif
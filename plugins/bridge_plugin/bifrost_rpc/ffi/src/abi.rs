@@ -0,0 +1,507 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Decode EOSIO binary action data into json by the contract's ABI.
+
+use eos_chain::AccountName;
+use lru::LruCache;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+// abi of recently seen contracts keyed by abi_sequence from action receipt
+static ABI_CACHE: Lazy<Mutex<LruCache<String, BTreeMap<u32, Arc<Abi>>>>> = Lazy::new(|| Mutex::new(LruCache::new(MAX_CACHED_CONTRACTS)));
+
+const MAX_CACHED_CONTRACTS: usize = 256;
+// older abis of a contract are only needed for actions applied right before a setabi
+const MAX_ABI_VERSIONS: usize = 4;
+
+// nested types deeper than this are treated as malformed abi
+const MAX_DEPTH: usize = 32;
+
+// 10^18 is the widest scale of an i64 amount, nodeos refuses wider symbols too
+pub(crate) const MAX_PRECISION: u8 = 18;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Abi {
+	pub version:  String,
+	pub types:    Vec<AbiTypeDef>,
+	pub structs:  Vec<AbiStruct>,
+	pub actions:  Vec<AbiAction>,
+	pub variants: Vec<AbiVariant>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AbiTypeDef {
+	pub new_type_name: String,
+	#[serde(rename = "type")]
+	pub type_:         String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct AbiStruct {
+	pub name:   String,
+	pub base:   String,
+	pub fields: Vec<AbiField>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AbiField {
+	pub name:  String,
+	#[serde(rename = "type")]
+	pub type_: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AbiAction {
+	pub name:  String,
+	#[serde(rename = "type")]
+	pub type_: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AbiVariant {
+	pub name:  String,
+	pub types: Vec<String>,
+}
+
+impl Abi {
+	pub fn from_json(json: &str) -> Result<Self, crate::Error> {
+		serde_json::from_str(json).map_err(|e| crate::Error::AbiError(e.to_string()))
+	}
+
+	pub fn decode_action(&self, action: &str, data: &[u8]) -> Result<Value, crate::Error> {
+		let type_name = self.actions.iter()
+			.find(|a| a.name == action)
+			.map(|a| a.type_.clone())
+			.ok_or_else(|| crate::Error::AbiError(format!("action '{}' is not in abi", action)))?;
+
+		self.decode(&type_name, data)
+	}
+
+	pub fn decode(&self, type_name: &str, data: &[u8]) -> Result<Value, crate::Error> {
+		let mut reader = Reader { data, pos: 0 };
+		self.decode_type(type_name, &mut reader, 0)
+	}
+
+	fn resolve<'a>(&'a self, type_name: &'a str) -> &'a str {
+		let mut name = type_name;
+		for _ in 0..MAX_DEPTH {
+			match self.types.iter().find(|t| t.new_type_name == name) {
+				Some(t) => name = &t.type_,
+				None => break,
+			}
+		}
+		name
+	}
+
+	fn decode_type(&self, type_name: &str, reader: &mut Reader, depth: usize) -> Result<Value, crate::Error> {
+		if depth > MAX_DEPTH {
+			return Err(crate::Error::AbiError("abi types are nested too deep".to_owned()));
+		}
+		let type_name = self.resolve(type_name);
+
+		if let Some(inner) = type_name.strip_suffix("$") {
+			// binary extension, could be missing at the end of data
+			if reader.remaining() == 0 {
+				return Ok(Value::Null);
+			}
+			return self.decode_type(inner, reader, depth + 1);
+		}
+		if let Some(inner) = type_name.strip_suffix("?") {
+			return match reader.read_u8()? {
+				0 => Ok(Value::Null),
+				_ => self.decode_type(inner, reader, depth + 1),
+			};
+		}
+		if let Some(inner) = type_name.strip_suffix("[]") {
+			// every element takes at least one byte, except empty structs that nobody uses in arrays
+			let len = reader.read_len()?;
+			let mut values = Vec::with_capacity(len);
+			for _ in 0..len {
+				values.push(self.decode_type(inner, reader, depth + 1)?);
+			}
+			return Ok(Value::Array(values));
+		}
+
+		if let Some(value) = decode_builtin(type_name, reader)? {
+			return Ok(value);
+		}
+
+		if let Some(variant) = self.variants.iter().find(|v| v.name == type_name) {
+			let index = reader.read_varuint32()? as usize;
+			let inner = variant.types.get(index)
+				.ok_or_else(|| crate::Error::AbiError(format!("variant index {} out of '{}'", index, type_name)))?;
+			let value = self.decode_type(inner, reader, depth + 1)?;
+			return Ok(json!([inner, value]));
+		}
+
+		if let Some(st) = self.structs.iter().find(|s| s.name == type_name) {
+			let mut object = Map::new();
+			if !st.base.is_empty() {
+				if let Value::Object(base) = self.decode_type(&st.base, reader, depth + 1)? {
+					object.extend(base);
+				}
+			}
+			for field in st.fields.iter() {
+				let value = self.decode_type(&field.type_, reader, depth + 1)?;
+				object.insert(field.name.clone(), value);
+			}
+			return Ok(Value::Object(object));
+		}
+
+		Err(crate::Error::AbiError(format!("unknown type '{}'", type_name)))
+	}
+}
+
+fn decode_builtin(type_name: &str, reader: &mut Reader) -> Result<Option<Value>, crate::Error> {
+	let value = match type_name {
+		"bool" => json!(reader.read_u8()? != 0),
+		"int8" => json!(reader.read_u8()? as i8),
+		"uint8" => json!(reader.read_u8()?),
+		"int16" => json!(reader.read_u16()? as i16),
+		"uint16" => json!(reader.read_u16()?),
+		"int32" => json!(reader.read_u32()? as i32),
+		"uint32" => json!(reader.read_u32()?),
+		// 64 bits and wider integers are strings, the same as nodeos abi serializer
+		"int64" => json!((reader.read_u64()? as i64).to_string()),
+		"uint64" => json!(reader.read_u64()?.to_string()),
+		"int128" => json!((reader.read_u128()? as i128).to_string()),
+		"uint128" => json!(reader.read_u128()?.to_string()),
+		"varint32" => {
+			let v = reader.read_varuint32()?;
+			json!(((v >> 1) as i32) ^ -((v & 1) as i32))
+		}
+		"varuint32" => json!(reader.read_varuint32()?),
+		"float32" => json!(f32::from_bits(reader.read_u32()?)),
+		"float64" => json!(f64::from_bits(reader.read_u64()?)),
+		"time_point" => json!(reader.read_u64()? as i64),
+		"time_point_sec" | "block_timestamp_type" => json!(reader.read_u32()?),
+		"name" => json!(AccountName::from(reader.read_u64()?).to_string()),
+		"bytes" => {
+			let len = reader.read_len()?;
+			json!(hex::encode(reader.read_bytes(len)?))
+		}
+		"string" => {
+			let len = reader.read_len()?;
+			let bytes = reader.read_bytes(len)?;
+			json!(String::from_utf8_lossy(bytes))
+		}
+		"checksum160" => json!(hex::encode(reader.read_bytes(20)?)),
+		"checksum256" => json!(hex::encode(reader.read_bytes(32)?)),
+		"checksum512" => json!(hex::encode(reader.read_bytes(64)?)),
+		"public_key" => {
+			let key_type = reader.read_varuint32()?;
+			json!(format!("{}:{}", key_type, hex::encode(reader.read_bytes(33)?)))
+		}
+		"signature" => {
+			let key_type = reader.read_varuint32()?;
+			json!(format!("{}:{}", key_type, hex::encode(reader.read_bytes(65)?)))
+		}
+		"symbol" => {
+			let symbol = reader.read_u64()?;
+			json!(format!("{},{}", symbol & 0xff, symbol_code(symbol >> 8)))
+		}
+		"symbol_code" => json!(symbol_code(reader.read_u64()?)),
+		"asset" => {
+			let amount = reader.read_u64()? as i64;
+			json!(format_asset(amount, reader.read_u64()?)?)
+		}
+		"extended_asset" => {
+			let amount = reader.read_u64()? as i64;
+			let quantity = format_asset(amount, reader.read_u64()?)?;
+			let contract = AccountName::from(reader.read_u64()?).to_string();
+			json!({ "quantity": quantity, "contract": contract })
+		}
+		_ => return Ok(None),
+	};
+
+	Ok(Some(value))
+}
+
+struct Reader<'a> {
+	data: &'a [u8],
+	pos:  usize,
+}
+
+impl<'a> Reader<'a> {
+	fn remaining(&self) -> usize {
+		self.data.len() - self.pos
+	}
+
+	fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], crate::Error> {
+		let bytes = self.pos.checked_add(len)
+			.and_then(|end| self.data.get(self.pos..end))
+			.ok_or_else(|| crate::Error::AbiError("action data is too short".to_owned()))?;
+		self.pos += len;
+		Ok(bytes)
+	}
+
+	fn read_u8(&mut self) -> Result<u8, crate::Error> {
+		Ok(self.read_bytes(1)?[0])
+	}
+
+	fn read_u16(&mut self) -> Result<u16, crate::Error> {
+		let mut buf = [0u8; 2];
+		buf.copy_from_slice(self.read_bytes(2)?);
+		Ok(u16::from_le_bytes(buf))
+	}
+
+	fn read_u32(&mut self) -> Result<u32, crate::Error> {
+		let mut buf = [0u8; 4];
+		buf.copy_from_slice(self.read_bytes(4)?);
+		Ok(u32::from_le_bytes(buf))
+	}
+
+	fn read_u64(&mut self) -> Result<u64, crate::Error> {
+		let mut buf = [0u8; 8];
+		buf.copy_from_slice(self.read_bytes(8)?);
+		Ok(u64::from_le_bytes(buf))
+	}
+
+	fn read_u128(&mut self) -> Result<u128, crate::Error> {
+		let mut buf = [0u8; 16];
+		buf.copy_from_slice(self.read_bytes(16)?);
+		Ok(u128::from_le_bytes(buf))
+	}
+
+	fn read_varuint32(&mut self) -> Result<u32, crate::Error> {
+		let (value, read) = read_varuint32(&self.data[self.pos..])
+			.ok_or_else(|| crate::Error::AbiError("invalid varuint32".to_owned()))?;
+		self.pos += read;
+		Ok(value)
+	}
+
+	// length prefix of bytes, strings and arrays, it cannot be longer than what's left
+	fn read_len(&mut self) -> Result<usize, crate::Error> {
+		let len = self.read_varuint32()? as usize;
+		if len > self.remaining() {
+			return Err(crate::Error::AbiError(format!("length {} is over the remaining {} bytes", len, self.remaining())));
+		}
+		Ok(len)
+	}
+}
+
+pub(crate) fn read_varuint32(data: &[u8]) -> Option<(u32, usize)> {
+	let mut value: u64 = 0;
+	for (i, byte) in data.iter().enumerate().take(5) {
+		value |= ((byte & 0x7f) as u64) << (7 * i);
+		if byte & 0x80 == 0 {
+			return Some((value as u32, i + 1));
+		}
+	}
+	None
+}
+
+fn symbol_code(code: u64) -> String {
+	(0..7)
+		.map(|i| ((code >> (8 * i)) & 0xff) as u8)
+		.take_while(|c| *c != 0)
+		.map(|c| c as char)
+		.collect()
+}
+
+// symbol is precision in the lowest byte followed by up to 7 characters
+pub(crate) fn format_asset(amount: i64, symbol: u64) -> Result<String, crate::Error> {
+	let precision = (symbol & 0xff) as u8;
+	let code = symbol_code(symbol >> 8);
+	if precision > MAX_PRECISION {
+		return Err(crate::Error::AbiError(format!("precision {} of {} is over {}", precision, code, MAX_PRECISION)));
+	}
+
+	let sign = if amount < 0 { "-" } else { "" };
+	let abs = (amount as i128).abs();
+	if precision == 0 {
+		return Ok(format!("{}{} {}", sign, abs, code));
+	}
+	let scale = 10i128.checked_pow(precision as u32)
+		.ok_or_else(|| crate::Error::AbiError(format!("precision {} of {} overflows", precision, code)))?;
+	Ok(format!("{}{}.{:0width$} {}", sign, abs / scale, abs % scale, code, width = precision as usize))
+}
+
+pub fn register_abi(contract: &str, abi_sequence: u32, json: &str) -> Result<(), crate::Error> {
+	let abi = Abi::from_json(json)?;
+	let mut cache = ABI_CACHE.lock().map_err(|_| crate::Error::AbiError("abi cache is poisoned".to_owned()))?;
+	if cache.get(contract).is_none() {
+		cache.put(contract.to_owned(), BTreeMap::new());
+	}
+	if let Some(abis) = cache.get_mut(contract) {
+		abis.insert(abi_sequence, Arc::new(abi));
+		while abis.len() > MAX_ABI_VERSIONS {
+			let oldest = match abis.keys().next() {
+				Some(sequence) => *sequence,
+				None => break,
+			};
+			abis.remove(&oldest);
+		}
+	}
+
+	Ok(())
+}
+
+/// Whether the abi is still cached, c++ side registers it again once it's evicted.
+pub fn is_abi_cached(contract: &str, abi_sequence: u32) -> bool {
+	ABI_CACHE.lock().ok()
+		.and_then(|mut cache| cache.get(contract).map(|abis| abis.contains_key(&abi_sequence)))
+		.unwrap_or(false)
+}
+
+/// ABI of the contract at `abi_sequence`, or the latest one if sequence is not given.
+pub fn cached_abi(contract: &str, abi_sequence: Option<u32>) -> Option<Arc<Abi>> {
+	let mut cache = ABI_CACHE.lock().ok()?;
+	let abis = cache.get(contract)?;
+	match abi_sequence {
+		Some(sequence) => abis.get(&sequence).cloned(),
+		None => abis.values().next_back().cloned(),
+	}
+}
+
+pub fn decode_action_data(
+	contract:     &str,
+	action:       &str,
+	abi_sequence: Option<u32>,
+	data:         &[u8],
+) -> Result<Value, crate::Error> {
+	let abi = cached_abi(contract, abi_sequence)
+		.ok_or_else(|| crate::Error::AbiError(format!("no abi of contract '{}' at sequence {:?}", contract, abi_sequence)))?;
+
+	abi.decode_action(action, data)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// 4,EOS
+	const EOS: u64 = 4 | (b'E' as u64) << 8 | (b'O' as u64) << 16 | (b'S' as u64) << 24;
+
+	fn symbol(precision: u8) -> u64 {
+		(EOS & !0xff) | precision as u64
+	}
+
+	fn abi() -> Abi {
+		Abi::from_json(r#"{
+			"version": "eosio::abi/1.1",
+			"types": [{ "new_type_name": "account", "type": "name" }],
+			"structs": [
+				{ "name": "base", "base": "", "fields": [{ "name": "owner", "type": "account" }] },
+				{ "name": "order", "base": "base", "fields": [
+					{ "name": "ids", "type": "uint16[]" },
+					{ "name": "note", "type": "string?" },
+					{ "name": "payment", "type": "payment" },
+					{ "name": "extra", "type": "uint8$" }
+				]}
+			],
+			"actions": [{ "name": "order", "type": "order" }],
+			"variants": [{ "name": "payment", "types": ["asset", "uint32"] }]
+		}"#).unwrap()
+	}
+
+	fn order(note: Option<&str>, variant: u8) -> Vec<u8> {
+		let mut data = vec![];
+		data.extend_from_slice(&0u64.to_le_bytes()); // owner
+		data.push(3);
+		for id in &[1u16, 2, 3] {
+			data.extend_from_slice(&id.to_le_bytes());
+		}
+		match note {
+			Some(note) => {
+				data.push(1);
+				data.push(note.len() as u8);
+				data.extend_from_slice(note.as_bytes());
+			}
+			None => data.push(0),
+		}
+		data.push(variant);
+		if variant == 0 {
+			data.extend_from_slice(&430_000i64.to_le_bytes());
+			data.extend_from_slice(&EOS.to_le_bytes());
+		} else {
+			data.extend_from_slice(&7u32.to_le_bytes());
+		}
+		data
+	}
+
+	#[test]
+	fn asset_of_any_precision_is_formatted_or_refused() {
+		assert_eq!(format_asset(43, symbol(0)).unwrap(), "43 EOS");
+		assert_eq!(format_asset(430_005, symbol(4)).unwrap(), "43.0005 EOS");
+		assert_eq!(format_asset(-5, symbol(4)).unwrap(), "-0.0005 EOS");
+		assert_eq!(format_asset(i64::MAX, symbol(18)).unwrap(), "9.223372036854775807 EOS");
+		assert_eq!(format_asset(i64::MIN, symbol(18)).unwrap(), "-9.223372036854775808 EOS");
+		assert!(format_asset(1, symbol(19)).is_err());
+		assert!(format_asset(1, symbol(255)).is_err());
+	}
+
+	#[test]
+	fn structs_arrays_optionals_and_variants_are_decoded() {
+		let decoded = abi().decode_action("order", &order(Some("hi"), 0)).unwrap();
+		assert_eq!(decoded, json!({
+			"owner": AccountName::from(0).to_string(),
+			"ids": [1, 2, 3],
+			"note": "hi",
+			"payment": ["asset", "43.0000 EOS"],
+			"extra": null,
+		}));
+
+		let mut data = order(None, 1);
+		data.push(9);
+		let decoded = abi().decode_action("order", &data).unwrap();
+		assert_eq!(decoded["note"], Value::Null);
+		assert_eq!(decoded["payment"], json!(["uint32", 7]));
+		assert_eq!(decoded["extra"], json!(9));
+	}
+
+	#[test]
+	fn truncated_data_is_refused() {
+		let data = order(Some("hi"), 0);
+		for len in 0..data.len() {
+			assert!(abi().decode_action("order", &data[..len]).is_err(), "{} bytes", len);
+		}
+	}
+
+	#[test]
+	fn length_over_remaining_bytes_is_refused() {
+		// owner, then an array of u16 claiming u32::MAX elements
+		let mut data = 0u64.to_le_bytes().to_vec();
+		data.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0x0f]);
+		assert!(abi().decode_action("order", &data).is_err());
+		assert!(abi().decode("string", &[0x05, b'a']).is_err());
+		assert!(abi().decode("bytes", &[0xff, 0xff, 0xff, 0xff, 0x0f]).is_err());
+	}
+
+	#[test]
+	fn unknown_variant_index_is_refused() {
+		assert!(abi().decode_action("order", &order(None, 2)).is_err());
+	}
+
+	#[test]
+	fn abi_cache_keeps_latest_versions() {
+		let json = r#"{ "actions": [] }"#;
+		for sequence in 0..(MAX_ABI_VERSIONS as u32 + 2) {
+			register_abi("abi.cache", sequence, json).unwrap();
+		}
+		assert!(!is_abi_cached("abi.cache", 0));
+		assert!(!is_abi_cached("abi.cache", 1));
+		assert!(is_abi_cached("abi.cache", 2));
+		assert!(is_abi_cached("abi.cache", MAX_ABI_VERSIONS as u32 + 1));
+		assert!(cached_abi("abi.cache", None).is_some());
+	}
+}
@@ -0,0 +1,184 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Merge proofs of actions in the same block, so they're sent with one header chain
+//! instead of carrying identical block headers and block id lists each.
+
+use crate::rpc_calls::{ActionProof, BifrostRuntime, ProveActionCall, ProveActionsCall};
+use codec::Encode;
+use core::marker::PhantomData;
+
+// proofs are in the same block only if they're verified by the same header and merkle
+fn target_of(call: &ProveActionCall<BifrostRuntime>) -> (u32, Vec<u8>, Vec<u8>) {
+	match call.block_headers.first() {
+		Some(header) => (header.block_header.block_num(), header.encode(), call.merkle.encode()),
+		None => (0, vec![], call.merkle.encode()),
+	}
+}
+
+/// Group proofs by the block they're in, keeping the order in which blocks first appear.
+/// A group has at most `max_size` proofs, the rest of the same block starts another group.
+pub fn group_by_target<I>(
+	calls:    Vec<(I, ProveActionCall<BifrostRuntime>)>,
+	max_size: usize,
+) -> Vec<Vec<(I, ProveActionCall<BifrostRuntime>)>> {
+	let mut targets: Vec<(u32, Vec<u8>, Vec<u8>)> = Vec::new();
+	let mut groups: Vec<Vec<(I, ProveActionCall<BifrostRuntime>)>> = Vec::new();
+	for (id, call) in calls.into_iter() {
+		let target = target_of(&call);
+		let position = targets.iter()
+			.zip(groups.iter())
+			.rposition(|(t, group)| *t == target && group.len() < max_size.max(1));
+		match position {
+			Some(position) => groups[position].push((id, call)),
+			None => {
+				targets.push(target);
+				groups.push(vec![(id, call)]);
+			}
+		}
+	}
+
+	groups
+}
+
+/// Turn proofs of the same block into one call, `calls` shouldn't be empty. The longest
+/// header chain among them is shared, it proves the block as well as any shorter one does.
+pub fn share_headers(calls: Vec<ProveActionCall<BifrostRuntime>>) -> ProveActionsCall<BifrostRuntime> {
+	let longest = calls.iter()
+		.enumerate()
+		.max_by_key(|(index, call)| (call.block_headers.len(), std::cmp::Reverse(*index)))
+		.map(|(index, _)| index)
+		.unwrap_or_default();
+	let (merkle, block_headers, block_ids_list) = {
+		let call = &calls[longest];
+		(call.merkle.clone(), call.block_headers.clone(), call.block_ids_list.clone())
+	};
+
+	let proofs = calls.into_iter()
+		.map(|call| ActionProof {
+			action: call.action,
+			action_receipt: call.action_receipt,
+			action_merkle_paths: call.action_merkle_paths,
+			trx_id: call.trx_id,
+		})
+		.collect();
+
+	ProveActionsCall {
+		proofs,
+		merkle,
+		block_headers,
+		block_ids_list,
+		_runtime: PhantomData,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::header;
+	use eos_chain::{
+		AccountName, Action, ActionName, ActionReceipt, Checksum256, Digest, IncrementalMerkle,
+		PermissionLevel, PermissionName, UnsignedInt, utils::flat_map::FlatMap,
+	};
+	use std::str::FromStr;
+
+	// a transfer proven by 15 headers, an id list every 12 blocks, like what bridge_plugin collects
+	fn transfer_proof(block_num: u32, seq: u64) -> ProveActionCall<BifrostRuntime> {
+		let mut data = vec![0u8; 8 + 8 + 16];
+		data.extend_from_slice(b"bifrost-address@bifrost:EOS");
+		let action = Action {
+			account: AccountName::from_str("eosio.token").unwrap(),
+			name: ActionName::from_str("transfer").unwrap(),
+			authorization: vec![PermissionLevel {
+				actor: AccountName::from_str("alice").unwrap(),
+				permission: PermissionName::from_str("active").unwrap(),
+			}],
+			data,
+		};
+		let action_receipt = ActionReceipt {
+			receiver: AccountName::from_str("bifrostcross").unwrap(),
+			act_digest: action.digest().unwrap(),
+			global_sequence: seq,
+			recv_sequence: seq,
+			auth_sequence: FlatMap::assign(vec![(AccountName::from_str("alice").unwrap(), seq)]),
+			code_sequence: UnsignedInt::from(1u32),
+			abi_sequence: UnsignedInt::from(1u32),
+		};
+		let block_headers = (0..15).map(|i| header(block_num + 12 * i, 1)).collect();
+		let mut block_ids_list = vec![vec![]];
+		block_ids_list.extend((0..14).map(|_| vec![Checksum256::from([7u8; 32]); 10]));
+
+		ProveActionCall {
+			action,
+			action_receipt,
+			action_merkle_paths: vec![Checksum256::from([3u8; 32]); 4],
+			merkle: IncrementalMerkle::new(block_num as u64, vec![Checksum256::from([5u8; 32]); 20]),
+			block_headers,
+			block_ids_list,
+			trx_id: Checksum256::from([seq as u8; 32]),
+			_runtime: PhantomData,
+		}
+	}
+
+	#[test]
+	fn proofs_of_same_block_are_grouped() {
+		let calls = vec![
+			(0, transfer_proof(1000, 1)),
+			(1, transfer_proof(2000, 2)),
+			(2, transfer_proof(1000, 3)),
+		];
+		let groups = group_by_target(calls, 20);
+		let ids: Vec<Vec<usize>> = groups.iter().map(|g| g.iter().map(|(id, _)| *id).collect()).collect();
+		assert_eq!(ids, vec![vec![0, 2], vec![1]]);
+	}
+
+	#[test]
+	fn group_size_is_capped() {
+		let calls = (0..5).map(|i| (i, transfer_proof(1000, i as u64))).collect();
+		let groups = group_by_target(calls, 2);
+		let sizes: Vec<usize> = groups.iter().map(|g| g.len()).collect();
+		assert_eq!(sizes, vec![2, 2, 1]);
+	}
+
+	#[test]
+	fn shared_call_keeps_every_action() {
+		let calls: Vec<_> = (0..3).map(|i| transfer_proof(1000, i)).collect();
+		let shared = share_headers(calls.clone());
+		assert_eq!(shared.proofs.len(), 3);
+		assert_eq!(shared.block_headers, calls[0].block_headers);
+		assert_eq!(shared.block_ids_list, calls[0].block_ids_list);
+		for (proof, call) in shared.proofs.iter().zip(calls.iter()) {
+			assert_eq!(proof.action_receipt, call.action_receipt);
+			assert_eq!(proof.trx_id, call.trx_id);
+		}
+	}
+
+	#[test]
+	fn shared_call_saves_most_bytes() {
+		for &n in [1usize, 2, 5, 10, 20, 50].iter() {
+			let calls: Vec<_> = (0..n).map(|i| transfer_proof(1000, i as u64)).collect();
+			let separate: usize = calls.iter().map(|call| call.encode().len()).sum();
+			let shared = share_headers(calls).encode().len();
+
+			if n > 1 {
+				// headers and id lists dominate the payload, sharing them must save most of the bytes
+				assert!(shared * 2 < separate, "{} proofs take {} bytes shared, {} one by one", n, shared, separate);
+			} else {
+				assert!(shared <= separate + 1);
+			}
+		}
+	}
+}
@@ -0,0 +1,161 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// Maps an EOS token to its asset on bifrost, configured by `assets` in relay config.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct AssetMapping {
+	pub contract:  String,
+	pub symbol:    String,
+	pub precision: u8,
+	pub asset_id:  u32,
+	pub decimals:  u8,
+}
+
+// 10^(from - to) if `from` is the wider one, or 10^(to - from)
+fn scale_between(from: u8, to: u8) -> Result<u128, crate::Error> {
+	let exp = if from > to { from - to } else { to - from };
+	10u128.checked_pow(exp as u32)
+		.ok_or_else(|| crate::Error::UnknownAsset(format!("precision {} and decimals {} are too far apart", from, to)))
+}
+
+impl AssetMapping {
+	// EOS amount is integer in unit of 10^-precision, bifrost balance is in unit of 10^-decimals
+	pub fn to_bifrost_balance(&self, amount: i64) -> Result<u128, crate::Error> {
+		if amount < 0 {
+			return Err(crate::Error::UnknownAsset("negative amount".to_owned()));
+		}
+		let amount = amount as u128;
+		let scale = scale_between(self.precision, self.decimals)?;
+
+		if self.decimals >= self.precision {
+			amount.checked_mul(scale)
+				.ok_or_else(|| crate::Error::UnknownAsset("amount overflows bifrost balance".to_owned()))
+		} else {
+			if amount % scale != 0 {
+				return Err(crate::Error::UnknownAsset(format!("amount loses precision on bifrost with {} decimals", self.decimals)));
+			}
+			Ok(amount / scale)
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClassifiedTransfer {
+	pub mapping: AssetMapping,
+	pub amount:  i64,
+}
+
+impl ClassifiedTransfer {
+	pub fn to_json(&self) -> Value {
+		json!({
+			"contract": self.mapping.contract,
+			"symbol": self.mapping.symbol,
+			"asset_id": self.mapping.asset_id,
+			"amount": self.amount,
+		})
+	}
+}
+
+pub fn default_assets() -> Vec<AssetMapping> {
+	// the only token relayed before asset registry exists
+	vec![AssetMapping {
+		contract: "eosio.token".to_owned(),
+		symbol: "EOS".to_owned(),
+		precision: 4,
+		asset_id: 0,
+		decimals: 12,
+	}]
+}
+
+/// Find bifrost asset for a transfer of `quantity` like `43.0000 EOS` issued by `contract`.
+pub fn classify_transfer(contract: &str, quantity: &str) -> Result<ClassifiedTransfer, crate::Error> {
+	let (amount, precision, symbol) = parse_quantity(quantity)?;
+	let config = crate::config::relay_config();
+	let mapping = config.assets.iter()
+		.find(|a| a.contract == contract && a.symbol == symbol && a.precision == precision)
+		.ok_or_else(|| crate::Error::UnknownAsset(format!("{}@{} with precision {}", symbol, contract, precision)))?;
+
+	// bifrost credits the amount itself, but refuse what it cannot hold exactly
+	mapping.to_bifrost_balance(amount)?;
+
+	Ok(ClassifiedTransfer {
+		mapping: mapping.clone(),
+		amount,
+	})
+}
+
+/// Whether any token of `contract` could be relayed.
+pub fn is_asset_contract(contract: &str) -> bool {
+	crate::config::relay_config().assets.iter().any(|a| a.contract == contract)
+}
+
+// "43.0000 EOS" => (430000, 4, "EOS")
+pub fn parse_quantity(quantity: &str) -> Result<(i64, u8, String), crate::Error> {
+	let invalid = || crate::Error::UnknownAsset(format!("invalid quantity '{}'", quantity));
+
+	let mut parts = quantity.trim().split_whitespace();
+	let (number, symbol) = match (parts.next(), parts.next(), parts.next()) {
+		(Some(number), Some(symbol), None) => (number, symbol),
+		_ => return Err(invalid()),
+	};
+	if symbol.is_empty() || symbol.len() > 7 || !symbol.chars().all(|c| c.is_ascii_uppercase()) {
+		return Err(invalid());
+	}
+
+	let (integer, fraction) = match number.find('.') {
+		Some(dot) => (&number[..dot], &number[dot + 1..]),
+		None => (number, ""),
+	};
+	let digits = format!("{}{}", integer, fraction);
+	let amount = digits.parse::<i64>().map_err(|_| invalid())?;
+
+	Ok((amount, fraction.len() as u8, symbol.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn mapping(precision: u8, decimals: u8) -> AssetMapping {
+		AssetMapping {
+			contract: "eosio.token".to_owned(),
+			symbol: "EOS".to_owned(),
+			precision,
+			asset_id: 0,
+			decimals,
+		}
+	}
+
+	#[test]
+	fn eos_amount_to_bifrost_balance() {
+		assert_eq!(mapping(4, 12).to_bifrost_balance(430_000).unwrap(), 430_000 * 100_000_000);
+		assert_eq!(mapping(4, 4).to_bifrost_balance(430_000).unwrap(), 430_000);
+		assert_eq!(mapping(6, 4).to_bifrost_balance(430_000).unwrap(), 4_300);
+		assert!(mapping(6, 4).to_bifrost_balance(430_001).is_err());
+		assert!(mapping(4, 12).to_bifrost_balance(-1).is_err());
+	}
+
+	#[test]
+	fn far_apart_precision_overflows() {
+		assert!(mapping(0, 39).to_bifrost_balance(1).is_err());
+		assert!(mapping(255, 0).to_bifrost_balance(1).is_err());
+		assert!(mapping(0, 255).to_bifrost_balance(1).is_err());
+		assert!(mapping(4, 38).to_bifrost_balance(i64::MAX).is_err());
+	}
+}
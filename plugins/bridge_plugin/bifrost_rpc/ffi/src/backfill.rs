@@ -0,0 +1,488 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Relay a past block range, for blocks missed while the relayer was down. Blocks are
+//! read from a `BlockSource`, matching transfers and schedule changes are turned into
+//! proofs the same way bridge_plugin does for live blocks, then sent by the submission queue.
+
+use crate::rpc_calls::{BifrostRuntime, ChangeScheduleCall, ProveActionCall};
+use core::marker::PhantomData;
+use eos_chain::{
+	Action, ActionReceipt, Checksum256, Digest, IncrementalMerkle, ProducerAuthoritySchedule, SignedBlockHeader,
+};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// A header is taken every 12 blocks, one producer round.
+pub const HEADER_INTERVAL: u32 = 12;
+pub const MAX_HEADERS: usize = 15;
+pub const MAX_IDS_PER_LIST: usize = 10;
+/// Blocks after a target block that are needed to prove it.
+pub const LOOKAHEAD: u32 = HEADER_INTERVAL * (MAX_HEADERS as u32 + 1);
+
+/// An action that might be relayed, `receipt_index` points into `SourceBlock::action_receipts`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SourceAction {
+	pub action:        Action,
+	pub receipt_index: usize,
+	pub trx_id:        Checksum256,
+}
+
+/// A new producer schedule, and the hash nodeos keeps for it as pending schedule hash.
+/// It's proposed by one block, and becomes active after another block turns final.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct SourceScheduleChange {
+	pub legacy_schedule_hash: Checksum256,
+	pub schedule:             ProducerAuthoritySchedule,
+}
+
+/// What backfill needs to know about a block.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SourceBlock {
+	pub block_num:         u32,
+	pub id:                Checksum256,
+	pub header:            SignedBlockHeader,
+	/// Merkle of block ids before this block.
+	pub blockroot_merkle:  IncrementalMerkle,
+	/// Every action receipt of this block in execution order.
+	#[serde(default)]
+	pub action_receipts:   Vec<ActionReceipt>,
+	#[serde(default)]
+	pub actions:           Vec<SourceAction>,
+	/// Schedule that becomes active after this block.
+	#[serde(default)]
+	pub schedule_change:   Option<SourceScheduleChange>,
+	/// Schedule proposed by the header of this block, see `ship::proposed_schedule`.
+	#[serde(default)]
+	pub proposed_schedule: Option<SourceScheduleChange>,
+}
+
+/// Where blocks come from, like blocks.log or a state-history node.
+pub trait BlockSource {
+	/// `None` if the source doesn't have this block.
+	fn block(&mut self, block_num: u32) -> Result<Option<SourceBlock>, crate::Error>;
+}
+
+/// Stand-in of a state-history websocket, replays blocks recorded as a json array.
+#[derive(Clone, Debug, Default)]
+pub struct MockStateHistory {
+	blocks: BTreeMap<u32, SourceBlock>,
+}
+
+impl MockStateHistory {
+	pub fn new(blocks: Vec<SourceBlock>) -> Self {
+		Self { blocks: blocks.into_iter().map(|b| (b.block_num, b)).collect() }
+	}
+
+	pub fn from_file(path: impl AsRef<Path>) -> Result<Self, crate::Error> {
+		let content = std::fs::read_to_string(path).map_err(|e| crate::Error::ConfigError(e.to_string()))?;
+		let blocks: Vec<SourceBlock> = serde_json::from_str(&content).map_err(|e| crate::Error::ConfigError(e.to_string()))?;
+		Ok(Self::new(blocks))
+	}
+}
+
+impl BlockSource for MockStateHistory {
+	fn block(&mut self, block_num: u32) -> Result<Option<SourceBlock>, crate::Error> {
+		Ok(self.blocks.get(&block_num).cloned())
+	}
+}
+
+/// Headers and id lists proving `target`, the same as `collect_incremental_merkle_and_blocks`
+/// of bridge_plugin: a header every 12 blocks up to 15 headers, and at most 10 ids of the
+/// blocks between two headers. `None` if there're not enough blocks after target yet.
+pub fn collect_headers(
+	target: u32,
+	blocks: &BTreeMap<u32, SourceBlock>,
+) -> Option<(Vec<SignedBlockHeader>, Vec<Vec<Checksum256>>)> {
+	let first = blocks.get(&target)?;
+	let mut block_headers = Vec::with_capacity(MAX_HEADERS);
+	block_headers.push(first.header.clone());
+	let mut last_header_num = target;
+
+	let mut block_ids_list: Vec<Vec<Checksum256>> = Vec::with_capacity(MAX_HEADERS);
+	block_ids_list.push(Vec::new());
+	block_ids_list.push(Vec::with_capacity(MAX_IDS_PER_LIST));
+	for (&num, block) in blocks.range(target + 1..) {
+		if num - last_header_num == HEADER_INTERVAL {
+			block_headers.push(block.header.clone());
+			last_header_num = num;
+			if block_headers.len() >= MAX_HEADERS {
+				break;
+			}
+			block_ids_list.push(Vec::with_capacity(MAX_IDS_PER_LIST));
+		} else if let Some(ids) = block_ids_list.last_mut() {
+			if ids.len() < MAX_IDS_PER_LIST {
+				ids.push(block.id);
+			}
+		}
+	}
+
+	if block_headers.len() < MAX_HEADERS {
+		return None;
+	}
+
+	Some((block_headers, block_ids_list))
+}
+
+/// Proofs and schedule changes found in a block range.
+#[derive(Clone, Debug, Default)]
+pub struct BackfillOutput {
+	pub schedule_changes: Vec<ChangeScheduleCall<BifrostRuntime>>,
+	pub proofs:           Vec<ProveActionCall<BifrostRuntime>>,
+	/// Target blocks that cannot be proven, the source doesn't have enough blocks.
+	pub incomplete:       Vec<u32>,
+}
+
+// a proposed schedule becomes active after the last block of the old schedule version, like
+// bridge_plugin sees `header.schedule_version + 1 == active_schedule.version` there
+fn activated_schedule(
+	target:    u32,
+	window:    &BTreeMap<u32, SourceBlock>,
+	proposals: &BTreeMap<u32, SourceScheduleChange>,
+) -> Result<Option<SourceScheduleChange>, u32> {
+	let block = match window.get(&target) {
+		Some(block) => block,
+		None => return Ok(None),
+	};
+	if let Some(ref change) = block.schedule_change {
+		return Ok(Some(change.clone()));
+	}
+	let next = match window.get(&(target + 1)) {
+		Some(next) => next,
+		None => return Ok(None),
+	};
+	let version = block.header.block_header.schedule_version;
+	if next.header.block_header.schedule_version != version + 1 {
+		return Ok(None);
+	}
+
+	// the proposal was read before, unless it's older than the range
+	match proposals.range(..=target).next_back() {
+		Some((_, proposal)) if proposal.schedule.version == version + 1 => Ok(Some(proposal.clone())),
+		_ => Err(target),
+	}
+}
+
+/// Read blocks `from..=to` and what's needed to prove them, build a proof for every
+/// action matching relay rules and a schedule change for every new producer schedule.
+/// A schedule change is found by the schedule version of headers, its schedule is the
+/// latest one proposed in the range or given by the source.
+pub fn collect(
+	source: &mut impl BlockSource,
+	from:   u32,
+	to:     u32,
+) -> Result<BackfillOutput, crate::Error> {
+	// eos blocks start from 1, and a target is proven with the block before it
+	let from = from.max(2);
+	let mut output = BackfillOutput::default();
+	let mut window: BTreeMap<u32, SourceBlock> = BTreeMap::new();
+	let mut proposals: BTreeMap<u32, SourceScheduleChange> = BTreeMap::new();
+	let mut next_to_read = from - 1;
+	let mut source_exhausted = false;
+
+	for target in from..=to {
+		// keep blocks from target - 1 to target + lookahead
+		while !source_exhausted && next_to_read <= target.saturating_add(LOOKAHEAD) {
+			match source.block(next_to_read)? {
+				Some(block) => {
+					if let Some(ref proposal) = block.proposed_schedule {
+						proposals.insert(next_to_read, proposal.clone());
+					}
+					window.insert(next_to_read, block);
+				}
+				None if next_to_read > target => source_exhausted = true,
+				None => (),
+			}
+			next_to_read += 1;
+		}
+		window = window.split_off(&(target - 1));
+
+		let schedule_change = match activated_schedule(target, &window, &proposals) {
+			Ok(change) => change,
+			Err(target) => {
+				println!("[!] Schedule changes at block {}, but it's proposed before the range\n", target);
+				output.incomplete.push(target);
+				continue;
+			}
+		};
+		let block = match window.get(&target) {
+			Some(block) => block,
+			None => continue,
+		};
+		let wanted = block.actions.iter()
+			.filter(|act| {
+				let receiver = block.action_receipts.get(act.receipt_index).map(|r| r.receiver);
+				receiver.map(|r| crate::rules::match_action(&act.action, &r) != crate::rules::RuleMatch::Unmatched).unwrap_or(false)
+			})
+			.count();
+		if wanted == 0 && schedule_change.is_none() {
+			continue;
+		}
+
+		// blockroot merkle of the previous block, like bridge_plugin does
+		let (merkle, (block_headers, block_ids_list)) = match (window.get(&(target - 1)), collect_headers(target, &window)) {
+			(Some(prev), Some(headers)) => (prev.blockroot_merkle.clone(), headers),
+			_ => {
+				output.incomplete.push(target);
+				continue;
+			}
+		};
+		let block = &window[&target];
+
+		if let Some(change) = schedule_change {
+			output.schedule_changes.push(ChangeScheduleCall {
+				legacy_schedule_hash: change.legacy_schedule_hash,
+				schedule: change.schedule,
+				merkle: merkle.clone(),
+				block_headers: block_headers.clone(),
+				block_ids_list: block_ids_list.clone(),
+				_runtime: PhantomData,
+			});
+		}
+
+		let digests: Vec<Checksum256> = block.action_receipts.iter()
+			.map(|r| r.digest())
+			.collect::<Result<_, _>>()
+			.map_err(|_| crate::Error::InvalidCall("failed to get action receipt digest"))?;
+		for act in block.actions.iter() {
+			let receipt = match block.action_receipts.get(act.receipt_index) {
+				Some(receipt) => receipt,
+				None => continue,
+			};
+			if crate::rules::match_action(&act.action, &receipt.receiver) == crate::rules::RuleMatch::Unmatched {
+				continue;
+			}
+			output.proofs.push(ProveActionCall {
+				action: act.action.clone(),
+				action_receipt: receipt.clone(),
+				action_merkle_paths: crate::merkle::get_proof(act.receipt_index, digests.clone()),
+				merkle: merkle.clone(),
+				block_headers: block_headers.clone(),
+				block_ids_list: block_ids_list.clone(),
+				trx_id: act.trx_id,
+				_runtime: PhantomData,
+			});
+		}
+	}
+
+	Ok(output)
+}
+
+/// Summary of a backfill run.
+#[derive(Clone, Debug, Default)]
+pub struct BackfillReport {
+	pub schedule_changes: usize,
+	pub proofs:           usize,
+	pub incomplete:       Vec<u32>,
+	pub sent:             usize,
+	pub failed:           usize,
+	pub still_queued:     usize,
+}
+
+impl BackfillReport {
+	pub fn to_json(&self) -> serde_json::Value {
+		serde_json::json!({
+			"schedule_changes": self.schedule_changes,
+			"proofs": self.proofs,
+			"incomplete": self.incomplete,
+			"sent": self.sent,
+			"failed": self.failed,
+			"still_queued": self.still_queued,
+		})
+	}
+}
+
+/// Collect a block range and send everything through the submission queue, schedule changes
+/// before proofs depending on them. The queue is processed until nothing more could be sent.
+pub async fn run(
+	source:     &mut impl BlockSource,
+	from:       u32,
+	to:         u32,
+	urls:       Vec<String>,
+	signer:     String,
+	max_passes: usize,
+) -> Result<BackfillReport, crate::Error> {
+	let output = collect(source, from, to)?;
+	let mut report = BackfillReport {
+		schedule_changes: output.schedule_changes.len(),
+		proofs: output.proofs.len(),
+		incomplete: output.incomplete,
+		..Default::default()
+	};
+
+	let mut queued = Vec::new();
+	for call in output.schedule_changes.into_iter() {
+		queued.push(crate::queue::enqueue(crate::queue::Submission::ChangeSchedule(call))?);
+	}
+	for call in output.proofs.into_iter() {
+		queued.push(crate::queue::enqueue(crate::queue::Submission::ProveAction(call))?);
+	}
+
+	for pass in 0..max_passes {
+		let results = crate::queue::process(urls.clone(), signer.clone()).await?;
+		for result in results.iter().filter(|r| queued.contains(&r.key.id)) {
+			if result.success {
+				report.sent += 1;
+			} else {
+				println!("[!] Backfill of {} at block {} failed: {}\n", result.kind, result.key.block_num, result.msg);
+			}
+		}
+
+		let pending = crate::queue::pending_ids();
+		report.still_queued = queued.iter().filter(|id| pending.contains(id)).count();
+		// a dry run sends nothing, later passes would only report the same submissions again
+		if report.still_queued == 0 || crate::config::relay_config().dry_run {
+			break;
+		}
+		// a schedule change needs a block or two before proofs depending on it are ready
+		if pass + 1 < max_passes {
+			futures_timer::Delay::new(std::time::Duration::from_secs(6)).await;
+		}
+	}
+	report.failed = queued.len() - report.sent - report.still_queued;
+
+	Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::{action, header, id, receipt};
+
+	fn block(block_num: u32) -> SourceBlock {
+		SourceBlock {
+			block_num,
+			id: id(block_num, 0),
+			header: header(block_num, 1),
+			blockroot_merkle: IncrementalMerkle::new(block_num as u64, vec![id(block_num - 1, 0)]),
+			action_receipts: vec![],
+			actions: vec![],
+			schedule_change: None,
+			proposed_schedule: None,
+		}
+	}
+
+	// a transfer and an action no rule relays, both executed in `block_num`
+	fn block_with_transfer(block_num: u32) -> SourceBlock {
+		let (issue, transfer) = (action("eosio.token", "issue"), action("eosio.token", "transfer"));
+		let mut block = block(block_num);
+		block.action_receipts = vec![receipt(&issue, 1), receipt(&transfer, 2)];
+		block.actions = vec![
+			SourceAction { action: issue, receipt_index: 0, trx_id: id(100, 0) },
+			SourceAction { action: transfer, receipt_index: 1, trx_id: id(101, 0) },
+		];
+		block
+	}
+
+	fn source(target: u32, last: u32) -> MockStateHistory {
+		let blocks = (1..=last).map(|num| if num == target { block_with_transfer(num) } else { block(num) });
+		MockStateHistory::new(blocks.collect())
+	}
+
+	#[test]
+	fn collect_proves_matching_actions() {
+		let mut source = source(5, 5 + LOOKAHEAD);
+		let output = collect(&mut source, 1, 20).unwrap();
+
+		assert!(output.schedule_changes.is_empty());
+		assert!(output.incomplete.is_empty());
+		assert_eq!(output.proofs.len(), 1);
+
+		let proof = &output.proofs[0];
+		let target = block_with_transfer(5);
+		assert_eq!(proof.action, target.actions[1].action);
+		assert_eq!(proof.action_receipt, target.action_receipts[1]);
+		assert_eq!(proof.trx_id, id(101, 0));
+		let digests: Vec<Checksum256> = target.action_receipts.iter().map(|r| r.digest().unwrap()).collect();
+		assert_eq!(proof.action_merkle_paths, crate::merkle::get_proof(1, digests));
+		// blockroot merkle of the block before target
+		assert_eq!(proof.merkle, block(4).blockroot_merkle);
+
+		// a header every 12 blocks, ids of at most 10 blocks between them
+		assert_eq!(proof.block_headers.len(), MAX_HEADERS);
+		assert_eq!(proof.block_headers[0], header(5, 1));
+		assert_eq!(proof.block_headers[1], header(17, 1));
+		assert_eq!(proof.block_ids_list.len(), MAX_HEADERS);
+		assert!(proof.block_ids_list[0].is_empty());
+		assert_eq!(proof.block_ids_list[1], (6..16).map(id).collect::<Vec<_>>());
+	}
+
+	// schedule 2 is proposed by block 3, blocks from 9 on are produced by it
+	fn source_with_schedule_change() -> MockStateHistory {
+		let blocks = (1..=8 + LOOKAHEAD).map(|num| {
+			let mut block = block(num);
+			if num >= 9 {
+				block.header = header(num, 2);
+			}
+			if num == 3 {
+				block.proposed_schedule = Some(proposal());
+			}
+			block
+		});
+		MockStateHistory::new(blocks.collect())
+	}
+
+	fn proposal() -> SourceScheduleChange {
+		SourceScheduleChange {
+			legacy_schedule_hash: id(200, 0),
+			schedule: ProducerAuthoritySchedule { version: 2, producers: vec![] },
+		}
+	}
+
+	#[test]
+	fn collect_changes_schedule_after_last_block_of_old_version() {
+		let mut source = source_with_schedule_change();
+		let output = collect(&mut source, 1, 20).unwrap();
+
+		assert!(output.proofs.is_empty());
+		assert!(output.incomplete.is_empty());
+		assert_eq!(output.schedule_changes.len(), 1);
+		let change = &output.schedule_changes[0];
+		assert_eq!(change.legacy_schedule_hash, id(200, 0));
+		assert_eq!(change.schedule, proposal().schedule);
+		assert_eq!(change.merkle, block(7).blockroot_merkle);
+		assert_eq!(change.block_headers[0], header(8, 1));
+	}
+
+	#[test]
+	fn collect_reports_schedule_proposed_before_range() {
+		let mut source = source_with_schedule_change();
+		let output = collect(&mut source, 5, 20).unwrap();
+
+		assert!(output.schedule_changes.is_empty());
+		assert_eq!(output.incomplete, vec![8]);
+	}
+
+	#[test]
+	fn collect_reports_blocks_without_enough_lookahead() {
+		let mut source = source(5, 100);
+		let output = collect(&mut source, 1, 20).unwrap();
+
+		assert!(output.proofs.is_empty());
+		assert_eq!(output.incomplete, vec![5]);
+	}
+
+	#[test]
+	fn collect_from_genesis_should_not_underflow() {
+		let mut source = source(1, 1 + LOOKAHEAD);
+		let output = collect(&mut source, 0, 1).unwrap();
+
+		// block 1 has no block before it to prove with
+		assert!(output.proofs.is_empty());
+		assert!(output.incomplete.is_empty());
+	}
+}
@@ -0,0 +1,154 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Relay a past block range to bifrost.
+//!
+//! backfill --url ws://127.0.0.1:9944 --signer //Alice --from 1000 --to 2000 --mock-ship blocks.json
+
+use rpc_client::backfill::{self, BackfillReport, BlockSource, MockStateHistory};
+use rpc_client::block_log::BlockLog;
+
+const USAGE: &str = "usage: backfill --url <bifrost ws url> --signer <seed> --from <block> --to <block> \
+	(--mock-ship <recorded blocks json> | --blocks-dir <dir of blocks.log>) \
+	[--config <relay config json>] [--store <relay store dir>] [--max-passes <n>] [--dry-run]";
+
+#[derive(Debug, Default)]
+struct Args {
+	urls:       Vec<String>,
+	signer:     String,
+	from:       u32,
+	to:         u32,
+	mock_ship:  Option<String>,
+	blocks_dir: Option<String>,
+	config:     Option<String>,
+	store:      Option<String>,
+	max_passes: usize,
+	dry_run:    bool,
+}
+
+fn parse_args() -> Result<Args, String> {
+	let mut args = Args { max_passes: 10, ..Default::default() };
+	let mut iter = std::env::args().skip(1);
+	while let Some(arg) = iter.next() {
+		let mut value = || iter.next().ok_or(format!("missing value of {}", arg));
+		match arg.as_str() {
+			"--url" => args.urls.push(value()?),
+			"--signer" => args.signer = value()?,
+			"--from" => args.from = value()?.parse().map_err(|_| "--from must be a block number".to_owned())?,
+			"--to" => args.to = value()?.parse().map_err(|_| "--to must be a block number".to_owned())?,
+			"--mock-ship" => args.mock_ship = Some(value()?),
+			"--blocks-dir" => args.blocks_dir = Some(value()?),
+			"--config" => args.config = Some(value()?),
+			"--store" => args.store = Some(value()?),
+			"--max-passes" => args.max_passes = value()?.parse().map_err(|_| "--max-passes must be a number".to_owned())?,
+			"--dry-run" => args.dry_run = true,
+			_ => return Err(format!("unknown argument {}", arg)),
+		}
+	}
+
+	if args.urls.is_empty() || args.signer.is_empty() {
+		return Err("--url and --signer are required".to_owned());
+	}
+	if args.from == 0 || args.from > args.to {
+		return Err("--from and --to must be a valid block range".to_owned());
+	}
+
+	Ok(args)
+}
+
+fn init_config(args: &Args) -> Result<(), String> {
+	let mut config: serde_json::Value = match args.config {
+		Some(ref path) => {
+			let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+			serde_json::from_str(&content).map_err(|e| e.to_string())?
+		}
+		None => serde_json::json!({}),
+	};
+	if args.dry_run {
+		config["dry_run"] = serde_json::Value::Bool(true);
+	}
+	rpc_client::config::init_relay_config(&config.to_string()).map_err(|e| e.to_string())?;
+
+	Ok(())
+}
+
+fn run(source: &mut impl BlockSource, args: &Args) -> Result<BackfillReport, rpc_client::Error> {
+	futures::executor::block_on(
+		backfill::run(source, args.from, args.to, args.urls.clone(), args.signer.clone(), args.max_passes)
+	)
+}
+
+fn main() {
+	let args = match parse_args() {
+		Ok(args) => args,
+		Err(e) => {
+			eprintln!("{}\n{}", e, USAGE);
+			std::process::exit(2);
+		}
+	};
+
+	if let Err(e) = init_config(&args) {
+		eprintln!("failed to load relay config: {}", e);
+		std::process::exit(1);
+	}
+	if let Some(ref path) = args.store {
+		if let Err(e) = rpc_client::store::open(path).and_then(|_| rpc_client::queue::restore()) {
+			eprintln!("failed to open relay store: {}", e);
+			std::process::exit(1);
+		}
+	}
+
+	let report = match (args.mock_ship.as_ref(), args.blocks_dir.as_ref()) {
+		(Some(path), None) => match MockStateHistory::from_file(path) {
+			Ok(mut source) => run(&mut source, &args),
+			Err(e) => {
+				eprintln!("failed to load recorded blocks: {}", e);
+				std::process::exit(1);
+			}
+		},
+		(None, Some(dir)) => match BlockLog::open(dir) {
+			Ok(mut source) => {
+				eprintln!("blocks.log has no traces, only producer schedule changes are relayed, actions need --mock-ship");
+				run(&mut source, &args)
+			}
+			Err(e) => {
+				eprintln!("failed to open block log: {}", e);
+				std::process::exit(1);
+			}
+		},
+		_ => {
+			eprintln!("exactly one block source is required\n{}", USAGE);
+			std::process::exit(2);
+		}
+	};
+	let _ = rpc_client::store::close();
+
+	match report {
+		Ok(report) => {
+			println!("{}", report.to_json());
+			if !report.incomplete.is_empty() {
+				eprintln!("blocks {:?} need more blocks after them to be proven", report.incomplete);
+			}
+			if report.failed > 0 || report.still_queued > 0 {
+				std::process::exit(1);
+			}
+		}
+		Err(e) => {
+			eprintln!("backfill failed: {}", e);
+			std::process::exit(1);
+		}
+	}
+}
@@ -0,0 +1,180 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Relay irreversible blocks of a stock nodeos to bifrost, by its state-history websocket.
+//!
+//! ship_relayer --ship-url ws://127.0.0.1:8080 --url ws://127.0.0.1:9944 --signer //Alice \
+//!     --start 1000 --blocks-dir data/blocks
+
+use eos_chain::IncrementalMerkle;
+use rpc_client::backfill;
+use rpc_client::block_log::BlockLog;
+use rpc_client::ship::{RecordedSession, ShipSource, Transport, WsTransport};
+
+const USAGE: &str = "usage: ship_relayer (--ship-url <state history ws url> | --recording <session json>) \
+	--url <bifrost ws url> --signer <seed> --start <block> \
+	(--merkle-seed <merkle json> | --blocks-dir <dir of blocks.log>) [--chunk <blocks>] \
+	[--follow-head] [--config <relay config json>] [--store <relay store dir>] [--dry-run]";
+
+#[derive(Debug, Default)]
+struct Args {
+	ship_url:    Option<String>,
+	recording:   Option<String>,
+	urls:        Vec<String>,
+	signer:      String,
+	start:       u32,
+	merkle_seed: Option<String>,
+	blocks_dir:  Option<String>,
+	chunk:       u32,
+	follow_head: bool,
+	config:      Option<String>,
+	store:       Option<String>,
+	dry_run:     bool,
+}
+
+fn parse_args() -> Result<Args, String> {
+	let mut args = Args { chunk: 60, ..Default::default() };
+	let mut iter = std::env::args().skip(1);
+	while let Some(arg) = iter.next() {
+		let mut value = || iter.next().ok_or(format!("missing value of {}", arg));
+		match arg.as_str() {
+			"--ship-url" => args.ship_url = Some(value()?),
+			"--recording" => args.recording = Some(value()?),
+			"--url" => args.urls.push(value()?),
+			"--signer" => args.signer = value()?,
+			"--start" => args.start = value()?.parse().map_err(|_| "--start must be a block number".to_owned())?,
+			"--merkle-seed" => args.merkle_seed = Some(value()?),
+			"--blocks-dir" => args.blocks_dir = Some(value()?),
+			"--chunk" => args.chunk = value()?.parse().map_err(|_| "--chunk must be a number".to_owned())?,
+			"--config" => args.config = Some(value()?),
+			"--store" => args.store = Some(value()?),
+			"--follow-head" => args.follow_head = true,
+			"--dry-run" => args.dry_run = true,
+			_ => return Err(format!("unknown argument {}", arg)),
+		}
+	}
+
+	if args.urls.is_empty() || args.signer.is_empty() {
+		return Err("--url and --signer are required".to_owned());
+	}
+	if args.ship_url.is_some() == args.recording.is_some() {
+		return Err("exactly one of --ship-url and --recording is required".to_owned());
+	}
+	if args.start == 0 || args.chunk == 0 {
+		return Err("--start and --chunk must be positive".to_owned());
+	}
+
+	Ok(args)
+}
+
+fn init_config(args: &Args) -> Result<(), String> {
+	let mut config: serde_json::Value = match args.config {
+		Some(ref path) => {
+			let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+			serde_json::from_str(&content).map_err(|e| e.to_string())?
+		}
+		None => serde_json::json!({}),
+	};
+	if args.dry_run {
+		config["dry_run"] = serde_json::Value::Bool(true);
+	}
+	rpc_client::config::init_relay_config(&config.to_string()).map_err(|e| e.to_string())?;
+
+	Ok(())
+}
+
+// blockroot merkle of the start block, ship doesn't carry block state
+fn merkle_seed(args: &Args) -> Result<IncrementalMerkle, String> {
+	match (args.merkle_seed.as_ref(), args.blocks_dir.as_ref()) {
+		(Some(path), None) => {
+			let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+			serde_json::from_str(&content).map_err(|e| e.to_string())
+		}
+		(None, Some(dir)) => {
+			let mut log = BlockLog::open(dir).map_err(|e| e.to_string())?;
+			log.blockroot_merkle(args.start).map_err(|e| e.to_string())
+		}
+		_ => Err("exactly one of --merkle-seed and --blocks-dir is required".to_owned()),
+	}
+}
+
+fn ship_source<T: Transport>(transport: T, args: &Args, merkle: IncrementalMerkle) -> ShipSource<T> {
+	if args.follow_head {
+		ShipSource::following_head(transport, args.start, merkle)
+	} else {
+		ShipSource::new(transport, args.start, merkle)
+	}
+}
+
+fn relay(mut source: ShipSource<impl Transport>, args: &Args) -> Result<(), rpc_client::Error> {
+	// the start block only provides merkle and header for the next one
+	let mut from = args.start + 1;
+	loop {
+		let to = from + args.chunk - 1;
+		let report = futures::executor::block_on(
+			backfill::run(&mut source, from, to, args.urls.clone(), args.signer.clone(), 1)
+		)?;
+		println!("[+] Relayed blocks {} to {}: {}\n", from, to, report.to_json());
+		if source.is_finished() {
+			if !report.incomplete.is_empty() {
+				println!("[!] Session ended before blocks {:?} could be proven\n", report.incomplete);
+			}
+			return Ok(());
+		}
+		from = to + 1;
+	}
+}
+
+fn main() {
+	let args = match parse_args() {
+		Ok(args) => args,
+		Err(e) => {
+			eprintln!("{}\n{}", e, USAGE);
+			std::process::exit(2);
+		}
+	};
+
+	if let Err(e) = init_config(&args) {
+		eprintln!("failed to load relay config: {}", e);
+		std::process::exit(1);
+	}
+	let merkle = match merkle_seed(&args) {
+		Ok(merkle) => merkle,
+		Err(e) => {
+			eprintln!("failed to get blockroot merkle of block {}: {}", args.start, e);
+			std::process::exit(1);
+		}
+	};
+	if let Some(ref path) = args.store {
+		if let Err(e) = rpc_client::store::open(path).and_then(|_| rpc_client::queue::restore()) {
+			eprintln!("failed to open relay store: {}", e);
+			std::process::exit(1);
+		}
+	}
+
+	let result = match (args.ship_url.as_ref(), args.recording.as_ref()) {
+		(Some(url), _) => WsTransport::connect(url).and_then(|ws| relay(ship_source(ws, &args, merkle), &args)),
+		(_, Some(path)) => RecordedSession::from_file(path)
+			.and_then(|session| relay(ship_source(session, &args, merkle), &args)),
+		_ => unreachable!("checked by parse_args"),
+	};
+	let _ = rpc_client::store::close();
+
+	if let Err(e) = result {
+		eprintln!("relayer stopped: {}", e);
+		std::process::exit(1);
+	}
+}
@@ -0,0 +1,393 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Irreversible blocks shared by every pending proof of bridge_plugin. Only what a proof
+//! needs is kept: header, id and blockroot merkle. Blocks older than the oldest pending
+//! proof are dropped, so the cache grows with the proof backlog instead of a fixed size.
+
+use crate::backfill::{collect_headers, SourceBlock, HEADER_INTERVAL, MAX_HEADERS};
+use codec::{Decode, Encode};
+use eos_chain::{Checksum256, IncrementalMerkle, SignedBlockHeader};
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+static BLOCK_CACHE: Lazy<RwLock<BlockCache>> = Lazy::new(|| RwLock::new(BlockCache::default()));
+
+pub const BLOCK_CACHE_TREE: &str = "block_cache";
+
+/// Blocks kept when no proof is pending, a new proof needs its previous block.
+const MIN_TAIL: u32 = 2;
+
+#[derive(Clone, Debug, PartialEq, Encode, Decode)]
+pub struct CachedBlock {
+	pub id:               Checksum256,
+	pub header:           SignedBlockHeader,
+	pub blockroot_merkle: IncrementalMerkle,
+}
+
+/// What a proof of a block takes from the cache.
+#[derive(Clone, Debug)]
+pub struct ProofParts {
+	/// Blockroot merkle of the block before target.
+	pub merkle:         IncrementalMerkle,
+	pub block_headers:  Vec<SignedBlockHeader>,
+	pub block_ids_list: Vec<Vec<Checksum256>>,
+}
+
+// headers and ids are collected by `backfill::collect_headers`, which reads source blocks
+fn source_block(block_num: u32, block: CachedBlock) -> SourceBlock {
+	SourceBlock {
+		block_num,
+		id: block.id,
+		header: block.header,
+		blockroot_merkle: block.blockroot_merkle,
+		action_receipts: Vec::new(),
+		actions: Vec::new(),
+		schedule_change: None,
+		proposed_schedule: None,
+	}
+}
+
+#[derive(Debug, Default)]
+pub struct BlockCache {
+	blocks: BTreeMap<u32, SourceBlock>,
+	/// Block number of the oldest pending proof.
+	floor:  Option<u32>,
+}
+
+impl BlockCache {
+	/// Add a block, returns block numbers dropped by retention.
+	pub fn insert(&mut self, block_num: u32, block: CachedBlock) -> Vec<u32> {
+		self.blocks.insert(block_num, source_block(block_num, block));
+		self.trim()
+	}
+
+	/// `None` once no proof is pending, returns block numbers dropped by retention.
+	pub fn set_floor(&mut self, floor: Option<u32>) -> Vec<u32> {
+		self.floor = floor;
+		self.trim()
+	}
+
+	fn trim(&mut self) -> Vec<u32> {
+		let last = match self.blocks.keys().next_back() {
+			Some(&last) => last,
+			None => return Vec::new(),
+		};
+		let mut keep_from = last.saturating_sub(MIN_TAIL - 1);
+		if let Some(floor) = self.floor {
+			// a proof of floor needs the blockroot merkle of floor - 1
+			keep_from = keep_from.min(floor.saturating_sub(1));
+		}
+
+		let kept = self.blocks.split_off(&keep_from);
+		let dropped = std::mem::replace(&mut self.blocks, kept);
+		dropped.keys().cloned().collect()
+	}
+
+	/// Drop cached blocks that were forked out, returns their block numbers.
+	pub fn drop_forked(&mut self, forked: &[(u32, Checksum256)]) -> Vec<u32> {
+		let dropped: Vec<u32> = forked.iter()
+			.filter(|(block_num, id)| self.blocks.get(block_num).map(|block| block.id) == Some(*id))
+			.map(|(block_num, _)| *block_num)
+			.collect();
+		for block_num in dropped.iter() {
+			self.blocks.remove(block_num);
+		}
+
+		dropped
+	}
+
+	pub fn len(&self) -> usize {
+		self.blocks.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.blocks.is_empty()
+	}
+
+	/// Whether every block from `target - 1` to the last header of its proof is cached.
+	pub fn is_ready(&self, target: u32) -> bool {
+		if target < 2 {
+			return false;
+		}
+		let last_header = last_header(target);
+		self.blocks.range(target - 1..=last_header).count() == (last_header - target + 2) as usize
+	}
+
+	/// First block a proof of `target` needs that is not cached, `None` once ready.
+	pub fn next_missing(&self, target: u32) -> Option<u32> {
+		if target < 2 {
+			return None;
+		}
+		(target - 1..=last_header(target)).find(|block_num| !self.blocks.contains_key(block_num))
+	}
+
+	/// A block read back from block log carries no blockroot merkle, it follows from the block before.
+	pub fn follow(&self, block_num: u32, id: Checksum256, header: SignedBlockHeader) -> Result<CachedBlock, crate::Error> {
+		let previous = block_num.checked_sub(1)
+			.and_then(|previous| self.blocks.get(&previous))
+			.ok_or(crate::Error::InvalidCall("cannot rebuild a block whose previous block is not cached"))?;
+		let mut blockroot_merkle = previous.blockroot_merkle.clone();
+		blockroot_merkle.append(previous.id)
+			.map_err(|_| crate::Error::InvalidCall("failed to append block id to merkle"))?;
+
+		Ok(CachedBlock { id, header, blockroot_merkle })
+	}
+
+	/// Merkle, headers and ids proving `target`, `None` until `is_ready`.
+	pub fn proof_parts(&self, target: u32) -> Option<ProofParts> {
+		if !self.is_ready(target) {
+			return None;
+		}
+		let merkle = self.blocks.get(&(target - 1))?.blockroot_merkle.clone();
+		let (block_headers, block_ids_list) = collect_headers(target, &self.blocks)?;
+
+		Some(ProofParts { merkle, block_headers, block_ids_list })
+	}
+
+	pub fn to_json(&self) -> Value {
+		json!({
+			"blocks": self.blocks.len(),
+			"first": self.blocks.keys().next(),
+			"last": self.blocks.keys().next_back(),
+			"floor": self.floor,
+		})
+	}
+}
+
+fn last_header(target: u32) -> u32 {
+	target + HEADER_INTERVAL * (MAX_HEADERS as u32 - 1)
+}
+
+fn cache_error() -> crate::Error {
+	crate::Error::ConfigError("block cache is poisoned".to_owned())
+}
+
+// cached blocks survive a restart if relay store is opened
+fn persist(block_num: u32, block: &CachedBlock) {
+	if !crate::store::is_open() {
+		return;
+	}
+	if let Err(e) = crate::store::put(BLOCK_CACHE_TREE, &block_num.to_be_bytes(), &block.encode()) {
+		println!("[!] Failed to persist cached block {} due to: {:?}\n", block_num, e);
+	}
+}
+
+fn unpersist(dropped: &[u32]) {
+	if !crate::store::is_open() {
+		return;
+	}
+	for block_num in dropped.iter() {
+		if let Err(e) = crate::store::remove(BLOCK_CACHE_TREE, &block_num.to_be_bytes()) {
+			println!("[!] Failed to remove cached block {} due to: {:?}\n", block_num, e);
+		}
+	}
+}
+
+/// Load blocks cached before last shutdown, returns how many are loaded.
+pub fn restore() -> Result<usize, crate::Error> {
+	let mut restored = Vec::new();
+	crate::store::for_each(BLOCK_CACHE_TREE, |key, mut value| {
+		let mut block_num = [0u8; 4];
+		if key.len() != block_num.len() {
+			println!("[!] Skip a cached block with a broken key\n");
+			return;
+		}
+		block_num.copy_from_slice(key);
+		match CachedBlock::decode(&mut value) {
+			Ok(block) => restored.push((u32::from_be_bytes(block_num), block)),
+			Err(e) => println!("[!] Skip a broken cached block: {:?}\n", e),
+		}
+	})?;
+
+	let mut cache = BLOCK_CACHE.write().map_err(|_| cache_error())?;
+	let count = restored.len();
+	for (block_num, block) in restored.into_iter() {
+		cache.blocks.insert(block_num, source_block(block_num, block));
+	}
+
+	Ok(count)
+}
+
+pub fn insert(block_num: u32, block: CachedBlock) -> Result<usize, crate::Error> {
+	persist(block_num, &block);
+	let mut cache = BLOCK_CACHE.write().map_err(|_| cache_error())?;
+	let dropped = cache.insert(block_num, block);
+	unpersist(&dropped);
+
+	Ok(cache.len())
+}
+
+/// Cache a block missed while nodeos was down, read back from block log.
+pub fn rebuild(block_num: u32, id: Checksum256, header: SignedBlockHeader) -> Result<usize, crate::Error> {
+	let mut cache = BLOCK_CACHE.write().map_err(|_| cache_error())?;
+	let block = cache.follow(block_num, id, header)?;
+	persist(block_num, &block);
+	let dropped = cache.insert(block_num, block);
+	unpersist(&dropped);
+	if dropped.contains(&block_num) {
+		return Err(crate::Error::InvalidCall("rebuilt block is older than the floor of block cache"));
+	}
+
+	Ok(cache.len())
+}
+
+pub fn set_floor(floor: Option<u32>) -> Result<usize, crate::Error> {
+	let mut cache = BLOCK_CACHE.write().map_err(|_| cache_error())?;
+	let dropped = cache.set_floor(floor);
+	unpersist(&dropped);
+
+	Ok(cache.len())
+}
+
+pub fn drop_forked(forked: &[(u32, Checksum256)]) -> Result<Vec<u32>, crate::Error> {
+	let mut cache = BLOCK_CACHE.write().map_err(|_| cache_error())?;
+	let dropped = cache.drop_forked(forked);
+	unpersist(&dropped);
+
+	Ok(dropped)
+}
+
+pub fn is_ready(target: u32) -> bool {
+	BLOCK_CACHE.read().map(|cache| cache.is_ready(target)).unwrap_or(false)
+}
+
+pub fn next_missing(target: u32) -> Option<u32> {
+	BLOCK_CACHE.read().ok().and_then(|cache| cache.next_missing(target))
+}
+
+pub fn proof_parts(target: u32) -> Option<ProofParts> {
+	BLOCK_CACHE.read().ok().and_then(|cache| cache.proof_parts(target))
+}
+
+pub fn cache_status() -> Value {
+	BLOCK_CACHE.read().map(|cache| cache.to_json()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::testing::{checksum, header, id};
+
+	fn block(block_num: u32) -> CachedBlock {
+		CachedBlock {
+			id: id(block_num, 0),
+			header: header(block_num, 1),
+			blockroot_merkle: IncrementalMerkle::new(block_num as u64, Vec::new()),
+		}
+	}
+
+	fn cache_of(range: std::ops::RangeInclusive<u32>) -> BlockCache {
+		let mut cache = BlockCache::default();
+		for num in range {
+			cache.insert(num, block(num));
+		}
+		cache
+	}
+
+	#[test]
+	fn only_tail_is_kept_without_pending_proofs() {
+		let cache = cache_of(100..=110);
+		assert_eq!(cache.len(), MIN_TAIL as usize);
+	}
+
+	#[test]
+	fn floor_keeps_blocks_from_previous_block() {
+		let mut cache = BlockCache::default();
+		cache.insert(100, block(100));
+		cache.set_floor(Some(101));
+		for num in 101..=300 {
+			cache.insert(num, block(num));
+		}
+		assert_eq!(cache.len(), 201);
+
+		let dropped = cache.set_floor(Some(201));
+		assert_eq!(dropped, (100..200).collect::<Vec<_>>());
+		let dropped = cache.set_floor(None);
+		assert_eq!(dropped.len(), 101 - MIN_TAIL as usize);
+	}
+
+	#[test]
+	fn ready_once_last_header_is_cached() {
+		let mut cache = BlockCache::default();
+		cache.set_floor(Some(101));
+		let last_header = 101 + HEADER_INTERVAL * (MAX_HEADERS as u32 - 1);
+		for num in 100..last_header {
+			cache.insert(num, block(num));
+		}
+		assert!(!cache.is_ready(101));
+		assert!(cache.proof_parts(101).is_none());
+
+		cache.insert(last_header, block(last_header));
+		let parts = cache.proof_parts(101).expect("enough blocks");
+		assert_eq!(parts.merkle, block(100).blockroot_merkle);
+		assert_eq!(parts.block_headers.len(), MAX_HEADERS);
+		assert_eq!(parts.block_ids_list.len(), MAX_HEADERS);
+		assert_eq!(parts.block_ids_list[1].len(), crate::backfill::MAX_IDS_PER_LIST);
+	}
+
+	#[test]
+	fn only_forked_blocks_are_dropped() {
+		let mut cache = cache_of(100..=101);
+		let other_fork = checksum(0xff);
+
+		assert!(cache.drop_forked(&[(101, other_fork)]).is_empty());
+		assert_eq!(cache.drop_forked(&[(101, block(101).id), (102, block(102).id)]), vec![101]);
+		assert_eq!(cache.len(), 1);
+	}
+
+	#[test]
+	fn gap_is_not_ready() {
+		let mut cache = BlockCache::default();
+		cache.set_floor(Some(101));
+		for num in (100..=400).filter(|&n| n != 150) {
+			cache.insert(num, block(num));
+		}
+		assert!(!cache.is_ready(101));
+		assert!(cache.is_ready(152));
+	}
+
+	#[test]
+	fn gap_is_rebuilt_from_previous_block() {
+		let mut cache = BlockCache::default();
+		cache.set_floor(Some(101));
+		for num in (100..=400).filter(|&n| n != 150) {
+			cache.insert(num, block(num));
+		}
+		// a merkle append can follow
+		let previous = CachedBlock { blockroot_merkle: IncrementalMerkle::new(0, Vec::new()), ..block(149) };
+		cache.insert(149, previous.clone());
+		assert_eq!(cache.next_missing(101), Some(150));
+
+		let rebuilt = cache.follow(150, id(150, 0), header(150, 1)).expect("previous block is cached");
+		let mut merkle = previous.blockroot_merkle;
+		merkle.append(previous.id).unwrap();
+		assert_eq!(rebuilt.blockroot_merkle, merkle);
+
+		cache.insert(150, rebuilt);
+		assert_eq!(cache.next_missing(101), None);
+		assert!(cache.is_ready(101));
+	}
+
+	#[test]
+	fn gap_without_previous_block_is_not_rebuilt() {
+		let cache = cache_of(100..=101);
+		assert!(cache.follow(103, id(103, 0), header(103, 1)).is_err());
+	}
+}
@@ -0,0 +1,438 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Random access to blocks in nodeos `blocks.log`, the same layout as libraries/chain/block_log.cpp.
+//!
+//! blocks.log starts with the log version, first block number (version > 1), genesis state
+//! or chain id, and a totem. Every block is a packed `signed_block` followed by its own
+//! position. blocks.index holds the position of each block as u64, from the first block on.
+
+use crate::backfill::{BlockSource, SourceBlock};
+use eos_chain::{Checksum256, IncrementalMerkle, Read, SignedBlock};
+use std::fs::File;
+use std::io::{Read as _, Seek, SeekFrom};
+use std::path::Path;
+
+pub const MIN_SUPPORTED_VERSION: u32 = 1;
+pub const MAX_SUPPORTED_VERSION: u32 = 3;
+const POS_SIZE: u64 = std::mem::size_of::<u64>() as u64;
+
+fn log_error(e: impl ToString) -> crate::Error {
+	crate::Error::BlockLogError(e.to_string())
+}
+
+pub struct BlockLog {
+	log:             File,
+	index:           File,
+	log_size:        u64,
+	version:         u32,
+	first_block_num: u32,
+	last_block_num:  u32,
+	// blockroot merkle of a block, the merkle of later blocks is built on it
+	merkle_seed:     Option<(u32, IncrementalMerkle)>,
+	merkle_cache:    Option<(u32, IncrementalMerkle)>,
+}
+
+impl BlockLog {
+	/// Open blocks.log and blocks.index in `dir`.
+	pub fn open(dir: impl AsRef<Path>) -> Result<Self, crate::Error> {
+		let dir = dir.as_ref();
+		let mut log = File::open(dir.join("blocks.log")).map_err(log_error)?;
+		let index = File::open(dir.join("blocks.index")).map_err(log_error)?;
+
+		let mut buf = [0u8; 4];
+		log.read_exact(&mut buf).map_err(log_error)?;
+		let version = u32::from_le_bytes(buf);
+		if version < MIN_SUPPORTED_VERSION || version > MAX_SUPPORTED_VERSION {
+			return Err(log_error(format!("unsupported block log version {}", version)));
+		}
+		let first_block_num = if version > 1 {
+			log.read_exact(&mut buf).map_err(log_error)?;
+			u32::from_le_bytes(buf)
+		} else {
+			1
+		};
+		if first_block_num == 0 {
+			return Err(log_error("first block number is 0"));
+		}
+
+		let log_size = log.metadata().map_err(log_error)?.len();
+		let index_size = index.metadata().map_err(log_error)?.len();
+		if index_size % POS_SIZE != 0 {
+			return Err(log_error("blocks.index is not aligned to block positions"));
+		}
+		let blocks = (index_size / POS_SIZE) as u32;
+		// an empty log has last block before the first one
+		let last_block_num = first_block_num + blocks - 1;
+
+		// a log from genesis needs no seed, merkle before block 1 is empty
+		let merkle_seed = if first_block_num == 1 {
+			Some((1, IncrementalMerkle::new(0, Vec::new())))
+		} else {
+			None
+		};
+
+		Ok(Self {
+			log,
+			index,
+			log_size,
+			version,
+			first_block_num,
+			last_block_num,
+			merkle_seed,
+			merkle_cache: None,
+		})
+	}
+
+	pub fn version(&self) -> u32 {
+		self.version
+	}
+
+	pub fn first_block_num(&self) -> u32 {
+		self.first_block_num
+	}
+
+	pub fn last_block_num(&self) -> u32 {
+		self.last_block_num
+	}
+
+	pub fn contains(&self, block_num: u32) -> bool {
+		block_num >= self.first_block_num && block_num <= self.last_block_num
+	}
+
+	/// A log that doesn't start from genesis needs the blockroot merkle of one of its
+	/// blocks, taken from nodeos or a snapshot, before `blockroot_merkle` works.
+	pub fn set_merkle_seed(&mut self, block_num: u32, merkle: IncrementalMerkle) {
+		self.merkle_seed = Some((block_num, merkle));
+		self.merkle_cache = None;
+	}
+
+	fn position(&mut self, block_num: u32) -> Result<u64, crate::Error> {
+		if !self.contains(block_num) {
+			return Err(log_error(format!("block {} is not in block log", block_num)));
+		}
+		let mut buf = [0u8; 8];
+		self.index.seek(SeekFrom::Start(POS_SIZE * (block_num - self.first_block_num) as u64)).map_err(log_error)?;
+		self.index.read_exact(&mut buf).map_err(log_error)?;
+
+		Ok(u64::from_le_bytes(buf))
+	}
+
+	/// Packed `signed_block` of a block, checked against the position written after it.
+	pub fn read_raw(&mut self, block_num: u32) -> Result<Vec<u8>, crate::Error> {
+		let pos = self.position(block_num)?;
+		let end = if block_num < self.last_block_num {
+			self.position(block_num + 1)?
+		} else {
+			self.log_size
+		};
+		if end < pos + POS_SIZE {
+			return Err(log_error(format!("block {} has a broken position in blocks.index", block_num)));
+		}
+
+		let mut bytes = vec![0u8; (end - pos) as usize];
+		self.log.seek(SeekFrom::Start(pos)).map_err(log_error)?;
+		self.log.read_exact(&mut bytes).map_err(log_error)?;
+
+		let mut trailer = [0u8; 8];
+		trailer.copy_from_slice(&bytes[bytes.len() - POS_SIZE as usize..]);
+		if u64::from_le_bytes(trailer) != pos {
+			return Err(log_error(format!("blocks.log and blocks.index disagree at block {}", block_num)));
+		}
+		bytes.truncate(bytes.len() - POS_SIZE as usize);
+
+		Ok(bytes)
+	}
+
+	pub fn read_block(&mut self, block_num: u32) -> Result<SignedBlock, crate::Error> {
+		let bytes = self.read_raw(block_num)?;
+		decode_block(&bytes, block_num)
+	}
+
+	pub fn block_id(&mut self, block_num: u32) -> Result<Checksum256, crate::Error> {
+		let block = self.read_block(block_num)?;
+		block.signed_block_header.block_header.id().map_err(|e| log_error(format!("{:?}", e)))
+	}
+
+	/// Merkle of all block ids before `block_num`, the `blockroot_merkle` of its block state.
+	/// Blocks are appended from the closest known merkle, so reading forward is cheap.
+	pub fn blockroot_merkle(&mut self, block_num: u32) -> Result<IncrementalMerkle, crate::Error> {
+		let (mut num, mut merkle) = match (self.merkle_cache.take(), self.merkle_seed.clone()) {
+			(Some(cache), _) if cache.0 <= block_num => cache,
+			(_, Some(seed)) if seed.0 <= block_num => seed,
+			_ => return Err(log_error(format!("no merkle seed at or before block {}", block_num))),
+		};
+		while num < block_num {
+			let id = self.block_id(num)?;
+			merkle.append(id).map_err(|e| log_error(format!("{:?}", e)))?;
+			num += 1;
+		}
+		self.merkle_cache = Some((num, merkle.clone()));
+
+		Ok(merkle)
+	}
+}
+
+fn decode_block(bytes: &[u8], block_num: u32) -> Result<SignedBlock, crate::Error> {
+	let mut pos = 0;
+	let block = SignedBlock::read(bytes, &mut pos).map_err(|e| log_error(format!("{:?}", e)))?;
+	let num = block.signed_block_header.block_header.block_num();
+	if num != block_num {
+		return Err(log_error(format!("expect block {}, but found block {}", block_num, num)));
+	}
+
+	Ok(block)
+}
+
+/// blocks.log has headers, ids and merkles, and producer schedules proposed by headers,
+/// which backfill turns into schedule changes. It has no traces, so actions are never
+/// found from it, they need a state-history node.
+impl BlockSource for BlockLog {
+	fn block(&mut self, block_num: u32) -> Result<Option<SourceBlock>, crate::Error> {
+		if !self.contains(block_num) {
+			return Ok(None);
+		}
+		let bytes = self.read_raw(block_num)?;
+		let block = decode_block(&bytes, block_num)?;
+		let id = block.signed_block_header.block_header.id().map_err(|e| log_error(format!("{:?}", e)))?;
+		let proposed_schedule = crate::ship::proposed_schedule(&bytes)?;
+		let blockroot_merkle = self.blockroot_merkle(block_num)?;
+
+		Ok(Some(SourceBlock {
+			block_num,
+			id,
+			header: block.signed_block_header,
+			blockroot_merkle,
+			action_receipts: Vec::new(),
+			actions: Vec::new(),
+			schedule_change: None,
+			proposed_schedule,
+		}))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Write;
+
+	// signed_block of blocks 1 to 3, packed by hand in the fc::raw layout of libraries/chain
+	// block.hpp: a signed_block_header, no transactions and no block extensions. Block 2
+	// proposes a legacy schedule by `new_producers`, block 3 a producer_authority_schedule
+	// by the producer schedule change extension. Ids, hashes and merkle nodes below are
+	// sha256 computed by python hashlib over the same bytes.
+	const BLOCKS: [&str; 3] = [
+		concat!(
+			"802b704d", "00000020637a973b", "0000", // timestamp, producer, confirmed
+			"0000000000000000000000000000000000000000000000000000000000000000", // previous
+			"1111111111111111111111111111111111111111111111111111111111111111", // transaction_mroot
+			"2222222222222222222222222222222222222222222222222222222222222222", // action_mroot
+			"00000000", "00", "00", // schedule_version, new_producers, header_extensions
+			"00", "0000000000000000000000000000000000000000000000000000000000000000", // k1 signature
+			"000000000000000000000000000000000000000000000000000000000000000000",
+			"00", "00", // transactions, block_extensions
+		),
+		concat!(
+			"802b704d", "00000020637a973b", "0000",
+			"0000000175b7eb78c698b87f4275643425b5dacaf083ad5c7f7fc4320c7a9034",
+			"1111111111111111111111111111111111111111111111111111111111111111",
+			"2222222222222222222222222222222222222222222222222222222222222222",
+			"00000000",
+			"01", "01000000", "01", "00000020637a973b", // new_producers { 1, [{ bifrost, PUBLIC_KEY }] }
+			"00", "02c0ded2bc1f1305fb0faac5e6c03ee3a1924234985427b6167ca569d13df435cf",
+			"00",
+			"00", "0000000000000000000000000000000000000000000000000000000000000000",
+			"000000000000000000000000000000000000000000000000000000000000000000",
+			"00", "00",
+		),
+		concat!(
+			"802b704d", "00000020637a973b", "0000",
+			"00000002db44233d480e77a2d565ee5d15d1d5daf80fd0e48170d120a1a9912b",
+			"1111111111111111111111111111111111111111111111111111111111111111",
+			"2222222222222222222222222222222222222222222222222222222222222222",
+			"00000000", "00",
+			"01", "0100", "37", "02000000", "01", "00000020637a973b", // { 2, [{ bifrost, { 1, [{ PUBLIC_KEY, 1 }] } }] }
+			"00", "01000000", "01",
+			"00", "02c0ded2bc1f1305fb0faac5e6c03ee3a1924234985427b6167ca569d13df435cf", "0100",
+			"00", "0000000000000000000000000000000000000000000000000000000000000000",
+			"000000000000000000000000000000000000000000000000000000000000000000",
+			"00", "00",
+		),
+	];
+	const IDS: [&str; 3] = [
+		"0000000175b7eb78c698b87f4275643425b5dacaf083ad5c7f7fc4320c7a9034",
+		"00000002db44233d480e77a2d565ee5d15d1d5daf80fd0e48170d120a1a9912b",
+		"000000031df790673c4305aa1fb018a4887b0a14a82e22f7dcbfcdbf746e9285",
+	];
+	// canonical pair of ids of block 1 and 2
+	const ROOT_1_2: &str = "fc16c0a8e31ebe87937e38149d7fb054316d57c6270c70eb43f558859e4f5c93";
+	// packed new_producers of block 2, and schedule extension of block 3
+	const LEGACY_SCHEDULE_HASH: &str = "5329f140c8fd38f3cc3b4c07cff495503bfe44fb4f8466c6b24c366a6358234d";
+	const SCHEDULE_HASH: &str = "ba5c66a42d65aeaf366367bec918fad16186034d69d9d6eacf41de6a2e6ab363";
+	const PUBLIC_KEY: &str = "EOS6MRyAjQq8ud7hVNYcfnVPJqcVpscN5So8BhtHuGYqET5GDW5CV";
+
+	fn block_bytes(block_num: u32) -> Vec<u8> {
+		hex::decode(BLOCKS[block_num as usize - 1]).expect("valid hex")
+	}
+
+	fn checksum(hex: &str) -> Checksum256 {
+		let mut bytes = [0u8; 32];
+		bytes.copy_from_slice(&hex::decode(hex).expect("valid hex"));
+		Checksum256::from(bytes)
+	}
+
+	fn temp_dir(name: &str) -> std::path::PathBuf {
+		let dir = std::env::temp_dir().join(format!("bifrost-block-log-{}-{}", name, std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	// a log header of version 3, followed by fixture blocks from `first_block_num` on
+	fn write_log(dir: &Path, first_block_num: u32, last_block_num: u32, broken_trailer: bool) {
+		let mut log = Vec::new();
+		log.extend_from_slice(&3u32.to_le_bytes());
+		log.extend_from_slice(&first_block_num.to_le_bytes());
+		log.extend_from_slice(&[7u8; 32]); // chain id
+		log.extend_from_slice(&u64::MAX.to_le_bytes()); // totem
+
+		let mut index = Vec::new();
+		for block_num in first_block_num..=last_block_num {
+			let pos = log.len() as u64;
+			log.extend_from_slice(&block_bytes(block_num));
+			let trailer = if broken_trailer { pos + 1 } else { pos };
+			log.extend_from_slice(&trailer.to_le_bytes());
+			index.extend_from_slice(&pos.to_le_bytes());
+		}
+
+		File::create(dir.join("blocks.log")).unwrap().write_all(&log).unwrap();
+		File::create(dir.join("blocks.index")).unwrap().write_all(&index).unwrap();
+	}
+
+	#[test]
+	fn open_should_read_block_range() {
+		let dir = temp_dir("range");
+		write_log(&dir, 2, 3, false);
+
+		let log = BlockLog::open(&dir).unwrap();
+		assert_eq!(log.version(), 3);
+		assert_eq!(log.first_block_num(), 2);
+		assert_eq!(log.last_block_num(), 3);
+		assert!(log.contains(2));
+		assert!(!log.contains(1));
+		assert!(!log.contains(4));
+	}
+
+	#[test]
+	fn read_raw_should_strip_trailing_position() {
+		let dir = temp_dir("raw");
+		write_log(&dir, 1, 3, false);
+
+		let mut log = BlockLog::open(&dir).unwrap();
+		assert_eq!(log.read_raw(1).unwrap(), block_bytes(1));
+		assert_eq!(log.read_raw(3).unwrap(), block_bytes(3));
+		assert_eq!(log.read_raw(2).unwrap(), block_bytes(2));
+		assert!(log.read_raw(4).is_err());
+	}
+
+	#[test]
+	fn read_raw_should_reject_mismatched_position() {
+		let dir = temp_dir("mismatch");
+		write_log(&dir, 1, 1, true);
+
+		let mut log = BlockLog::open(&dir).unwrap();
+		assert!(log.read_raw(1).is_err());
+	}
+
+	#[test]
+	fn read_block_should_check_block_number() {
+		let dir = temp_dir("number");
+		write_log(&dir, 1, 3, false);
+		// block 1 at the position of block 100
+		let mut log = BlockLog::open(&dir).unwrap();
+		log.first_block_num = 100;
+		log.last_block_num = 102;
+		assert!(log.read_block(100).is_err());
+	}
+
+	#[test]
+	fn blocks_should_have_ids_and_merkles() {
+		let dir = temp_dir("blocks");
+		write_log(&dir, 1, 3, false);
+
+		let mut log = BlockLog::open(&dir).unwrap();
+		for block_num in 1..=3 {
+			assert_eq!(log.block_id(block_num).unwrap(), checksum(IDS[block_num as usize - 1]));
+		}
+
+		let block = log.block(3).unwrap().unwrap();
+		assert_eq!(block.block_num, 3);
+		assert_eq!(block.id, checksum(IDS[2]));
+		assert_eq!(block.header.block_header.previous, checksum(IDS[1]));
+		assert_eq!(block.blockroot_merkle, IncrementalMerkle::new(2, vec![checksum(IDS[1]), checksum(ROOT_1_2)]));
+		assert!(block.actions.is_empty());
+
+		assert_eq!(log.blockroot_merkle(1).unwrap(), IncrementalMerkle::new(0, vec![]));
+		assert_eq!(log.blockroot_merkle(2).unwrap(), IncrementalMerkle::new(1, vec![checksum(IDS[0])]));
+		assert!(log.block(4).unwrap().is_none());
+	}
+
+	#[test]
+	fn blocks_should_have_proposed_schedules() {
+		let dir = temp_dir("schedule");
+		write_log(&dir, 1, 3, false);
+
+		let mut log = BlockLog::open(&dir).unwrap();
+		assert_eq!(log.block(1).unwrap().unwrap().proposed_schedule, None);
+
+		// a legacy schedule is hashed as it's packed, and converted with threshold and weight 1
+		let legacy = log.block(2).unwrap().unwrap().proposed_schedule.unwrap();
+		assert_eq!(legacy.legacy_schedule_hash, checksum(LEGACY_SCHEDULE_HASH));
+		assert_eq!(legacy.schedule.version, 1);
+		assert_eq!(legacy.schedule.producers.len(), 1);
+		assert_eq!(legacy.schedule.producers[0].producer_name.to_string(), "bifrost");
+		let authority = &legacy.schedule.producers[0].authority.1;
+		assert_eq!(authority.threshold, 1);
+		assert_eq!(authority.keys[0].key.to_string(), PUBLIC_KEY);
+		assert_eq!(authority.keys[0].weight, 1);
+
+		let extension = log.block(3).unwrap().unwrap().proposed_schedule.unwrap();
+		assert_eq!(extension.legacy_schedule_hash, checksum(SCHEDULE_HASH));
+		assert_eq!(extension.schedule.version, 2);
+		assert_eq!(extension.schedule.producers[0].authority, legacy.schedule.producers[0].authority);
+	}
+
+	#[test]
+	fn merkle_needs_seed_after_genesis() {
+		let dir = temp_dir("seed");
+		write_log(&dir, 2, 3, false);
+
+		let mut log = BlockLog::open(&dir).unwrap();
+		assert!(log.blockroot_merkle(3).is_err());
+
+		log.set_merkle_seed(2, IncrementalMerkle::new(1, vec![checksum(IDS[0])]));
+		assert_eq!(log.blockroot_merkle(3).unwrap(), IncrementalMerkle::new(2, vec![checksum(IDS[1]), checksum(ROOT_1_2)]));
+	}
+
+	#[test]
+	fn open_should_reject_unknown_version() {
+		let dir = temp_dir("version");
+		File::create(dir.join("blocks.log")).unwrap().write_all(&9u32.to_le_bytes()).unwrap();
+		File::create(dir.join("blocks.index")).unwrap();
+
+		assert!(BlockLog::open(&dir).is_err());
+	}
+}
@@ -0,0 +1,105 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::assets::AssetMapping;
+use crate::rules::FilterRule;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::sync::{Arc, RwLock};
+
+static RELAY_CONFIG: Lazy<RwLock<Arc<RelayConfig>>> = Lazy::new(|| RwLock::new(Arc::new(RelayConfig::default())));
+
+/// Relayer settings, loaded once from the json passed by `init_relay_config`.
+/// Every field has a default, so an empty object `{}` is a valid config.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct RelayConfig {
+	/// Warn once the signer's free balance drops below this amount.
+	pub min_balance_warning: u128,
+	/// Fee assumed for a single submission when no better estimation is available.
+	pub estimated_fee: u128,
+	/// Refuse a submission if its estimated fee goes over this cap.
+	pub max_fee: Option<u128>,
+	/// Validate and encode calls, but never broadcast them.
+	pub dry_run: bool,
+	/// Max encoded size of the calls in one batch submission.
+	pub max_batch_bytes: usize,
+	/// Max number of proofs in one batch submission.
+	pub max_batch_calls: usize,
+	/// Max weight of one batch submission, a batch over it is split in half.
+	pub max_batch_weight: u64,
+	/// Prove actions of the same block with one shared header chain, ignored if bifrost has no `prove_actions`.
+	pub share_headers: bool,
+	/// How many encoded proofs are kept for retries, see `payload_cache`.
+	pub payload_cache_size: usize,
+	/// Seconds to keep finished outbox entries, or unresolved ones that nobody could resolve.
+	pub outbox_retention: u64,
+	/// Seconds before a signed or broadcast extrinsic is looked up on bifrost again.
+	pub outbox_stale_after: u64,
+	/// How many finalized blocks to look back for extrinsics sent before a restart.
+	pub outbox_reconcile_depth: u32,
+	/// Seconds to wait for the EOS transfer of a bifrost redeem request before it's expired.
+	pub redeem_timeout: u64,
+	/// Seconds to keep expired redeem requests and transfers that match no request.
+	pub redeem_retention: u64,
+	/// Network tag expected in the memo of inbound transfers.
+	pub memo_network: String,
+	/// Accepted ss58 prefix of memo addresses, addresses of other networks are refunded.
+	pub ss58_prefix: u8,
+	/// EOS tokens that could be relayed, and their assets on bifrost.
+	pub assets: Vec<AssetMapping>,
+	/// Which actions should be relayed, see `rules::FilterRule`.
+	pub rules: Vec<FilterRule>,
+}
+
+impl Default for RelayConfig {
+	fn default() -> Self {
+		Self {
+			min_balance_warning: 1_000_000_000_000, // 1 BNC
+			estimated_fee: 10_000_000_000,
+			max_fee: None,
+			dry_run: false,
+			max_batch_bytes: 512 * 1024,
+			max_batch_calls: 20,
+			max_batch_weight: 1_000_000_000_000,
+			share_headers: false,
+			payload_cache_size: 256,
+			outbox_retention: 7 * 24 * 3600,
+			outbox_stale_after: 300,
+			outbox_reconcile_depth: 256,
+			redeem_timeout: 3600,
+			redeem_retention: 7 * 24 * 3600,
+			memo_network: "bifrost".to_owned(),
+			ss58_prefix: 6, // bifrost
+			assets: crate::assets::default_assets(),
+			rules: crate::rules::default_rules(),
+		}
+	}
+}
+
+/// Current config, shared instead of copied since it's read on every submission.
+pub fn relay_config() -> Arc<RelayConfig> {
+	RELAY_CONFIG.read().map(|config| Arc::clone(&config)).unwrap_or_default()
+}
+
+pub fn init_relay_config(json: &str) -> Result<RelayConfig, crate::Error> {
+	let config: RelayConfig = serde_json::from_str(json).map_err(|e| crate::Error::ConfigError(e.to_string()))?;
+	crate::rules::load_rules(&config.rules)?;
+	let mut global = RELAY_CONFIG.write().map_err(|_| crate::Error::ConfigError("relay config is poisoned".to_owned()))?;
+	*global = Arc::new(config.clone());
+
+	Ok(config)
+}
@@ -0,0 +1,252 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::ffi_types::CrossOutRequestFFI;
+use crate::rpc_calls::{BifrostRuntime, CrossOutRequestEvent};
+use codec::{Decode, Encode};
+use eos_chain::AccountName;
+use futures::{channel::oneshot, future::{self, Either}};
+use once_cell::sync::Lazy;
+use sp_core::crypto::Ss58Codec;
+use std::{
+	ffi::CString,
+	str::FromStr,
+	sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+	thread,
+	time::{Duration, Instant},
+};
+use subxt::{Client, EventSubscription};
+
+pub type CrossOutCallback = extern "C" fn(*const CrossOutRequestFFI);
+
+static CROSS_OUT_CALLBACK: Lazy<Mutex<Option<CrossOutCallback>>> = Lazy::new(|| Mutex::new(None));
+static WATCHER: Lazy<Mutex<Option<Watcher>>> = Lazy::new(|| Mutex::new(None));
+
+const MIN_BACKOFF: u64 = 3; // seconds
+const MAX_BACKOFF: u64 = 60;
+
+/// A request from bifrost to send assets to an EOS account.
+#[derive(Clone, Debug, PartialEq, Encode, Decode)]
+pub struct CrossOutRequest {
+	pub request_id:      u64,
+	pub bifrost_account: String, // ss58 address
+	pub eos_recipient:   AccountName,
+	pub asset:           String,
+	pub amount:          u128,
+}
+
+impl CrossOutRequest {
+	pub fn from_event(event: CrossOutRequestEvent<BifrostRuntime>) -> Result<Self, crate::Error> {
+		let recipient = String::from_utf8(event.eos_recipient).map_err(|_| crate::Error::CStrConvertError)?;
+		let eos_recipient = AccountName::from_str(&recipient)
+			.map_err(|_| crate::Error::InvalidCall("eos recipient is not a valid account name"))?;
+		let asset = String::from_utf8(event.asset).map_err(|_| crate::Error::CStrConvertError)?;
+
+		Ok(Self {
+			request_id: event.request_id,
+			bifrost_account: event.bifrost_account.to_ss58check(),
+			eos_recipient,
+			asset,
+			amount: event.amount,
+		})
+	}
+}
+
+pub fn register_callback(callback: CrossOutCallback) {
+	if let Ok(mut cb) = CROSS_OUT_CALLBACK.lock() {
+		*cb = Some(callback);
+	}
+}
+
+// hand the request over to c++ side, strings only live during the callback.
+fn deliver(request: &CrossOutRequest) {
+	let callback = match CROSS_OUT_CALLBACK.lock().ok().and_then(|cb| *cb) {
+		Some(callback) => callback,
+		None => {
+			println!("[!] No callback for cross out request: {:?}\n", request);
+			return;
+		}
+	};
+
+	let bifrost_account = CString::new(request.bifrost_account.as_str()).unwrap_or_default();
+	let asset = CString::new(request.asset.as_str()).unwrap_or_default();
+	let amount = CString::new(request.amount.to_string()).unwrap_or_default();
+	let ffi = CrossOutRequestFFI {
+		request_id: request.request_id,
+		bifrost_account: bifrost_account.as_ptr(),
+		eos_recipient: request.eos_recipient,
+		asset: asset.as_ptr(),
+		amount: amount.as_ptr(),
+	};
+
+	callback(&ffi);
+}
+
+// the watching thread, `running` is checked between reconnects and `stop` wakes the thread up
+// while it's waiting for the next event.
+struct Watcher {
+	running: Arc<AtomicBool>,
+	stop:    oneshot::Sender<()>,
+	handle:  thread::JoinHandle<()>,
+}
+
+/// Watch finalized bifrost blocks in a background thread until `unsubscribe` is called.
+pub fn subscribe(url: String) -> Result<(), crate::Error> {
+	let mut watcher = WATCHER.lock().map_err(|_| crate::Error::SubxtError("cross out watcher is poisoned"))?;
+	if watcher.is_some() {
+		return Err(crate::Error::SubxtError("cross out requests have been subscribed"));
+	}
+
+	let running = Arc::new(AtomicBool::new(true));
+	let (stop, mut stopped) = oneshot::channel();
+	let flag = running.clone();
+	let handle = thread::spawn(move || {
+		let mut backoff = MIN_BACKOFF;
+		while flag.load(Ordering::SeqCst) {
+			let started = Instant::now();
+			match futures::executor::block_on(watch_cross_out_requests(&url, &mut stopped)) {
+				Ok(true) => {
+					println!("[!] Cross out subscription ended, reconnecting in {}s\n", backoff);
+				}
+				Ok(false) => break,
+				Err(e) => {
+					println!("[!] Cross out subscription got failure due to: {:?}, reconnecting in {}s\n", e, backoff);
+				}
+			}
+			// a session which lived long enough isn't a flapping one
+			if started.elapsed() > Duration::from_secs(MAX_BACKOFF) {
+				backoff = MIN_BACKOFF;
+			}
+			wait(&flag, backoff);
+			backoff = next_backoff(backoff);
+		}
+	});
+	*watcher = Some(Watcher { running, stop, handle });
+
+	Ok(())
+}
+
+/// Stop the watching thread and wait for it to exit, so no request is delivered after it returns.
+/// The lock is held meanwhile, a new subscription never runs beside the old thread.
+pub fn unsubscribe() {
+	if let Ok(mut watcher) = WATCHER.lock() {
+		if let Some(current) = watcher.take() {
+			current.running.store(false, Ordering::SeqCst);
+			let _ = current.stop.send(());
+			if current.handle.join().is_err() {
+				println!("[!] Cross out watcher exited with a panic\n");
+			}
+		}
+	}
+}
+
+fn next_backoff(backoff: u64) -> u64 {
+	(backoff * 2).min(MAX_BACKOFF)
+}
+
+// sleep in steps of one second so that unsubscribing isn't blocked by a long backoff
+fn wait(running: &AtomicBool, secs: u64) {
+	for _ in 0..secs {
+		if !running.load(Ordering::SeqCst) {
+			return;
+		}
+		thread::sleep(Duration::from_secs(1));
+	}
+}
+
+// returns whether the subscription should be reconnected, it's false once `stopped` is signaled.
+async fn watch_cross_out_requests(url: &str, stopped: &mut oneshot::Receiver<()>) -> Result<bool, crate::Error> {
+	let client: Client<BifrostRuntime> = subxt::ClientBuilder::new()
+		.set_url(url)
+		.build()
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to create subxt client"))?;
+
+	let sub = client.subscribe_finalized_events()
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to subscribe finalized events"))?;
+	let decoder = client.events_decoder();
+	let mut sub = EventSubscription::<BifrostRuntime>::new(sub, decoder);
+	sub.filter_event::<CrossOutRequestEvent<BifrostRuntime>>();
+
+	loop {
+		// a finalized block could take long to come, don't let it hold unsubscribing
+		let next = sub.next();
+		futures::pin_mut!(next);
+		let raw = match future::select(next, &mut *stopped).await {
+			Either::Left((Some(raw), _)) => raw,
+			Either::Left((None, _)) => return Ok(true),
+			Either::Right(_) => return Ok(false),
+		};
+
+		let raw = raw.map_err(|_| crate::Error::SubxtError("failed to decode bifrost events"))?;
+		let event = match CrossOutRequestEvent::<BifrostRuntime>::decode(&mut &raw.data[..]) {
+			Ok(event) => event,
+			Err(e) => {
+				println!("[!] Failed to decode cross out request due to: {:?}\n", e);
+				continue;
+			}
+		};
+
+		match CrossOutRequest::from_event(event) {
+			Ok(request) => {
+				crate::redeem::track(request.clone());
+				deliver(&request);
+			}
+			Err(e) => println!("[!] Invalid cross out request: {:?}\n", e),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn backoff_should_grow_to_limit() {
+		let mut backoff = MIN_BACKOFF;
+		let mut waits = vec![];
+		for _ in 0..7 {
+			waits.push(backoff);
+			backoff = next_backoff(backoff);
+		}
+		assert_eq!(waits, vec![3, 6, 12, 24, 48, 60, 60]);
+	}
+
+	#[test]
+	fn wait_should_return_once_stopped() {
+		let running = AtomicBool::new(false);
+		let start = Instant::now();
+		wait(&running, MAX_BACKOFF);
+		assert!(start.elapsed() < Duration::from_secs(1));
+	}
+
+	#[test]
+	fn unsubscribe_should_join_watcher_before_next_subscription() {
+		// nothing listens there, the watcher keeps reconnecting
+		let url = "ws://127.0.0.1:1".to_owned();
+		assert!(subscribe(url.clone()).is_ok());
+		assert!(subscribe(url.clone()).is_err());
+
+		let start = Instant::now();
+		unsubscribe();
+		assert!(start.elapsed() < Duration::from_secs(MIN_BACKOFF));
+		assert!(WATCHER.lock().unwrap().is_none());
+
+		assert!(subscribe(url).is_ok());
+		unsubscribe();
+	}
+}
@@ -15,6 +15,7 @@
 // along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::Error;
+use crate::rpc_calls::{BifrostRuntime, ChangeScheduleCall, ProveActionCall};
 use eos_chain::{
     Action, AccountName, ActionName, ActionReceipt, PermissionLevel, Checksum256,
     Signature, BlockHeader, Extension, utils::flat_map::FlatMap, UnsignedInt, PublicKey,
@@ -355,7 +356,7 @@ impl<'a> TryInto<SignedBlockHeader> for &'a SignedBlockHeaderFFI {
 #[derive(Clone, Debug)]
 #[repr(C)]
 pub struct ProducerAuthorityScheduleFFI {
-    pub version: c_uint,
+    pub version: u32,
     pub producers: *const ProducerAuthorityFFI, // should be 21 producers
     pub producers_size: usize,
 }
@@ -387,7 +388,7 @@ impl<'a> TryInto<ProducerAuthoritySchedule> for &'a ProducerAuthorityScheduleFFI
 #[repr(C)]
 pub struct ProducerAuthorityFFI {
     pub producer_name: AccountName,
-    pub tag: UnsignedInt,
+    pub tag: u32, // index of block_signing_authority variant
     pub v0_ffi: *const BlockSigningAuthorityV0FFI,
 }
 
@@ -399,7 +400,7 @@ impl<'a> TryInto<ProducerAuthority> for &'a ProducerAuthorityFFI {
         }
 
         let authority = {
-            let tag = self.tag.clone();
+            let tag = UnsignedInt::from(self.tag);
             let v0_ffi = &unsafe { ptr::read(self.v0_ffi) };
             let authority_v0: BlockSigningAuthorityV0 = v0_ffi.try_into()?;
             BlockSigningAuthority(tag, authority_v0)
@@ -468,6 +469,136 @@ impl<'a> TryInto<KeyWeight> for &'a KeyWeightFFI {
     }
 }
 
+pub(crate) fn block_headers_from_ffi(blocks_ffi: *const SignedBlockHeaderFFI, size: size_t) -> FFIResult<Vec<SignedBlockHeader>> {
+    let blocks_ffi = unsafe { slice::from_raw_parts(blocks_ffi, size) };
+    let mut block_headers = Vec::with_capacity(size);
+    for block in blocks_ffi.iter() {
+        block_headers.push(block.try_into()?);
+    }
+
+    Ok(block_headers)
+}
+
+pub(crate) fn ids_list_from_ffi(ids_list: *const Checksum256FFI, size: size_t) -> FFIResult<Vec<Vec<Checksum256>>> {
+    let mut block_ids_list: Vec<Vec<Checksum256>> = Vec::with_capacity(15);
+    block_ids_list.push(Vec::new());
+    let ids_list_ffi = unsafe { slice::from_raw_parts(ids_list, size) };
+    for ids in ids_list_ffi.iter().skip(1) { // skip first ids due to it's am empty list(null pointer)
+        block_ids_list.push(ids.try_into()?);
+    }
+
+    Ok(block_ids_list)
+}
+
+// all arguments of change_schedule_v2, not passed through ffi itself
+#[derive(Clone, Debug)]
+pub struct ChangeScheduleFFI {
+    pub legacy_schedule_hash: Checksum256,
+    pub schedule: *const ProducerAuthorityScheduleFFI,
+    pub imcre_merkle: *const IncrementalMerkleFFI,
+    pub blocks_ffi: *const SignedBlockHeaderFFI,
+    pub blocks_ffi_size: size_t,
+    pub ids_list: *const Checksum256FFI,
+    pub ids_list_size: size_t,
+}
+
+impl<'a> TryInto<ChangeScheduleCall<BifrostRuntime>> for &'a ChangeScheduleFFI {
+    type Error = Error;
+    fn try_into(self) -> Result<ChangeScheduleCall<BifrostRuntime>, Self::Error> {
+        if self.schedule.is_null() || self.imcre_merkle.is_null() || self.blocks_ffi.is_null() || self.ids_list.is_null() {
+            return Err(Error::NullPtr("ChangeScheduleFFI".to_owned()));
+        }
+
+        let schedule: ProducerAuthoritySchedule = {
+            let ffi = &unsafe { ptr::read(self.schedule) };
+            ffi.try_into()?
+        };
+        let merkle: IncrementalMerkle = {
+            let ffi = &unsafe { ptr::read(self.imcre_merkle) };
+            ffi.try_into()?
+        };
+        let block_headers = block_headers_from_ffi(self.blocks_ffi, self.blocks_ffi_size)?;
+        let block_ids_list = ids_list_from_ffi(self.ids_list, self.ids_list_size)?;
+
+        Ok(ChangeScheduleCall {
+            legacy_schedule_hash: self.legacy_schedule_hash,
+            schedule,
+            merkle,
+            block_headers,
+            block_ids_list,
+            _runtime: PhantomData,
+        })
+    }
+}
+
+// all arguments of prove_action, used to send many proofs at once
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct ProveActionFFI {
+    pub act_ffi: *const ActionFFI,
+    pub imcre_merkle: *const IncrementalMerkleFFI,
+    pub act_receipt: *const ActionReceiptFFI,
+    pub action_merkle_paths: *const Checksum256FFI,
+    pub blocks_ffi: *const SignedBlockHeaderFFI,
+    pub blocks_ffi_size: size_t,
+    pub ids_list: *const Checksum256FFI,
+    pub ids_list_size: size_t,
+    pub trx_id: Checksum256,
+}
+
+impl<'a> TryInto<ProveActionCall<BifrostRuntime>> for &'a ProveActionFFI {
+    type Error = Error;
+    fn try_into(self) -> Result<ProveActionCall<BifrostRuntime>, Self::Error> {
+        if self.act_ffi.is_null() || self.imcre_merkle.is_null() || self.act_receipt.is_null()
+            || self.action_merkle_paths.is_null() || self.blocks_ffi.is_null() || self.ids_list.is_null()
+        {
+            return Err(Error::NullPtr("ProveActionFFI".to_owned()));
+        }
+
+        let action: Action = {
+            let ffi = &unsafe { ptr::read(self.act_ffi) };
+            ffi.try_into()?
+        };
+        let merkle: IncrementalMerkle = {
+            let ffi = &unsafe { ptr::read(self.imcre_merkle) };
+            ffi.try_into()?
+        };
+        let action_receipt: ActionReceipt = {
+            let ffi = &unsafe { ptr::read(self.act_receipt) };
+            ffi.try_into()?
+        };
+        let action_merkle_paths: Vec<Checksum256> = {
+            let ffi = &unsafe { ptr::read(self.action_merkle_paths) };
+            ffi.try_into()?
+        };
+
+        let block_headers = block_headers_from_ffi(self.blocks_ffi, self.blocks_ffi_size)?;
+        let block_ids_list = ids_list_from_ffi(self.ids_list, self.ids_list_size)?;
+
+        Ok(ProveActionCall {
+            action,
+            action_receipt,
+            action_merkle_paths,
+            merkle,
+            block_headers,
+            block_ids_list,
+            trx_id: self.trx_id,
+            _runtime: PhantomData,
+        })
+    }
+}
+
+// cross out request from bifrost, passed to c++ callback
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct CrossOutRequestFFI {
+    pub request_id: c_ulonglong,
+    pub bifrost_account: *const c_char, // ss58 address
+    pub eos_recipient: AccountName,
+    pub asset: *const c_char,
+    pub amount: *const c_char, // u128 as decimal string
+}
+
 pub(crate) fn char_to_string(cstr: *const c_char) -> FFIResult<String> {
     if cstr.is_null() {
         return Err(Error::NullPtr("char_to_string function".to_owned()));
@@ -514,15 +645,101 @@ pub(crate) fn generate_raw_result(success: bool, msg: impl AsRef<str>) -> Box<Rp
                 .unwrap_or(
                     CString::new("unknow error type.").expect("failed to get raw pointer of error message")
                 );
-    let result = RpcResponse { success, msg: c_str.into_raw() };
+    let code = if success { 0 } else { crate::ERROR_CODE_GENERIC };
+    let result = RpcResponse { success, msg: c_str.into_raw(), code };
 
     Box::new(result)
 }
 
+pub(crate) fn generate_error_result(e: &Error) -> Box<RpcResponse> {
+    let mut result = generate_raw_result(false, e.to_string());
+    result.code = e.code();
+
+    result
+}
+
 // this struct will return to c++ caller
 #[derive(Clone, Debug)]
 #[repr(C)]
 pub struct RpcResponse {
     success: bool,
     msg: *const c_char, // this could be error message or successful message
+    code: u32, // 0 on success, otherwise one of the ERROR_CODE_* constants
+}
+
+impl RpcResponse {
+    // take back the message given away by `generate_raw_result`, it's freed once dropped
+    pub(crate) unsafe fn take_msg(&mut self) -> Option<CString> {
+        if self.msg.is_null() {
+            return None;
+        }
+        let msg = CString::from_raw(self.msg as *mut c_char);
+        self.msg = ptr::null();
+
+        Some(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::size_of;
+
+    // byte offset of `field` in `value`, like offsetof on c++ side
+    fn offset_of<T, F>(value: &T, field: &F) -> usize {
+        field as *const F as usize - value as *const T as usize
+    }
+
+    // same sizes and offsets as the static_asserts in ffi_types.hpp, nodeos is built for 64-bit only
+    #[test]
+    fn producer_authority_schedule_layouts_match_cpp() {
+        let key_weight = KeyWeightFFI { key: ptr::null(), weight: 0 };
+        assert_eq!(size_of::<KeyWeightFFI>(), 16);
+        assert_eq!(offset_of(&key_weight, &key_weight.weight), 8);
+
+        let v0 = BlockSigningAuthorityV0FFI { threshold: 0, keys: ptr::null(), keys_size: 0 };
+        assert_eq!(size_of::<BlockSigningAuthorityV0FFI>(), 24);
+        assert_eq!(offset_of(&v0, &v0.keys), 8);
+        assert_eq!(offset_of(&v0, &v0.keys_size), 16);
+
+        let producer = ProducerAuthorityFFI {
+            producer_name: AccountName::from_str("bifrost").unwrap(),
+            tag: 0,
+            v0_ffi: ptr::null(),
+        };
+        assert_eq!(size_of::<ProducerAuthorityFFI>(), 24);
+        assert_eq!(offset_of(&producer, &producer.tag), 8);
+        assert_eq!(offset_of(&producer, &producer.v0_ffi), 16);
+
+        let schedule = ProducerAuthorityScheduleFFI { version: 0, producers: ptr::null(), producers_size: 0 };
+        assert_eq!(size_of::<ProducerAuthorityScheduleFFI>(), 24);
+        assert_eq!(offset_of(&schedule, &schedule.producers), 8);
+        assert_eq!(offset_of(&schedule, &schedule.producers_size), 16);
+    }
+
+    #[test]
+    fn producer_authority_schedule_from_ffi() {
+        let public_key = "EOS6MRyAjQq8ud7hVNYcfnVPJqcVpscN5So8BhtHuGYqET5GDW5CV";
+        let key = CString::new(public_key).unwrap();
+        let keys = [KeyWeightFFI { key: key.as_ptr(), weight: 1 }];
+        let v0 = BlockSigningAuthorityV0FFI { threshold: 1, keys: keys.as_ptr(), keys_size: keys.len() };
+        let producers = [ProducerAuthorityFFI {
+            producer_name: AccountName::from_str("bifrost").unwrap(),
+            tag: 0,
+            v0_ffi: &v0,
+        }];
+        let ffi = ProducerAuthorityScheduleFFI { version: 2, producers: producers.as_ptr(), producers_size: producers.len() };
+
+        let schedule: ProducerAuthoritySchedule = (&ffi).try_into().unwrap();
+        assert_eq!(schedule.version, 2);
+        assert_eq!(schedule.producers.len(), 1);
+        assert_eq!(schedule.producers[0].producer_name.to_string(), "bifrost");
+
+        let BlockSigningAuthority(tag, authority) = &schedule.producers[0].authority;
+        assert_eq!(*tag, UnsignedInt::from(0u32));
+        assert_eq!(authority.threshold, 1);
+        assert_eq!(authority.keys.len(), 1);
+        assert_eq!(authority.keys[0].key.to_string(), public_key);
+        assert_eq!(authority.keys[0].weight, 1);
+    }
 }
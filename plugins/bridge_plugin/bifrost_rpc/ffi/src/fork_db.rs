@@ -0,0 +1,399 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Head blocks may be forked out, so they're kept in a fork database keyed by id and
+//! previous id. Actions found on the current branch are only tentative, they're rolled
+//! back on a fork switch. Blocks are released to the proof builder once LIB passes them.
+
+use crate::backfill::{SourceAction, SourceBlock};
+use eos_chain::Checksum256;
+use std::collections::{HashMap, HashSet};
+
+/// What happened to a block given to `ForkDb::add`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+	Added,
+	Duplicate,
+	/// At or below the last released block, it's final already.
+	Stale,
+	/// Its previous block is unknown.
+	Unlinkable,
+}
+
+/// A block that left the current branch, with actions tentatively collected from it.
+#[derive(Clone, Debug)]
+pub struct RolledBack {
+	pub block_num: u32,
+	pub id:        Checksum256,
+	pub actions:   Vec<SourceAction>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Changes {
+	pub rolled_back: Vec<RolledBack>,
+	/// Blocks that joined the current branch, in block order.
+	pub applied:     Vec<(u32, Checksum256)>,
+	/// Final blocks in block order, they'll never be rolled back.
+	pub released:    Vec<SourceBlock>,
+}
+
+impl Changes {
+	pub fn is_fork_switch(&self) -> bool {
+		!self.rolled_back.is_empty()
+	}
+
+	fn merge(&mut self, other: Changes) {
+		self.rolled_back.extend(other.rolled_back);
+		self.applied.extend(other.applied);
+		self.released.extend(other.released);
+	}
+}
+
+#[derive(Debug, Default)]
+pub struct ForkDb {
+	blocks:      HashMap<Checksum256, SourceBlock>,
+	// previous id => ids of blocks built on it
+	children:    HashMap<Checksum256, Vec<Checksum256>>,
+	head:        Option<Checksum256>,
+	// the oldest block kept, every other block descends from it
+	root:        Option<(u32, Checksum256)>,
+	released_to: u32,
+	lib:         u32,
+	// matched actions of blocks on current branch, not final yet
+	tentative:   HashMap<Checksum256, Vec<SourceAction>>,
+}
+
+fn previous(block: &SourceBlock) -> Checksum256 {
+	block.header.block_header.previous
+}
+
+// actions relay rules are interested in
+fn matched_actions(block: &SourceBlock) -> Vec<SourceAction> {
+	block.actions.iter()
+		.filter(|act| {
+			block.action_receipts.get(act.receipt_index)
+				.map(|receipt| crate::rules::match_action(&act.action, &receipt.receiver) != crate::rules::RuleMatch::Unmatched)
+				.unwrap_or(false)
+		})
+		.cloned()
+		.collect()
+}
+
+impl ForkDb {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn len(&self) -> usize {
+		self.blocks.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.blocks.is_empty()
+	}
+
+	pub fn head(&self) -> Option<&SourceBlock> {
+		self.head.and_then(|id| self.blocks.get(&id))
+	}
+
+	pub fn lib(&self) -> u32 {
+		self.lib
+	}
+
+	pub fn get(&self, id: &Checksum256) -> Option<&SourceBlock> {
+		self.blocks.get(id)
+	}
+
+	/// Actions collected from a block of current branch, until it's final or forked out.
+	pub fn tentative(&self, id: &Checksum256) -> Option<&[SourceAction]> {
+		self.tentative.get(id).map(|actions| actions.as_slice())
+	}
+
+	/// Add a head or irreversible block. The first block becomes the root, its blockroot
+	/// merkle is trusted as it is; the merkle of any later block is built from its previous one.
+	pub fn add(&mut self, mut block: SourceBlock) -> Result<(Outcome, Changes), crate::Error> {
+		let mut changes = Changes::default();
+		if self.blocks.contains_key(&block.id) {
+			return Ok((Outcome::Duplicate, changes));
+		}
+
+		let root = match self.root {
+			Some(root) => root,
+			None => {
+				let id = block.id;
+				self.root = Some((block.block_num, id));
+				self.released_to = block.block_num - 1;
+				self.tentative.insert(id, matched_actions(&block));
+				changes.applied.push((block.block_num, id));
+				self.blocks.insert(id, block);
+				self.head = Some(id);
+				return Ok((Outcome::Added, changes));
+			}
+		};
+		if block.block_num <= root.0.max(self.released_to) {
+			return Ok((Outcome::Stale, changes));
+		}
+		let parent = match self.blocks.get(&previous(&block)) {
+			Some(parent) if parent.block_num + 1 == block.block_num => parent,
+			_ => return Ok((Outcome::Unlinkable, changes)),
+		};
+
+		let mut merkle = parent.blockroot_merkle.clone();
+		merkle.append(parent.id).map_err(|_| crate::Error::InvalidCall("failed to append block id to merkle"))?;
+		block.blockroot_merkle = merkle;
+
+		let id = block.id;
+		let block_num = block.block_num;
+		self.children.entry(previous(&block)).or_default().push(id);
+		self.blocks.insert(id, block);
+
+		// the longest branch wins, the first seen one on a tie
+		let head_num = self.head().map(|head| head.block_num).unwrap_or_default();
+		if block_num > head_num {
+			self.switch_head(id, &mut changes);
+		}
+		// the new head might be final already
+		changes.merge(self.release());
+
+		Ok((Outcome::Added, changes))
+	}
+
+	// ids from `id` back to root, newest first
+	fn branch(&self, id: Checksum256) -> Vec<Checksum256> {
+		let mut branch = Vec::new();
+		let mut current = Some(id);
+		while let Some(id) = current {
+			match self.blocks.get(&id) {
+				Some(block) => {
+					branch.push(id);
+					current = if Some(id) == self.root.map(|r| r.1) { None } else { Some(previous(block)) };
+				}
+				None => break,
+			}
+		}
+		branch
+	}
+
+	fn switch_head(&mut self, new_head: Checksum256, changes: &mut Changes) {
+		let new_branch = self.branch(new_head);
+		let on_new_branch: HashSet<Checksum256> = new_branch.iter().cloned().collect();
+
+		if let Some(old_head) = self.head {
+			for id in self.branch(old_head).into_iter().take_while(|id| !on_new_branch.contains(id)) {
+				let actions = self.tentative.remove(&id).unwrap_or_default();
+				let block_num = self.blocks.get(&id).map(|b| b.block_num).unwrap_or_default();
+				changes.rolled_back.push(RolledBack { block_num, id, actions });
+			}
+		}
+
+		for id in new_branch.into_iter().rev() {
+			if self.tentative.contains_key(&id) {
+				continue;
+			}
+			if let Some(block) = self.blocks.get(&id).filter(|block| block.block_num > self.released_to) {
+				self.tentative.insert(id, matched_actions(block));
+				changes.applied.push((block.block_num, id));
+			}
+		}
+		self.head = Some(new_head);
+	}
+
+	/// LIB reported by the node, final blocks of current branch are released.
+	pub fn set_lib(&mut self, lib: u32) -> Changes {
+		if lib <= self.lib {
+			return Changes::default();
+		}
+		self.lib = lib;
+		self.release()
+	}
+
+	fn release(&mut self) -> Changes {
+		let mut changes = Changes::default();
+		let head = match self.head {
+			Some(head) => head,
+			None => return changes,
+		};
+
+		let mut final_ids: Vec<Checksum256> = self.branch(head)
+			.into_iter()
+			.filter(|id| {
+				let num = self.blocks[id].block_num;
+				num > self.released_to && num <= self.lib
+			})
+			.collect();
+		if final_ids.is_empty() {
+			return changes;
+		}
+		final_ids.reverse();
+
+		for id in final_ids.iter() {
+			self.tentative.remove(id);
+			changes.released.push(self.blocks[id].clone());
+		}
+		let last = final_ids[final_ids.len() - 1];
+		self.released_to = self.blocks[&last].block_num;
+		self.prune(last);
+
+		changes
+	}
+
+	// the last released block becomes root, anything not built on it is dropped
+	fn prune(&mut self, new_root: Checksum256) {
+		let mut keep = HashSet::new();
+		let mut pending = vec![new_root];
+		while let Some(id) = pending.pop() {
+			keep.insert(id);
+			if let Some(children) = self.children.get(&id) {
+				pending.extend(children.iter().cloned());
+			}
+		}
+
+		self.blocks.retain(|id, _| keep.contains(id));
+		self.children.retain(|id, _| keep.contains(id));
+		self.tentative.retain(|id, _| keep.contains(id));
+		self.root = self.blocks.get(&new_root).map(|block| (block.block_num, new_root));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::{header_after, id, id_bytes};
+	use eos_chain::IncrementalMerkle;
+
+	fn block(block_num: u32, fork: u8, previous_fork: u8) -> SourceBlock {
+		let header = header_after(id_bytes(block_num - 1, previous_fork), 1);
+
+		SourceBlock {
+			block_num,
+			id: id(block_num, fork),
+			header,
+			blockroot_merkle: IncrementalMerkle::new(0, Vec::new()),
+			action_receipts: Vec::new(),
+			actions: Vec::new(),
+			schedule_change: None,
+			proposed_schedule: None,
+		}
+	}
+
+	fn released_ids(changes: &Changes) -> Vec<Checksum256> {
+		changes.released.iter().map(|b| b.id).collect()
+	}
+
+	// main branch 100..=105
+	fn main_branch() -> ForkDb {
+		let mut db = ForkDb::new();
+		for num in 100..=105 {
+			let (outcome, _) = db.add(block(num, 0, 0)).unwrap();
+			assert_eq!(outcome, Outcome::Added);
+		}
+		db
+	}
+
+	#[test]
+	fn nothing_is_released_before_lib() {
+		let mut db = main_branch();
+		assert_eq!(db.head().map(|b| b.id), Some(id(105, 0)));
+		assert!(db.set_lib(99).released.is_empty());
+
+		let changes = db.set_lib(102);
+		assert_eq!(released_ids(&changes), vec![id(100, 0), id(101, 0), id(102, 0)]);
+		// released once only
+		assert!(db.set_lib(102).released.is_empty());
+		assert_eq!(released_ids(&db.set_lib(103)), vec![id(103, 0)]);
+	}
+
+	#[test]
+	fn longer_fork_rolls_back_tentative_blocks() {
+		let mut db = main_branch();
+		// a fork from 103, as long as the main branch first
+		db.add(block(104, 1, 0)).unwrap();
+		let (_, changes) = db.add(block(105, 1, 1)).unwrap();
+		assert!(!changes.is_fork_switch());
+		assert_eq!(db.head().map(|b| b.id), Some(id(105, 0)));
+
+		// then longer
+		let (_, changes) = db.add(block(106, 1, 1)).unwrap();
+		assert!(changes.is_fork_switch());
+		let rolled_back: Vec<Checksum256> = changes.rolled_back.iter().map(|r| r.id).collect();
+		assert_eq!(rolled_back, vec![id(105, 0), id(104, 0)]);
+		assert_eq!(changes.applied, vec![(104, id(104, 1)), (105, id(105, 1)), (106, id(106, 1))]);
+		assert!(db.tentative(&id(104, 0)).is_none());
+		assert!(db.tentative(&id(104, 1)).is_some());
+
+		let changes = db.set_lib(105);
+		assert_eq!(
+			released_ids(&changes),
+			vec![id(100, 0), id(101, 0), id(102, 0), id(103, 0), id(104, 1), id(105, 1)]
+		);
+		// blocks of the dead branch are pruned
+		assert!(db.get(&id(104, 0)).is_none());
+		assert!(db.get(&id(105, 0)).is_none());
+	}
+
+	#[test]
+	fn switching_back_applies_old_branch_again() {
+		let mut db = main_branch();
+		db.add(block(105, 1, 0)).unwrap();
+		db.add(block(106, 1, 1)).unwrap();
+		assert_eq!(db.head().map(|b| b.id), Some(id(106, 1)));
+
+		db.add(block(106, 0, 0)).unwrap();
+		let (_, changes) = db.add(block(107, 0, 0)).unwrap();
+		let rolled_back: Vec<Checksum256> = changes.rolled_back.iter().map(|r| r.id).collect();
+		assert_eq!(rolled_back, vec![id(106, 1), id(105, 1)]);
+		assert_eq!(changes.applied, vec![(105, id(105, 0)), (106, id(106, 0)), (107, id(107, 0))]);
+	}
+
+	#[test]
+	fn final_blocks_are_stale() {
+		let mut db = main_branch();
+		db.set_lib(103);
+		assert_eq!(db.add(block(103, 1, 0)).unwrap().0, Outcome::Stale);
+		assert_eq!(db.add(block(104, 0, 0)).unwrap().0, Outcome::Duplicate);
+		assert_eq!(db.add(block(108, 0, 0)).unwrap().0, Outcome::Unlinkable);
+		// a fork below lib is dropped once lib passes it
+		assert_eq!(db.add(block(104, 2, 0)).unwrap().0, Outcome::Added);
+		db.set_lib(104);
+		assert!(db.get(&id(104, 2)).is_none());
+	}
+
+	#[test]
+	fn block_arriving_after_lib_is_released_at_once() {
+		let mut db = ForkDb::new();
+		db.add(block(100, 0, 0)).unwrap();
+		assert_eq!(released_ids(&db.set_lib(102)), vec![id(100, 0)]);
+		let (_, changes) = db.add(block(101, 0, 0)).unwrap();
+		assert_eq!(released_ids(&changes), vec![id(101, 0)]);
+		let (_, changes) = db.add(block(102, 0, 0)).unwrap();
+		assert_eq!(released_ids(&changes), vec![id(102, 0)]);
+	}
+
+	#[test]
+	fn merkle_is_built_along_each_branch() {
+		let mut db = main_branch();
+		db.add(block(104, 1, 0)).unwrap();
+		let main = db.get(&id(104, 0)).unwrap().blockroot_merkle.clone();
+		let fork = db.get(&id(104, 1)).unwrap().blockroot_merkle.clone();
+		// same ancestors, same merkle
+		assert_eq!(main, fork);
+
+		db.add(block(105, 1, 1)).unwrap();
+		let main = db.get(&id(105, 0)).unwrap().blockroot_merkle.clone();
+		let fork = db.get(&id(105, 1)).unwrap().blockroot_merkle.clone();
+		assert_ne!(main, fork);
+	}
+}
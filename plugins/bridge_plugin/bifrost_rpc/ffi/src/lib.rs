@@ -14,18 +14,44 @@
 // You should have received a copy of the GNU General Public License
 // along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
 
-use eos_chain::{Action, ActionReceipt, Checksum256, IncrementalMerkle, ProducerAuthoritySchedule, SignedBlockHeader};
+use eos_chain::{AccountName, Action, ActionReceipt, Checksum256, IncrementalMerkle, ProducerAuthoritySchedule, SignedBlockHeader};
 use std::{
     convert::TryInto,
     fmt::{self, Display},
-    os::raw::c_char,
+    marker::PhantomData,
+    os::raw::{c_char, c_void},
+    panic,
     ptr,
     slice,
 };
 
+mod abi;
+mod aggregate;
+mod assets;
+pub mod backfill;
+pub mod block_log;
+mod block_cache;
+pub mod config;
+mod cross_out;
 mod ffi_types;
 use ffi_types::*;
+pub mod fork_db;
+mod memo;
+mod merkle;
+mod outbox;
+mod packed;
+mod payload_cache;
+pub mod queue;
+mod redeem;
 mod rpc_calls;
+mod rules;
+mod schedule_chain;
+pub mod ship;
+use rpc_calls::{BifrostRuntime, ChangeScheduleCall, ProveActionCall};
+mod status;
+pub mod store;
+#[cfg(test)]
+mod testing;
 
 #[derive(Clone, Debug)]
 pub enum Error {
@@ -35,6 +61,63 @@ pub enum Error {
     SignatureError,
     WrongSudoSeed,
     SubxtError(&'static str),
+    ConfigError(String),
+    InsufficientBalance(u128, u128),
+    FeeTooHigh(u128, u128),
+    InvalidCall(&'static str),
+    AlreadyProven,
+    InvalidMemo(String),
+    UnknownAsset(String),
+    AbiError(String),
+    BatchInterrupted(&'static str),
+    StoreError(String),
+    AlreadySubmitted,
+    SubmissionInFlight,
+    BlockLogError(String),
+    ShipError(String),
+    UnpackError(String),
+    DryRun(String),
+}
+
+// error codes returned to c++ caller by RpcResponse, 0 means success
+pub const ERROR_CODE_GENERIC: u32 = 1;
+pub const ERROR_CODE_INSUFFICIENT_BALANCE: u32 = 2;
+pub const ERROR_CODE_FEE_TOO_HIGH: u32 = 3;
+pub const ERROR_CODE_INVALID_CALL: u32 = 4;
+pub const ERROR_CODE_ALREADY_PROVEN: u32 = 5;
+pub const ERROR_CODE_INVALID_MEMO: u32 = 6;
+pub const ERROR_CODE_UNKNOWN_ASSET: u32 = 7;
+pub const ERROR_CODE_ABI_ERROR: u32 = 8;
+pub const ERROR_CODE_BATCH_INTERRUPTED: u32 = 9;
+pub const ERROR_CODE_STORE_ERROR: u32 = 10;
+pub const ERROR_CODE_ALREADY_SUBMITTED: u32 = 11;
+pub const ERROR_CODE_SUBMISSION_IN_FLIGHT: u32 = 12;
+pub const ERROR_CODE_BLOCK_LOG_ERROR: u32 = 13;
+pub const ERROR_CODE_SHIP_ERROR: u32 = 14;
+pub const ERROR_CODE_UNPACK_ERROR: u32 = 15;
+pub const ERROR_CODE_DRY_RUN: u32 = 16;
+
+impl Error {
+    pub fn code(&self) -> u32 {
+        match *self {
+            Self::InsufficientBalance(..) => ERROR_CODE_INSUFFICIENT_BALANCE,
+            Self::FeeTooHigh(..) => ERROR_CODE_FEE_TOO_HIGH,
+            Self::InvalidCall(_) => ERROR_CODE_INVALID_CALL,
+            Self::AlreadyProven => ERROR_CODE_ALREADY_PROVEN,
+            Self::InvalidMemo(_) => ERROR_CODE_INVALID_MEMO,
+            Self::UnknownAsset(_) => ERROR_CODE_UNKNOWN_ASSET,
+            Self::AbiError(_) => ERROR_CODE_ABI_ERROR,
+            Self::BatchInterrupted(_) => ERROR_CODE_BATCH_INTERRUPTED,
+            Self::StoreError(_) => ERROR_CODE_STORE_ERROR,
+            Self::AlreadySubmitted => ERROR_CODE_ALREADY_SUBMITTED,
+            Self::SubmissionInFlight => ERROR_CODE_SUBMISSION_IN_FLIGHT,
+            Self::BlockLogError(_) => ERROR_CODE_BLOCK_LOG_ERROR,
+            Self::ShipError(_) => ERROR_CODE_SHIP_ERROR,
+            Self::UnpackError(_) => ERROR_CODE_UNPACK_ERROR,
+            Self::DryRun(_) => ERROR_CODE_DRY_RUN,
+            _ => ERROR_CODE_GENERIC,
+        }
+    }
 }
 
 impl Display for Error {
@@ -46,41 +129,1146 @@ impl Display for Error {
             Self::SignatureError => write!(f, "Failed to convert string to Signature."),
             Self::WrongSudoSeed => write!(f, "Wrong sudo seed, failed to sign transaction."),
             Self::SubxtError(e) => write!(f, "Error from subxt crate: {}", e),
+            Self::ConfigError(ref e) => write!(f, "Invalid relay config: {}", e),
+            Self::InsufficientBalance(free, fee) => write!(f, "Signer free balance {} cannot cover the estimated fee {}.", free, fee),
+            Self::FeeTooHigh(fee, cap) => write!(f, "Estimated fee {} exceeds the max fee {}.", fee, cap),
+            Self::InvalidCall(e) => write!(f, "Invalid call: {}", e),
+            Self::AlreadyProven => write!(f, "This action has been proven on bifrost."),
+            Self::InvalidMemo(ref e) => write!(f, "Invalid transfer memo: {}", e),
+            Self::UnknownAsset(ref e) => write!(f, "Unknown asset: {}", e),
+            Self::AbiError(ref e) => write!(f, "Failed to decode by abi: {}", e),
+            Self::BatchInterrupted(e) => write!(f, "Batch is interrupted: {}", e),
+            Self::StoreError(ref e) => write!(f, "Relay store error: {}", e),
+            Self::AlreadySubmitted => write!(f, "The same payload has been finalized on bifrost."),
+            Self::SubmissionInFlight => write!(f, "The same payload was sent before, wait for reconciliation."),
+            Self::BlockLogError(ref e) => write!(f, "Failed to read block log: {}", e),
+            Self::ShipError(ref e) => write!(f, "State history error: {}", e),
+            Self::UnpackError(ref e) => write!(f, "Failed to unpack argument: {}", e),
+            Self::DryRun(ref report) => write!(f, "Dry run, nothing is submitted: {}", report),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Self::NullPtr(_) => "Null pointer.",
+            Self::CStrConvertError => "Failed to convert c string to rust string.",
+            Self::PublicKeyError => "Failed to convert string to PublicKeyError.",
+            Self::SignatureError => "Failed to convert string to Signature.",
+            Self::WrongSudoSeed => "Wrong sudo seed, failed to sign transaction.",
+            Self::SubxtError(e) => e,
+            Self::ConfigError(_) => "Invalid relay config.",
+            Self::InsufficientBalance(..) => "Signer free balance cannot cover the estimated fee.",
+            Self::FeeTooHigh(..) => "Estimated fee exceeds the max fee.",
+            Self::InvalidCall(e) => e,
+            Self::AlreadyProven => "This action has been proven on bifrost.",
+            Self::InvalidMemo(_) => "Invalid transfer memo.",
+            Self::UnknownAsset(_) => "Unknown asset.",
+            Self::AbiError(_) => "Failed to decode by abi.",
+            Self::BatchInterrupted(e) => e,
+            Self::StoreError(_) => "Relay store error.",
+            Self::AlreadySubmitted => "The same payload has been finalized on bifrost.",
+            Self::SubmissionInFlight => "The same payload was sent before, wait for reconciliation.",
+            Self::BlockLogError(_) => "Failed to read block log.",
+            Self::ShipError(_) => "State history error.",
+            Self::UnpackError(_) => "Failed to unpack argument.",
+            Self::DryRun(_) => "Dry run, nothing is submitted.",
+        }
+    }
+}
+
+/// Free a result returned by this library with its message, c++ side must not touch it afterwards.
+#[no_mangle]
+pub extern "C" fn rpc_result_free(result: *mut RpcResponse) {
+    if result.is_null() {
+        return;
+    }
+
+    let mut result = unsafe { Box::from_raw(result) };
+    unsafe { result.take_msg(); }
+}
+
+#[no_mangle]
+pub extern "C" fn init_relay_config(config: *const c_char) -> Box<RpcResponse> {
+    if config.is_null() {
+        return generate_raw_result(false, "cannot initialize relay config due to null pointer");
+    }
+
+    let config = {
+        let config = char_to_string(config);
+        if config.is_err() {
+            return generate_raw_result(false, "This is not an valid relay config.");
+        }
+        config.unwrap()
+    };
+
+    match crate::config::init_relay_config(&config) {
+        Ok(config) => generate_raw_result(true, format!("{:?}", config)),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn check_signer_balance(
+    urls:   *const c_char,
+    signer: *const c_char,
+) -> Box<RpcResponse> {
+    let (urls, signer) = match urls_and_signer(urls, signer) {
+        Ok(r) => r,
+        Err(resp) => return resp,
+    };
+
+    let result = futures::executor::block_on(async move {
+        crate::rpc_calls::check_signer_balance(urls, signer).await
+    });
+
+    match result {
+        Ok(free) => generate_raw_result(true, free.to_string()),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn relay_status() -> Box<RpcResponse> {
+    let status = crate::status::relay_status();
+
+    generate_raw_result(true, status.to_json().to_string())
+}
+
+#[no_mangle]
+pub extern "C" fn register_cross_out_callback(callback: crate::cross_out::CrossOutCallback) {
+    crate::cross_out::register_callback(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn subscribe_cross_out_requests(urls: *const c_char) -> Box<RpcResponse> {
+    if urls.is_null() {
+        return generate_raw_result(false, "cannot subscribe cross out requests due to null bifrost node address");
+    }
+
+    let url = {
+        let urls = char_to_string(urls);
+        if urls.is_err() {
+            return generate_raw_result(false, "This is not an valid bifrost node address.");
+        }
+        urls.unwrap()
+    };
+
+    match crate::cross_out::subscribe(url) {
+        Ok(_) => generate_raw_result(true, "subscribed cross out requests"),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn unsubscribe_cross_out_requests() {
+    crate::cross_out::unsubscribe();
+}
+
+#[no_mangle]
+pub extern "C" fn match_redeem_transfer(
+    memo:   *const c_char,
+    trx_id: Checksum256,
+) -> Box<RpcResponse> {
+    if memo.is_null() {
+        return generate_raw_result(false, "cannot match redeem transfer due to null memo");
+    }
+
+    let memo = {
+        let memo = char_to_string(memo);
+        if memo.is_err() {
+            return generate_raw_result(false, "This is not an valid transfer memo.");
+        }
+        memo.unwrap()
+    };
+
+    match crate::redeem::match_transfer(&memo, trx_id) {
+        Some(request_id) => generate_raw_result(true, request_id.to_string()),
+        None => generate_raw_result(false, format!("no pending redeem request for memo: {}", memo)),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn redeem_requests_status() -> Box<RpcResponse> {
+    generate_raw_result(true, crate::redeem::redeem_status().to_string())
+}
+
+// msg is a json array of request ids settled by this call
+#[no_mangle]
+pub extern "C" fn confirm_redeem_requests(
+    urls:   *const c_char,
+    signer: *const c_char,
+) -> Box<RpcResponse> {
+    let (urls, signer) = match urls_and_signer(urls, signer) {
+        Ok(r) => r,
+        Err(resp) => return resp,
+    };
+
+    let result = futures::executor::block_on(async move {
+        crate::redeem::confirm_proven(urls, signer).await
+    });
+
+    match result {
+        Ok(settled) => generate_raw_result(true, serde_json::json!(settled).to_string()),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn parse_transfer_memo(memo: *const c_char) -> Box<RpcResponse> {
+    if memo.is_null() {
+        return generate_raw_result(false, "cannot parse transfer memo due to null pointer");
+    }
+
+    let memo = {
+        let memo = char_to_string(memo);
+        if memo.is_err() {
+            return generate_raw_result(false, "This is not an valid transfer memo.");
+        }
+        memo.unwrap()
+    };
+
+    match crate::memo::parse_memo(&memo) {
+        Ok(memo) => generate_raw_result(true, memo.to_json().to_string()),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn validate_transfer_memo(
+    memo:     *const c_char,
+    contract: AccountName,
+    from:     AccountName,
+    quantity: *const c_char,
+    trx_id:   Checksum256,
+) -> Box<RpcResponse> {
+    if memo.is_null() || quantity.is_null() {
+        return generate_raw_result(false, "cannot validate transfer memo due to there're null points");
+    }
+
+    let (memo, quantity) = match (char_to_string(memo), char_to_string(quantity)) {
+        (Ok(memo), Ok(quantity)) => (memo, quantity),
+        _ => return generate_raw_result(false, "This is not an valid transfer memo."),
+    };
+
+    match crate::memo::validate_transfer(&memo, contract, from, &quantity, trx_id) {
+        Ok(memo) => generate_raw_result(true, memo.to_json().to_string()),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn refund_queue() -> Box<RpcResponse> {
+    generate_raw_result(true, crate::memo::refund_queue().to_string())
+}
+
+// refunds for the host to send, nothing here signs them
+#[no_mangle]
+pub extern "C" fn take_refunds() -> Box<RpcResponse> {
+    let refunds = crate::memo::take_refunds();
+    generate_raw_result(true, serde_json::Value::Array(refunds.iter().map(|r| r.to_json()).collect()).to_string())
+}
+
+#[no_mangle]
+pub extern "C" fn observe_refund_transfer(
+    memo:      *const c_char,
+    contract:  AccountName,
+    to:        AccountName,
+    quantity:  *const c_char,
+    trx_id:    Checksum256,
+    block_num: u32,
+) -> bool {
+    if memo.is_null() || quantity.is_null() {
+        return false;
+    }
+
+    match (char_to_string(memo), char_to_string(quantity)) {
+        (Ok(memo), Ok(quantity)) => crate::memo::observe_refund(&memo, contract, to, &quantity, trx_id, block_num),
+        _ => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn settle_refunds(
+    block_num:    u32,
+    trx_ids:      *const Checksum256,
+    trx_ids_size: size_t,
+) -> u32 {
+    if trx_ids.is_null() && trx_ids_size > 0 {
+        return 0;
+    }
+
+    let trx_ids = if trx_ids_size == 0 { &[][..] } else { unsafe { slice::from_raw_parts(trx_ids, trx_ids_size) } };
+    let settled = crate::memo::settle_refunds(block_num, trx_ids);
+    for trx_id in settled.iter() {
+        println!("[+] Refund of transfer {} is irreversible\n", trx_id);
+    }
+
+    settled.len() as u32
+}
+
+#[no_mangle]
+pub extern "C" fn classify_transfer(
+    contract: AccountName,
+    quantity: *const c_char,
+) -> Box<RpcResponse> {
+    if quantity.is_null() {
+        return generate_raw_result(false, "cannot classify transfer due to null quantity");
+    }
+
+    let quantity = {
+        let quantity = char_to_string(quantity);
+        if quantity.is_err() {
+            return generate_raw_result(false, "This is not an valid asset quantity.");
+        }
+        quantity.unwrap()
+    };
+
+    match crate::assets::classify_transfer(&contract.to_string(), &quantity) {
+        Ok(transfer) => generate_raw_result(true, transfer.to_json().to_string()),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn is_asset_contract(contract: AccountName) -> bool {
+    crate::assets::is_asset_contract(&contract.to_string())
+}
+
+#[no_mangle]
+pub extern "C" fn is_contract_abi_cached(contract: AccountName, abi_sequence: u32) -> bool {
+    crate::abi::is_abi_cached(&contract.to_string(), abi_sequence)
+}
+
+#[no_mangle]
+pub extern "C" fn register_contract_abi(
+    contract:     AccountName,
+    abi_sequence: u32,
+    abi_json:     *const c_char,
+) -> Box<RpcResponse> {
+    if abi_json.is_null() {
+        return generate_raw_result(false, "cannot register abi due to null pointer");
+    }
+
+    let abi_json = {
+        let abi_json = char_to_string(abi_json);
+        if abi_json.is_err() {
+            return generate_raw_result(false, "This is not an valid abi json.");
+        }
+        abi_json.unwrap()
+    };
+
+    match crate::abi::register_abi(&contract.to_string(), abi_sequence, &abi_json) {
+        Ok(_) => generate_raw_result(true, format!("registered abi of {} at sequence {}", contract, abi_sequence)),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decode_action_data(
+    act_ffi:     *const ActionFFI,
+    act_receipt: *const ActionReceiptFFI, // optional, use the latest abi if it's null
+) -> Box<RpcResponse> {
+    if act_ffi.is_null() {
+        return generate_raw_result(false, "cannot decode action due to null pointer");
+    }
+
+    let action: Action = {
+        let ffi = &unsafe { ptr::read(act_ffi) };
+        let r: Result<Action, _> = ffi.try_into();
+        if r.is_err() {
+            return generate_raw_result(false, r.unwrap_err().to_string());
+        }
+        r.unwrap()
+    };
+
+    let abi_sequence = if act_receipt.is_null() {
+        None
+    } else {
+        let receipt = &unsafe { ptr::read(act_receipt) };
+        Some(receipt.abi_sequence.0)
+    };
+
+    // data comes from any contract, never unwind into c++ whatever it is
+    let decoded = panic::catch_unwind(|| crate::abi::decode_action_data(
+        &action.account.to_string(),
+        &action.name.to_string(),
+        abi_sequence,
+        &action.data,
+    ));
+
+    match decoded {
+        Ok(Ok(value)) => generate_raw_result(true, value.to_string()),
+        Ok(Err(e)) => generate_error_result(&e),
+        Err(_) => generate_raw_result(false, "failed to decode action data due to a panic"),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn match_action_rules(
+    act_ffi:  *const ActionFFI,
+    receiver: AccountName,
+) -> crate::rules::RuleMatch {
+    if act_ffi.is_null() {
+        return crate::rules::RuleMatch::Unmatched;
+    }
+
+    let action: Action = {
+        let ffi = &unsafe { ptr::read(act_ffi) };
+        let r: Result<Action, _> = ffi.try_into();
+        if r.is_err() {
+            return crate::rules::RuleMatch::Unmatched;
+        }
+        r.unwrap()
+    };
+
+    // an action that breaks the decoder is not relayed
+    panic::catch_unwind(|| crate::rules::match_action(&action, &receiver)).unwrap_or(crate::rules::RuleMatch::Unmatched)
+}
+
+#[no_mangle]
+pub extern "C" fn change_schedule(
+    urls:                 *const c_char,
+    signer:               *const c_char,
+    legacy_schedule_hash: Checksum256,
+    schedule:             *const c_char,
+    imcre_merkle:         *const c_char,
+    blocks_ffi:           *const c_char,
+    blocks_ffi_size:      size_t,
+    ids_list:             *const c_char,
+    ids_list_size:        size_t
+) -> Box<RpcResponse> {
+    let (urls, signer) = match urls_and_signer(urls, signer) {
+        Ok(r) => r,
+        Err(resp) => return resp,
+    };
+
+    let call = match change_schedule_args(legacy_schedule_hash, schedule, imcre_merkle, blocks_ffi, blocks_ffi_size, ids_list, ids_list_size) {
+        Ok(call) => call,
+        Err(resp) => return resp,
+    };
+
+    let result = futures::executor::block_on(async move {
+        crate::rpc_calls::change_schedule_call(urls, signer, call).await
+    });
+
+    // send and watch extrinsic until finalized
+    transaction_result(result)
+}
+
+// every argument is packed by fc::raw::pack on c++ side
+#[no_mangle]
+pub extern "C" fn change_schedule_packed(
+    urls:                 *const c_char,
+    signer:               *const c_char,
+    legacy_schedule_hash: Checksum256,
+    schedule:             *const c_char,
+    schedule_size:        size_t,
+    imcre_merkle:         *const c_char,
+    imcre_merkle_size:    size_t,
+    blocks:               *const c_char,
+    blocks_size:          size_t,
+    ids_list:             *const c_char,
+    ids_list_size:        size_t
+) -> Box<RpcResponse> {
+    let (urls, signer) = match urls_and_signer(urls, signer) {
+        Ok(r) => r,
+        Err(resp) => return resp,
+    };
+
+    if schedule.is_null() || imcre_merkle.is_null() || blocks.is_null() || ids_list.is_null() {
+        return generate_raw_result(false, "cannot change schedule due to there're null points");
+    }
+    let packed = |ptr: *const c_char, size: size_t| unsafe { slice::from_raw_parts(ptr as *const u8, size) };
+
+    let call = crate::packed::change_schedule_call(
+        legacy_schedule_hash,
+        packed(schedule, schedule_size),
+        packed(imcre_merkle, imcre_merkle_size),
+        packed(blocks, blocks_size),
+        packed(ids_list, ids_list_size),
+    );
+    let call = match call {
+        Ok(call) => call,
+        Err(e) => return generate_error_result(&e),
+    };
+
+    let result = futures::executor::block_on(async move {
+        crate::rpc_calls::change_schedule_call(urls, signer, call).await
+    });
+
+    // send and watch extrinsic until finalized
+    transaction_result(result)
+}
+
+// same as change_schedule, but arguments are ffi structs instead of json
+#[no_mangle]
+pub extern "C" fn change_schedule_v2(
+    urls:                 *const c_char,
+    signer:               *const c_char,
+    legacy_schedule_hash: Checksum256,
+    schedule:             *const ProducerAuthorityScheduleFFI,
+    imcre_merkle:         *const IncrementalMerkleFFI,
+    blocks_ffi:           *const SignedBlockHeaderFFI,
+    blocks_ffi_size:      size_t,
+    ids_list:             *const Checksum256FFI,
+    ids_list_size:        size_t
+) -> Box<RpcResponse> {
+    let (urls, signer) = match urls_and_signer(urls, signer) {
+        Ok(r) => r,
+        Err(resp) => return resp,
+    };
+
+    let ffi = ChangeScheduleFFI {
+        legacy_schedule_hash,
+        schedule,
+        imcre_merkle,
+        blocks_ffi,
+        blocks_ffi_size,
+        ids_list,
+        ids_list_size,
+    };
+    let call: ChangeScheduleCall<BifrostRuntime> = match (&ffi).try_into() {
+        Ok(call) => call,
+        Err(e) => return generate_error_result(&e),
+    };
+
+    let result = futures::executor::block_on(async move {
+        crate::rpc_calls::change_schedule_call(urls, signer, call).await
+    });
+
+    // send and watch extrinsic until finalized
+    transaction_result(result)
+}
+
+#[no_mangle]
+pub extern "C" fn change_schedule_dry_run(
+    urls:                 *const c_char,
+    signer:               *const c_char,
+    legacy_schedule_hash: Checksum256,
+    schedule:             *const c_char,
+    imcre_merkle:         *const c_char,
+    blocks_ffi:           *const c_char,
+    blocks_ffi_size:      size_t,
+    ids_list:             *const c_char,
+    ids_list_size:        size_t
+) -> Box<RpcResponse> {
+    let (urls, signer) = match urls_and_signer(urls, signer) {
+        Ok(r) => r,
+        Err(resp) => return resp,
+    };
+
+    let call = match change_schedule_args(legacy_schedule_hash, schedule, imcre_merkle, blocks_ffi, blocks_ffi_size, ids_list, ids_list_size) {
+        Ok(call) => call,
+        Err(resp) => return resp,
+    };
+
+    let result = futures::executor::block_on(async move {
+        crate::rpc_calls::dry_run(urls, signer, call).await
+    });
+
+    match result {
+        Ok(report) => generate_raw_result(true, report.to_json().to_string()),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn estimate_change_schedule_fee(
+    urls:                 *const c_char,
+    signer:               *const c_char,
+    legacy_schedule_hash: Checksum256,
+    schedule:             *const c_char,
+    imcre_merkle:         *const c_char,
+    blocks_ffi:           *const c_char,
+    blocks_ffi_size:      size_t,
+    ids_list:             *const c_char,
+    ids_list_size:        size_t
+) -> Box<RpcResponse> {
+    let (urls, signer) = match urls_and_signer(urls, signer) {
+        Ok(r) => r,
+        Err(resp) => return resp,
+    };
+
+    let call = match change_schedule_args(legacy_schedule_hash, schedule, imcre_merkle, blocks_ffi, blocks_ffi_size, ids_list, ids_list_size) {
+        Ok(call) => call,
+        Err(resp) => return resp,
+    };
+
+    let result = futures::executor::block_on(async move {
+        crate::rpc_calls::estimate_fee(urls, signer, call).await
+    });
+
+    match result {
+        Ok(fee) => generate_raw_result(true, fee.to_json().to_string()),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn prove_action(
+    urls:                *const c_char,
+    signer:              *const c_char,
+    act_ffi:             *const ActionFFI,
+    imcre_merkle:        *const IncrementalMerkleFFI,
+    act_receipt:         *const ActionReceiptFFI,
+    action_merkle_paths: *const Checksum256FFI,
+    blocks_ffi:          *const SignedBlockHeaderFFI,
+    blocks_ffi_size:     size_t,
+    ids_list:            *const Checksum256FFI,
+    ids_list_size:       size_t,
+    trx_id:              Checksum256
+) -> Box<RpcResponse> {
+    let call = match prove_action_args(act_ffi, imcre_merkle, act_receipt, action_merkle_paths, blocks_ffi, blocks_ffi_size, ids_list, ids_list_size, trx_id) {
+        Ok(call) => call,
+        Err(resp) => return resp,
+    };
+
+    let (urls, signer) = match urls_and_signer(urls, signer) {
+        Ok(r) => r,
+        Err(resp) => return resp,
+    };
+
+    let result = futures::executor::block_on(async move {
+        crate::rpc_calls::prove_action_call(urls, signer, call).await
+    });
+
+    // send and watch extrinsic until finalized
+    transaction_result(result)
+}
+
+#[no_mangle]
+pub extern "C" fn prove_action_dry_run(
+    urls:                *const c_char,
+    signer:              *const c_char,
+    act_ffi:             *const ActionFFI,
+    imcre_merkle:        *const IncrementalMerkleFFI,
+    act_receipt:         *const ActionReceiptFFI,
+    action_merkle_paths: *const Checksum256FFI,
+    blocks_ffi:          *const SignedBlockHeaderFFI,
+    blocks_ffi_size:     size_t,
+    ids_list:            *const Checksum256FFI,
+    ids_list_size:       size_t,
+    trx_id:              Checksum256
+) -> Box<RpcResponse> {
+    let call = match prove_action_args(act_ffi, imcre_merkle, act_receipt, action_merkle_paths, blocks_ffi, blocks_ffi_size, ids_list, ids_list_size, trx_id) {
+        Ok(call) => call,
+        Err(resp) => return resp,
+    };
+
+    let (urls, signer) = match urls_and_signer(urls, signer) {
+        Ok(r) => r,
+        Err(resp) => return resp,
+    };
+
+    let result = futures::executor::block_on(async move {
+        crate::rpc_calls::dry_run(urls, signer, call).await
+    });
+
+    match result {
+        Ok(report) => generate_raw_result(true, report.to_json().to_string()),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn prove_actions_batch(
+    urls:       *const c_char,
+    signer:     *const c_char,
+    items:      *const ProveActionFFI,
+    items_size: size_t,
+) -> Box<RpcResponse> {
+    if items.is_null() || items_size == 0 {
+        return generate_raw_result(false, "cannot send actions to bifrost node due to there's no proof");
+    }
+
+    let (urls, signer) = match urls_and_signer(urls, signer) {
+        Ok(r) => r,
+        Err(resp) => return resp,
+    };
+
+    let items = unsafe { slice::from_raw_parts(items, items_size) };
+    let mut calls = Vec::with_capacity(items_size);
+    for item in items.iter() {
+        match item.try_into() {
+            Ok(call) => calls.push(call),
+            Err(e) => return generate_error_result(&e),
+        }
+    }
+
+    let result = futures::executor::block_on(async move {
+        crate::rpc_calls::prove_actions_batch_call(urls, signer, calls).await
+    });
+
+    // the whole response succeeds once batches are sent, check each item for its own result
+    match result {
+        Ok(results) => {
+            let results: Vec<_> = results.iter().map(|r| r.to_json()).collect();
+            generate_raw_result(true, serde_json::Value::Array(results).to_string())
+        }
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn enqueue_change_schedule(
+    legacy_schedule_hash: Checksum256,
+    schedule:             *const c_char,
+    imcre_merkle:         *const c_char,
+    blocks_ffi:           *const c_char,
+    blocks_ffi_size:      size_t,
+    ids_list:             *const c_char,
+    ids_list_size:        size_t
+) -> Box<RpcResponse> {
+    let call = match change_schedule_args(legacy_schedule_hash, schedule, imcre_merkle, blocks_ffi, blocks_ffi_size, ids_list, ids_list_size) {
+        Ok(call) => call,
+        Err(resp) => return resp,
+    };
+
+    // id of queued submission
+    match crate::queue::enqueue(crate::queue::Submission::ChangeSchedule(call)) {
+        Ok(id) => generate_raw_result(true, id.to_string()),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn enqueue_prove_action(
+    act_ffi:             *const ActionFFI,
+    imcre_merkle:        *const IncrementalMerkleFFI,
+    act_receipt:         *const ActionReceiptFFI,
+    action_merkle_paths: *const Checksum256FFI,
+    blocks_ffi:          *const SignedBlockHeaderFFI,
+    blocks_ffi_size:     size_t,
+    ids_list:            *const Checksum256FFI,
+    ids_list_size:       size_t,
+    trx_id:              Checksum256
+) -> Box<RpcResponse> {
+    let call = match prove_action_args(act_ffi, imcre_merkle, act_receipt, action_merkle_paths, blocks_ffi, blocks_ffi_size, ids_list, ids_list_size, trx_id) {
+        Ok(call) => call,
+        Err(resp) => return resp,
+    };
+
+    match crate::queue::enqueue(crate::queue::Submission::ProveAction(call)) {
+        Ok(id) => generate_raw_result(true, id.to_string()),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn cache_block(
+    block_num:    u32,
+    id:           Checksum256,
+    header:       *const SignedBlockHeaderFFI,
+    imcre_merkle: *const IncrementalMerkleFFI,
+) -> bool {
+    if header.is_null() || imcre_merkle.is_null() {
+        println!("[!] Cannot cache block {} due to there're null points\n", block_num);
+        return false;
+    }
+
+    let header: Result<SignedBlockHeader, _> = {
+        let ffi = &unsafe { ptr::read(header) };
+        ffi.try_into()
+    };
+    let blockroot_merkle: Result<IncrementalMerkle, _> = {
+        let ffi = &unsafe { ptr::read(imcre_merkle) };
+        ffi.try_into()
+    };
+
+    let cached = header.and_then(|header| blockroot_merkle.map(|blockroot_merkle| (header, blockroot_merkle)))
+        .and_then(|(header, blockroot_merkle)| crate::block_cache::insert(block_num, crate::block_cache::CachedBlock { id, header, blockroot_merkle }));
+    match cached {
+        Ok(_) => true,
+        Err(e) => {
+            println!("[!] Failed to cache block {} due to: {:?}\n", block_num, e);
+            false
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_block_cache_floor(block_num: u32) -> bool {
+    let floor = if block_num == 0 { None } else { Some(block_num) };
+    match crate::block_cache::set_floor(floor) {
+        Ok(_) => true,
+        Err(e) => {
+            println!("[!] Failed to set block cache floor to {} due to: {:?}\n", block_num, e);
+            false
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn block_cache_ready(block_num: u32) -> bool {
+    crate::block_cache::is_ready(block_num)
+}
+
+// 0 once every block is cached
+#[no_mangle]
+pub extern "C" fn block_cache_next_missing(block_num: u32) -> u32 {
+    crate::block_cache::next_missing(block_num).unwrap_or(0)
+}
+
+#[no_mangle]
+pub extern "C" fn rebuild_cached_block(
+    block_num: u32,
+    id:        Checksum256,
+    header:    *const SignedBlockHeaderFFI,
+) -> bool {
+    if header.is_null() {
+        println!("[!] Cannot rebuild block {} due to there're null points\n", block_num);
+        return false;
+    }
+
+    let header: Result<SignedBlockHeader, _> = {
+        let ffi = &unsafe { ptr::read(header) };
+        ffi.try_into()
+    };
+    match header.and_then(|header| crate::block_cache::rebuild(block_num, id, header)) {
+        Ok(_) => true,
+        Err(e) => {
+            println!("[!] Failed to rebuild block {} due to: {:?}\n", block_num, e);
+            false
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn block_cache_status() -> Box<RpcResponse> {
+    generate_raw_result(true, crate::block_cache::cache_status().to_string())
+}
+
+#[no_mangle]
+pub extern "C" fn enqueue_change_schedule_cached(
+    legacy_schedule_hash: Checksum256,
+    schedule:             *const ProducerAuthorityScheduleFFI,
+    block_num:            u32,
+) -> Box<RpcResponse> {
+    if schedule.is_null() {
+        return generate_raw_result(false, "cannot enqueue schedule change due to null schedule");
+    }
+
+    let new_schedule: ProducerAuthoritySchedule = {
+        let ffi = &unsafe { ptr::read(schedule) };
+        match ffi.try_into() {
+            Ok(schedule) => schedule,
+            Err(e) => return generate_error_result(&e),
+        }
+    };
+
+    let parts = match crate::block_cache::proof_parts(block_num) {
+        Some(parts) => parts,
+        None => return generate_raw_result(false, format!("blocks to prove block {} are not cached yet", block_num)),
+    };
+
+    let call = ChangeScheduleCall {
+        legacy_schedule_hash,
+        schedule: new_schedule,
+        merkle: parts.merkle,
+        block_headers: parts.block_headers,
+        block_ids_list: parts.block_ids_list,
+        _runtime: PhantomData,
+    };
+    match crate::queue::enqueue(crate::queue::Submission::ChangeSchedule(call)) {
+        Ok(id) => generate_raw_result(true, id.to_string()),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn enqueue_prove_action_cached(
+    act_ffi:             *const ActionFFI,
+    act_receipt:         *const ActionReceiptFFI,
+    action_merkle_paths: *const Checksum256FFI,
+    trx_id:              Checksum256,
+    block_num:           u32,
+) -> Box<RpcResponse> {
+    if act_ffi.is_null() || act_receipt.is_null() || action_merkle_paths.is_null() {
+        return generate_raw_result(false, "cannot enqueue action proof due to there're null points");
+    }
+
+    let action: Action = {
+        let ffi = &unsafe { ptr::read(act_ffi) };
+        match ffi.try_into() {
+            Ok(action) => action,
+            Err(e) => return generate_error_result(&e),
+        }
+    };
+    let action_receipt: ActionReceipt = {
+        let ffi = &unsafe { ptr::read(act_receipt) };
+        match ffi.try_into() {
+            Ok(receipt) => receipt,
+            Err(e) => return generate_error_result(&e),
+        }
+    };
+    let action_merkle_paths: Vec<Checksum256> = {
+        let ffi = &unsafe { ptr::read(action_merkle_paths) };
+        match ffi.try_into() {
+            Ok(paths) => paths,
+            Err(e) => return generate_error_result(&e),
+        }
+    };
+
+    let parts = match crate::block_cache::proof_parts(block_num) {
+        Some(parts) => parts,
+        None => return generate_raw_result(false, format!("blocks to prove block {} are not cached yet", block_num)),
+    };
+
+    let call = ProveActionCall {
+        action,
+        action_receipt,
+        action_merkle_paths,
+        merkle: parts.merkle,
+        block_headers: parts.block_headers,
+        block_ids_list: parts.block_ids_list,
+        trx_id,
+        _runtime: PhantomData,
+    };
+    match crate::queue::enqueue(crate::queue::Submission::ProveAction(call)) {
+        Ok(id) => generate_raw_result(true, id.to_string()),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn process_submission_queue(
+    urls:   *const c_char,
+    signer: *const c_char,
+) -> Box<RpcResponse> {
+    let (urls, signer) = match urls_and_signer(urls, signer) {
+        Ok(r) => r,
+        Err(resp) => return resp,
+    };
+
+    let result = futures::executor::block_on(async move {
+        crate::queue::process(urls, signer).await
+    });
+
+    match result {
+        Ok(results) => {
+            let results: Vec<_> = results.iter().map(|r| r.to_json()).collect();
+            generate_raw_result(true, serde_json::Value::Array(results).to_string())
+        }
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn submission_queue_status() -> Box<RpcResponse> {
+    generate_raw_result(true, crate::queue::queue_status().to_string())
+}
+
+#[no_mangle]
+pub extern "C" fn submission_queue_pending() -> u64 {
+    crate::queue::pending_ids().len() as u64
+}
+
+/// Called once for each entry by `relay_store_load`, buffers are only valid during the call.
+pub type StoreEntryCallback = extern "C" fn(
+    ctx:        *mut c_void,
+    key:        *const c_char,
+    key_size:   size_t,
+    value:      *const c_char,
+    value_size: size_t,
+);
+
+#[no_mangle]
+pub extern "C" fn open_relay_store(path: *const c_char) -> Box<RpcResponse> {
+    if path.is_null() {
+        return generate_raw_result(false, "cannot open relay store due to null path");
+    }
+
+    let path = {
+        let path = char_to_string(path);
+        if path.is_err() {
+            return generate_raw_result(false, "This is not an valid relay store path.");
         }
+        path.unwrap()
+    };
+
+    if let Err(e) = crate::store::open(&path) {
+        return generate_error_result(&e);
+    }
+
+    // submissions queued, blocks cached, redeem requests tracked and refunds queued before last shutdown
+    let restored = crate::queue::restore()
+        .and_then(|submissions| crate::block_cache::restore().map(|blocks| (submissions, blocks)))
+        .and_then(|(submissions, blocks)| crate::redeem::restore().map(|requests| (submissions, blocks, requests)))
+        .and_then(|(submissions, blocks, requests)| crate::memo::restore_refunds().map(|refunds| (submissions, blocks, requests, refunds)));
+    match restored {
+        Ok((submissions, blocks, requests, refunds)) => generate_raw_result(true, format!(
+            "restored {} queued submissions, {} cached blocks, {} redeem requests and {} refunds", submissions, blocks, requests, refunds
+        )),
+        Err(e) => generate_error_result(&e),
     }
 }
 
-impl std::error::Error for Error {
-    fn description(&self) -> &str {
-        match *self {
-            Self::NullPtr(_) => "Null pointer.",
-            Self::CStrConvertError => "Failed to convert c string to rust string.",
-            Self::PublicKeyError => "Failed to convert string to PublicKeyError.",
-            Self::SignatureError => "Failed to convert string to Signature.",
-            Self::WrongSudoSeed => "Wrong sudo seed, failed to sign transaction.",
-            Self::SubxtError(e) => e,
+#[no_mangle]
+pub extern "C" fn relay_store_put(
+    tree:       *const c_char,
+    key:        *const c_char,
+    key_size:   size_t,
+    value:      *const c_char,
+    value_size: size_t,
+) -> Box<RpcResponse> {
+    if tree.is_null() || key.is_null() || value.is_null() {
+        return generate_raw_result(false, "cannot write relay store due to there're null points");
+    }
+
+    let tree = match char_to_string(tree) {
+        Ok(tree) => tree,
+        Err(e) => return generate_error_result(&e),
+    };
+    let key = unsafe { slice::from_raw_parts(key as *const u8, key_size) };
+    let value = unsafe { slice::from_raw_parts(value as *const u8, value_size) };
+
+    match crate::store::put(&tree, key, value) {
+        Ok(_) => generate_raw_result(true, ""),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn relay_store_remove(
+    tree:     *const c_char,
+    key:      *const c_char,
+    key_size: size_t,
+) -> Box<RpcResponse> {
+    if tree.is_null() || key.is_null() {
+        return generate_raw_result(false, "cannot write relay store due to there're null points");
+    }
+
+    let tree = match char_to_string(tree) {
+        Ok(tree) => tree,
+        Err(e) => return generate_error_result(&e),
+    };
+    let key = unsafe { slice::from_raw_parts(key as *const u8, key_size) };
+
+    match crate::store::remove(&tree, key) {
+        Ok(_) => generate_raw_result(true, ""),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn relay_store_load(
+    tree:     *const c_char,
+    callback: StoreEntryCallback,
+    ctx:      *mut c_void,
+) -> Box<RpcResponse> {
+    if tree.is_null() {
+        return generate_raw_result(false, "cannot read relay store due to null tree name");
+    }
+
+    let tree = match char_to_string(tree) {
+        Ok(tree) => tree,
+        Err(e) => return generate_error_result(&e),
+    };
+
+    let loaded = crate::store::for_each(&tree, |key, value| {
+        callback(ctx, key.as_ptr() as *const c_char, key.len(), value.as_ptr() as *const c_char, value.len());
+    });
+
+    // how many entries are loaded
+    match loaded {
+        Ok(count) => generate_raw_result(true, count.to_string()),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn close_relay_store() -> Box<RpcResponse> {
+    match crate::store::close() {
+        Ok(_) => generate_raw_result(true, ""),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn reconcile_outbox(urls: *const c_char) -> Box<RpcResponse> {
+    if urls.is_null() {
+        return generate_raw_result(false, "cannot reconcile outbox due to null bifrost node address");
+    }
+
+    let urls = {
+        let urls = char_to_string(urls);
+        if urls.is_err() {
+            return generate_raw_result(false, "This is not an valid bifrost node address.");
         }
+        vec![urls.unwrap()]
+    };
+
+    let result = futures::executor::block_on(async move {
+        crate::outbox::reconcile(urls).await
+    });
+
+    match result {
+        Ok(reconciliation) => generate_raw_result(true, reconciliation.to_json().to_string()),
+        Err(e) => generate_error_result(&e),
     }
 }
 
 #[no_mangle]
-pub extern "C" fn change_schedule(
-    urls:                 *const c_char,
-    signer:               *const c_char,
-    legacy_schedule_hash: Checksum256,
-    schedule:             *const c_char,
-    imcre_merkle:         *const c_char,
-    blocks_ffi:           *const c_char,
-    blocks_ffi_size:      size_t,
-    ids_list:             *const c_char,
-    ids_list_size:        size_t
+pub extern "C" fn outbox_entries() -> Box<RpcResponse> {
+    match crate::outbox::entries() {
+        Ok(entries) => {
+            let entries: Vec<_> = entries.iter().map(|e| e.to_json()).collect();
+            generate_raw_result(true, serde_json::Value::Array(entries).to_string())
+        }
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn query_producer_schedule(
+    urls:     *const c_char,
+    schedule: *const c_char, // optional, compare it with the stored one if it's not null
 ) -> Box<RpcResponse> {
-    // check pointers null or not
-    match (urls.is_null(), signer.is_null(), schedule.is_null(), imcre_merkle.is_null(), blocks_ffi.is_null(), ids_list.is_null()) {
-        (false, false, false, false, false, false) => (),
-        _ => {
-            return generate_raw_result(false, "cannot send action to bifrost node to prove it due to there're null points");
+    if urls.is_null() {
+        return generate_raw_result(false, "cannot query producer schedule due to null bifrost node address");
+    }
+
+    let urls = {
+        let urls = char_to_string(urls);
+        if urls.is_err() {
+            return generate_raw_result(false, "This is not an valid bifrost node address.");
+        }
+        vec![urls.unwrap()]
+    };
+
+    let supplied = if schedule.is_null() {
+        None
+    } else {
+        let schedule_str = char_to_string(schedule);
+        if schedule_str.is_err() {
+            return generate_raw_result(false, "This is not an valid producer schedule.");
+        }
+        let schedule: Result<ProducerAuthoritySchedule, _> = serde_json::from_str(schedule_str.as_ref().unwrap());
+        if schedule.is_err() {
+            return generate_raw_result(false, "Failed to deserialize producer schedule");
         }
+        Some(schedule.unwrap())
+    };
+
+    let result = futures::executor::block_on(async move {
+        crate::rpc_calls::query_producer_schedule(urls, supplied).await
+    });
+
+    match result {
+        Ok(sync) => generate_raw_result(true, sync.to_json().to_string()),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn is_action_proven(
+    urls:        *const c_char,
+    act_receipt: *const ActionReceiptFFI,
+) -> Box<RpcResponse> {
+    if urls.is_null() || act_receipt.is_null() {
+        return generate_raw_result(false, "cannot query action receipt due to there're null points");
     }
 
     let urls = {
@@ -88,26 +1276,124 @@ pub extern "C" fn change_schedule(
         if urls.is_err() {
             return generate_raw_result(false, "This is not an valid bifrost node address.");
         }
+        vec![urls.unwrap()]
+    };
+
+    let action_receipt: ActionReceipt = {
+        let act_ffi = &unsafe { ptr::read(act_receipt) };
+        let r: Result<ActionReceipt, _> = act_ffi.try_into();
+        if r.is_err() {
+            return generate_raw_result(false, r.unwrap_err().to_string());
+        }
+        r.unwrap()
+    };
+
+    let result = futures::executor::block_on(async move {
+        crate::rpc_calls::is_action_proven(urls, action_receipt).await
+    });
+
+    match result {
+        Ok(proven) => generate_raw_result(true, proven.to_string()),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn estimate_prove_action_fee(
+    urls:                *const c_char,
+    signer:              *const c_char,
+    act_ffi:             *const ActionFFI,
+    imcre_merkle:        *const IncrementalMerkleFFI,
+    act_receipt:         *const ActionReceiptFFI,
+    action_merkle_paths: *const Checksum256FFI,
+    blocks_ffi:          *const SignedBlockHeaderFFI,
+    blocks_ffi_size:     size_t,
+    ids_list:            *const Checksum256FFI,
+    ids_list_size:       size_t,
+    trx_id:              Checksum256
+) -> Box<RpcResponse> {
+    let call = match prove_action_args(act_ffi, imcre_merkle, act_receipt, action_merkle_paths, blocks_ffi, blocks_ffi_size, ids_list, ids_list_size, trx_id) {
+        Ok(call) => call,
+        Err(resp) => return resp,
+    };
+
+    let (urls, signer) = match urls_and_signer(urls, signer) {
+        Ok(r) => r,
+        Err(resp) => return resp,
+    };
+
+    let result = futures::executor::block_on(async move {
+        crate::rpc_calls::estimate_fee(urls, signer, call).await
+    });
+
+    match result {
+        Ok(fee) => generate_raw_result(true, fee.to_json().to_string()),
+        Err(e) => generate_error_result(&e),
+    }
+}
+
+fn transaction_result(result: Result<String, Error>) -> Box<RpcResponse> {
+    match result {
+        Ok(tx_hash) => {
+            println!("[+] Transaction got finalized and its id: {:?}\n", tx_hash);
+            generate_raw_result(true, tx_hash)
+        }
+        Err(e) => {
+            println!("[+] Transaction got failure due to: {:?}\n", e);
+            generate_error_result(&e)
+        }
+    }
+}
+
+fn urls_and_signer(urls: *const c_char, signer: *const c_char) -> Result<(Vec<String>, String), Box<RpcResponse>> {
+    if urls.is_null() || signer.is_null() {
+        return Err(generate_raw_result(false, "cannot connect to bifrost node due to there're null points"));
+    }
 
+    let urls = {
+        let urls = char_to_string(urls);
+        if urls.is_err() {
+            return Err(generate_raw_result(false, "This is not an valid bifrost node address."));
+        }
         vec![urls.unwrap()]
     };
 
     let signer = {
         let signer = char_to_string(signer);
         if signer.is_err() {
-            return generate_raw_result(false, "This is not an valid bifrost node address.");
+            return Err(generate_raw_result(false, "This is not an valid signer seed."));
         }
         signer.unwrap()
     };
 
+    Ok((urls, signer))
+}
+
+fn change_schedule_args(
+    legacy_schedule_hash: Checksum256,
+    schedule:             *const c_char,
+    imcre_merkle:         *const c_char,
+    blocks_ffi:           *const c_char,
+    _blocks_ffi_size:     size_t,
+    ids_list:             *const c_char,
+    _ids_list_size:       size_t
+) -> Result<ChangeScheduleCall<BifrostRuntime>, Box<RpcResponse>> {
+    // check pointers null or not
+    match (schedule.is_null(), imcre_merkle.is_null(), blocks_ffi.is_null(), ids_list.is_null()) {
+        (false, false, false, false) => (),
+        _ => {
+            return Err(generate_raw_result(false, "cannot send action to bifrost node to prove it due to there're null points"));
+        }
+    }
+
     let new_schedule = {
         let new_schedule_str = char_to_string(schedule);
         if new_schedule_str.is_err() {
-            return generate_raw_result(false, "This is not an valid producer schedule.");
+            return Err(generate_raw_result(false, "This is not an valid producer schedule."));
         }
         let new_schedule: Result<ProducerAuthoritySchedule, _> = serde_json::from_str(new_schedule_str.as_ref().unwrap());
         if new_schedule.is_err() {
-            return generate_raw_result(false, "Failed to deserialize producer schedule".to_owned());
+            return Err(generate_raw_result(false, "Failed to deserialize producer schedule".to_owned()));
         }
         new_schedule.unwrap()
     };
@@ -115,11 +1401,11 @@ pub extern "C" fn change_schedule(
     let merkle: IncrementalMerkle = {
         let imcre_merkle_str = char_to_string(imcre_merkle);
         if imcre_merkle_str.is_err() {
-            return generate_raw_result(false, "This is not an valid IncrementalMerklee.");
+            return Err(generate_raw_result(false, "This is not an valid IncrementalMerklee."));
         }
         let merkle: Result<IncrementalMerkle, _> = serde_json::from_str(imcre_merkle_str.as_ref().unwrap());
         if merkle.is_err() {
-            return generate_raw_result(false, "Failed to deserialize IncrementalMerklee".to_owned());
+            return Err(generate_raw_result(false, "Failed to deserialize IncrementalMerklee".to_owned()));
         }
         merkle.unwrap()
     };
@@ -127,89 +1413,38 @@ pub extern "C" fn change_schedule(
     let block_headers: Vec<SignedBlockHeader> = {
         let blockers_str = char_to_string(blocks_ffi);
         if blockers_str.is_err() {
-            return generate_raw_result(false, "This is not an valid SignedBlockHeader.");
+            return Err(generate_raw_result(false, "This is not an valid SignedBlockHeader."));
         }
         let block_headers: Result<Vec<SignedBlockHeader>, _> = serde_json::from_str(blockers_str.as_ref().unwrap());
         if block_headers.is_err() {
-            return generate_raw_result(false, "Failed to deserialize SignedBlockHeader".to_owned());
+            return Err(generate_raw_result(false, "Failed to deserialize SignedBlockHeader".to_owned()));
         }
         block_headers.unwrap()
     };
 
     let ids_lists: Vec<Vec<Checksum256>> = {
-        let ids_lists_str = char_to_string(blocks_ffi);
+        let ids_lists_str = char_to_string(ids_list);
         if ids_lists_str.is_err() {
-            return generate_raw_result(false, "This is not an valid block id list string.");
+            return Err(generate_raw_result(false, "This is not an valid block id list string."));
         }
         let ids_lists: Result<Vec<Vec<Checksum256>>, _> = serde_json::from_str(ids_lists_str.as_ref().unwrap());
         if ids_lists.is_err() {
-            return generate_raw_result(false, "Failed to deserialize block id list".to_owned());
+            return Err(generate_raw_result(false, "Failed to deserialize block id list".to_owned()));
         }
         ids_lists.unwrap()
     };
-//    let merkle: IncrementalMerkle = {
-//        let imcre_merkle = &unsafe { ptr::read(imcre_merkle) };
-//        let r: Result<IncrementalMerkle, _> = imcre_merkle.try_into();
-//        if r.is_err() {
-//            return generate_raw_result(false, r.unwrap_err().to_string());
-//        }
-//        r.unwrap()
-//    };
-
-//    let block_headers: Vec<SignedBlockHeader> = {
-//        let blocks_ffi = &unsafe { slice::from_raw_parts(blocks_ffi, blocks_ffi_size) };
-//        let mut block_headers: Vec<_> = Vec::with_capacity(blocks_ffi_size);
-//        for block in blocks_ffi.iter() {
-//            let ffi = &unsafe { ptr::read(block) };
-//            let r: Result<SignedBlockHeader, Error> = ffi.try_into();
-//            if r.is_err() {
-//                return generate_raw_result(false, r.unwrap_err().to_string());
-//            }
-//            block_headers.push(r.unwrap());
-//        }
-//        block_headers
-//    };
-
-//    let mut ids_lists: Vec<Vec<Checksum256>>= Vec::with_capacity(15);
-//    ids_lists.push(Vec::new());
-//    let ids_list_ffi = &unsafe { slice::from_raw_parts(ids_list, ids_list_size) };
-//    for ids in ids_list_ffi.iter().skip(1) { // skip first ids due to it's am empty list(null pointer)
-//        let r: Result<Vec<Checksum256>, _> = ids.try_into();
-//        if r.is_err() {
-//            return generate_raw_result(false, r.unwrap_err().to_string());
-//        }
-//        ids_lists.push(r.unwrap());
-//    }
-
-    let result = futures::executor::block_on(async move {
-        crate::rpc_calls::change_schedule_call(
-            urls,
-            signer,
-            legacy_schedule_hash,
-            new_schedule,
-            merkle,
-            block_headers,
-            ids_lists,
-        ).await
-    });
 
-    // send and watch extrinsic until finalized
-    match result {
-        Ok(tx_hash) => {
-            println!("[+] Transaction got finalized and its id: {:?}\n", tx_hash);
-            generate_raw_result(true, tx_hash.to_string())
-        }
-        Err(e) => {
-            println!("[+] Transaction got failure due to: {:?}\n", e);
-            generate_raw_result(false, e.to_string())
-        }
-    }
+    Ok(ChangeScheduleCall {
+        legacy_schedule_hash,
+        schedule: new_schedule,
+        merkle,
+        block_headers,
+        block_ids_list: ids_lists,
+        _runtime: PhantomData,
+    })
 }
 
-#[no_mangle]
-pub extern "C" fn prove_action(
-    urls:                *const c_char,
-    signer:              *const c_char,
+fn prove_action_args(
     act_ffi:             *const ActionFFI,
     imcre_merkle:        *const IncrementalMerkleFFI,
     act_receipt:         *const ActionReceiptFFI,
@@ -219,117 +1454,51 @@ pub extern "C" fn prove_action(
     ids_list:            *const Checksum256FFI,
     ids_list_size:       size_t,
     trx_id:              Checksum256
-) -> Box<RpcResponse> {
-    match (
-        urls.is_null(), signer.is_null(), act_ffi.is_null(), imcre_merkle.is_null(),
-        act_receipt.is_null(), action_merkle_paths.is_null(), blocks_ffi.is_null(), ids_list.is_null()
-    ) {
-        (false, false, false, false, false, false, false, false) => (),
-        _ => { // if there's any null pointer, just return
-            return generate_raw_result(false, "cannot send action to bifrost node to prove it due to there're null points");
-        }
-    }
-
-    let action: Action = {
-        let ffi = &unsafe { ptr::read(act_ffi) };
-        let r: Result<Action, _> = ffi.try_into();
-        if r.is_err() {
-            return generate_raw_result(false, r.unwrap_err().to_string());
-        }
-        r.unwrap()
-    };
-
-    let merkle: IncrementalMerkle = {
-        let imcre_merkle = &unsafe { ptr::read(imcre_merkle) };
-        let r: Result<IncrementalMerkle, _> = imcre_merkle.try_into();
-        if r.is_err() {
-            return generate_raw_result(false, r.unwrap_err().to_string());
-        }
-        r.unwrap()
-    };
-
-    let action_receipt: ActionReceipt = {
-        let act_ffi = &unsafe { ptr::read(act_receipt) };
-        let r: Result<ActionReceipt, _> = act_ffi.try_into();
-        if r.is_err() {
-            return generate_raw_result(false, r.unwrap_err().to_string());
-        }
-        r.unwrap()
-    };
-
-    let action_merkle_paths: Vec<Checksum256> = {
-        let paths = &unsafe { ptr::read(action_merkle_paths) };
-        let r: Result<Vec<Checksum256>, _> = paths.try_into();
-        if r.is_err() {
-            return generate_raw_result(false, r.unwrap_err().to_string());
-        }
-        r.unwrap()
+) -> Result<ProveActionCall<BifrostRuntime>, Box<RpcResponse>> {
+    let ffi = ProveActionFFI {
+        act_ffi,
+        imcre_merkle,
+        act_receipt,
+        action_merkle_paths,
+        blocks_ffi,
+        blocks_ffi_size,
+        ids_list,
+        ids_list_size,
+        trx_id,
     };
 
-    let block_headers: Vec<SignedBlockHeader> = {
-        let blocks_ffi = &unsafe { slice::from_raw_parts(blocks_ffi, blocks_ffi_size) };
-        let mut block_headers: Vec<_> = Vec::with_capacity(blocks_ffi_size);
-        for block in blocks_ffi.iter() {
-            let ffi = &unsafe { ptr::read(block) };
-            let r: Result<SignedBlockHeader, Error> = ffi.try_into();
-            if r.is_err() {
-                return generate_raw_result(false, r.unwrap_err().to_string());
-            }
-            block_headers.push(r.unwrap());
-        }
-        block_headers
-    };
-
-    let mut ids_lists: Vec<Vec<Checksum256>>= Vec::with_capacity(15);
-    ids_lists.push(Vec::new());
-    let ids_list_ffi = &unsafe { slice::from_raw_parts(ids_list, ids_list_size) };
-    for ids in ids_list_ffi.iter().skip(1) { // skip first ids due to it's am empty list(null pointer)
-        let r: Result<Vec<Checksum256>, _> = ids.try_into();
-        if r.is_err() {
-            return generate_raw_result(false, r.unwrap_err().to_string());
-        }
-        ids_lists.push(r.unwrap());
-    }
-
-    let urls = {
-        let urls = char_to_string(urls);
-        if urls.is_err() {
-            return generate_raw_result(false, "This is not an valid bifrost node address.");
-        }
-        vec![urls.unwrap()]
-    };
+    (&ffi).try_into().map_err(|e: Error| generate_error_result(&e))
+}
 
-    let signer = {
-        let signer = char_to_string(signer);
-        if signer.is_err() {
-            return generate_raw_result(false, "This is not an valid bifrost node address.");
-        }
-        signer.unwrap()
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{checksum, header, header_json, id_bytes};
+    use std::ffi::CString;
 
-    let result = futures::executor::block_on(async move {
-        crate::rpc_calls::prove_action_call(
-            urls,
-            signer,
-            action,
-            action_receipt,
-            action_merkle_paths,
-            merkle,
-            block_headers,
-            ids_lists,
-            trx_id
-        ).await
-    });
+    // json arguments as c++ side writes them by fc::json::to_string
+    #[test]
+    fn change_schedule_args_reads_ids_list_from_its_own_argument() {
+        let schedule = CString::new(r#"{"version": 2, "producers": []}"#).unwrap();
+        let merkle = CString::new(r#"{"_active_nodes": [], "_node_count": 0}"#).unwrap();
+        let blocks = CString::new(format!("[{}]", header_json(id_bytes(100, 0), 1))).unwrap();
+        let ids_list = CString::new(format!(r#"[[], ["{}"]]"#, "3".repeat(64))).unwrap();
 
-    // send and watch extrinsic until finalized
-    match result {
-        Ok(tx_hash) => {
-            println!("[+] Transaction got finalized and its id: {:?}\n", tx_hash);
-            generate_raw_result(true, tx_hash.to_string())
-        }
-        Err(e) => {
-            println!("[+] Transaction got failure due to: {:?}\n", e);
-            generate_raw_result(false, e.to_string())
-        }
+        let call = change_schedule_args(
+            checksum(0x44),
+            schedule.as_ptr(),
+            merkle.as_ptr(),
+            blocks.as_ptr(),
+            blocks.as_bytes().len(),
+            ids_list.as_ptr(),
+            ids_list.as_bytes().len(),
+        );
+        let call = match call {
+            Ok(call) => call,
+            Err(_) => panic!("block id lists should be read from ids_list"),
+        };
+        assert_eq!(call.schedule.version, 2);
+        assert_eq!(call.block_headers, vec![header(101, 1)]);
+        assert_eq!(call.block_ids_list, vec![vec![], vec![checksum(0x33)]]);
     }
 }
@@ -0,0 +1,465 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Memo of an inbound transfer looks like `5GrwvaEF...@bifrost:vEOS`,
+//! the ss58 destination, the network tag and the asset to receive on bifrost.
+//! A transfer nobody could be credited by is queued for a refund, which the host
+//! sends back from the cross account with memo `refund:<trx_id>`.
+
+use codec::{Decode, Encode};
+use eos_chain::{AccountName, Checksum256};
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use sp_core::crypto::{AccountId32, Ss58Codec};
+use std::sync::Mutex;
+
+const REFUND_PREFIX: &str = "refund:";
+
+pub const REFUNDS_TREE: &str = "refunds";
+const QUEUE_KEY: &[u8] = b"queue";
+
+/// Refunds over this are refused and only logged, the transfers are still on EOS for a manual refund.
+const MAX_REFUNDS: usize = 1024;
+/// Seconds before a refund handed over to the host is handed over again, EOS transactions expire in 30s.
+const RESEND_AFTER: u64 = 300;
+
+static REFUND_QUEUE: Lazy<Mutex<RefundQueue>> = Lazy::new(|| Mutex::new(RefundQueue::default()));
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferMemo {
+	pub address:     String,
+	pub account:     AccountId32,
+	pub ss58_prefix: u8,
+	pub network:     String,
+	pub asset:       String,
+}
+
+impl TransferMemo {
+	pub fn to_json(&self) -> Value {
+		json!({
+			"address": self.address,
+			"ss58_prefix": self.ss58_prefix,
+			"network": self.network,
+			"asset": self.asset,
+		})
+	}
+}
+
+/// Inbound transfer that cannot be credited to anyone on bifrost.
+#[derive(Clone, Debug, PartialEq, Encode, Decode)]
+pub struct RefundRequest {
+	pub trx_id:    Checksum256,
+	pub contract:  AccountName,
+	pub from:      AccountName,
+	pub quantity:  String,
+	pub memo:      String,
+	pub reason:    String,
+	pub queued_at: u64,
+	pub sent_at:   Option<u64>,
+	/// Refund transfer seen on EOS and its block, it's settled once the block is irreversible.
+	pub refunded:  Option<(Checksum256, u32)>,
+}
+
+impl RefundRequest {
+	pub fn to_json(&self) -> Value {
+		json!({
+			"trx_id": self.trx_id.to_string(),
+			"contract": self.contract.to_string(),
+			"from": self.from.to_string(),
+			"quantity": self.quantity,
+			"memo": self.memo,
+			"reason": self.reason,
+			"queued_at": self.queued_at,
+			"sent_at": self.sent_at,
+			"refunded_by": self.refunded.map(|(trx_id, _)| trx_id.to_string()),
+		})
+	}
+}
+
+/// Refunds waiting to be sent back to EOS by the host.
+#[derive(Clone, Debug, Default, PartialEq, Encode, Decode)]
+pub struct RefundQueue {
+	refunds: Vec<RefundRequest>,
+}
+
+impl RefundQueue {
+	pub fn push(&mut self, refund: RefundRequest) -> Result<(), crate::Error> {
+		if self.refunds.iter().any(|r| r.trx_id == refund.trx_id) {
+			return Ok(());
+		}
+		if self.refunds.len() >= MAX_REFUNDS {
+			return Err(crate::Error::InvalidMemo(format!("refund queue is full, refund {} manually", refund.trx_id)));
+		}
+		self.refunds.push(refund);
+
+		Ok(())
+	}
+
+	// refunds never sent, or sent so long ago that the transaction must have expired
+	pub fn take(&mut self, now: u64) -> Vec<RefundRequest> {
+		self.refunds.iter_mut()
+			.filter(|r| r.refunded.is_none())
+			.filter(|r| r.sent_at.map_or(true, |sent_at| sent_at + RESEND_AFTER <= now))
+			.map(|r| {
+				r.sent_at = Some(now);
+				r.clone()
+			})
+			.collect()
+	}
+
+	/// A transfer back to the sender of `trx_id` is applied in `block_num`, it's not settled until
+	/// the block is irreversible. Returns false if it doesn't refund a queued transfer exactly.
+	pub fn observe(
+		&mut self,
+		trx_id:    &Checksum256,
+		contract:  AccountName,
+		to:        AccountName,
+		quantity:  &str,
+		refund_id: Checksum256,
+		block_num: u32,
+	) -> bool {
+		match self.refunds.iter_mut().find(|r| r.trx_id == *trx_id) {
+			Some(r) if r.contract == contract && r.from == to && r.quantity == quantity => {
+				r.refunded = Some((refund_id, block_num));
+				true
+			}
+			_ => false,
+		}
+	}
+
+	/// Block `block_num` with transactions `trx_ids` became irreversible. Refunds in it are settled,
+	/// refunds seen in it or before but missing from it were forked out and could be sent again.
+	pub fn settle_irreversible(&mut self, block_num: u32, trx_ids: &[Checksum256]) -> Vec<Checksum256> {
+		let mut settled = Vec::new();
+		self.refunds.retain(|r| match r.refunded {
+			Some((refund_id, seen_in)) if seen_in == block_num && trx_ids.contains(&refund_id) => {
+				settled.push(r.trx_id);
+				false
+			}
+			_ => true,
+		});
+		for r in self.refunds.iter_mut() {
+			if r.refunded.map_or(false, |(_, seen_in)| seen_in <= block_num) {
+				r.refunded = None;
+			}
+		}
+
+		settled
+	}
+
+	pub fn len(&self) -> usize {
+		self.refunds.len()
+	}
+}
+
+/// Parse memo with network tag and ss58 prefix of relay config.
+pub fn parse_memo(memo: &str) -> Result<TransferMemo, crate::Error> {
+	let config = crate::config::relay_config();
+	parse_memo_with(memo, &config.memo_network, config.ss58_prefix)
+}
+
+pub fn parse_memo_with(memo: &str, memo_network: &str, expected_prefix: u8) -> Result<TransferMemo, crate::Error> {
+	let invalid = |reason: &str| crate::Error::InvalidMemo(reason.to_owned());
+
+	let memo = memo.trim();
+	let (address, destination) = split_once(memo, '@').ok_or_else(|| invalid("missing '@' between address and network"))?;
+	let (network, asset) = split_once(destination, ':').ok_or_else(|| invalid("missing ':' between network and asset"))?;
+
+	if network != memo_network {
+		return Err(invalid(&format!("unknown network tag '{}', expect '{}'", network, memo_network)));
+	}
+	if asset.is_empty() || !asset.chars().all(|c| c.is_ascii_alphanumeric()) {
+		return Err(invalid(&format!("invalid asset '{}'", asset)));
+	}
+
+	// checksum is verified while decoding
+	let (account, format) = AccountId32::from_ss58check_with_version(address)
+		.map_err(|e| invalid(&format!("invalid ss58 address '{}': {:?}", address, e)))?;
+	let ss58_prefix: u8 = format.into();
+	if ss58_prefix != expected_prefix {
+		return Err(invalid(&format!("ss58 prefix {} doesn't belong to bifrost, expect {}", ss58_prefix, expected_prefix)));
+	}
+
+	Ok(TransferMemo {
+		address: address.to_owned(),
+		account,
+		ss58_prefix,
+		network: network.to_owned(),
+		asset: asset.to_owned(),
+	})
+}
+
+// validate memo of an inbound transfer, queue it for refunding if nobody could be credited.
+pub fn validate_transfer(
+	memo:     &str,
+	contract: AccountName,
+	from:     AccountName,
+	quantity: &str,
+	trx_id:   Checksum256,
+) -> Result<TransferMemo, crate::Error> {
+	parse_memo(memo).map_err(|e| {
+		let refund = RefundRequest {
+			trx_id,
+			contract,
+			from,
+			quantity: quantity.to_owned(),
+			memo: memo.to_owned(),
+			reason: e.to_string(),
+			queued_at: crate::status::now(),
+			sent_at: None,
+			refunded: None,
+		};
+		match with_queue(|queue| queue.push(refund)) {
+			Some(Err(full)) => println!("[!] Failed to queue refund of {}: {:?}\n", trx_id, full),
+			None => println!("[!] Failed to queue refund of {}: refund queue is poisoned\n", trx_id),
+			_ => {}
+		}
+		e
+	})
+}
+
+fn persist(queue: &RefundQueue) {
+	if !crate::store::is_open() {
+		return;
+	}
+	if let Err(e) = crate::store::put(REFUNDS_TREE, QUEUE_KEY, &queue.encode()) {
+		println!("[!] Failed to persist refund queue due to: {:?}\n", e);
+	}
+}
+
+/// Load refunds queued before last shutdown, returns how many are loaded.
+pub fn restore_refunds() -> Result<usize, crate::Error> {
+	let restored = match crate::store::get(REFUNDS_TREE, QUEUE_KEY)? {
+		Some(value) => RefundQueue::decode(&mut &value[..])
+			.map_err(|e| crate::Error::StoreError(format!("broken refund queue: {:?}", e)))?,
+		None => return Ok(0),
+	};
+
+	let mut queue = REFUND_QUEUE.lock().map_err(|_| crate::Error::ConfigError("refund queue is poisoned".to_owned()))?;
+	*queue = restored;
+
+	Ok(queue.len())
+}
+
+// every change of refund queue is on disk before the host sees it
+fn with_queue<R>(f: impl FnOnce(&mut RefundQueue) -> R) -> Option<R> {
+	let mut queue = REFUND_QUEUE.lock().ok()?;
+	let result = f(&mut queue);
+	persist(&queue);
+
+	Some(result)
+}
+
+/// Refunds the host should send now, they're handed over again until they're settled.
+pub fn take_refunds() -> Vec<RefundRequest> {
+	with_queue(|queue| queue.take(crate::status::now())).unwrap_or_default()
+}
+
+/// Transaction id of the transfer refunded by a memo like `refund:<trx_id>`.
+pub fn parse_refund_memo(memo: &str) -> Option<Checksum256> {
+	let id = memo.trim().strip_prefix(REFUND_PREFIX)?;
+	let bytes = hex::decode(id).ok()?;
+	if bytes.len() != 32 {
+		return None;
+	}
+	let mut trx_id = [0u8; 32];
+	trx_id.copy_from_slice(&bytes);
+
+	Some(Checksum256::from(trx_id))
+}
+
+/// A transfer from the cross account with a refund memo is applied, see `RefundQueue::observe`.
+pub fn observe_refund(
+	memo:      &str,
+	contract:  AccountName,
+	to:        AccountName,
+	quantity:  &str,
+	refund_id: Checksum256,
+	block_num: u32,
+) -> bool {
+	match parse_refund_memo(memo) {
+		Some(trx_id) => with_queue(|queue| queue.observe(&trx_id, contract, to, quantity, refund_id, block_num)).unwrap_or(false),
+		None => false,
+	}
+}
+
+/// Settle refunds that are irreversible on EOS, returns the refunded transfers.
+pub fn settle_refunds(block_num: u32, trx_ids: &[Checksum256]) -> Vec<Checksum256> {
+	with_queue(|queue| queue.settle_irreversible(block_num, trx_ids)).unwrap_or_default()
+}
+
+pub fn refund_queue() -> Value {
+	let queue = REFUND_QUEUE.lock().map(|q| q.clone()).unwrap_or_default();
+	Value::Array(queue.refunds.iter().map(RefundRequest::to_json).collect())
+}
+
+fn split_once(s: &str, delimiter: char) -> Option<(&str, &str)> {
+	let index = s.find(delimiter)?;
+	Some((&s[..index], &s[index + delimiter.len_utf8()..]))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_core::crypto::Ss58AddressFormat;
+	use std::str::FromStr;
+
+	// //Alice with generic substrate prefix 42
+	const ALICE_42: &str = "5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY";
+
+	fn alice(prefix: u8) -> String {
+		let (account, _) = AccountId32::from_ss58check_with_version(ALICE_42).unwrap();
+		account.to_ss58check_with_version(Ss58AddressFormat::Custom(prefix))
+	}
+
+	fn refund(byte: u8) -> RefundRequest {
+		RefundRequest {
+			trx_id: Checksum256::from([byte; 32]),
+			contract: AccountName::from_str("eosio.token").unwrap(),
+			from: AccountName::from_str("jim").unwrap(),
+			quantity: "1.0000 EOS".to_owned(),
+			memo: "nobody".to_owned(),
+			reason: "bad memo".to_owned(),
+			queued_at: 100,
+			sent_at: None,
+			refunded: None,
+		}
+	}
+
+	#[test]
+	fn valid_memo() {
+		let address = alice(6);
+		let memo = parse_memo_with(&format!("{}@bifrost:vEOS", address), "bifrost", 6).unwrap();
+		assert_eq!(memo.address, address);
+		assert_eq!(memo.ss58_prefix, 6);
+		assert_eq!(memo.network, "bifrost");
+		assert_eq!(memo.asset, "vEOS");
+	}
+
+	#[test]
+	fn memo_of_other_network_is_refused() {
+		assert!(parse_memo_with(&format!("{}@bifrost:vEOS", ALICE_42), "bifrost", 6).is_err());
+		assert!(parse_memo_with(&format!("{}@polkadot:vEOS", alice(6)), "bifrost", 6).is_err());
+	}
+
+	#[test]
+	fn memo_with_bad_checksum_is_refused() {
+		let mut address = alice(6);
+		let last = address.pop().unwrap();
+		address.push(if last == 'a' { 'b' } else { 'a' });
+		assert!(parse_memo_with(&format!("{}@bifrost:vEOS", address), "bifrost", 6).is_err());
+	}
+
+	#[test]
+	fn malformed_memo_is_refused() {
+		let address = alice(6);
+		for memo in &[
+			"".to_owned(),
+			address.clone(),
+			format!("{}@bifrost", address),
+			format!("{}@bifrost:", address),
+			format!("{}@bifrost:v-EOS", address),
+			"nobody@bifrost:vEOS".to_owned(),
+		] {
+			assert!(parse_memo_with(memo, "bifrost", 6).is_err(), "{}", memo);
+		}
+	}
+
+	fn observe(queue: &mut RefundQueue, byte: u8, refund_id: Checksum256, block_num: u32) -> bool {
+		let r = refund(byte);
+		queue.observe(&r.trx_id, r.contract, r.from, &r.quantity, refund_id, block_num)
+	}
+
+	#[test]
+	fn refund_memo() {
+		let trx_id = Checksum256::from([0xab; 32]);
+		assert_eq!(parse_refund_memo(&format!("refund:{}", "ab".repeat(32))), Some(trx_id));
+		assert_eq!(parse_refund_memo(&format!("refund:{}", "ab".repeat(31))), None);
+		assert_eq!(parse_refund_memo("refund:xyz"), None);
+		assert_eq!(parse_refund_memo("redeem:42"), None);
+	}
+
+	#[test]
+	fn refunds_are_resent_until_settled() {
+		let mut queue = RefundQueue::default();
+		queue.push(refund(1)).unwrap();
+		queue.push(refund(1)).unwrap();
+		assert_eq!(queue.len(), 1);
+
+		assert_eq!(queue.take(1000).len(), 1);
+		assert!(queue.take(1000 + RESEND_AFTER - 1).is_empty());
+		assert_eq!(queue.take(1000 + RESEND_AFTER).len(), 1);
+
+		// seen on EOS, but not settled before its block is irreversible
+		let refund_id = Checksum256::from([9u8; 32]);
+		assert!(observe(&mut queue, 1, refund_id, 50));
+		assert!(queue.take(u64::MAX / 2).is_empty());
+		assert!(queue.settle_irreversible(49, &[refund_id]).is_empty());
+		assert_eq!(queue.settle_irreversible(50, &[refund_id]), vec![refund(1).trx_id]);
+		assert_eq!(queue.len(), 0);
+	}
+
+	#[test]
+	fn forked_out_refund_could_be_sent_again() {
+		let mut queue = RefundQueue::default();
+		queue.push(refund(1)).unwrap();
+		queue.take(1000);
+
+		let refund_id = Checksum256::from([9u8; 32]);
+		assert!(observe(&mut queue, 1, refund_id, 50));
+		assert!(queue.settle_irreversible(50, &[Checksum256::from([8u8; 32])]).is_empty());
+		assert_eq!(queue.len(), 1);
+		assert_eq!(queue.take(1000 + RESEND_AFTER).len(), 1);
+	}
+
+	#[test]
+	fn refund_of_other_amount_is_not_observed() {
+		let mut queue = RefundQueue::default();
+		queue.push(refund(1)).unwrap();
+
+		let r = refund(1);
+		let refund_id = Checksum256::from([9u8; 32]);
+		assert!(!queue.observe(&r.trx_id, r.contract, r.from, "0.5000 EOS", refund_id, 50));
+		assert!(!queue.observe(&r.trx_id, r.contract, AccountName::from_str("bob").unwrap(), &r.quantity, refund_id, 50));
+		assert!(!observe(&mut queue, 2, refund_id, 50));
+		assert!(queue.refunds[0].refunded.is_none());
+	}
+
+	#[test]
+	fn refund_queue_is_bounded() {
+		let mut queue = RefundQueue::default();
+		for i in 0..MAX_REFUNDS {
+			let mut r = refund(0);
+			r.trx_id = Checksum256::from({
+				let mut bytes = [0u8; 32];
+				bytes[..8].copy_from_slice(&(i as u64).to_le_bytes());
+				bytes
+			});
+			queue.push(r).unwrap();
+		}
+		assert!(queue.push(refund(0xff)).is_err());
+	}
+
+	#[test]
+	fn refund_queue_survives_encoding() {
+		let mut queue = RefundQueue::default();
+		queue.push(refund(1)).unwrap();
+		queue.push(refund(2)).unwrap();
+		queue.take(1000);
+		assert!(observe(&mut queue, 2, Checksum256::from([9u8; 32]), 50));
+		assert_eq!(RefundQueue::decode(&mut &queue.encode()[..]).unwrap(), queue);
+	}
+}
@@ -0,0 +1,156 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Merkle paths of action receipts, the same as `get_proof` in libraries/chain/merkle.cpp.
+
+use codec::Encode;
+use eos_chain::Checksum256;
+use sha2::{Digest as _, Sha256};
+
+fn to_bytes(digest: &Checksum256) -> [u8; 32] {
+	let mut bytes = [0u8; 32];
+	bytes.copy_from_slice(&digest.encode()[..32]);
+	bytes
+}
+
+// the first bit tells which side a node is on, so a proof doesn't need to say it
+fn make_canonical_left(digest: &Checksum256) -> Checksum256 {
+	let mut bytes = to_bytes(digest);
+	bytes[0] &= 0x7f;
+	Checksum256::from(bytes)
+}
+
+fn make_canonical_right(digest: &Checksum256) -> Checksum256 {
+	let mut bytes = to_bytes(digest);
+	bytes[0] |= 0x80;
+	Checksum256::from(bytes)
+}
+
+fn hash_pair(left: &Checksum256, right: &Checksum256) -> Checksum256 {
+	let mut hasher = Sha256::new();
+	hasher.update(&to_bytes(&make_canonical_left(left)));
+	hasher.update(&to_bytes(&make_canonical_right(right)));
+
+	let mut bytes = [0u8; 32];
+	bytes.copy_from_slice(&hasher.finalize());
+	Checksum256::from(bytes)
+}
+
+/// Merkle root of `ids`, like `merkle` in libraries/chain/merkle.cpp.
+pub fn merkle_root(mut ids: Vec<Checksum256>) -> Checksum256 {
+	if ids.is_empty() {
+		return Checksum256::default();
+	}
+
+	while ids.len() > 1 {
+		if ids.len() % 2 == 1 {
+			ids.push(ids[ids.len() - 1]);
+		}
+		ids = ids.chunks(2).map(|pair| hash_pair(&pair[0], &pair[1])).collect();
+	}
+
+	ids[0]
+}
+
+/// Sibling of each level from the leaf at `position` up to the root.
+pub fn get_proof(mut position: usize, mut ids: Vec<Checksum256>) -> Vec<Checksum256> {
+	let mut paths = Vec::new();
+	if position >= ids.len() {
+		return paths;
+	}
+
+	while ids.len() > 1 {
+		if ids.len() % 2 == 1 {
+			ids.push(ids[ids.len() - 1]);
+		}
+
+		if position % 2 == 1 { // right node
+			paths.push(make_canonical_left(&ids[position - 1]));
+		} else {
+			paths.push(make_canonical_right(&ids[position + 1]));
+		}
+		position /= 2;
+
+		ids = ids.chunks(2).map(|pair| hash_pair(&pair[0], &pair[1])).collect();
+	}
+
+	paths
+}
+
+/// Fold a proof back to root, the side of each node is told by its first bit.
+#[cfg(test)]
+pub(crate) fn proof_root(leaf: Checksum256, paths: &[Checksum256]) -> Checksum256 {
+	paths.iter().fold(leaf, |node, path| {
+		if to_bytes(path)[0] & 0x80 == 0x80 {
+			hash_pair(&node, path)
+		} else {
+			hash_pair(path, &node)
+		}
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn leaves(count: u8) -> Vec<Checksum256> {
+		(1..=count).map(|byte| Checksum256::from([byte; 32])).collect()
+	}
+
+	fn from_hex(s: &str) -> Checksum256 {
+		let mut bytes = [0u8; 32];
+		bytes.copy_from_slice(&hex::decode(s).unwrap());
+		Checksum256::from(bytes)
+	}
+
+	#[test]
+	fn merkle_root_should_match_nodeos() {
+		// computed with sha256 over canonical pairs, the same as libraries/chain/merkle.cpp
+		assert_eq!(merkle_root(vec![]), Checksum256::default());
+		assert_eq!(merkle_root(leaves(1)), Checksum256::from([1; 32]));
+		assert_eq!(merkle_root(leaves(2)), from_hex("431e8a83c908aaf1c9d3188fb67a51e14e9872a40f07f67251105c050f56ada5"));
+		assert_eq!(merkle_root(leaves(3)), from_hex("146e9cca1b0add9dd997d5e6aaa2b45c42d5fd7b62cd204bd897d28adc3a76a9"));
+		assert_eq!(merkle_root(leaves(5)), from_hex("56078cc5c5e42182bb3875146edd99eca74652b33d3acdbf3a5d8de9c34efc7f"));
+	}
+
+	#[test]
+	fn every_proof_should_lead_to_root() {
+		for count in 1..=9 {
+			let ids = leaves(count);
+			let root = merkle_root(ids.clone());
+			for (position, leaf) in ids.iter().enumerate() {
+				let paths = get_proof(position, ids.clone());
+				assert_eq!(proof_root(*leaf, &paths), root, "leaf {} of {}", position, count);
+			}
+		}
+	}
+
+	#[test]
+	fn last_leaf_of_odd_count_pairs_with_itself() {
+		let ids = leaves(3);
+		let paths = get_proof(2, ids.clone());
+		assert_eq!(paths.len(), 2);
+		assert_eq!(paths[0], make_canonical_right(&ids[2]));
+		assert_eq!(paths[1], make_canonical_left(&hash_pair(&ids[0], &ids[1])));
+	}
+
+	#[test]
+	fn proof_of_single_or_missing_leaf_is_empty() {
+		assert!(get_proof(0, leaves(1)).is_empty());
+		assert!(get_proof(3, leaves(3)).is_empty());
+		assert!(get_proof(0, vec![]).is_empty());
+	}
+}
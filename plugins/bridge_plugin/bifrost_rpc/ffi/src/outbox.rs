@@ -0,0 +1,439 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Journal of every extrinsic sent to bifrost. A payload is recorded with an id derived
+//! from the encoded call before it's signed, then its extrinsic hash and final outcome.
+//! After a crash, `reconcile` looks for unresolved extrinsics in the tx pool and recent
+//! blocks, so a payload is neither lost nor sent twice. While running, `reconcile_stale`
+//! does the same for extrinsics that stay unresolved for too long.
+
+use codec::{Decode, Encode};
+use jsonrpsee::common::Params;
+use serde_json::{json, Value};
+use sp_core::hashing::{blake2_256, twox_128};
+use sp_core::sr25519::Pair;
+use subxt::{Call, Client, DefaultNodeRuntime as BifrostRuntime, ExtrinsicSuccess, PairSigner, Raw};
+use subxt::system::Phase;
+
+pub const OUTBOX_TREE: &str = "outbox";
+
+pub type OutboxId = [u8; 32];
+
+#[derive(Clone, Debug, PartialEq, Encode, Decode)]
+pub enum OutboxState {
+	/// Recorded, but never signed. It's safe to send.
+	Pending,
+	/// Signed and maybe broadcasted, whether it reached bifrost is unknown.
+	Signed,
+	/// Accepted by bifrost tx pool.
+	Broadcast,
+	/// Included in a finalized block and dispatched successfully.
+	Finalized([u8; 32]),
+	/// Rejected by bifrost node or failed in dispatch, it could be sent again.
+	Failed(Vec<u8>),
+}
+
+#[derive(Clone, Debug, PartialEq, Encode, Decode)]
+pub struct OutboxEntry {
+	pub id:             OutboxId,
+	pub call:           Vec<u8>,
+	pub extrinsic_hash: Option<[u8; 32]>,
+	pub state:          OutboxState,
+	pub updated_at:     u64,
+}
+
+impl OutboxEntry {
+	pub fn to_json(&self) -> Value {
+		let state = match self.state {
+			OutboxState::Pending => json!("pending"),
+			OutboxState::Signed => json!("signed"),
+			OutboxState::Broadcast => json!("broadcast"),
+			OutboxState::Finalized(block) => json!({ "finalized": format!("0x{}", hex::encode(block)) }),
+			OutboxState::Failed(ref e) => json!({ "failed": String::from_utf8_lossy(e) }),
+		};
+		json!({
+			"id": format!("0x{}", hex::encode(self.id)),
+			"extrinsic_hash": self.extrinsic_hash.map(|h| format!("0x{}", hex::encode(h))),
+			"state": state,
+			"updated_at": self.updated_at,
+		})
+	}
+
+	// signed or broadcast long ago, but never resolved
+	fn is_stale(&self, now: u64, stale_after: u64) -> bool {
+		match self.state {
+			OutboxState::Signed | OutboxState::Broadcast => self.updated_at.saturating_add(stale_after) <= now,
+			_ => false,
+		}
+	}
+
+	// a pending entry is about to be signed, everything else is kept for `retention` seconds
+	fn is_expired(&self, now: u64, retention: u64) -> bool {
+		match self.state {
+			OutboxState::Pending => false,
+			_ => self.updated_at.saturating_add(retention) < now,
+		}
+	}
+}
+
+/// Same payload always gets the same id.
+pub fn outbox_id(encoded_call: &[u8]) -> OutboxId {
+	blake2_256(encoded_call)
+}
+
+fn load(id: &OutboxId) -> Result<Option<OutboxEntry>, crate::Error> {
+	let value = crate::store::get(OUTBOX_TREE, id)?;
+	Ok(value.and_then(|value| OutboxEntry::decode(&mut &value[..]).ok()))
+}
+
+fn save(entry: &OutboxEntry) -> Result<(), crate::Error> {
+	crate::store::put(OUTBOX_TREE, &entry.id, &entry.encode())
+}
+
+fn update(id: &OutboxId, state: OutboxState, extrinsic_hash: Option<[u8; 32]>) -> Result<(), crate::Error> {
+	if let Some(mut entry) = load(id)? {
+		entry.state = state;
+		entry.extrinsic_hash = extrinsic_hash.or(entry.extrinsic_hash);
+		entry.updated_at = crate::status::now();
+		save(&entry)?;
+	}
+
+	Ok(())
+}
+
+/// Record a payload before signing it. Refuse it if the same payload has been finalized,
+/// or it's signed before and not reconciled yet.
+pub fn begin(encoded_call: &[u8]) -> Result<OutboxId, crate::Error> {
+	let id = outbox_id(encoded_call);
+	match load(&id)? {
+		Some(OutboxEntry { state: OutboxState::Finalized(_), .. }) => Err(crate::Error::AlreadySubmitted),
+		Some(OutboxEntry { state: OutboxState::Signed, .. }) | Some(OutboxEntry { state: OutboxState::Broadcast, .. }) => {
+			Err(crate::Error::SubmissionInFlight)
+		}
+		_ => {
+			save(&OutboxEntry {
+				id,
+				call: encoded_call.to_vec(),
+				extrinsic_hash: None,
+				state: OutboxState::Pending,
+				updated_at: crate::status::now(),
+			})?;
+			Ok(id)
+		}
+	}
+}
+
+/// Sign, record and broadcast a call, returns the extrinsic hash. Without relay store
+/// it's the same as `Client::submit`.
+pub async fn submit<C>(
+	client: &Client<BifrostRuntime>,
+	signer: &PairSigner<BifrostRuntime, Pair>,
+	call:   C,
+) -> Result<<BifrostRuntime as subxt::system::System>::Hash, crate::Error>
+	where C: Call<BifrostRuntime> + Clone + Send + Sync
+{
+	if !crate::store::is_open() {
+		return client.submit(call, signer).await.map_err(|e| crate::rpc_calls::broadcast_error(&e));
+	}
+
+	let encoded = client.encode(call.clone()).map_err(|_| crate::Error::SubxtError("failed to encode this call"))?;
+	let id = begin(&encoded.0)?;
+	let extrinsic = client.create_signed(call, signer)
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to sign this transaction"))?;
+	let extrinsic_hash = blake2_256(&extrinsic.encode());
+	update(&id, OutboxState::Signed, Some(extrinsic_hash))?;
+
+	match client.submit_extrinsic(extrinsic).await {
+		Ok(hash) => {
+			update(&id, OutboxState::Broadcast, None)?;
+			Ok(hash)
+		}
+		Err(e) => {
+			update(&id, OutboxState::Failed(e.to_string().into_bytes()), None)?;
+			Err(crate::rpc_calls::broadcast_error(&e))
+		}
+	}
+}
+
+/// Like `submit`, but wait until the extrinsic is finalized.
+pub async fn watch<C>(
+	client: &Client<BifrostRuntime>,
+	signer: &PairSigner<BifrostRuntime, Pair>,
+	call:   C,
+) -> Result<ExtrinsicSuccess<BifrostRuntime>, crate::Error>
+	where C: Call<BifrostRuntime> + Clone + Send + Sync
+{
+	if !crate::store::is_open() {
+		return client.watch(call, signer).await.map_err(|e| crate::rpc_calls::broadcast_error(&e));
+	}
+
+	let encoded = client.encode(call.clone()).map_err(|_| crate::Error::SubxtError("failed to encode this call"))?;
+	let id = begin(&encoded.0)?;
+	let extrinsic = client.create_signed(call, signer)
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to sign this transaction"))?;
+	let extrinsic_hash = blake2_256(&extrinsic.encode());
+	update(&id, OutboxState::Signed, Some(extrinsic_hash))?;
+
+	match client.submit_and_watch_extrinsic(extrinsic).await {
+		Ok(success) if success.find_event_raw("System", "ExtrinsicFailed").is_some() => {
+			update(&id, OutboxState::Failed(b"failed in dispatch".to_vec()), None)?;
+			Err(crate::Error::SubxtError("failed to dispatch this transaction"))
+		}
+		Ok(success) => {
+			update(&id, OutboxState::Finalized(success.block.into()), None)?;
+			Ok(success)
+		}
+		Err(e) => {
+			update(&id, OutboxState::Failed(e.to_string().into_bytes()), None)?;
+			Err(crate::rpc_calls::broadcast_error(&e))
+		}
+	}
+}
+
+/// Result of a reconciliation, ids are grouped by what was found.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Reconciliation {
+	pub in_pool:   Vec<OutboxId>,
+	pub finalized: Vec<OutboxId>,
+	pub lost:      Vec<OutboxId>, // never reached bifrost, safe to send again
+	pub failed:    Vec<OutboxId>, // included, but failed in dispatch, safe to send again
+	pub pruned:    usize,
+}
+
+impl Reconciliation {
+	pub fn to_json(&self) -> Value {
+		let ids = |ids: &[OutboxId]| ids.iter().map(|id| format!("0x{}", hex::encode(id))).collect::<Vec<_>>();
+		json!({
+			"in_pool": ids(&self.in_pool),
+			"finalized": ids(&self.finalized),
+			"lost": ids(&self.lost),
+			"failed": ids(&self.failed),
+			"pruned": self.pruned,
+		})
+	}
+}
+
+/// Resolve extrinsics that were signed before last shutdown, by the tx pool and
+/// the latest `config.outbox_reconcile_depth` finalized blocks.
+pub async fn reconcile(urls: impl IntoIterator<Item=String>) -> Result<Reconciliation, crate::Error> {
+	let url: String = urls.into_iter().take(1).next().ok_or(crate::Error::SubxtError("failed to create subxt client"))?;
+	let client: Client<BifrostRuntime> = subxt::ClientBuilder::new()
+		.set_url(url)
+		.build()
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to create subxt client"))?;
+
+	reconcile_entries(&client, |_| true).await
+}
+
+/// Resolve extrinsics that stay signed or broadcast over `config.outbox_stale_after`,
+/// or their payloads are refused as in flight forever.
+pub async fn reconcile_stale(client: &Client<BifrostRuntime>) -> Result<Reconciliation, crate::Error> {
+	if !crate::store::is_open() {
+		return Ok(Reconciliation::default());
+	}
+	let stale_after = crate::config::relay_config().outbox_stale_after;
+	let now = crate::status::now();
+
+	reconcile_entries(client, |entry| entry.is_stale(now, stale_after)).await
+}
+
+async fn reconcile_entries(
+	client: &Client<BifrostRuntime>,
+	filter: impl Fn(&OutboxEntry) -> bool,
+) -> Result<Reconciliation, crate::Error> {
+	let config = crate::config::relay_config();
+	let now = crate::status::now();
+	let mut unresolved = Vec::new();
+	let mut expired = Vec::new();
+	crate::store::for_each(OUTBOX_TREE, |_, mut value| {
+		if let Ok(entry) = OutboxEntry::decode(&mut value) {
+			if entry.is_expired(now, config.outbox_retention) {
+				expired.push(entry.id);
+			} else if entry.is_stale(now, 0) && filter(&entry) {
+				unresolved.push(entry);
+			}
+		}
+	})?;
+
+	let mut reconciliation = Reconciliation::default();
+	for id in expired.iter() {
+		crate::store::remove(OUTBOX_TREE, id)?;
+	}
+	reconciliation.pruned = expired.len();
+	if unresolved.is_empty() {
+		return Ok(reconciliation);
+	}
+
+	let pool: Vec<sp_core::Bytes> = client.rpc_client()
+		.request("author_pendingExtrinsics", Params::None)
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to query pending extrinsics"))?;
+	let pool: Vec<[u8; 32]> = pool.iter().map(|ext| blake2_256(&ext.0)).collect();
+
+	// walk back from finalized head, collect hashes of extrinsics in recent blocks
+	let mut included: Vec<([u8; 32], [u8; 32], u32)> = Vec::new(); // (extrinsic hash, block hash, index)
+	let mut block_hash = client.finalized_head().await.map_err(|_| crate::Error::SubxtError("failed to query finalized head"))?;
+	for _ in 0..config.outbox_reconcile_depth {
+		let block = match client.block(Some(block_hash)).await {
+			Ok(Some(block)) => block,
+			_ => break,
+		};
+		for (index, ext) in block.block.extrinsics.iter().enumerate() {
+			included.push((blake2_256(&ext.encode()), block_hash.into(), index as u32));
+		}
+		if block.block.header.number == 0 {
+			break;
+		}
+		block_hash = block.block.header.parent_hash;
+	}
+
+	for entry in unresolved.into_iter() {
+		let extrinsic_hash = match entry.extrinsic_hash {
+			Some(hash) => hash,
+			None => {
+				update(&entry.id, OutboxState::Pending, None)?;
+				reconciliation.lost.push(entry.id);
+				continue;
+			}
+		};
+
+		if let Some((_, block, index)) = included.iter().find(|(hash, _, _)| *hash == extrinsic_hash) {
+			if dispatch_failed(client, *block, *index).await? {
+				update(&entry.id, OutboxState::Failed(b"failed in dispatch".to_vec()), None)?;
+				reconciliation.failed.push(entry.id);
+			} else {
+				update(&entry.id, OutboxState::Finalized(*block), None)?;
+				reconciliation.finalized.push(entry.id);
+			}
+		} else if pool.contains(&extrinsic_hash) {
+			update(&entry.id, OutboxState::Broadcast, None)?;
+			reconciliation.in_pool.push(entry.id);
+		} else {
+			update(&entry.id, OutboxState::Pending, None)?;
+			reconciliation.lost.push(entry.id);
+		}
+	}
+
+	Ok(reconciliation)
+}
+
+// whether System::Events of the block has a failure raised by the extrinsic at `index`
+async fn dispatch_failed(client: &Client<BifrostRuntime>, block: [u8; 32], index: u32) -> Result<bool, crate::Error> {
+	let key = [twox_128(b"System"), twox_128(b"Events")].concat();
+	let params = Params::Array(vec![
+		json!(format!("0x{}", hex::encode(key))),
+		json!(format!("0x{}", hex::encode(block))),
+	]);
+	let events: Option<sp_core::Bytes> = client.rpc_client()
+		.request("state_getStorage", params)
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to query block events"))?;
+	let events = match events {
+		Some(events) => events,
+		None => return Ok(false),
+	};
+	let events = client.events_decoder()
+		.decode_events(&mut &events.0[..])
+		.map_err(|_| crate::Error::SubxtError("failed to decode block events"))?;
+
+	Ok(events.iter().any(|(phase, event)| match (phase, event) {
+		(Phase::ApplyExtrinsic(i), Raw::Error(_)) => *i == index,
+		(Phase::ApplyExtrinsic(i), Raw::Event(e)) => *i == index && e.module == "System" && e.variant == "ExtrinsicFailed",
+		_ => false,
+	}))
+}
+
+pub fn entries() -> Result<Vec<OutboxEntry>, crate::Error> {
+	let mut entries = Vec::new();
+	crate::store::for_each(OUTBOX_TREE, |_, mut value| {
+		if let Ok(entry) = OutboxEntry::decode(&mut value) {
+			entries.push(entry);
+		}
+	})?;
+
+	Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry(state: OutboxState, updated_at: u64) -> OutboxEntry {
+		OutboxEntry { id: outbox_id(b"call"), call: b"call".to_vec(), extrinsic_hash: None, state, updated_at }
+	}
+
+	#[test]
+	fn begin_follows_entry_state() {
+		let _lock = crate::store::TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+		let dir = std::env::temp_dir().join(format!("bifrost-outbox-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		crate::store::open(&dir).unwrap();
+
+		// a fresh payload is recorded as pending, and could be begun again before it's signed
+		let id = begin(b"call").unwrap();
+		assert_eq!(id, outbox_id(b"call"));
+		assert_eq!(load(&id).unwrap().unwrap().state, OutboxState::Pending);
+		assert_eq!(begin(b"call").unwrap(), id);
+
+		update(&id, OutboxState::Signed, Some([1; 32])).unwrap();
+		assert!(matches!(begin(b"call"), Err(crate::Error::SubmissionInFlight)));
+		update(&id, OutboxState::Broadcast, None).unwrap();
+		assert!(matches!(begin(b"call"), Err(crate::Error::SubmissionInFlight)));
+
+		// a failed payload is sent again
+		update(&id, OutboxState::Failed(b"failed in dispatch".to_vec()), None).unwrap();
+		assert_eq!(begin(b"call").unwrap(), id);
+		let restarted = load(&id).unwrap().unwrap();
+		assert_eq!(restarted.state, OutboxState::Pending);
+		assert_eq!(restarted.extrinsic_hash, None);
+
+		update(&id, OutboxState::Finalized([2; 32]), None).unwrap();
+		assert!(matches!(begin(b"call"), Err(crate::Error::AlreadySubmitted)));
+		// other payloads are not affected
+		assert!(begin(b"another call").is_ok());
+
+		crate::store::close().unwrap();
+		let _ = std::fs::remove_dir_all(&dir);
+	}
+
+	#[test]
+	fn only_signed_or_broadcast_entries_go_stale() {
+		assert!(entry(OutboxState::Broadcast, 100).is_stale(400, 300));
+		assert!(entry(OutboxState::Signed, 100).is_stale(400, 300));
+		assert!(!entry(OutboxState::Broadcast, 100).is_stale(399, 300));
+		assert!(!entry(OutboxState::Pending, 100).is_stale(400, 300));
+		assert!(!entry(OutboxState::Finalized([0; 32]), 100).is_stale(400, 300));
+		assert!(!entry(OutboxState::Failed(vec![]), 100).is_stale(400, 300));
+		assert!(!entry(OutboxState::Broadcast, u64::max_value()).is_stale(400, 300));
+	}
+
+	#[test]
+	fn every_entry_but_pending_expires() {
+		for state in vec![
+			OutboxState::Signed,
+			OutboxState::Broadcast,
+			OutboxState::Finalized([0; 32]),
+			OutboxState::Failed(vec![]),
+		] {
+			assert!(entry(state.clone(), 100).is_expired(201, 100));
+			assert!(!entry(state, 100).is_expired(200, 100));
+		}
+		assert!(!entry(OutboxState::Pending, 100).is_expired(u64::max_value(), 100));
+	}
+}
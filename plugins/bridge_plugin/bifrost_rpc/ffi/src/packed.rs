@@ -0,0 +1,220 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Arguments packed by `fc::raw::pack` on the c++ side, each one passed as a buffer and
+//! its size. They're decoded into eos_chain types directly, without a json round trip.
+
+use crate::rpc_calls::{BifrostRuntime, ChangeScheduleCall};
+use crate::ship::Unpacker;
+use core::marker::PhantomData;
+use eos_chain::{Checksum256, IncrementalMerkle, ProducerAuthoritySchedule, SignedBlockHeader};
+
+fn unpack_error(what: &str, e: crate::Error) -> crate::Error {
+	let reason = match e {
+		crate::Error::ShipError(reason) => reason,
+		e => e.to_string(),
+	};
+	crate::Error::UnpackError(format!("{}: {}", what, reason))
+}
+
+// every byte of an argument should be consumed, or it's not what c++ side packed
+fn unpack_all<'a, T>(
+	what:   &str,
+	bytes:  &'a [u8],
+	unpack: impl FnOnce(&mut Unpacker<'a>) -> Result<T, crate::Error>,
+) -> Result<T, crate::Error> {
+	let mut unpacker = Unpacker::new(bytes);
+	let value = unpack(&mut unpacker).map_err(|e| unpack_error(what, e))?;
+	if unpacker.remaining() != 0 {
+		return Err(crate::Error::UnpackError(format!("{}: {} bytes left", what, unpacker.remaining())));
+	}
+
+	Ok(value)
+}
+
+fn checksums(unpacker: &mut Unpacker) -> Result<Vec<Checksum256>, crate::Error> {
+	let len = unpacker.varuint32()?;
+	(0..len).map(|_| unpacker.checksum256()).collect()
+}
+
+pub fn unpack_schedule(bytes: &[u8]) -> Result<ProducerAuthoritySchedule, crate::Error> {
+	unpack_all("producer schedule", bytes, |unpacker| unpacker.read())
+}
+
+/// fc reflects incremental_merkle as (_active_nodes)(_node_count).
+pub fn unpack_merkle(bytes: &[u8]) -> Result<IncrementalMerkle, crate::Error> {
+	unpack_all("incremental merkle", bytes, |unpacker| {
+		let active_nodes = checksums(unpacker)?;
+		let node_count = unpacker.u64()?;
+		Ok(IncrementalMerkle::new(node_count, active_nodes))
+	})
+}
+
+pub fn unpack_block_headers(bytes: &[u8]) -> Result<Vec<SignedBlockHeader>, crate::Error> {
+	unpack_all("block headers", bytes, |unpacker| {
+		let len = unpacker.varuint32()?;
+		(0..len).map(|_| unpacker.read()).collect()
+	})
+}
+
+pub fn unpack_ids_list(bytes: &[u8]) -> Result<Vec<Vec<Checksum256>>, crate::Error> {
+	unpack_all("block ids list", bytes, |unpacker| {
+		let len = unpacker.varuint32()?;
+		(0..len).map(|_| checksums(unpacker)).collect()
+	})
+}
+
+/// Schedule change from a packed producer_authority_schedule, incremental_merkle,
+/// vector<signed_block_header> and vector<vector<block_id_type>>.
+pub fn change_schedule_call(
+	legacy_schedule_hash: Checksum256,
+	schedule:             &[u8],
+	merkle:               &[u8],
+	block_headers:        &[u8],
+	block_ids_list:       &[u8],
+) -> Result<ChangeScheduleCall<BifrostRuntime>, crate::Error> {
+	Ok(ChangeScheduleCall {
+		legacy_schedule_hash,
+		schedule: unpack_schedule(schedule)?,
+		merkle: unpack_merkle(merkle)?,
+		block_headers: unpack_block_headers(block_headers)?,
+		block_ids_list: unpack_ids_list(block_ids_list)?,
+		_runtime: PhantomData,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::{checksum, header};
+
+	const PUBLIC_KEY: &str = "EOS6MRyAjQq8ud7hVNYcfnVPJqcVpscN5So8BhtHuGYqET5GDW5CV";
+
+	// fixtures below are written by hand, field by field, after the rules of fc::raw::pack:
+	// integers are little endian, lengths and variant indices are varuint32, an optional is
+	// a 0 or 1 byte before its value. they are not dumped from nodeos.
+
+	// producer_authority_schedule { 2, [{ bifrost, block_signing_authority_v0 { 1, [{ PUBLIC_KEY, 1 }] } }] }
+	const PACKED_SCHEDULE: &str = concat!(
+		"02000000",         // version
+		"01",               // producers
+		"00000020637a973b", // producer_name
+		"00",               // block_signing_authority_v0
+		"01000000",         // threshold
+		"01",               // keys
+		"00", "02c0ded2bc1f1305fb0faac5e6c03ee3a1924234985427b6167ca569d13df435cf", // k1 key
+		"0100",             // weight
+	);
+
+	// incremental_merkle { _active_nodes: [0x11.., 0x22..], _node_count: 3 }
+	const PACKED_MERKLE: &str = concat!(
+		"02",
+		"1111111111111111111111111111111111111111111111111111111111111111",
+		"2222222222222222222222222222222222222222222222222222222222222222",
+		"0300000000000000",
+	);
+
+	// vector<signed_block_header> of block 101
+	const PACKED_HEADERS: &str = concat!(
+		"01",
+		"802b704d",         // timestamp, 2020-08-01T12:00:00.000
+		"00000020637a973b", // producer
+		"0000",             // confirmed
+		"0000006400000000000000000000000000000000000000000000000000000000", // previous
+		"1111111111111111111111111111111111111111111111111111111111111111", // transaction_mroot
+		"2222222222222222222222222222222222222222222222222222222222222222", // action_mroot
+		"01000000",         // schedule_version
+		"00",               // new_producers
+		"00",               // header_extensions
+		"00", "0000000000000000000000000000000000000000000000000000000000000000", // k1 signature
+		"000000000000000000000000000000000000000000000000000000000000000000",
+	);
+
+	// vector<vector<block_id_type>> { [], [0x33..] }
+	const PACKED_IDS_LIST: &str = concat!(
+		"02",
+		"00",
+		"01", "3333333333333333333333333333333333333333333333333333333333333333",
+	);
+
+	fn bytes(packed: &str) -> Vec<u8> {
+		hex::decode(packed).expect("valid hex")
+	}
+
+	#[test]
+	fn schedule_packed_by_cpp() {
+		let schedule = unpack_schedule(&bytes(PACKED_SCHEDULE)).unwrap();
+		assert_eq!(schedule.version, 2);
+		assert_eq!(schedule.producers.len(), 1);
+		assert_eq!(schedule.producers[0].producer_name.to_string(), "bifrost");
+
+		let authority = &schedule.producers[0].authority.1;
+		assert_eq!(authority.threshold, 1);
+		assert_eq!(authority.keys.len(), 1);
+		assert_eq!(authority.keys[0].key.to_string(), PUBLIC_KEY);
+		assert_eq!(authority.keys[0].weight, 1);
+	}
+
+	#[test]
+	fn merkle_packed_by_cpp() {
+		let merkle = unpack_merkle(&bytes(PACKED_MERKLE)).unwrap();
+		assert_eq!(merkle, IncrementalMerkle::new(3, vec![checksum(0x11), checksum(0x22)]));
+	}
+
+	#[test]
+	fn block_headers_packed_by_cpp() {
+		let headers = unpack_block_headers(&bytes(PACKED_HEADERS)).unwrap();
+		assert_eq!(headers, vec![header(101, 1)]);
+		assert_eq!(headers[0].block_header.block_num(), 101);
+	}
+
+	#[test]
+	fn ids_list_packed_by_cpp() {
+		let ids_list = unpack_ids_list(&bytes(PACKED_IDS_LIST)).unwrap();
+		assert_eq!(ids_list, vec![vec![], vec![checksum(0x33)]]);
+	}
+
+	#[test]
+	fn trailing_bytes_are_refused() {
+		let mut packed = bytes(PACKED_MERKLE);
+		packed.push(0);
+		match unpack_merkle(&packed) {
+			Err(crate::Error::UnpackError(e)) => assert!(e.contains("1 bytes left")),
+			other => panic!("unexpected result: {:?}", other),
+		}
+	}
+
+	#[test]
+	fn truncated_argument_is_refused() {
+		let packed = bytes(PACKED_IDS_LIST);
+		assert!(matches!(unpack_ids_list(&packed[..packed.len() - 1]), Err(crate::Error::UnpackError(_))));
+	}
+
+	#[test]
+	fn change_schedule_from_packed_arguments() {
+		let call = change_schedule_call(
+			checksum(0x44),
+			&bytes(PACKED_SCHEDULE),
+			&bytes(PACKED_MERKLE),
+			&bytes(PACKED_HEADERS),
+			&bytes(PACKED_IDS_LIST),
+		).unwrap();
+		assert_eq!(call.legacy_schedule_hash, checksum(0x44));
+		assert_eq!(call.schedule.version, 2);
+		assert_eq!(call.block_headers.len(), 1);
+		assert_eq!(call.block_ids_list[1], vec![checksum(0x33)]);
+	}
+}
@@ -0,0 +1,346 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Encoded proofs of batches, so a proof that failed and is retried, or goes into another
+//! batch or group, isn't encoded again. A proof sent alone is encoded by the client. Parts of a proof are cached one by one, keyed by the runtime
+//! they are encoded for, the block the proof is for and the digest of its action receipt,
+//! and a payload is put together from them, alone or sharing headers with other proofs.
+
+use crate::rpc_calls::{BifrostRuntime, ProveActionCall};
+use codec::{Compact, Encode};
+use eos_chain::{Checksum256, Digest};
+use lru::LruCache;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::sync::Mutex;
+use subxt::Encoded;
+
+static PAYLOAD_CACHE: Lazy<Mutex<PayloadCache>> = Lazy::new(|| {
+	Mutex::new(PayloadCache::new(crate::config::relay_config().payload_cache_size))
+});
+
+static CALL_PREFIXES: Lazy<Mutex<Option<(u32, CallPrefixes)>>> = Lazy::new(|| Mutex::new(None));
+
+/// Runtime spec version, target block number and receipt digest.
+pub type PayloadKey = (u32, u32, Checksum256);
+
+/// A proof, encoded in the parts that a call proving it alone and a call sharing its headers
+/// with other proofs are both put together from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncodedProof {
+	/// Action, action receipt and action merkle paths.
+	pub action:  Vec<u8>,
+	/// Blockroot merkle, block headers and block id lists.
+	pub chain:   Vec<u8>,
+	pub trx_id:  Vec<u8>,
+	/// Number of block headers, proofs sharing headers take the longest chain.
+	pub headers: usize,
+}
+
+impl EncodedProof {
+	pub fn new(call: &ProveActionCall<BifrostRuntime>) -> Self {
+		Self {
+			action: (&call.action, &call.action_receipt, &call.action_merkle_paths).encode(),
+			chain: (&call.merkle, &call.block_headers, &call.block_ids_list).encode(),
+			trx_id: call.trx_id.encode(),
+			headers: call.block_headers.len(),
+		}
+	}
+}
+
+/// Module and call index of the calls, what `Client::encode` puts before the call arguments.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CallPrefixes {
+	pub prove_action:  Vec<u8>,
+	pub prove_actions: Vec<u8>,
+}
+
+impl CallPrefixes {
+	/// Prefix of `encoded`, which is `call` encoded by the client.
+	pub fn prefix_of(encoded: &Encoded, call: &impl Encode) -> Vec<u8> {
+		let args = call.encode().len();
+		encoded.0[..encoded.0.len().saturating_sub(args)].to_vec()
+	}
+
+	/// Payload of a proof sent alone, like `ProveActionCall` encoded by the client.
+	pub fn alone(&self, proof: &EncodedProof) -> Encoded {
+		let mut payload = self.prove_action.clone();
+		payload.extend_from_slice(&proof.action);
+		payload.extend_from_slice(&proof.chain);
+		payload.extend_from_slice(&proof.trx_id);
+		Encoded(payload)
+	}
+
+	/// Payload of proofs sharing the longest header chain of them, like `aggregate::share_headers`
+	/// encoded by the client. A single proof goes alone.
+	pub fn shared(&self, proofs: &[EncodedProof]) -> Encoded {
+		if proofs.len() == 1 {
+			return self.alone(&proofs[0]);
+		}
+
+		// the first of the longest ones, as `share_headers` takes
+		let longest = proofs.iter()
+			.enumerate()
+			.max_by_key(|(index, proof)| (proof.headers, std::cmp::Reverse(*index)))
+			.map(|(_, proof)| proof);
+
+		let mut payload = self.prove_actions.clone();
+		Compact(proofs.len() as u32).encode_to(&mut payload);
+		for proof in proofs.iter() {
+			payload.extend_from_slice(&proof.action);
+			payload.extend_from_slice(&proof.trx_id);
+		}
+		if let Some(longest) = longest {
+			payload.extend_from_slice(&longest.chain);
+		}
+		Encoded(payload)
+	}
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PayloadCacheStats {
+	pub entries:  usize,
+	pub capacity: usize,
+	pub hits:     u64,
+	pub misses:   u64,
+}
+
+impl PayloadCacheStats {
+	pub fn to_json(&self) -> Value {
+		json!({
+			"entries": self.entries,
+			"capacity": self.capacity,
+			"hits": self.hits,
+			"misses": self.misses,
+		})
+	}
+}
+
+pub struct PayloadCache {
+	proofs: LruCache<PayloadKey, EncodedProof>,
+	hits:   u64,
+	misses: u64,
+}
+
+impl PayloadCache {
+	pub fn new(capacity: usize) -> Self {
+		Self { proofs: LruCache::new(capacity.max(1)), hits: 0, misses: 0 }
+	}
+
+	/// Counts a hit or a miss.
+	pub fn get(&mut self, key: &PayloadKey) -> Option<EncodedProof> {
+		match self.proofs.get(key) {
+			Some(proof) => {
+				self.hits += 1;
+				Some(proof.clone())
+			}
+			None => {
+				self.misses += 1;
+				None
+			}
+		}
+	}
+
+	pub fn put(&mut self, key: PayloadKey, proof: EncodedProof) {
+		self.proofs.put(key, proof);
+	}
+
+	/// Encoded `call`, it's encoded and cached on a miss. Nothing is cached without a key.
+	pub fn get_or_encode(&mut self, spec_version: u32, call: &ProveActionCall<BifrostRuntime>) -> EncodedProof {
+		let key = match key_of(spec_version, call) {
+			Some(key) => key,
+			None => return EncodedProof::new(call),
+		};
+		if let Some(proof) = self.get(&key) {
+			return proof;
+		}
+
+		let proof = EncodedProof::new(call);
+		self.put(key, proof.clone());
+		proof
+	}
+
+	pub fn resize(&mut self, capacity: usize) {
+		if self.proofs.cap() != capacity.max(1) {
+			self.proofs.resize(capacity.max(1));
+		}
+	}
+
+	pub fn stats(&self) -> PayloadCacheStats {
+		PayloadCacheStats {
+			entries: self.proofs.len(),
+			capacity: self.proofs.cap(),
+			hits: self.hits,
+			misses: self.misses,
+		}
+	}
+}
+
+fn target_block(call: &ProveActionCall<BifrostRuntime>) -> u32 {
+	call.block_headers.first().map(|header| header.block_header.block_num()).unwrap_or_default()
+}
+
+/// Key of a proof encoded for runtime `spec_version`, `None` if its receipt digest cannot be computed.
+pub fn key_of(spec_version: u32, call: &ProveActionCall<BifrostRuntime>) -> Option<PayloadKey> {
+	let digest = call.action_receipt.digest().ok()?;
+	Some((spec_version, target_block(call), digest))
+}
+
+/// Encoded `calls`, each one is taken from the cache or encoded and cached on a miss.
+pub fn get_or_encode(spec_version: u32, calls: &[ProveActionCall<BifrostRuntime>]) -> Vec<EncodedProof> {
+	let capacity = crate::config::relay_config().payload_cache_size;
+	match PAYLOAD_CACHE.lock() {
+		Ok(mut cache) => {
+			cache.resize(capacity);
+			calls.iter().map(|call| cache.get_or_encode(spec_version, call)).collect()
+		}
+		Err(_) => calls.iter().map(EncodedProof::new).collect(),
+	}
+}
+
+/// Call prefixes of runtime `spec_version`, they are found by `find` once the runtime changes.
+pub fn prefixes<E>(
+	spec_version: u32,
+	find:         impl FnOnce() -> Result<CallPrefixes, E>,
+) -> Result<CallPrefixes, E> {
+	if let Ok(known) = CALL_PREFIXES.lock() {
+		if let Some((version, prefixes)) = known.as_ref() {
+			if *version == spec_version {
+				return Ok(prefixes.clone());
+			}
+		}
+	}
+
+	let prefixes = find()?;
+	if let Ok(mut known) = CALL_PREFIXES.lock() {
+		*known = Some((spec_version, prefixes.clone()));
+	}
+
+	Ok(prefixes)
+}
+
+pub fn stats() -> PayloadCacheStats {
+	PAYLOAD_CACHE.lock().map(|cache| cache.stats()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::{header, prove_call};
+
+	const SPEC_VERSION: u32 = 7;
+
+	fn key(block_num: u32, seq: u8) -> PayloadKey {
+		(SPEC_VERSION, block_num, Checksum256::from([seq; 32]))
+	}
+
+	fn proof(seq: u8) -> EncodedProof {
+		EncodedProof { action: vec![seq], chain: vec![], trx_id: vec![], headers: 1 }
+	}
+
+	fn prefixes() -> CallPrefixes {
+		CallPrefixes { prove_action: vec![0x20, 0x01], prove_actions: vec![0x20, 0x05] }
+	}
+
+	#[test]
+	fn hits_and_misses_are_counted() {
+		let mut cache = PayloadCache::new(4);
+		assert!(cache.get(&key(100, 1)).is_none());
+		cache.put(key(100, 1), proof(1));
+		assert_eq!(cache.get(&key(100, 1)), Some(proof(1)));
+		assert!(cache.get(&key(101, 1)).is_none());
+
+		let stats = cache.stats();
+		assert_eq!((stats.entries, stats.hits, stats.misses), (1, 1, 2));
+	}
+
+	#[test]
+	fn least_recently_used_is_evicted() {
+		let mut cache = PayloadCache::new(2);
+		cache.put(key(100, 1), proof(1));
+		cache.put(key(100, 2), proof(2));
+		assert!(cache.get(&key(100, 1)).is_some());
+		cache.put(key(100, 3), proof(3));
+
+		assert!(cache.get(&key(100, 2)).is_none());
+		assert!(cache.get(&key(100, 1)).is_some());
+		assert!(cache.get(&key(100, 3)).is_some());
+	}
+
+	#[test]
+	fn shrinking_drops_old_payloads() {
+		let mut cache = PayloadCache::new(3);
+		for seq in 1..=3 {
+			cache.put(key(100, seq), proof(seq));
+		}
+		cache.resize(1);
+		assert_eq!(cache.stats().entries, 1);
+		assert!(cache.get(&key(100, 3)).is_some());
+	}
+
+	#[test]
+	fn retry_after_regrouping_is_a_hit() {
+		let mut cache = PayloadCache::new(8);
+		let calls: Vec<_> = (1..=3).map(|seq| prove_call(100, 1, seq)).collect();
+
+		// first try groups proofs 1 and 2, the retry groups proofs 1 and 3
+		for call in calls[..2].iter() {
+			cache.get_or_encode(SPEC_VERSION, call);
+		}
+		cache.get_or_encode(SPEC_VERSION, &calls[0]);
+		cache.get_or_encode(SPEC_VERSION, &calls[2]);
+
+		let stats = cache.stats();
+		assert_eq!((stats.entries, stats.hits, stats.misses), (3, 1, 3));
+	}
+
+	#[test]
+	fn another_runtime_is_a_miss() {
+		let mut cache = PayloadCache::new(8);
+		let call = prove_call(100, 1, 1);
+		cache.get_or_encode(SPEC_VERSION, &call);
+		cache.get_or_encode(SPEC_VERSION + 1, &call);
+		cache.get_or_encode(SPEC_VERSION, &call);
+
+		let stats = cache.stats();
+		assert_eq!((stats.entries, stats.hits, stats.misses), (2, 1, 2));
+	}
+
+	#[test]
+	fn payload_alone_is_the_encoded_call() {
+		let call = prove_call(100, 1, 1);
+		let payload = prefixes().alone(&EncodedProof::new(&call));
+
+		let mut expected = prefixes().prove_action;
+		expected.extend(call.encode());
+		assert_eq!(payload.0, expected);
+		assert_eq!(CallPrefixes::prefix_of(&payload, &call), prefixes().prove_action);
+	}
+
+	#[test]
+	fn shared_payload_is_the_encoded_shared_call() {
+		let mut calls: Vec<_> = (1..=3).map(|seq| prove_call(100, 1, seq)).collect();
+		// the second proof has the longest header chain
+		calls[1].block_headers.push(header(112, 1));
+		calls[1].block_ids_list.push(vec![Checksum256::from([9u8; 32])]);
+		let proofs: Vec<_> = calls.iter().map(EncodedProof::new).collect();
+		let payload = prefixes().shared(&proofs);
+
+		let mut expected = prefixes().prove_actions;
+		expected.extend(crate::aggregate::share_headers(calls).encode());
+		assert_eq!(payload.0, expected);
+	}
+}
@@ -0,0 +1,611 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+//! One queue for everything sent to bifrost. Work is ordered by EOS block number and
+//! schedule version, and an action proof is held back until bifrost trusts the producer
+//! schedule that signed its block headers.
+
+use crate::rpc_calls::{BifrostRuntime, ChangeScheduleCall, ProveActionCall};
+use crate::schedule_chain::{find_gaps, link, Link, StoredSchedule};
+use codec::{Decode, Encode};
+use eos_chain::Checksum256;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+static SUBMISSION_QUEUE: Lazy<Mutex<SubmissionQueue>> = Lazy::new(|| Mutex::new(SubmissionQueue::default()));
+
+#[derive(Clone, Debug, PartialEq, Encode, Decode)]
+pub enum Submission {
+	ChangeSchedule(ChangeScheduleCall<BifrostRuntime>),
+	ProveAction(ProveActionCall<BifrostRuntime>),
+}
+
+impl Submission {
+	pub fn kind(&self) -> &'static str {
+		match self {
+			Self::ChangeSchedule(_) => "change_schedule",
+			Self::ProveAction(_) => "prove_action",
+		}
+	}
+
+	// the block being proven, it's the first one of block headers
+	pub fn block_num(&self) -> u32 {
+		self.block_headers().first().map(|h| h.block_header.block_num()).unwrap_or_default()
+	}
+
+	/// Schedule version bifrost must have before this submission can be verified,
+	/// that's the newest version which signed any of its block headers.
+	pub fn required_version(&self) -> u32 {
+		self.block_headers().iter().map(|h| h.block_header.schedule_version).max().unwrap_or_default()
+	}
+
+	// a schedule change goes before proofs of the same block
+	fn rank(&self) -> u8 {
+		match self {
+			Self::ChangeSchedule(_) => 0,
+			Self::ProveAction(_) => 1,
+		}
+	}
+
+	/// Digest of the proven action receipt, c++ side keys its pending proofs by it.
+	pub fn receipt_digest(&self) -> Option<Checksum256> {
+		match self {
+			Self::ChangeSchedule(_) => None,
+			Self::ProveAction(call) => call.action_receipt.digest().ok(),
+		}
+	}
+
+	fn block_headers(&self) -> &[eos_chain::SignedBlockHeader] {
+		match self {
+			Self::ChangeSchedule(call) => &call.block_headers,
+			Self::ProveAction(call) => &call.block_headers,
+		}
+	}
+
+	/// Whether it could be sent while bifrost trusts `stored`, a schedule change
+	/// must be the next one of the stored schedule.
+	pub fn is_ready(&self, stored: &StoredSchedule) -> bool {
+		if self.required_version() > stored.version {
+			return false;
+		}
+		match self {
+			Self::ChangeSchedule(call) => link(stored, call) == Link::Next,
+			Self::ProveAction(_) => true,
+		}
+	}
+
+	// a schedule bifrost has moved past will never be accepted
+	fn is_stale(&self, stored: &StoredSchedule) -> bool {
+		match self {
+			Self::ChangeSchedule(call) => link(stored, call) == Link::Stale,
+			Self::ProveAction(_) => false,
+		}
+	}
+
+	// the right version of schedule change, but for another schedule than the stored one
+	fn is_conflict(&self, stored: &StoredSchedule) -> bool {
+		match self {
+			Self::ChangeSchedule(call) => link(stored, call) == Link::HashMismatch,
+			Self::ProveAction(_) => false,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Encode, Decode)]
+pub struct QueueKey {
+	pub block_num:        u32,
+	pub schedule_version: u32,
+	rank:                 u8,
+	pub id:               u64,
+}
+
+#[derive(Debug, Default)]
+pub struct SubmissionQueue {
+	next_id:        u64,
+	items:          BTreeMap<QueueKey, Submission>,
+	stored:         Option<StoredSchedule>, // schedule bifrost trusted in the last pass
+}
+
+impl SubmissionQueue {
+	/// Queue a submission, it's on disk before this returns if relay store is opened.
+	pub fn push(&mut self, submission: Submission) -> Result<u64, crate::Error> {
+		let key = QueueKey {
+			block_num: submission.block_num(),
+			schedule_version: submission.required_version(),
+			rank: submission.rank(),
+			id: self.next_id + 1,
+		};
+		persist(&key, &submission)?;
+		self.next_id = key.id;
+		self.items.insert(key, submission);
+
+		Ok(key.id)
+	}
+
+	/// Take out what's ready in order, and drop schedule changes that are stale.
+	/// Only one schedule change is taken, the next one waits until bifrost accepts it.
+	pub fn take_ready(&mut self, stored: StoredSchedule) -> Vec<(QueueKey, Submission)> {
+		let stored = stored.fill_hash(self.stored);
+		self.stored = Some(stored);
+		let mut change_taken = false;
+		let mut keys = Vec::new();
+		for (key, s) in self.items.iter() {
+			if s.is_stale(&stored) {
+				keys.push(*key);
+			} else if s.is_ready(&stored) {
+				if let Submission::ChangeSchedule(_) = s {
+					if change_taken {
+						continue;
+					}
+					change_taken = true;
+				}
+				keys.push(*key);
+			}
+		}
+
+		let mut ready = Vec::with_capacity(keys.len());
+		for key in keys {
+			if let Some(submission) = self.items.remove(&key) {
+				if submission.is_stale(&stored) {
+					unpersist(&key);
+					println!("[!] Drop change schedule to version {} since bifrost has version {}\n", key.schedule_version, stored.version);
+					continue;
+				}
+				ready.push((key, submission));
+			}
+		}
+
+		ready
+	}
+
+	/// Schedule versions that queued work needs, but no queued change brings to bifrost.
+	pub fn gaps(&self) -> Vec<u32> {
+		let stored_version = match self.stored {
+			Some(stored) => stored.version,
+			None => return vec![],
+		};
+		let changes = self.items.values().filter_map(|s| match s {
+			Submission::ChangeSchedule(call) => Some(call.schedule.version),
+			Submission::ProveAction(_) => None,
+		});
+		let needed = self.items.values().map(|s| s.required_version());
+
+		find_gaps(stored_version, changes, needed)
+	}
+
+	// ids of schedule changes that don't chain to the stored schedule
+	pub fn conflicts(&self) -> Vec<u64> {
+		match self.stored {
+			Some(ref stored) => self.items.iter().filter(|(_, s)| s.is_conflict(stored)).map(|(key, _)| key.id).collect(),
+			None => vec![],
+		}
+	}
+
+	/// Drop submissions proving a block that was forked out, returns their ids.
+	pub fn drop_forked(&mut self, forked: &[(u32, Checksum256)]) -> Vec<u64> {
+		let keys: Vec<QueueKey> = self.items.iter()
+			.filter(|(key, s)| {
+				forked.iter().any(|(block_num, id)| {
+					*block_num == key.block_num && s.block_headers().first().and_then(|h| h.block_header.id().ok()) == Some(*id)
+				})
+			})
+			.map(|(key, _)| *key)
+			.collect();
+		for key in keys.iter() {
+			self.items.remove(key);
+			unpersist(key);
+		}
+
+		keys.iter().map(|key| key.id).collect()
+	}
+
+	// failed submission keeps its place in the queue
+	pub fn put_back(&mut self, key: QueueKey, submission: Submission) {
+		self.items.insert(key, submission);
+	}
+
+	pub fn to_json(&self) -> Value {
+		let items: Vec<Value> = self.items.iter().map(|(key, s)| {
+			json!({
+				"id": key.id,
+				"kind": s.kind(),
+				"block_num": key.block_num,
+				"schedule_version": key.schedule_version,
+				"receipt_digest": s.receipt_digest().map(|d| d.to_string()),
+				"held": self.stored.map(|stored| !s.is_ready(&stored)).unwrap_or(true),
+			})
+		}).collect();
+
+		json!({
+			"stored_version": self.stored.map(|stored| stored.version),
+			"stored_hash": self.stored.and_then(|stored| stored.hash).map(|h| h.to_string()),
+			"pending": items.len(),
+			"items": items,
+			"gaps": self.gaps(),
+			"conflicts": self.conflicts(),
+		})
+	}
+}
+
+/// Outcome of a queued submission after a pass of `process`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueueResult {
+	pub key:            QueueKey,
+	pub kind:           &'static str,
+	pub receipt_digest: Option<Checksum256>,
+	pub success:        bool,
+	/// Failed and dropped from the queue, sending it again never helps.
+	pub permanent:      bool,
+	pub code:           u32,
+	pub msg:            String,
+}
+
+impl QueueResult {
+	pub fn to_json(&self) -> Value {
+		json!({
+			"id": self.key.id,
+			"kind": self.kind,
+			"block_num": self.key.block_num,
+			"schedule_version": self.key.schedule_version,
+			"receipt_digest": self.receipt_digest.map(|d| d.to_string()),
+			"success": self.success,
+			"permanent": self.permanent,
+			"code": self.code,
+			"msg": self.msg,
+		})
+	}
+}
+
+// queued submissions survive a restart if relay store is opened
+fn persist(key: &QueueKey, submission: &Submission) -> Result<(), crate::Error> {
+	if !crate::store::is_open() {
+		return Ok(());
+	}
+	let value = (key, submission).encode();
+	crate::store::put(crate::store::SUBMISSIONS_TREE, &key.id.to_be_bytes(), &value)
+}
+
+fn unpersist(key: &QueueKey) {
+	if !crate::store::is_open() {
+		return;
+	}
+	if let Err(e) = crate::store::remove(crate::store::SUBMISSIONS_TREE, &key.id.to_be_bytes()) {
+		println!("[!] Failed to remove persisted submission {} due to: {:?}\n", key.id, e);
+	}
+}
+
+/// Load submissions that were queued before last shutdown, returns how many are loaded.
+pub fn restore() -> Result<usize, crate::Error> {
+	let mut restored = Vec::new();
+	crate::store::for_each(crate::store::SUBMISSIONS_TREE, |_, mut value| {
+		match <(QueueKey, Submission)>::decode(&mut value) {
+			Ok(entry) => restored.push(entry),
+			Err(e) => println!("[!] Skip a broken persisted submission: {:?}\n", e),
+		}
+	})?;
+
+	let mut queue = SUBMISSION_QUEUE.lock().map_err(|_| crate::Error::ConfigError("submission queue is poisoned".to_owned()))?;
+	let count = restored.len();
+	for (key, submission) in restored.into_iter() {
+		queue.next_id = queue.next_id.max(key.id);
+		queue.items.insert(key, submission);
+	}
+
+	Ok(count)
+}
+
+pub fn enqueue(submission: Submission) -> Result<u64, crate::Error> {
+	let mut queue = SUBMISSION_QUEUE.lock().map_err(|_| crate::Error::ConfigError("submission queue is poisoned".to_owned()))?;
+	queue.push(submission)
+}
+
+pub fn drop_forked(forked: &[(u32, Checksum256)]) -> Result<Vec<u64>, crate::Error> {
+	let mut queue = SUBMISSION_QUEUE.lock().map_err(|_| crate::Error::ConfigError("submission queue is poisoned".to_owned()))?;
+	Ok(queue.drop_forked(forked))
+}
+
+/// Ids of submissions still waiting in the queue.
+pub fn pending_ids() -> Vec<u64> {
+	SUBMISSION_QUEUE.lock().map(|queue| queue.items.keys().map(|key| key.id).collect()).unwrap_or_default()
+}
+
+pub fn queue_status() -> Value {
+	SUBMISSION_QUEUE.lock().map(|queue| queue.to_json()).unwrap_or_default()
+}
+
+// errors that will never go away by sending it again
+fn is_permanent(e: &crate::Error) -> bool {
+	match e {
+		crate::Error::AlreadyProven | crate::Error::AlreadySubmitted | crate::Error::InvalidCall(_) => true,
+		_ => false,
+	}
+}
+
+/// Send whatever bifrost is able to verify now. Consecutive action proofs go in one batch,
+/// a failed submission stays in the queue for next pass unless it could never succeed.
+pub async fn process(urls: Vec<String>, signer: String) -> Result<Vec<QueueResult>, crate::Error> {
+	let url = urls.first().cloned().ok_or(crate::Error::SubxtError("failed to create subxt client"))?;
+	let client: subxt::Client<BifrostRuntime> = subxt::ClientBuilder::new()
+		.set_url(url)
+		.build()
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to create subxt client"))?;
+	// nothing leaves the queue if the signer cannot pay for it
+	crate::rpc_calls::ensure_signer_can_pay(&client, &signer).await?;
+	// an extrinsic unresolved for too long keeps its payload refused as in flight, look it up again
+	if let Err(e) = crate::outbox::reconcile_stale(&client).await {
+		println!("[!] Failed to reconcile stale extrinsics due to: {:?}\n", e);
+	}
+
+	let stored = {
+		let (version, hash) = crate::rpc_calls::stored_schedule(&client).await?;
+		StoredSchedule { version, hash }
+	};
+
+	let ready = {
+		let mut queue = SUBMISSION_QUEUE.lock().map_err(|_| crate::Error::ConfigError("submission queue is poisoned".to_owned()))?;
+		let ready = queue.take_ready(stored);
+		let gaps = queue.gaps();
+		if !gaps.is_empty() {
+			println!("[!] Schedule changes to versions {:?} are never captured, work depends on them is held\n", gaps);
+		}
+		let conflicts = queue.conflicts();
+		if !conflicts.is_empty() {
+			println!("[!] Queued schedule changes {:?} are not built on the schedule bifrost trusts\n", conflicts);
+		}
+		ready
+	};
+
+	let mut results = Vec::with_capacity(ready.len());
+	let mut ready = ready.into_iter().peekable();
+	while let Some((key, submission)) = ready.next() {
+		match submission {
+			Submission::ChangeSchedule(call) => {
+				let result = crate::rpc_calls::change_schedule_call(urls.clone(), &signer, call.clone()).await;
+				results.push(finish(key, Submission::ChangeSchedule(call), result));
+			}
+			Submission::ProveAction(call) => {
+				let mut proofs = vec![(key, call)];
+				while let Some((_, Submission::ProveAction(_))) = ready.peek() {
+					if let Some((key, Submission::ProveAction(call))) = ready.next() {
+						proofs.push((key, call));
+					}
+				}
+
+				if proofs.len() == 1 {
+					let (key, call) = proofs.remove(0);
+					let result = crate::rpc_calls::prove_action_call(urls.clone(), &signer, call.clone()).await;
+					results.push(finish(key, Submission::ProveAction(call), result));
+					continue;
+				}
+
+				let calls = proofs.iter().map(|(_, call)| call.clone()).collect();
+				match crate::rpc_calls::prove_actions_batch_call(urls.clone(), &signer, calls).await {
+					Ok(items) => {
+						for (item, (key, call)) in items.into_iter().zip(proofs.into_iter()) {
+							let result = if item.success {
+								Ok(item.msg)
+							} else if item.code == crate::ERROR_CODE_ALREADY_PROVEN {
+								Err(crate::Error::AlreadyProven)
+							} else if item.code == crate::ERROR_CODE_ALREADY_SUBMITTED {
+								Err(crate::Error::AlreadySubmitted)
+							} else if item.code == crate::ERROR_CODE_INVALID_CALL {
+								Err(crate::Error::InvalidCall("this proof is invalid"))
+							} else if item.code == crate::ERROR_CODE_DRY_RUN {
+								// stays queued, nothing is sent
+								Err(crate::Error::DryRun(item.msg.clone()))
+							} else {
+								Err(crate::Error::SubxtError("failed to prove this action in batch"))
+							};
+							let mut result = finish(key, Submission::ProveAction(call), result);
+							if !item.success {
+								result.code = item.code;
+								result.msg = item.msg;
+							}
+							results.push(result);
+						}
+					}
+					Err(e) => {
+						for (key, call) in proofs.into_iter() {
+							results.push(finish(key, Submission::ProveAction(call), Err(e.clone())));
+						}
+					}
+				}
+			}
+		}
+	}
+
+	Ok(results)
+}
+
+fn finish(key: QueueKey, submission: Submission, result: Result<String, crate::Error>) -> QueueResult {
+	let kind = submission.kind();
+	let receipt_digest = submission.receipt_digest();
+	match result {
+		Ok(msg) => {
+			unpersist(&key);
+			QueueResult { key, kind, receipt_digest, success: true, permanent: false, code: 0, msg }
+		}
+		Err(e) => {
+			let permanent = is_permanent(&e);
+			if permanent {
+				unpersist(&key);
+			} else if let Ok(mut queue) = SUBMISSION_QUEUE.lock() {
+				queue.put_back(key, submission);
+			}
+			QueueResult { key, kind, receipt_digest, success: false, permanent, code: e.code(), msg: e.to_string() }
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::{change_call, checksum, header, prove_call};
+
+	fn change(block_num: u32, version: u32, legacy_schedule_hash: Checksum256) -> Submission {
+		Submission::ChangeSchedule(change_call(block_num, version, legacy_schedule_hash))
+	}
+
+	fn proof(block_num: u32, schedule_version: u32, seq: u64) -> Submission {
+		Submission::ProveAction(prove_call(block_num, schedule_version, seq))
+	}
+
+	// pushes are written into relay store if another test has opened it
+	fn lock() -> std::sync::MutexGuard<'static, ()> {
+		crate::store::TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	fn stored(version: u32, byte: u8) -> StoredSchedule {
+		StoredSchedule { version, hash: Some(checksum(byte)) }
+	}
+
+	fn taken(queue: &mut SubmissionQueue, stored: StoredSchedule) -> Vec<(&'static str, u32)> {
+		queue.take_ready(stored).iter().map(|(key, s)| (s.kind(), key.block_num)).collect()
+	}
+
+	#[test]
+	fn proofs_of_forked_blocks_are_dropped() {
+		let _lock = lock();
+		let mut queue = SubmissionQueue::default();
+		let forked = queue.push(proof(10, 1, 1)).unwrap();
+		let kept = queue.push(proof(11, 1, 2)).unwrap();
+		let forked_id = header(10, 1).block_header.id().unwrap();
+		let other_fork = checksum(0xff);
+
+		assert!(queue.drop_forked(&[(10, other_fork)]).is_empty());
+		assert_eq!(queue.drop_forked(&[(10, forked_id), (11, other_fork)]), vec![forked]);
+		assert_eq!(queue.items.keys().map(|key| key.id).collect::<Vec<_>>(), vec![kept]);
+	}
+
+	#[test]
+	fn ready_work_is_taken_by_block_and_change_first() {
+		let _lock = lock();
+		let mut queue = SubmissionQueue::default();
+		queue.push(proof(10, 1, 1)).unwrap();
+		queue.push(change(5, 2, checksum(1))).unwrap();
+		queue.push(proof(5, 1, 2)).unwrap();
+
+		assert_eq!(taken(&mut queue, stored(1, 1)), vec![
+			("change_schedule", 5),
+			("prove_action", 5),
+			("prove_action", 10),
+		]);
+		assert!(queue.items.is_empty());
+	}
+
+	#[test]
+	fn proof_is_held_until_its_schedule_is_stored() {
+		let _lock = lock();
+		let mut queue = SubmissionQueue::default();
+		queue.push(proof(20, 2, 1)).unwrap();
+		queue.push(proof(10, 1, 2)).unwrap();
+
+		assert_eq!(taken(&mut queue, stored(1, 1)), vec![("prove_action", 10)]);
+		assert_eq!(queue.gaps(), vec![2]);
+		assert_eq!(taken(&mut queue, stored(2, 2)), vec![("prove_action", 20)]);
+	}
+
+	#[test]
+	fn only_next_change_is_taken_in_one_pass() {
+		let _lock = lock();
+		let mut queue = SubmissionQueue::default();
+		queue.push(change(5, 2, checksum(1))).unwrap();
+		queue.push(change(8, 3, checksum(2))).unwrap();
+
+		assert_eq!(taken(&mut queue, stored(1, 1)), vec![("change_schedule", 5)]);
+		assert_eq!(taken(&mut queue, stored(2, 2)), vec![("change_schedule", 8)]);
+	}
+
+	#[test]
+	fn stale_change_is_dropped_and_conflict_is_kept() {
+		let _lock = lock();
+		let mut queue = SubmissionQueue::default();
+		queue.push(change(5, 2, checksum(1))).unwrap();
+		let conflict = queue.push(change(8, 4, checksum(9))).unwrap();
+
+		assert!(taken(&mut queue, stored(3, 3)).is_empty());
+		assert_eq!(queue.items.len(), 1);
+		assert_eq!(queue.conflicts(), vec![conflict]);
+	}
+
+	#[test]
+	fn unknown_stored_hash_keeps_the_last_one() {
+		let _lock = lock();
+		let mut queue = SubmissionQueue::default();
+		let conflict = queue.push(change(5, 2, checksum(9))).unwrap();
+
+		assert!(taken(&mut queue, stored(1, 1)).is_empty());
+		assert!(taken(&mut queue, StoredSchedule { version: 1, hash: None }).is_empty());
+		assert_eq!(queue.conflicts(), vec![conflict]);
+	}
+
+	#[test]
+	fn failed_submission_keeps_its_place() {
+		let _lock = lock();
+		let mut queue = SubmissionQueue::default();
+		queue.push(proof(5, 1, 1)).unwrap();
+		queue.push(proof(10, 1, 2)).unwrap();
+
+		let mut ready = queue.take_ready(stored(1, 1));
+		let (key, submission) = ready.remove(0);
+		queue.put_back(key, submission);
+		queue.push(proof(7, 1, 3)).unwrap();
+
+		assert_eq!(taken(&mut queue, stored(1, 1)), vec![("prove_action", 5), ("prove_action", 7)]);
+	}
+
+	#[test]
+	fn proof_is_known_by_its_receipt_digest() {
+		let submission = proof(5, 1, 1);
+		let expected = match submission {
+			Submission::ProveAction(ref call) => call.action_receipt.digest().unwrap(),
+			_ => unreachable!(),
+		};
+		assert_eq!(submission.receipt_digest(), Some(expected));
+		assert_eq!(change(5, 2, checksum(1)).receipt_digest(), None);
+	}
+
+	#[test]
+	fn queue_survives_reopening_store() {
+		let _lock = lock();
+		let dir = std::env::temp_dir().join(format!("bifrost-queue-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+
+		crate::store::open(&dir).unwrap();
+		let mut queue = SubmissionQueue::default();
+		queue.push(proof(10, 1, 1)).unwrap();
+		queue.push(change(5, 2, checksum(1))).unwrap();
+		let last = queue.push(proof(5, 1, 2)).unwrap();
+		crate::store::close().unwrap();
+
+		crate::store::open(&dir).unwrap();
+		assert_eq!(restore().unwrap(), 3);
+		{
+			let restored = SUBMISSION_QUEUE.lock().unwrap();
+			assert_eq!(restored.next_id, last);
+			assert_eq!(restored.items, queue.items);
+		}
+		// ids go on after the restored ones
+		assert_eq!(enqueue(proof(12, 1, 3)).unwrap(), last + 1);
+
+		*SUBMISSION_QUEUE.lock().unwrap() = SubmissionQueue::default();
+		crate::store::close().unwrap();
+		let _ = std::fs::remove_dir_all(&dir);
+	}
+}
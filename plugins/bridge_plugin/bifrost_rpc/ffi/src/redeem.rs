@@ -0,0 +1,370 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Ties EOS redeem transfers sent by the cross account back to the bifrost
+//! requests that started them. The request id travels in the transfer memo,
+//! like `redeem:42`. Once the transfer proof is accepted, a confirmation goes to
+//! bifrost and the request is settled after bifrost accepts the confirmation.
+
+use crate::cross_out::CrossOutRequest;
+use crate::rpc_calls::{BifrostRuntime, ConfirmCrossOutCall};
+use codec::{Decode, Encode};
+use core::marker::PhantomData;
+use eos_chain::Checksum256;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use sp_core::{sr25519::Pair, Pair as TraitPair};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use subxt::{Client, PairSigner};
+
+const REQUEST_ID_PREFIX: &str = "redeem:";
+
+pub const REDEEM_TREE: &str = "redeem";
+const TRACKER_KEY: &[u8] = b"tracker";
+
+/// Transfers matching nothing are kept for inspection, only the latest ones.
+const MAX_UNMATCHED: usize = 256;
+
+static REDEEM_TRACKER: Lazy<Mutex<RedeemTracker>> = Lazy::new(|| Mutex::new(RedeemTracker::default()));
+
+#[derive(Clone, Debug, PartialEq, Encode, Decode)]
+pub struct TrackedRequest {
+	pub request:     CrossOutRequest,
+	pub received_at: u64,
+	pub trx_id:      Option<Checksum256>,
+	/// Proof of the transfer is accepted, confirmation is not yet.
+	pub proven:      bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Encode, Decode)]
+pub struct UnmatchedTransfer {
+	pub memo:    String,
+	pub trx_id:  Checksum256,
+	pub seen_at: u64,
+}
+
+#[derive(Debug, Default, Encode, Decode)]
+pub struct RedeemTracker {
+	pending:   BTreeMap<u64, TrackedRequest>,
+	matched:   BTreeMap<u64, TrackedRequest>,
+	expired:   BTreeMap<u64, TrackedRequest>,
+	unmatched: Vec<UnmatchedTransfer>,
+	/// Requests confirmed on bifrost, they're dropped from the tracker.
+	settled:   u64,
+}
+
+impl RedeemTracker {
+	pub fn track(&mut self, request: CrossOutRequest, now: u64) {
+		let request_id = request.request_id;
+		if self.matched.contains_key(&request_id) {
+			return;
+		}
+		self.expired.remove(&request_id);
+		self.pending.insert(request_id, TrackedRequest { request, received_at: now, trx_id: None, proven: false });
+	}
+
+	/// Match an outgoing EOS transfer by the request id in its memo.
+	pub fn match_transfer(&mut self, memo: &str, trx_id: Checksum256, now: u64) -> Option<u64> {
+		let request_id = parse_request_id(memo);
+		let tracked = request_id.and_then(|id| self.pending.remove(&id).or_else(|| self.expired.remove(&id)));
+
+		match tracked {
+			Some(mut tracked) => {
+				let request_id = tracked.request.request_id;
+				tracked.trx_id = Some(trx_id);
+				self.matched.insert(request_id, tracked);
+				Some(request_id)
+			}
+			None => {
+				self.unmatched.push(UnmatchedTransfer { memo: memo.to_owned(), trx_id, seen_at: now });
+				if self.unmatched.len() > MAX_UNMATCHED {
+					let excess = self.unmatched.len() - MAX_UNMATCHED;
+					self.unmatched.drain(..excess);
+				}
+				None
+			}
+		}
+	}
+
+	/// The transfer proof has been accepted by bifrost, the request waits for its confirmation.
+	pub fn mark_proven(&mut self, trx_id: &Checksum256) -> Option<u64> {
+		let tracked = self.matched.values_mut().find(|t| t.trx_id.as_ref() == Some(trx_id))?;
+		tracked.proven = true;
+
+		Some(tracked.request.request_id)
+	}
+
+	/// Requests whose transfer is proven, and the trx_id to confirm each of them with.
+	pub fn to_confirm(&self) -> Vec<(u64, Checksum256)> {
+		self.matched.values()
+			.filter(|t| t.proven)
+			.filter_map(|t| t.trx_id.map(|trx_id| (t.request.request_id, trx_id)))
+			.collect()
+	}
+
+	/// Bifrost accepted the confirmation, nothing is left to track for the request.
+	pub fn settle(&mut self, request_id: u64) -> bool {
+		let settled = self.matched.remove(&request_id).is_some();
+		if settled {
+			self.settled += 1;
+		}
+		settled
+	}
+
+	pub fn expire(&mut self, now: u64, timeout: u64) {
+		let expired: Vec<u64> = self.pending.iter()
+			.filter(|(_, t)| now.saturating_sub(t.received_at) > timeout)
+			.map(|(id, _)| *id)
+			.collect();
+		for id in expired {
+			if let Some(tracked) = self.pending.remove(&id) {
+				self.expired.insert(id, tracked);
+			}
+		}
+	}
+
+	/// Drop expired requests and unmatched transfers older than `retention` seconds.
+	pub fn prune(&mut self, now: u64, retention: u64) {
+		self.expired.retain(|_, t| now.saturating_sub(t.received_at) <= retention);
+		self.unmatched.retain(|u| now.saturating_sub(u.seen_at) <= retention);
+	}
+
+	pub fn to_json(&self) -> Value {
+		let requests = |map: &BTreeMap<u64, TrackedRequest>| -> Vec<Value> {
+			map.values().map(|t| json!({
+				"request_id": t.request.request_id,
+				"bifrost_account": t.request.bifrost_account,
+				"eos_recipient": t.request.eos_recipient.to_string(),
+				"asset": t.request.asset,
+				"amount": t.request.amount.to_string(),
+				"received_at": t.received_at,
+				"trx_id": t.trx_id.map(|id| id.to_string()),
+				"proven": t.proven,
+			})).collect()
+		};
+
+		json!({
+			"pending": requests(&self.pending),
+			"matched": requests(&self.matched),
+			"expired": requests(&self.expired),
+			"unmatched": self.unmatched.iter().map(|u| json!({
+				"memo": u.memo,
+				"trx_id": u.trx_id.to_string(),
+				"seen_at": u.seen_at,
+			})).collect::<Vec<_>>(),
+			"settled": self.settled,
+		})
+	}
+}
+
+pub fn parse_request_id(memo: &str) -> Option<u64> {
+	memo.split(|c: char| c.is_whitespace() || c == ';' || c == ',')
+		.find_map(|token| token.strip_prefix(REQUEST_ID_PREFIX))
+		.and_then(|id| id.parse::<u64>().ok())
+}
+
+// tracked requests survive a restart if relay store is opened
+fn persist(tracker: &RedeemTracker) {
+	if !crate::store::is_open() {
+		return;
+	}
+	if let Err(e) = crate::store::put(REDEEM_TREE, TRACKER_KEY, &tracker.encode()) {
+		println!("[!] Failed to persist redeem requests due to: {:?}\n", e);
+	}
+}
+
+/// Load requests tracked before last shutdown, returns how many are loaded.
+pub fn restore() -> Result<usize, crate::Error> {
+	let restored = match crate::store::get(REDEEM_TREE, TRACKER_KEY)? {
+		Some(value) => RedeemTracker::decode(&mut &value[..])
+			.map_err(|e| crate::Error::StoreError(format!("broken redeem requests: {:?}", e)))?,
+		None => return Ok(0),
+	};
+
+	let mut tracker = REDEEM_TRACKER.lock().map_err(|_| crate::Error::ConfigError("redeem tracker is poisoned".to_owned()))?;
+	*tracker = restored;
+
+	Ok(tracker.pending.len() + tracker.matched.len() + tracker.expired.len())
+}
+
+// apply a change to the global tracker and write it through
+fn with_tracker<R>(f: impl FnOnce(&mut RedeemTracker) -> R) -> Option<R> {
+	let mut tracker = REDEEM_TRACKER.lock().ok()?;
+	let result = f(&mut tracker);
+	persist(&tracker);
+
+	Some(result)
+}
+
+pub fn track(request: CrossOutRequest) {
+	with_tracker(|tracker| tracker.track(request, crate::status::now()));
+}
+
+pub fn match_transfer(memo: &str, trx_id: Checksum256) -> Option<u64> {
+	with_tracker(|tracker| tracker.match_transfer(memo, trx_id, crate::status::now()))?
+}
+
+pub fn mark_proven(trx_id: &Checksum256) -> Option<u64> {
+	with_tracker(|tracker| tracker.mark_proven(trx_id))?
+}
+
+/// Send a confirmation for every request whose transfer is proven, a request is
+/// settled once bifrost accepts its confirmation. Returns settled request ids.
+pub async fn confirm_proven(
+	urls:   impl IntoIterator<Item=String>,
+	signer: impl AsRef<str>,
+) -> Result<Vec<u64>, crate::Error> {
+	let to_confirm = REDEEM_TRACKER.lock().map(|tracker| tracker.to_confirm()).unwrap_or_default();
+	if to_confirm.is_empty() || crate::config::relay_config().dry_run {
+		return Ok(Vec::new());
+	}
+
+	let url: String = urls.into_iter().take(1).next().ok_or(crate::Error::SubxtError("failed to create subxt client"))?;
+	let client: Client<BifrostRuntime> = subxt::ClientBuilder::new()
+		.set_url(url)
+		.build()
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to create subxt client"))?;
+
+	let signer = Pair::from_string(signer.as_ref(), None).map_err(|_| crate::Error::WrongSudoSeed)?;
+	let mut signer = PairSigner::<BifrostRuntime, Pair>::new(signer);
+
+	let mut settled = Vec::with_capacity(to_confirm.len());
+	for (request_id, trx_id) in to_confirm.into_iter() {
+		let call = ConfirmCrossOutCall::<BifrostRuntime> { request_id, trx_id, _runtime: PhantomData };
+		// proofs sent right before could still be in tx pool, take the nonce after them
+		signer.set_nonce(crate::rpc_calls::next_nonce(&client, &signer).await?);
+		// journaled like proofs, a confirmation finalized before its settlement got lost is not sent again
+		match crate::outbox::watch(&client, &signer, call).await {
+			Ok(_) | Err(crate::Error::AlreadySubmitted) => {
+				with_tracker(|tracker| tracker.settle(request_id));
+				println!("[+] Redeem request {} got settled by transaction: {}\n", request_id, trx_id);
+				settled.push(request_id);
+			}
+			Err(e) => println!("[!] Failed to confirm redeem request {} due to: {:?}\n", request_id, e),
+		}
+	}
+
+	Ok(settled)
+}
+
+pub fn redeem_status() -> Value {
+	let config = crate::config::relay_config();
+	let now = crate::status::now();
+	with_tracker(|tracker| {
+		tracker.expire(now, config.redeem_timeout);
+		tracker.prune(now, config.redeem_retention);
+		tracker.to_json()
+	}).unwrap_or(Value::Null)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use eos_chain::AccountName;
+	use std::str::FromStr;
+
+	fn request(request_id: u64) -> CrossOutRequest {
+		CrossOutRequest {
+			request_id,
+			bifrost_account: "5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY".to_owned(),
+			eos_recipient: AccountName::from_str("jim").unwrap(),
+			asset: "EOS".to_owned(),
+			amount: 430_000_000_000_000,
+		}
+	}
+
+	fn trx_id(byte: u8) -> Checksum256 {
+		Checksum256::from([byte; 32])
+	}
+
+	#[test]
+	fn request_id_in_memo() {
+		assert_eq!(parse_request_id("redeem:42"), Some(42));
+		assert_eq!(parse_request_id("to jim; redeem:7"), Some(7));
+		assert_eq!(parse_request_id("redeem:abc"), None);
+		assert_eq!(parse_request_id("5GrwvaEF@bifrost:vEOS"), None);
+	}
+
+	#[test]
+	fn transfer_matches_pending_request() {
+		let mut tracker = RedeemTracker::default();
+		tracker.track(request(42), 100);
+
+		assert_eq!(tracker.match_transfer("redeem:42", trx_id(1), 110), Some(42));
+		assert!(tracker.pending.is_empty());
+		assert_eq!(tracker.matched[&42].trx_id, Some(trx_id(1)));
+		// tracked again by a resubscription, it's matched already
+		tracker.track(request(42), 120);
+		assert!(tracker.pending.is_empty());
+	}
+
+	#[test]
+	fn expired_request_could_still_be_matched() {
+		let mut tracker = RedeemTracker::default();
+		tracker.track(request(42), 100);
+		tracker.expire(200, 50);
+		assert!(tracker.expired.contains_key(&42));
+
+		assert_eq!(tracker.match_transfer("redeem:42", trx_id(1), 210), Some(42));
+		assert!(tracker.expired.is_empty());
+	}
+
+	#[test]
+	fn mismatched_transfer_is_kept_apart() {
+		let mut tracker = RedeemTracker::default();
+		tracker.track(request(42), 100);
+
+		assert_eq!(tracker.match_transfer("redeem:43", trx_id(1), 110), None);
+		assert_eq!(tracker.match_transfer("no request id", trx_id(2), 110), None);
+		assert!(tracker.pending.contains_key(&42));
+		assert_eq!(tracker.unmatched.len(), 2);
+
+		tracker.prune(110 + 3600 + 1, 3600);
+		assert!(tracker.unmatched.is_empty());
+	}
+
+	#[test]
+	fn settled_only_after_proven_and_confirmed() {
+		let mut tracker = RedeemTracker::default();
+		tracker.track(request(42), 100);
+		tracker.match_transfer("redeem:42", trx_id(1), 110);
+		assert!(tracker.to_confirm().is_empty());
+
+		assert_eq!(tracker.mark_proven(&trx_id(2)), None);
+		assert_eq!(tracker.mark_proven(&trx_id(1)), Some(42));
+		assert_eq!(tracker.to_confirm(), vec![(42, trx_id(1))]);
+
+		assert!(tracker.settle(42));
+		assert!(!tracker.settle(42));
+		assert!(tracker.matched.is_empty());
+		assert_eq!(tracker.settled, 1);
+	}
+
+	#[test]
+	fn tracker_survives_encoding() {
+		let mut tracker = RedeemTracker::default();
+		tracker.track(request(42), 100);
+		tracker.track(request(43), 100);
+		tracker.match_transfer("redeem:43", trx_id(1), 110);
+		tracker.mark_proven(&trx_id(1));
+
+		let decoded = RedeemTracker::decode(&mut &tracker.encode()[..]).unwrap();
+		assert_eq!(decoded.pending, tracker.pending);
+		assert_eq!(decoded.to_confirm(), vec![(43, trx_id(1))]);
+	}
+}
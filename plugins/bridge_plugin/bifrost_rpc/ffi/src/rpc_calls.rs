@@ -14,17 +14,19 @@
 // You should have received a copy of the GNU General Public License
 // along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
 
-use codec::Encode;
+use crate::payload_cache::CallPrefixes;
+use codec::{Decode, Encode};
 use core::marker::PhantomData;
 use eos_chain::{
 	Action, ActionReceipt, Checksum256, Digest, IncrementalMerkle,
-	ProducerAuthoritySchedule, SignedBlockHeader
+	ProducerAuthority, ProducerAuthoritySchedule, SignedBlockHeader
 };
+use jsonrpsee::common::Params;
 use once_cell::sync::Lazy; // sync::OnceCell is thread-safe
 use once_cell::sync::OnceCell; // sync::OnceCell is thread-safe
 use subxt::{
-	PairSigner, DefaultNodeRuntime as BifrostRuntime, Call, Client,
-	system::{AccountStoreExt, System, SystemEventsDecoder}, Error as SubxtErr,
+	PairSigner, DefaultNodeRuntime as BifrostRuntime, Call, Client, Encoded, Event, Store,
+	system::{AccountStoreExt, System, SystemEventsDecoder},
 };
 use sp_core::{sr25519::Pair, Pair as TraitPair};
 use std::sync::{Arc, Mutex};
@@ -50,36 +52,269 @@ pub trait BridgeEos: System {}
 
 impl BridgeEos for BifrostRuntime {}
 
-#[derive(Clone, Debug, PartialEq, Call, Encode)]
+#[derive(Clone, Debug, PartialEq, Call, Encode, Decode)]
 pub struct ChangeScheduleCall<T: BridgeEos> {
-	legacy_schedule_hash: Checksum256,
-	schedule:             ProducerAuthoritySchedule,
-	merkle:               IncrementalMerkle,
-	block_headers:        Vec<SignedBlockHeader>,
-	block_ids_list:       Vec<Vec<Checksum256>>,
-	pub _runtime:         PhantomData<T>,
+	pub legacy_schedule_hash: Checksum256,
+	pub schedule:             ProducerAuthoritySchedule,
+	pub merkle:               IncrementalMerkle,
+	pub block_headers:        Vec<SignedBlockHeader>,
+	pub block_ids_list:       Vec<Vec<Checksum256>>,
+	pub _runtime:             PhantomData<T>,
 }
 
-#[derive(Clone, Debug, PartialEq, Call, Encode)]
+#[derive(Clone, Debug, PartialEq, Call, Encode, Decode)]
 pub struct ProveActionCall<T: BridgeEos> {
-	action:               Action,
-	action_receipt:       ActionReceipt,
-	action_merkle_paths:  Vec<Checksum256>,
-	merkle:               IncrementalMerkle,
-	block_headers:        Vec<SignedBlockHeader>,
-	block_ids_list:       Vec<Vec<Checksum256>>,
-	trx_id:               Checksum256,
-	pub _runtime:         PhantomData<T>,
+	pub action:               Action,
+	pub action_receipt:       ActionReceipt,
+	pub action_merkle_paths:  Vec<Checksum256>,
+	pub merkle:               IncrementalMerkle,
+	pub block_headers:        Vec<SignedBlockHeader>,
+	pub block_ids_list:       Vec<Vec<Checksum256>>,
+	pub trx_id:               Checksum256,
+	pub _runtime:             PhantomData<T>,
+}
+
+/// One action of a multi-action proof, everything else is shared with the other actions.
+#[derive(Clone, Debug, PartialEq, Encode)]
+pub struct ActionProof {
+	pub action:               Action,
+	pub action_receipt:       ActionReceipt,
+	pub action_merkle_paths:  Vec<Checksum256>,
+	pub trx_id:               Checksum256,
+}
+
+// actions in the same block proven by one header chain, see `aggregate`
+#[derive(Clone, Debug, PartialEq, Call, Encode)]
+pub struct ProveActionsCall<T: BridgeEos> {
+	pub proofs:               Vec<ActionProof>,
+	pub merkle:               IncrementalMerkle,
+	pub block_headers:        Vec<SignedBlockHeader>,
+	pub block_ids_list:       Vec<Vec<Checksum256>>,
+	pub _runtime:             PhantomData<T>,
+}
+
+#[subxt::module]
+pub trait Utility: System {}
+
+impl Utility for BifrostRuntime {}
+
+// dispatch many encoded calls in one extrinsic, it stops at the first failed call
+#[derive(Clone, Debug, PartialEq, Call, Encode)]
+pub struct BatchCall<T: Utility> {
+	pub calls:    Vec<Encoded>,
+	pub _runtime: PhantomData<T>,
+}
+
+// the call at `index` failed, calls before it succeeded and calls after it are not dispatched
+#[derive(Clone, Debug, PartialEq, Event, Decode)]
+pub struct BatchInterruptedEvent<T: Utility> {
+	pub index:    u32,
+	pub _runtime: PhantomData<T>,
+}
+
+#[derive(Clone, Debug, PartialEq, Event, Decode)]
+pub struct BatchCompletedEvent<T: Utility> {
+	pub _runtime: PhantomData<T>,
+}
+
+// bifrost asks for sending assets back to EOS, emitted by bridge-eos pallet
+#[derive(Clone, Debug, PartialEq, Event, Decode)]
+pub struct CrossOutRequestEvent<T: BridgeEos> {
+	pub request_id:      u64,
+	pub bifrost_account: <T as System>::AccountId,
+	pub eos_recipient:   Vec<u8>,
+	pub asset:           Vec<u8>, // token symbol like EOS
+	pub amount:          u128,
+}
+
+// the EOS transfer of a cross out request is proven, bridge-eos pallet settles the request
+#[derive(Clone, Debug, PartialEq, Call, Encode)]
+pub struct ConfirmCrossOutCall<T: BridgeEos> {
+	pub request_id: u64,
+	pub trx_id:     Checksum256,
+	pub _runtime:   PhantomData<T>,
+}
+
+// action receipts that have been proven by bridge-eos pallet
+#[derive(Clone, Debug, PartialEq, Store, Encode)]
+pub struct BridgeActionReceiptStore<T: BridgeEos> {
+	#[store(returns = Action)]
+	pub action_receipt: ActionReceipt,
+	pub _runtime:       PhantomData<T>,
+}
+
+// schedule version that bridge-eos pallet currently trusts
+#[derive(Clone, Debug, Default, PartialEq, Store, Encode)]
+pub struct PendingScheduleVersionStore<T: BridgeEos> {
+	#[store(returns = u32)]
+	pub _runtime: PhantomData<T>,
+}
+
+// producers and schedule hash of each schedule version
+#[derive(Clone, Debug, PartialEq, Store, Encode)]
+pub struct ProducerSchedulesStore<T: BridgeEos> {
+	#[store(returns = (Vec<ProducerAuthority>, Checksum256))]
+	pub version:  u32,
+	pub _runtime: PhantomData<T>,
+}
+
+/// Producer schedule stored on bifrost, compared with the one from EOS if it's supplied.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScheduleSync {
+	pub stored_version:   u32,
+	pub stored_hash:      Option<Checksum256>,
+	pub supplied_version: Option<u32>,
+	pub supplied_hash:    Option<Checksum256>,
+}
+
+impl ScheduleSync {
+	pub fn in_sync(&self) -> bool {
+		match (self.supplied_version, self.supplied_hash) {
+			(Some(version), Some(hash)) => version == self.stored_version && Some(hash) == self.stored_hash,
+			_ => false,
+		}
+	}
+
+	// EOS has moved to a newer schedule than bifrost knows, a change_schedule is missing
+	pub fn needs_change(&self) -> bool {
+		self.supplied_version.map(|version| version > self.stored_version).unwrap_or(false)
+	}
+
+	pub fn to_json(&self) -> serde_json::Value {
+		serde_json::json!({
+			"stored_version": self.stored_version,
+			"stored_hash": self.stored_hash.map(|h| h.to_string()),
+			"supplied_version": self.supplied_version,
+			"supplied_hash": self.supplied_hash.map(|h| h.to_string()),
+			"in_sync": self.in_sync(),
+			"needs_change": self.needs_change(),
+		})
+	}
+}
+
+/// Checks that can be done before talking to bifrost node.
+pub trait ValidateCall {
+	fn validate(&self) -> Result<(), crate::Error>;
+}
+
+impl<T: BridgeEos> ValidateCall for ChangeScheduleCall<T> {
+	fn validate(&self) -> Result<(), crate::Error> {
+		if self.schedule.producers.is_empty() {
+			return Err(crate::Error::InvalidCall("new producer schedule is empty"));
+		}
+		validate_block_headers(&self.block_headers, &self.block_ids_list)
+	}
+}
+
+impl<T: BridgeEos> ValidateCall for ProveActionCall<T> {
+	fn validate(&self) -> Result<(), crate::Error> {
+		let act_digest = self.action.digest().map_err(|_| crate::Error::InvalidCall("failed to get action digest"))?;
+		if act_digest != self.action_receipt.act_digest {
+			return Err(crate::Error::InvalidCall("action digest mismatches the one in action receipt"));
+		}
+		validate_block_headers(&self.block_headers, &self.block_ids_list)
+	}
+}
+
+impl<T: BridgeEos> ValidateCall for ProveActionsCall<T> {
+	fn validate(&self) -> Result<(), crate::Error> {
+		if self.proofs.is_empty() {
+			return Err(crate::Error::InvalidCall("there's no action to prove"));
+		}
+		for proof in self.proofs.iter() {
+			let act_digest = proof.action.digest().map_err(|_| crate::Error::InvalidCall("failed to get action digest"))?;
+			if act_digest != proof.action_receipt.act_digest {
+				return Err(crate::Error::InvalidCall("action digest mismatches the one in action receipt"));
+			}
+		}
+		validate_block_headers(&self.block_headers, &self.block_ids_list)
+	}
+}
+
+fn validate_block_headers(block_headers: &[SignedBlockHeader], block_ids_list: &[Vec<Checksum256>]) -> Result<(), crate::Error> {
+	if block_headers.is_empty() || block_headers.len() > 15 {
+		return Err(crate::Error::InvalidCall("there should be 1 to 15 block headers"));
+	}
+	if block_ids_list.len() > block_headers.len() + 1 {
+		return Err(crate::Error::InvalidCall("too many block id lists"));
+	}
+	let ascending = block_headers.windows(2).all(|pair| {
+		pair[0].block_header.block_num() < pair[1].block_header.block_num()
+	});
+	if !ascending {
+		return Err(crate::Error::InvalidCall("block headers are not in ascending order"));
+	}
+
+	Ok(())
+}
+
+/// Encoded call and signed extrinsic of a submission that is never broadcasted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DryRunReport {
+	pub call:      Vec<u8>,
+	pub extrinsic: Vec<u8>,
+}
+
+impl DryRunReport {
+	pub fn to_json(&self) -> serde_json::Value {
+		serde_json::json!({
+			"dry_run": true,
+			"call": format!("0x{}", hex::encode(&self.call)),
+			"extrinsic": format!("0x{}", hex::encode(&self.extrinsic)),
+		})
+	}
+}
+
+/// Outcome of one proof in a batch submission, in the same order as the input proofs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchItemResult {
+	pub index:   usize,
+	pub success: bool,
+	pub code:    u32,
+	pub msg:     String,
+}
+
+impl BatchItemResult {
+	fn ok(index: usize, msg: impl Into<String>) -> Self {
+		Self { index, success: true, code: 0, msg: msg.into() }
+	}
+
+	fn err(index: usize, e: &crate::Error) -> Self {
+		Self { index, success: false, code: e.code(), msg: e.to_string() }
+	}
+
+	pub fn to_json(&self) -> serde_json::Value {
+		serde_json::json!({
+			"index": self.index,
+			"success": self.success,
+			"code": self.code,
+			"msg": self.msg,
+		})
+	}
+}
+
+/// Weight and fee of a signed extrinsic, as reported by `payment_queryInfo`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeeInfo {
+	pub weight:      u64,
+	pub class:       String,
+	pub partial_fee: u128,
+}
+
+impl FeeInfo {
+	pub fn to_json(&self) -> serde_json::Value {
+		serde_json::json!({
+			"weight": self.weight,
+			"class": self.class,
+			"partial_fee": self.partial_fee.to_string(),
+		})
+	}
 }
 
 pub async fn change_schedule_call(
-	urls:                 impl IntoIterator<Item=String>,
-	signer:               impl AsRef<str>,
-	legacy_schedule_hash: Checksum256,
-	schedule:             ProducerAuthoritySchedule,
-	merkle:               IncrementalMerkle,
-	block_headers:        Vec<SignedBlockHeader>,
-	block_ids_list:       Vec<Vec<Checksum256>>
+	urls:   impl IntoIterator<Item=String>,
+	signer: impl AsRef<str>,
+	call:   ChangeScheduleCall<BifrostRuntime>,
 ) -> Result<String, crate::Error> {
 	let url: String = urls.into_iter().take(1).next().ok_or(crate::Error::SubxtError("failed to create subxt client"))?;
 	let client: Client<BifrostRuntime> = subxt::ClientBuilder::new()
@@ -91,29 +326,25 @@ pub async fn change_schedule_call(
 	let signer = Pair::from_string(signer.as_ref(), None).map_err(|_| crate::Error::WrongSudoSeed)?;
 	let signer = PairSigner::<BifrostRuntime, Pair>::new(signer);
 
-	let args = ChangeScheduleCall::<BifrostRuntime> {
-		legacy_schedule_hash,
-		schedule,
-		merkle,
-		block_headers,
-		block_ids_list,
-		_runtime: PhantomData
-	};
-	let block_hash = client.submit(args, &signer).await.map_err(|_| crate::Error::SubxtError("failed to commit this transaction"))?;
+	call.validate()?;
+	if crate::config::relay_config().dry_run {
+		// reported as an error, so that callers never take the call as sent
+		let report = dry_run_report(&client, &signer, call).await?;
+		return Err(crate::Error::DryRun(report.to_json().to_string()));
+	}
+
+	let estimated_fee = ensure_fee_under_cap(&client, &signer, call.clone()).await?;
+	ensure_signer_balance(&client, &signer, estimated_fee).await?;
+
+	let block_hash = crate::outbox::submit(&client, &signer, call).await?;
 
 	Ok(block_hash.to_string())
 }
 
 pub async fn prove_action_call(
-	urls:                impl IntoIterator<Item=String>,
-	signer:              impl AsRef<str>,
-	action:              Action,
-	action_receipt:      ActionReceipt,
-	action_merkle_paths: Vec<Checksum256>,
-	merkle:              IncrementalMerkle,
-	block_headers:       Vec<SignedBlockHeader>,
-	block_ids_list:      Vec<Vec<Checksum256>>,
-	trx_id:              Checksum256
+	urls:   impl IntoIterator<Item=String>,
+	signer: impl AsRef<str>,
+	call:   ProveActionCall<BifrostRuntime>,
 ) -> Result<String, crate::Error> {
 	let url: String = urls.into_iter().take(1).next().ok_or(crate::Error::SubxtError("failed to create subxt client"))?;
 	let client: Client<BifrostRuntime> = subxt::ClientBuilder::new()
@@ -125,9 +356,23 @@ pub async fn prove_action_call(
 	let signer = Pair::from_string(signer.as_ref(), None).map_err(|_| crate::Error::WrongSudoSeed)?;
 	let mut signer = PairSigner::<BifrostRuntime, Pair>::new(signer);
 
+	call.validate()?;
+	// after a restart, proofs that had been sent could be loaded again, don't pay for them twice.
+	if action_proven(&client, &call.action_receipt).await? {
+		return Err(crate::Error::AlreadyProven);
+	}
+	if crate::config::relay_config().dry_run {
+		// reported as an error, so that callers never take the call as sent
+		let report = dry_run_report(&client, &signer, call).await?;
+		return Err(crate::Error::DryRun(report.to_json().to_string()));
+	}
+
+	let estimated_fee = ensure_fee_under_cap(&client, &signer, call.clone()).await?;
+	ensure_signer_balance(&client, &signer, estimated_fee).await?;
+
 	// set nonce to avoid multiple trades using the same nonce, that will cause some trades will be abandoned.
 	// https://substrate.dev/docs/en/knowledgebase/learn-substrate/tx-pool
-	static atomic_nonce: AtomicU32 = AtomicU32::new(0);
+	let atomic_nonce = &ATOMIC_NONCE;
 //	static atomic_nonce: AtomicU32 = AtomicU32::new(0);
 //	static signer_current_nonce: AtomicU32 = AtomicU32::new(0);
 //	static mut latest_nonce: u32 = 0;
@@ -176,20 +421,9 @@ pub async fn prove_action_call(
 
 //	println!("atomic_nonce is: {:?}", atomic_nonce);
 //	println!("signer_current_nonce is: {:?}", atomic_nonce.load(Ordering::Relaxed));
-	println!("signer_current_nonce is: {:?}", current_nonce);
 	signer.set_nonce(atomic_nonce.load(Ordering::Relaxed));
 	atomic_nonce.fetch_add(1, Ordering::SeqCst);
 
-	let call = ProveActionCall::<BifrostRuntime> {
-		action,
-		action_receipt,
-		action_merkle_paths,
-		merkle,
-		block_headers,
-		block_ids_list,
-		trx_id,
-		_runtime: PhantomData
-	};
 //	let block_hash = client.submit(call, &signer).await.map_err(|e| {
 //		if let SubxtErr::Rpc(err) = e {
 //			// the full error: Rpc(Request(Error { code: ServerError(1014), message: "Priority is too low: (0 vs 0)",
@@ -209,19 +443,25 @@ pub async fn prove_action_call(
 //		})?;
 //		return Ok(trx_id.to_string());
 //	}
-	match client.submit(call.clone(), &signer).await {
-		Ok(trx_id) => Ok(trx_id.to_string()),
-		Err(SubxtErr::Rpc(e)) => {
-//			signer.increment_nonce();
-			let trx_id = client.submit(call, &signer).await.map_err(|e| {
-				println ! ("error is: {:?}", e.to_string());
-				crate::Error::SubxtError("failed to commit this transaction")
-			})?;
-			Ok(trx_id.to_string())
+	let eos_trx_id = call.trx_id;
+	// outbox refuses a proof that has been sent, so the retry cannot send it twice
+	let result = match crate::outbox::submit(&client, &signer, call.clone()).await {
+		Err(e) if is_transient(&e) => {
+			signer.set_nonce(next_nonce(&client, &signer).await?);
+			crate::outbox::submit(&client, &signer, call).await
+		}
+		result => result,
+	}.map(|trx_id| trx_id.to_string());
+
+	// proof of a redeem transfer lets the bifrost request behind it be confirmed
+	if result.is_ok() && eos_trx_id != Checksum256::default() {
+		if let Some(request_id) = crate::redeem::mark_proven(&eos_trx_id) {
+			println!("[+] Redeem request {} got proven by transaction: {}\n", request_id, eos_trx_id);
 		}
-		_ => Err(crate::Error::SubxtError("failed to commit this transaction"))
 	}
 
+	result
+
 //	let mut index = 0u32;
 //	loop {
 //		println!("signer_current_nonce is: {:?}, index: {:?}", current_nonce, index);
@@ -278,6 +518,486 @@ pub async fn prove_action_call(
 //	Ok(block_hash.to_string())
 }
 
+/// Send many proofs with `utility.batch`, split into several batches if they don't fit in one extrinsic.
+pub async fn prove_actions_batch_call(
+	urls:   impl IntoIterator<Item=String>,
+	signer: impl AsRef<str>,
+	calls:  Vec<ProveActionCall<BifrostRuntime>>,
+) -> Result<Vec<BatchItemResult>, crate::Error> {
+	let url: String = urls.into_iter().take(1).next().ok_or(crate::Error::SubxtError("failed to create subxt client"))?;
+	let client: Client<BifrostRuntime> = subxt::ClientBuilder::new()
+		.set_url(url)
+		.build()
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to create subxt client"))?;
+
+	let signer = Pair::from_string(signer.as_ref(), None).map_err(|_| crate::Error::WrongSudoSeed)?;
+	let signer = PairSigner::<BifrostRuntime, Pair>::new(signer);
+
+	let config = crate::config::relay_config();
+	let mut results: Vec<Option<BatchItemResult>> = vec![None; calls.len()];
+	let mut valid: Vec<(usize, ProveActionCall<BifrostRuntime>)> = Vec::with_capacity(calls.len());
+	for (index, call) in calls.into_iter().enumerate() {
+		if let Err(e) = call.validate() {
+			results[index] = Some(BatchItemResult::err(index, &e));
+			continue;
+		}
+		match action_proven(&client, &call.action_receipt).await {
+			Ok(false) => valid.push((index, call)),
+			Ok(true) => results[index] = Some(BatchItemResult::err(index, &crate::Error::AlreadyProven)),
+			Err(e) => results[index] = Some(BatchItemResult::err(index, &e)),
+		}
+	}
+
+	// proofs of the same block share one header chain if it's enabled and bifrost could take them,
+	// otherwise each proof goes alone
+	let share_headers = config.share_headers && supports_prove_actions(&client);
+	if config.share_headers && !share_headers {
+		println!("[!] Bifrost runtime has no prove_actions call, proofs are sent one by one\n");
+	}
+	let groups = if share_headers {
+		crate::aggregate::group_by_target(valid, config.max_batch_calls)
+	} else {
+		valid.into_iter().map(|item| vec![item]).collect()
+	};
+	let prefixes = match groups.first().and_then(|group| group.first()) {
+		Some((_, call)) => {
+			let spec_version = spec_version(&client).await;
+			spec_version.and_then(|spec_version| Ok((spec_version, call_prefixes(&client, spec_version, call)?)))
+		}
+		None => Ok(Default::default()),
+	};
+	let mut pending: Vec<PendingCall> = Vec::with_capacity(groups.len());
+	for group in groups.into_iter() {
+		let indices: Vec<usize> = group.iter().map(|(index, _)| *index).collect();
+		let trx_ids: Vec<Checksum256> = group.iter().map(|(_, call)| call.trx_id).collect();
+		match &prefixes {
+			// a retried proof reuses its parts encoded last time, whichever group it was in
+			Ok((spec_version, prefixes)) => {
+				let calls: Vec<ProveActionCall<BifrostRuntime>> = group.into_iter().map(|(_, call)| call).collect();
+				let encoded = prefixes.shared(&crate::payload_cache::get_or_encode(*spec_version, &calls));
+				pending.push(PendingCall { indices, trx_ids, encoded });
+			}
+			Err(e) => {
+				for index in indices {
+					results[index] = Some(BatchItemResult::err(index, e));
+				}
+			}
+		}
+	}
+
+	// chunks that respect max size and count, chunks over the max weight are halved before sending
+	let mut chunks = split_by_size(pending, config.max_batch_bytes, config.max_batch_calls);
+	chunks.reverse();
+	while let Some(chunk) = chunks.pop() {
+		let batch = BatchCall::<BifrostRuntime> {
+			calls: chunk.iter().map(|pending| pending.encoded.clone()).collect(),
+			_runtime: PhantomData,
+		};
+		let indices: Vec<usize> = chunk.iter().flat_map(|pending| pending.indices.iter().cloned()).collect();
+
+		if chunk.len() > 1 {
+			if let Ok(info) = query_fee_info(&client, &signer, batch.clone()).await {
+				if info.weight > config.max_batch_weight {
+					let mut chunk = chunk;
+					let tail = chunk.split_off(chunk.len() / 2);
+					chunks.push(tail);
+					chunks.push(chunk);
+					continue;
+				}
+			}
+		}
+
+		if config.dry_run {
+			// every proof fails with the report, so that no caller takes them as sent
+			let outcome = match dry_run_report(&client, &signer, batch).await {
+				Ok(report) => crate::Error::DryRun(report.to_json().to_string()),
+				Err(e) => e,
+			};
+			for &index in indices.iter() {
+				results[index] = Some(BatchItemResult::err(index, &outcome));
+			}
+			continue;
+		}
+
+		let checked = match ensure_fee_under_cap(&client, &signer, batch.clone()).await {
+			Ok(fee) => ensure_signer_balance(&client, &signer, fee).await,
+			Err(e) => Err(e),
+		};
+		if let Err(e) = checked {
+			for &index in indices.iter() {
+				results[index] = Some(BatchItemResult::err(index, &e));
+			}
+			continue;
+		}
+
+		let success = match crate::outbox::watch(&client, &signer, batch).await {
+			Ok(success) => success,
+			Err(e) => {
+				for &index in indices.iter() {
+					results[index] = Some(BatchItemResult::err(index, &e));
+				}
+				continue;
+			}
+		};
+
+		let block_hash = success.block.to_string();
+		let interrupted = success.find_event::<BatchInterruptedEvent<BifrostRuntime>>()
+			.ok()
+			.flatten()
+			.map(|event| event.index as usize);
+		for (position, pending) in chunk.iter().enumerate() {
+			for (index, trx_id) in pending.indices.iter().zip(pending.trx_ids.iter()) {
+				let result = match interrupted {
+					Some(at) if position == at => BatchItemResult::err(*index, &crate::Error::BatchInterrupted("this proof is rejected by bifrost")),
+					Some(at) if position > at => BatchItemResult::err(*index, &crate::Error::BatchInterrupted("an earlier proof in the batch is rejected")),
+					_ => {
+						// proof of a redeem transfer lets the bifrost request behind it be confirmed
+						if *trx_id != Checksum256::default() {
+							if let Some(request_id) = crate::redeem::mark_proven(trx_id) {
+								println!("[+] Redeem request {} got proven by transaction: {}\n", request_id, trx_id);
+							}
+						}
+						BatchItemResult::ok(*index, block_hash.clone())
+					}
+				};
+				results[*index] = Some(result);
+			}
+		}
+	}
+
+	Ok(results.into_iter()
+		.enumerate()
+		.map(|(index, result)| result.unwrap_or_else(|| BatchItemResult::err(index, &crate::Error::SubxtError("this proof is not sent"))))
+		.collect())
+}
+
+// an encoded call in a batch, and the input proofs it carries
+struct PendingCall {
+	indices: Vec<usize>,
+	trx_ids: Vec<Checksum256>,
+	encoded: Encoded,
+}
+
+// group encoded calls in order, a new group starts once max bytes or max count is reached.
+// a single call larger than max bytes still gets its own group, bifrost node has the final word.
+fn split_by_size(
+	calls:     Vec<PendingCall>,
+	max_bytes: usize,
+	max_calls: usize,
+) -> Vec<Vec<PendingCall>> {
+	let mut chunks = Vec::new();
+	let mut chunk: Vec<PendingCall> = Vec::new();
+	let mut chunk_bytes = 0usize;
+	for call in calls.into_iter() {
+		let size = call.encoded.0.len();
+		if !chunk.is_empty() && (chunk_bytes + size > max_bytes || chunk.len() >= max_calls.max(1)) {
+			chunks.push(std::mem::take(&mut chunk));
+			chunk_bytes = 0;
+		}
+		chunk_bytes += size;
+		chunk.push(call);
+	}
+	if !chunk.is_empty() {
+		chunks.push(chunk);
+	}
+
+	chunks
+}
+
+pub async fn query_producer_schedule(
+	urls:     impl IntoIterator<Item=String>,
+	supplied: Option<ProducerAuthoritySchedule>,
+) -> Result<ScheduleSync, crate::Error> {
+	let url: String = urls.into_iter().take(1).next().ok_or(crate::Error::SubxtError("failed to create subxt client"))?;
+	let client: Client<BifrostRuntime> = subxt::ClientBuilder::new()
+		.set_url(url)
+		.build()
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to create subxt client"))?;
+
+	let (stored_version, stored_hash) = stored_schedule(&client).await?;
+
+	let (supplied_version, supplied_hash) = match supplied {
+		Some(schedule) => {
+			let hash = schedule.schedule_hash().map_err(|_| crate::Error::InvalidCall("failed to hash producer schedule"))?;
+			(Some(schedule.version), Some(hash))
+		}
+		None => (None, None),
+	};
+
+	Ok(ScheduleSync {
+		stored_version,
+		stored_hash,
+		supplied_version,
+		supplied_hash,
+	})
+}
+
+pub(crate) async fn stored_schedule(client: &Client<BifrostRuntime>) -> Result<(u32, Option<Checksum256>), crate::Error> {
+	let version = client.fetch_or_default(&PendingScheduleVersionStore::<BifrostRuntime>::default(), None)
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to query pending schedule version"))?;
+
+	let store = ProducerSchedulesStore::<BifrostRuntime> {
+		version,
+		_runtime: PhantomData,
+	};
+	let hash = client.fetch(&store, None)
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to query producer schedule"))?
+		.map(|(_, hash)| hash);
+
+	Ok((version, hash))
+}
+
+pub async fn is_action_proven(
+	urls:           impl IntoIterator<Item=String>,
+	action_receipt: ActionReceipt,
+) -> Result<bool, crate::Error> {
+	let url: String = urls.into_iter().take(1).next().ok_or(crate::Error::SubxtError("failed to create subxt client"))?;
+	let client: Client<BifrostRuntime> = subxt::ClientBuilder::new()
+		.set_url(url)
+		.build()
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to create subxt client"))?;
+
+	action_proven(&client, &action_receipt).await
+}
+
+pub(crate) async fn action_proven(
+	client:         &Client<BifrostRuntime>,
+	action_receipt: &ActionReceipt,
+) -> Result<bool, crate::Error> {
+	let store = BridgeActionReceiptStore::<BifrostRuntime> {
+		action_receipt: action_receipt.clone(),
+		_runtime: PhantomData,
+	};
+	let proven = client.fetch(&store, None)
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to query proven action receipt"))?;
+
+	Ok(proven.is_some())
+}
+
+// a runtime without `prove_actions` rejects a shared header chain, whatever the config says
+fn supports_prove_actions(client: &Client<BifrostRuntime>) -> bool {
+	client.metadata()
+		.module_with_calls(<ProveActionsCall<BifrostRuntime> as Call<BifrostRuntime>>::MODULE)
+		.and_then(|module| module.call(<ProveActionsCall<BifrostRuntime> as Call<BifrostRuntime>>::FUNCTION, ()))
+		.is_ok()
+}
+
+pub async fn dry_run<C>(
+	urls:   impl IntoIterator<Item=String>,
+	signer: impl AsRef<str>,
+	call:   C,
+) -> Result<DryRunReport, crate::Error>
+	where C: Call<BifrostRuntime> + ValidateCall + Clone + Send + Sync
+{
+	call.validate()?;
+
+	let url: String = urls.into_iter().take(1).next().ok_or(crate::Error::SubxtError("failed to create subxt client"))?;
+	let client: Client<BifrostRuntime> = subxt::ClientBuilder::new()
+		.set_url(url)
+		.build()
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to create subxt client"))?;
+
+	let signer = Pair::from_string(signer.as_ref(), None).map_err(|_| crate::Error::WrongSudoSeed)?;
+	let signer = PairSigner::<BifrostRuntime, Pair>::new(signer);
+
+	dry_run_report(&client, &signer, call).await
+}
+
+// signing still needs bifrost node for nonce, genesis hash and runtime version,
+// but nothing will be submitted.
+pub(crate) async fn dry_run_report<C>(
+	client: &Client<BifrostRuntime>,
+	signer: &PairSigner<BifrostRuntime, Pair>,
+	call:   C,
+) -> Result<DryRunReport, crate::Error>
+	where C: Call<BifrostRuntime> + Clone + Send + Sync
+{
+	let encoded = client.encode(call.clone()).map_err(|_| crate::Error::SubxtError("failed to encode this call"))?;
+	let extrinsic = client.create_signed(call, signer)
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to sign this transaction"))?;
+
+	Ok(DryRunReport {
+		call: encoded.0,
+		extrinsic: extrinsic.encode(),
+	})
+}
+
+pub async fn estimate_fee<C>(
+	urls:   impl IntoIterator<Item=String>,
+	signer: impl AsRef<str>,
+	call:   C,
+) -> Result<FeeInfo, crate::Error>
+	where C: Call<BifrostRuntime> + Send + Sync
+{
+	let url: String = urls.into_iter().take(1).next().ok_or(crate::Error::SubxtError("failed to create subxt client"))?;
+	let client: Client<BifrostRuntime> = subxt::ClientBuilder::new()
+		.set_url(url)
+		.build()
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to create subxt client"))?;
+
+	let signer = Pair::from_string(signer.as_ref(), None).map_err(|_| crate::Error::WrongSudoSeed)?;
+	let signer = PairSigner::<BifrostRuntime, Pair>::new(signer);
+
+	query_fee_info(&client, &signer, call).await
+}
+
+// spec version of bifrost runtime, payloads encoded for another runtime are not reused.
+async fn spec_version(client: &Client<BifrostRuntime>) -> Result<u32, crate::Error> {
+	let version: serde_json::Value = client.rpc_client()
+		.request("state_getRuntimeVersion", Params::None)
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to query runtime version"))?;
+
+	version["specVersion"].as_u64()
+		.map(|spec_version| spec_version as u32)
+		.ok_or(crate::Error::SubxtError("failed to parse runtime version"))
+}
+
+// module and call index of proof calls, found by encoding `call` once for each runtime.
+fn call_prefixes(
+	client:       &Client<BifrostRuntime>,
+	spec_version: u32,
+	call:         &ProveActionCall<BifrostRuntime>,
+) -> Result<CallPrefixes, crate::Error> {
+	crate::payload_cache::prefixes(spec_version, || {
+		let shared = ProveActionsCall::<BifrostRuntime> {
+			proofs: vec![],
+			merkle: call.merkle.clone(),
+			block_headers: vec![],
+			block_ids_list: vec![],
+			_runtime: PhantomData,
+		};
+		let alone = client.encode(call.clone()).map_err(|_| crate::Error::SubxtError("failed to encode this call"))?;
+		let shared_encoded = client.encode(shared.clone()).map_err(|_| crate::Error::SubxtError("failed to encode this call"))?;
+
+		Ok(CallPrefixes {
+			prove_action: CallPrefixes::prefix_of(&alone, call),
+			prove_actions: CallPrefixes::prefix_of(&shared_encoded, &shared),
+		})
+	})
+}
+
+// sign the call without submitting it, then ask bifrost node what it would cost.
+pub(crate) async fn query_fee_info<C>(
+	client: &Client<BifrostRuntime>,
+	signer: &PairSigner<BifrostRuntime, Pair>,
+	call:   C,
+) -> Result<FeeInfo, crate::Error>
+	where C: Call<BifrostRuntime> + Send + Sync
+{
+	let extrinsic = client.create_signed(call, signer)
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to sign this transaction"))?;
+	let encoded = sp_core::Bytes(extrinsic.encode());
+
+	let params = Params::Array(vec![
+		serde_json::to_value(encoded).map_err(|_| crate::Error::SubxtError("failed to encode this transaction"))?
+	]);
+	let info: serde_json::Value = client.rpc_client()
+		.request("payment_queryInfo", params)
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to query fee info"))?;
+
+	// partialFee could be a number or a string depends on bifrost node version
+	let partial_fee = match &info["partialFee"] {
+		serde_json::Value::String(fee) => fee.parse::<u128>().ok(),
+		serde_json::Value::Number(fee) => fee.as_u64().map(u128::from),
+		_ => None,
+	}.ok_or(crate::Error::SubxtError("failed to parse partial fee"))?;
+
+	Ok(FeeInfo {
+		weight: info["weight"].as_u64().ok_or(crate::Error::SubxtError("failed to parse weight"))?,
+		class: info["class"].as_str().unwrap_or("normal").to_owned(),
+		partial_fee,
+	})
+}
+
+// estimate fee of the call, and refuse it if fee goes over the configured cap.
+// falls back to the configured estimated fee if bifrost node cannot tell the fee.
+pub(crate) async fn ensure_fee_under_cap<C>(
+	client: &Client<BifrostRuntime>,
+	signer: &PairSigner<BifrostRuntime, Pair>,
+	call:   C,
+) -> Result<u128, crate::Error>
+	where C: Call<BifrostRuntime> + Send + Sync
+{
+	let config = crate::config::relay_config();
+	let fee = match query_fee_info(client, signer, call).await {
+		Ok(info) => info.partial_fee,
+		Err(e) => {
+			println!("[!] Failed to estimate fee due to: {:?}, use configured fee instead\n", e);
+			config.estimated_fee
+		}
+	};
+
+	match config.max_fee {
+		Some(cap) if fee > cap => Err(crate::Error::FeeTooHigh(fee, cap)),
+		_ => Ok(fee),
+	}
+}
+
+pub async fn check_signer_balance(
+	urls:   impl IntoIterator<Item=String>,
+	signer: impl AsRef<str>,
+) -> Result<u128, crate::Error> {
+	let url: String = urls.into_iter().take(1).next().ok_or(crate::Error::SubxtError("failed to create subxt client"))?;
+	let client: Client<BifrostRuntime> = subxt::ClientBuilder::new()
+		.set_url(url)
+		.build()
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to create subxt client"))?;
+
+	ensure_signer_can_pay(&client, signer).await
+}
+
+// balance check with the estimated fee of relay config, done before every pass of submission queue
+pub(crate) async fn ensure_signer_can_pay(
+	client: &Client<BifrostRuntime>,
+	signer: impl AsRef<str>,
+) -> Result<u128, crate::Error> {
+	let signer = Pair::from_string(signer.as_ref(), None).map_err(|_| crate::Error::WrongSudoSeed)?;
+	let signer = PairSigner::<BifrostRuntime, Pair>::new(signer);
+
+	let estimated_fee = crate::config::relay_config().estimated_fee;
+	ensure_signer_balance(client, &signer, estimated_fee).await
+}
+
+// query signer free balance, refuse to go on if it cannot cover the fee of next submission.
+pub(crate) async fn ensure_signer_balance(
+	client:        &Client<BifrostRuntime>,
+	signer:        &PairSigner<BifrostRuntime, Pair>,
+	estimated_fee: u128,
+) -> Result<u128, crate::Error> {
+	let free = client.account(&signer.signer().public().into(), None)
+		.await
+		.map_err(|_| crate::Error::SubxtError("failed to query signer balance"))?
+		.data
+		.free;
+
+	let config = crate::config::relay_config();
+	let low_balance = free < config.min_balance_warning;
+	crate::status::update_signer_balance(free, low_balance);
+
+	if free < estimated_fee {
+		println!("[!] Signer free balance {} cannot cover the estimated fee {}\n", free, estimated_fee);
+		return Err(crate::Error::InsufficientBalance(free, estimated_fee));
+	}
+	if low_balance {
+		println!("[!] Signer free balance {} is below the warning threshold {}\n", free, config.min_balance_warning);
+	}
+
+	Ok(free)
+}
+
 async fn get_available_bifrost_client(urls: impl IntoIterator<Item=String>)
 	-> Result<&'static Mutex<subxt::Client<BifrostRuntime>>, crate::Error>
 {
@@ -291,6 +1011,43 @@ async fn get_available_bifrost_client(urls: impl IntoIterator<Item=String>)
 	Err(crate::Error::SubxtError("failed to get client builder"))
 }
 
+// nonce shared by extrinsics that are sent without waiting for the previous one to be included
+static ATOMIC_NONCE: AtomicU32 = AtomicU32::new(0);
+
+// next nonce of the signer, after extrinsics of its own that are still in tx pool
+// tx pool turns down a nonce that's in use as too low priority, and a used one as outdated
+const NONCE_TAKEN: &str = "the nonce of this transaction is taken";
+
+/// Error of a failed broadcast, those a fresh nonce could get through are told apart.
+pub(crate) fn broadcast_error(e: &subxt::Error) -> crate::Error {
+	let reason = e.to_string();
+	if reason.contains("Priority is too low") || reason.contains("Transaction is outdated") {
+		crate::Error::SubxtError(NONCE_TAKEN)
+	} else {
+		crate::Error::SubxtError("failed to commit this transaction")
+	}
+}
+
+// anything else, like a failure before the broadcast or a rejected proof, fails the same way again
+fn is_transient(e: &crate::Error) -> bool {
+	match e {
+		crate::Error::SubxtError(reason) => *reason == NONCE_TAKEN,
+		_ => false,
+	}
+}
+
+pub(crate) async fn next_nonce(
+	client: &Client<BifrostRuntime>,
+	signer: &PairSigner<BifrostRuntime, Pair>,
+) -> Result<u32, crate::Error> {
+	let current_nonce = client.account(&signer.signer().public().into(), None).await.map_err(|_| crate::Error::WrongSudoSeed)?.nonce;
+	if ATOMIC_NONCE.load(Ordering::Relaxed) <= current_nonce {
+		ATOMIC_NONCE.swap(current_nonce, Ordering::Relaxed);
+	}
+
+	Ok(ATOMIC_NONCE.fetch_add(1, Ordering::SeqCst))
+}
+
 // update nonce to avoid using the same nonce
 pub fn get_latest_nonce(atomic_nonce: &AtomicU32, current_nonce: u32) -> u32 {
 	if atomic_nonce.load(Ordering::Relaxed) < current_nonce {
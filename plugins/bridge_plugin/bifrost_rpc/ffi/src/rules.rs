@@ -0,0 +1,399 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Decide which EOS actions should be relayed. An action is relayed once any
+//! rule matches it, every field of a rule that is set must match.
+
+use crate::abi::{format_asset, read_varuint32};
+use eos_chain::{AccountName, Action};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Deserialize;
+use std::sync::RwLock;
+
+static RULES: Lazy<RwLock<Vec<CompiledRule>>> = Lazy::new(|| {
+	let rules = default_rules().iter().filter_map(|r| CompiledRule::compile(r).ok()).collect();
+	RwLock::new(rules)
+});
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct FilterRule {
+	pub contract:             Option<String>,
+	pub action:               Option<String>,
+	pub receiver:             Option<String>,
+	/// Only take the notification that the contract receives itself.
+	pub receiver_is_contract: bool,
+	/// Actor that should be in the authorization, like `alice` or `alice@active`.
+	pub authorization:        Option<String>,
+	pub from:                 Option<String>,
+	pub to:                   Option<String>,
+	pub min_quantity:         Option<String>,
+	pub max_quantity:         Option<String>,
+	pub memo_regex:           Option<String>,
+}
+
+impl Default for FilterRule {
+	fn default() -> Self {
+		Self {
+			contract: None,
+			action: None,
+			receiver: None,
+			receiver_is_contract: true,
+			authorization: None,
+			from: None,
+			to: None,
+			min_quantity: None,
+			max_quantity: None,
+			memo_regex: None,
+		}
+	}
+}
+
+pub fn default_rules() -> Vec<FilterRule> {
+	vec![FilterRule {
+		action: Some("transfer".to_owned()),
+		..Default::default()
+	}]
+}
+
+/// Fields of a token transfer that rules can match on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferData {
+	pub from:     String,
+	pub to:       String,
+	pub quantity: String,
+	pub memo:     String,
+}
+
+#[derive(Clone, Debug)]
+struct CompiledRule {
+	rule:         FilterRule,
+	memo_regex:   Option<Regex>,
+	min_quantity: Option<(i64, u8, String)>,
+	max_quantity: Option<(i64, u8, String)>,
+}
+
+impl CompiledRule {
+	fn compile(rule: &FilterRule) -> Result<Self, crate::Error> {
+		let memo_regex = match rule.memo_regex {
+			Some(ref re) => Some(Regex::new(re).map_err(|e| crate::Error::ConfigError(e.to_string()))?),
+			None => None,
+		};
+		let quantity = |q: &Option<String>| -> Result<_, crate::Error> {
+			match q {
+				Some(q) => crate::assets::parse_quantity(q).map(Some).map_err(|e| crate::Error::ConfigError(e.to_string())),
+				None => Ok(None),
+			}
+		};
+
+		Ok(Self {
+			rule: rule.clone(),
+			memo_regex,
+			min_quantity: quantity(&rule.min_quantity)?,
+			max_quantity: quantity(&rule.max_quantity)?,
+		})
+	}
+
+	fn matches(&self, action: &Action, receiver: &AccountName, data: Option<&TransferData>) -> bool {
+		let rule = &self.rule;
+		let contract = action.account.to_string();
+		let receiver = receiver.to_string();
+
+		if rule.contract.as_ref().map_or(false, |c| *c != contract) { return false; }
+		if rule.action.as_ref().map_or(false, |a| *a != action.name.to_string()) { return false; }
+		if rule.receiver.as_ref().map_or(false, |r| *r != receiver) { return false; }
+		if rule.receiver_is_contract && receiver != contract { return false; }
+
+		if let Some(ref actor) = rule.authorization {
+			let authorized = action.authorization.iter().any(|p| {
+				*actor == p.actor.to_string() || *actor == format!("{}@{}", p.actor, p.permission)
+			});
+			if !authorized { return false; }
+		}
+
+		let needs_data = rule.from.is_some() || rule.to.is_some() || self.memo_regex.is_some()
+			|| self.min_quantity.is_some() || self.max_quantity.is_some();
+		if !needs_data {
+			return true;
+		}
+		let data = match data {
+			Some(data) => data,
+			None => return false,
+		};
+
+		if rule.from.as_ref().map_or(false, |from| *from != data.from) { return false; }
+		if rule.to.as_ref().map_or(false, |to| *to != data.to) { return false; }
+		if self.memo_regex.as_ref().map_or(false, |re| !re.is_match(&data.memo)) { return false; }
+
+		let quantity = match crate::assets::parse_quantity(&data.quantity) {
+			Ok(quantity) => quantity,
+			Err(_) => return self.min_quantity.is_none() && self.max_quantity.is_none(),
+		};
+		// quantities of different tokens are not comparable
+		let same_token = |bound: &(i64, u8, String)| bound.1 == quantity.1 && bound.2 == quantity.2;
+		if let Some(ref min) = self.min_quantity {
+			if !same_token(min) || quantity.0 < min.0 { return false; }
+		}
+		if let Some(ref max) = self.max_quantity {
+			if !same_token(max) || quantity.0 > max.0 { return false; }
+		}
+
+		true
+	}
+}
+
+pub fn load_rules(rules: &[FilterRule]) -> Result<(), crate::Error> {
+	let compiled = rules.iter().map(CompiledRule::compile).collect::<Result<Vec<_>, _>>()?;
+	let mut global = RULES.write().map_err(|_| crate::Error::ConfigError("filter rules are poisoned".to_owned()))?;
+	*global = compiled;
+
+	Ok(())
+}
+
+/// What the rules make of an action, handed over to the plugin as is.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RuleMatch {
+	/// No rule matches, nothing is relayed without rules.
+	Unmatched = 0,
+	/// A rule matches an action that isn't a token transfer.
+	Action = 1,
+	/// A rule matches a transfer laid out like eosio.token's.
+	Transfer = 2,
+}
+
+pub fn match_action(action: &Action, receiver: &AccountName) -> RuleMatch {
+	match RULES.read() {
+		Ok(rules) => classify(&rules, action, receiver),
+		Err(_) => RuleMatch::Unmatched,
+	}
+}
+
+fn classify(rules: &[CompiledRule], action: &Action, receiver: &AccountName) -> RuleMatch {
+	if first_match(rules, action, receiver).is_none() {
+		return RuleMatch::Unmatched;
+	}
+	// the plugin unpacks transfers by eosio.token layout, a rule on anything else only matches an action
+	if action.name.to_string() == "transfer" && decode_transfer(&action.data).is_ok() {
+		RuleMatch::Transfer
+	} else {
+		RuleMatch::Action
+	}
+}
+
+fn first_match(rules: &[CompiledRule], action: &Action, receiver: &AccountName) -> Option<usize> {
+	// prefer contract's own abi, only a transfer without abi is taken as eosio.token layout
+	let data = decode_with_abi(action).or_else(|| {
+		if action.name.to_string() == "transfer" {
+			decode_transfer(&action.data).ok()
+		} else {
+			None
+		}
+	});
+
+	rules.iter().position(|rule| rule.matches(action, receiver, data.as_ref()))
+}
+
+fn decode_with_abi(action: &Action) -> Option<TransferData> {
+	let decoded = crate::abi::decode_action_data(
+		&action.account.to_string(),
+		&action.name.to_string(),
+		None,
+		&action.data,
+	).ok()?;
+	let field = |name: &str| decoded.get(name).and_then(|v| v.as_str()).map(|v| v.to_owned());
+
+	Some(TransferData {
+		from: field("from")?,
+		to: field("to")?,
+		quantity: field("quantity")?,
+		memo: field("memo").unwrap_or_default(),
+	})
+}
+
+// layout of eosio.token::transfer: name from, name to, asset quantity, string memo
+pub fn decode_transfer(data: &[u8]) -> Result<TransferData, crate::Error> {
+	let invalid = || crate::Error::InvalidCall("action data is not a token transfer");
+	let read_u64 = |pos: usize| -> Result<u64, crate::Error> {
+		let bytes = data.get(pos..pos + 8).ok_or_else(invalid)?;
+		let mut buf = [0u8; 8];
+		buf.copy_from_slice(bytes);
+		Ok(u64::from_le_bytes(buf))
+	};
+
+	let from = AccountName::from(read_u64(0)?).to_string();
+	let to = AccountName::from(read_u64(8)?).to_string();
+	let amount = read_u64(16)? as i64;
+	let symbol = read_u64(24)?;
+	let quantity = format_asset(amount, symbol).map_err(|_| invalid())?;
+
+	let (len, read) = read_varuint32(data.get(32..).ok_or_else(invalid)?).ok_or_else(invalid)?;
+	let start = 32 + read;
+	let memo = data.get(start..).and_then(|memo| memo.get(..len as usize)).ok_or_else(invalid)?;
+	let memo = String::from_utf8(memo.to_vec()).map_err(|_| invalid())?;
+
+	Ok(TransferData { from, to, quantity, memo })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use eos_chain::{ActionName, PermissionLevel, PermissionName};
+	use std::str::FromStr;
+
+	const ALICE: u64 = 0x3435_0000_0000_0000;
+	const BOB: u64 = 0x3dcd_0000_0000_0000;
+	// 4,EOS
+	const EOS: u64 = 4 | (b'E' as u64) << 8 | (b'O' as u64) << 16 | (b'S' as u64) << 24;
+
+	fn name(value: u64) -> String {
+		AccountName::from(value).to_string()
+	}
+
+	fn transfer_data(from: u64, to: u64, amount: i64, memo: &str) -> Vec<u8> {
+		let mut data = vec![];
+		data.extend_from_slice(&from.to_le_bytes());
+		data.extend_from_slice(&to.to_le_bytes());
+		data.extend_from_slice(&amount.to_le_bytes());
+		data.extend_from_slice(&EOS.to_le_bytes());
+		data.push(memo.len() as u8);
+		data.extend_from_slice(memo.as_bytes());
+		data
+	}
+
+	fn action(contract: &str, action: &str, data: Vec<u8>) -> Action {
+		Action {
+			account: AccountName::from_str(contract).unwrap(),
+			name: ActionName::from_str(action).unwrap(),
+			authorization: vec![PermissionLevel {
+				actor: AccountName::from(ALICE),
+				permission: PermissionName::from_str("active").unwrap(),
+			}],
+			data,
+		}
+	}
+
+	fn compile(rules: &[FilterRule]) -> Vec<CompiledRule> {
+		rules.iter().map(|r| CompiledRule::compile(r).unwrap()).collect()
+	}
+
+	fn contract(contract: &str) -> AccountName {
+		AccountName::from_str(contract).unwrap()
+	}
+
+	#[test]
+	fn transfer_layout_is_decoded() {
+		let data = decode_transfer(&transfer_data(ALICE, BOB, 430_000, "hello")).unwrap();
+		assert_eq!(data, TransferData {
+			from: name(ALICE),
+			to: name(BOB),
+			quantity: "43.0000 EOS".to_owned(),
+			memo: "hello".to_owned(),
+		});
+		assert!(decode_transfer(&transfer_data(ALICE, BOB, 430_000, "hello")[..36]).is_err());
+	}
+
+	#[test]
+	fn no_rules_relay_nothing() {
+		let transfer = action("eosio.token", "transfer", transfer_data(ALICE, BOB, 1, ""));
+		assert_eq!(first_match(&[], &transfer, &contract("eosio.token")), None);
+	}
+
+	#[test]
+	fn default_rules_relay_transfers_received_by_contract() {
+		let rules = compile(&default_rules());
+		let transfer = action("eosio.token", "transfer", transfer_data(ALICE, BOB, 1, ""));
+
+		assert_eq!(first_match(&rules, &transfer, &contract("eosio.token")), Some(0));
+		// notifications to from and to are the same action
+		assert_eq!(first_match(&rules, &transfer, &AccountName::from(BOB)), None);
+		let issue = action("eosio.token", "issue", transfer_data(ALICE, BOB, 1, ""));
+		assert_eq!(first_match(&rules, &issue, &contract("eosio.token")), None);
+	}
+
+	#[test]
+	fn rules_match_account_and_action() {
+		let rules = compile(&[FilterRule {
+			contract: Some("eosio.token".to_owned()),
+			action: Some("transfer".to_owned()),
+			authorization: Some(format!("{}@active", name(ALICE))),
+			..Default::default()
+		}]);
+		let receiver = contract("eosio.token");
+
+		assert_eq!(first_match(&rules, &action("eosio.token", "transfer", vec![]), &receiver), Some(0));
+		assert_eq!(first_match(&rules, &action("eosio.token", "issue", vec![]), &receiver), None);
+		assert_eq!(first_match(&rules, &action("other.token", "transfer", vec![]), &contract("other.token")), None);
+
+		let mut by_bob = action("eosio.token", "transfer", vec![]);
+		by_bob.authorization[0].actor = AccountName::from(BOB);
+		assert_eq!(first_match(&rules, &by_bob, &receiver), None);
+	}
+
+	#[test]
+	fn rules_match_amount_and_memo() {
+		let rules = compile(&[FilterRule {
+			to: Some(name(BOB)),
+			min_quantity: Some("1.0000 EOS".to_owned()),
+			max_quantity: Some("100.0000 EOS".to_owned()),
+			memo_regex: Some("@bifrost:".to_owned()),
+			..Default::default()
+		}]);
+		let receiver = contract("eosio.token");
+		let transfer = |to, amount, memo: &str| action("eosio.token", "transfer", transfer_data(ALICE, to, amount, memo));
+
+		assert_eq!(first_match(&rules, &transfer(BOB, 10_000, "addr@bifrost:vEOS"), &receiver), Some(0));
+		assert_eq!(first_match(&rules, &transfer(BOB, 1_000_000, "addr@bifrost:vEOS"), &receiver), Some(0));
+		assert_eq!(first_match(&rules, &transfer(BOB, 9_999, "addr@bifrost:vEOS"), &receiver), None);
+		assert_eq!(first_match(&rules, &transfer(BOB, 1_000_001, "addr@bifrost:vEOS"), &receiver), None);
+		assert_eq!(first_match(&rules, &transfer(BOB, 10_000, "no destination"), &receiver), None);
+		assert_eq!(first_match(&rules, &transfer(ALICE, 10_000, "addr@bifrost:vEOS"), &receiver), None);
+	}
+
+	#[test]
+	fn data_of_other_actions_is_not_taken_as_transfer() {
+		let rules = compile(&[FilterRule {
+			from: Some(name(ALICE)),
+			..Default::default()
+		}]);
+		let receiver = contract("eosio.token");
+
+		let transfer = action("eosio.token", "transfer", transfer_data(ALICE, BOB, 1, ""));
+		assert_eq!(first_match(&rules, &transfer, &receiver), Some(0));
+		// same bytes, but nobody knows what they mean without abi
+		let other = action("eosio.token", "open", transfer_data(ALICE, BOB, 1, ""));
+		assert_eq!(first_match(&rules, &other, &receiver), None);
+	}
+
+	#[test]
+	fn only_matched_transfers_are_unpacked() {
+		let rules = compile(&[FilterRule {
+			contract: Some("eosio.token".to_owned()),
+			..Default::default()
+		}]);
+		let receiver = contract("eosio.token");
+
+		let transfer = action("eosio.token", "transfer", transfer_data(ALICE, BOB, 1, ""));
+		assert_eq!(classify(&rules, &transfer, &receiver), RuleMatch::Transfer);
+		let open = action("eosio.token", "open", transfer_data(ALICE, BOB, 1, ""));
+		assert_eq!(classify(&rules, &open, &receiver), RuleMatch::Action);
+		let truncated = action("eosio.token", "transfer", transfer_data(ALICE, BOB, 1, "")[..20].to_vec());
+		assert_eq!(classify(&rules, &truncated, &receiver), RuleMatch::Action);
+		let other = action("other.token", "transfer", transfer_data(ALICE, BOB, 1, ""));
+		assert_eq!(classify(&rules, &other, &contract("other.token")), RuleMatch::Unmatched);
+	}
+}
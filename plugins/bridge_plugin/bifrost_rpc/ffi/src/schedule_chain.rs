@@ -0,0 +1,143 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Producer schedule changes must reach bifrost one by one, v_n → v_n+1, each one
+//! built on the schedule bifrost currently trusts.
+
+use crate::rpc_calls::{BifrostRuntime, ChangeScheduleCall};
+use eos_chain::Checksum256;
+
+/// Schedule version and hash that bifrost trusts now.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StoredSchedule {
+	pub version: u32,
+	/// Not known if bifrost has no hash stored for the version, like the initial schedule.
+	pub hash:    Option<Checksum256>,
+}
+
+impl StoredSchedule {
+	/// Keep the hash seen before for the same version if bifrost doesn't tell it this time.
+	pub fn fill_hash(self, previous: Option<StoredSchedule>) -> Self {
+		match previous {
+			Some(previous) if self.hash.is_none() && previous.version == self.version => {
+				Self { hash: previous.hash, ..self }
+			}
+			_ => self,
+		}
+	}
+}
+
+/// How a schedule change relates to the stored schedule.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Link {
+	/// The next change, it could be sent now.
+	Next,
+	/// Bifrost has this version or a newer one already.
+	Stale,
+	/// Some change before it hasn't been accepted by bifrost yet.
+	Ahead,
+	/// Right version, but it's not built on the schedule bifrost trusts.
+	HashMismatch,
+}
+
+// an unknown stored hash cannot be checked here, bifrost verifies the change itself
+
+pub fn link(stored: &StoredSchedule, call: &ChangeScheduleCall<BifrostRuntime>) -> Link {
+	let version = call.schedule.version;
+	if version <= stored.version {
+		Link::Stale
+	} else if version > stored.version + 1 {
+		Link::Ahead
+	} else {
+		match stored.hash {
+			Some(hash) if hash != call.legacy_schedule_hash => Link::HashMismatch,
+			_ => Link::Next,
+		}
+	}
+}
+
+/// Versions after `stored_version` that are needed but no change brings them.
+/// `changes` are the versions that queued changes bring, `needed` the versions
+/// that queued work depends on, including the changes themselves.
+pub fn find_gaps(
+	stored_version: u32,
+	changes:        impl IntoIterator<Item=u32>,
+	needed:         impl IntoIterator<Item=u32>,
+) -> Vec<u32> {
+	let changes: std::collections::BTreeSet<u32> = changes.into_iter().collect();
+	let highest = needed.into_iter().chain(changes.iter().cloned()).max().unwrap_or_default();
+
+	let first = match stored_version.checked_add(1) {
+		Some(first) => first,
+		None => return vec![],
+	};
+	(first..=highest)
+		.filter(|version| !changes.contains(version))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::{change_call, checksum};
+
+	fn change(version: u32, legacy_schedule_hash: Checksum256) -> ChangeScheduleCall<BifrostRuntime> {
+		change_call(100, version, legacy_schedule_hash)
+	}
+
+	#[test]
+	fn changes_link_to_stored_schedule() {
+		let stored = StoredSchedule { version: 2, hash: Some(checksum(2)) };
+
+		assert_eq!(link(&stored, &change(3, checksum(2))), Link::Next);
+		assert_eq!(link(&stored, &change(3, checksum(9))), Link::HashMismatch);
+		assert_eq!(link(&stored, &change(2, checksum(1))), Link::Stale);
+		assert_eq!(link(&stored, &change(1, checksum(0))), Link::Stale);
+		assert_eq!(link(&stored, &change(4, checksum(3))), Link::Ahead);
+	}
+
+	#[test]
+	fn unknown_stored_hash_is_not_a_mismatch() {
+		let stored = StoredSchedule { version: 2, hash: None };
+		assert_eq!(link(&stored, &change(3, checksum(9))), Link::Next);
+		assert_eq!(link(&stored, &change(4, checksum(9))), Link::Ahead);
+	}
+
+	#[test]
+	fn unknown_hash_is_filled_from_last_sighting() {
+		let seen = StoredSchedule { version: 2, hash: Some(checksum(2)) };
+		let unknown = StoredSchedule { version: 2, hash: None };
+
+		assert_eq!(unknown.fill_hash(Some(seen)), seen);
+		assert_eq!(unknown.fill_hash(None), unknown);
+		// a newer version has another schedule
+		let newer = StoredSchedule { version: 3, hash: None };
+		assert_eq!(newer.fill_hash(Some(seen)), newer);
+		// what bifrost tells always wins
+		let changed = StoredSchedule { version: 2, hash: Some(checksum(7)) };
+		assert_eq!(changed.fill_hash(Some(seen)), changed);
+	}
+
+	#[test]
+	fn gaps_are_versions_nobody_brings() {
+		assert_eq!(find_gaps(1, vec![], vec![]), Vec::<u32>::new());
+		assert_eq!(find_gaps(1, vec![2, 3], vec![1, 2, 3]), Vec::<u32>::new());
+		assert_eq!(find_gaps(1, vec![3], vec![2]), vec![2]);
+		assert_eq!(find_gaps(1, vec![], vec![4]), vec![2, 3, 4]);
+		assert_eq!(find_gaps(5, vec![], vec![3, 5]), Vec::<u32>::new());
+		assert_eq!(find_gaps(u32::MAX, vec![], vec![u32::MAX]), Vec::<u32>::new());
+	}
+}
@@ -0,0 +1,1093 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Client of the state-history websocket protocol served by plugins/state_history_plugin,
+//! so the relayer could run against a stock nodeos instead of one with bridge_plugin.
+//!
+//! The node sends its abi as text first, then a binary `get_blocks_result_v0` for every
+//! requested block. Requests and results are fc::raw packed `state_request`/`state_result`.
+
+use crate::backfill::{BlockSource, SourceAction, SourceBlock, SourceScheduleChange, LOOKAHEAD};
+use crate::fork_db::{ForkDb, Outcome};
+use eos_chain::{Action, ActionReceipt, Checksum256, IncrementalMerkle, Read, SignedBlock};
+use serde::Deserialize;
+use sha2::{Digest as _, Sha256};
+use std::collections::{BTreeMap, VecDeque};
+use std::path::Path;
+
+fn ship_error(e: impl ToString) -> crate::Error {
+	crate::Error::ShipError(e.to_string())
+}
+
+/// A websocket frame.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+	Text(String),
+	Binary(Vec<u8>),
+}
+
+/// How frames reach the node, a websocket or a recorded session.
+pub trait Transport {
+	fn send(&mut self, frame: Vec<u8>) -> Result<(), crate::Error>;
+	/// `None` once the node closes the connection.
+	fn recv(&mut self) -> Result<Option<Frame>, crate::Error>;
+}
+
+pub struct WsTransport {
+	socket: tungstenite::WebSocket<tungstenite::client::AutoStream>,
+}
+
+impl WsTransport {
+	pub fn connect(url: &str) -> Result<Self, crate::Error> {
+		let (socket, _) = tungstenite::connect(url).map_err(ship_error)?;
+		Ok(Self { socket })
+	}
+}
+
+impl Transport for WsTransport {
+	fn send(&mut self, frame: Vec<u8>) -> Result<(), crate::Error> {
+		self.socket.write_message(tungstenite::Message::Binary(frame)).map_err(ship_error)
+	}
+
+	fn recv(&mut self) -> Result<Option<Frame>, crate::Error> {
+		loop {
+			match self.socket.read_message() {
+				Ok(tungstenite::Message::Text(text)) => return Ok(Some(Frame::Text(text))),
+				Ok(tungstenite::Message::Binary(bytes)) => return Ok(Some(Frame::Binary(bytes))),
+				Ok(tungstenite::Message::Close(_)) => return Ok(None),
+				Ok(_) => continue, // ping and pong are answered by tungstenite
+				Err(tungstenite::Error::ConnectionClosed) => return Ok(None),
+				Err(e) => return Err(ship_error(e)),
+			}
+		}
+	}
+}
+
+/// Frames of a recorded session, replayed in order. Binary frames are hex in the recording.
+#[derive(Clone, Debug, Default)]
+pub struct RecordedSession {
+	frames:   VecDeque<Frame>,
+	/// Everything sent by the client.
+	pub sent: Vec<Vec<u8>>,
+}
+
+#[derive(Deserialize)]
+struct Recording {
+	abi:    String,
+	frames: Vec<String>,
+}
+
+impl RecordedSession {
+	pub fn new(abi: String, frames: Vec<Vec<u8>>) -> Self {
+		let mut session = Self::default();
+		session.frames.push_back(Frame::Text(abi));
+		session.frames.extend(frames.into_iter().map(Frame::Binary));
+		session
+	}
+
+	pub fn from_file(path: impl AsRef<Path>) -> Result<Self, crate::Error> {
+		let content = std::fs::read_to_string(path).map_err(ship_error)?;
+		let recording: Recording = serde_json::from_str(&content).map_err(ship_error)?;
+		let frames = recording.frames.iter()
+			.map(|frame| hex::decode(frame.trim_start_matches("0x")).map_err(ship_error))
+			.collect::<Result<_, _>>()?;
+		Ok(Self::new(recording.abi, frames))
+	}
+}
+
+impl Transport for RecordedSession {
+	fn send(&mut self, frame: Vec<u8>) -> Result<(), crate::Error> {
+		self.sent.push(frame);
+		Ok(())
+	}
+
+	fn recv(&mut self) -> Result<Option<Frame>, crate::Error> {
+		Ok(self.frames.pop_front())
+	}
+}
+
+/// fc::raw reader over a received frame, or an argument packed by c++ side.
+pub struct Unpacker<'a> {
+	bytes: &'a [u8],
+	pos:   usize,
+}
+
+impl<'a> Unpacker<'a> {
+	pub fn new(bytes: &'a [u8]) -> Self {
+		Self { bytes, pos: 0 }
+	}
+
+	fn take(&mut self, len: usize) -> Result<&'a [u8], crate::Error> {
+		if self.pos + len > self.bytes.len() {
+			return Err(ship_error("unexpected end of state history message"));
+		}
+		let bytes = &self.bytes[self.pos..self.pos + len];
+		self.pos += len;
+		Ok(bytes)
+	}
+
+	fn skip(&mut self, len: usize) -> Result<(), crate::Error> {
+		self.take(len).map(|_| ())
+	}
+
+	/// Bytes not read yet.
+	pub fn remaining(&self) -> usize {
+		self.bytes.len() - self.pos
+	}
+
+	pub fn u8(&mut self) -> Result<u8, crate::Error> {
+		Ok(self.take(1)?[0])
+	}
+
+	pub fn bool(&mut self) -> Result<bool, crate::Error> {
+		Ok(self.u8()? != 0)
+	}
+
+	pub fn u32(&mut self) -> Result<u32, crate::Error> {
+		let mut buf = [0u8; 4];
+		buf.copy_from_slice(self.take(4)?);
+		Ok(u32::from_le_bytes(buf))
+	}
+
+	pub fn u64(&mut self) -> Result<u64, crate::Error> {
+		let mut buf = [0u8; 8];
+		buf.copy_from_slice(self.take(8)?);
+		Ok(u64::from_le_bytes(buf))
+	}
+
+	pub fn varuint32(&mut self) -> Result<u32, crate::Error> {
+		let mut value: u64 = 0;
+		let mut shift = 0;
+		loop {
+			let byte = self.u8()?;
+			value |= u64::from(byte & 0x7f) << shift;
+			if byte & 0x80 == 0 {
+				break;
+			}
+			shift += 7;
+			if shift >= 35 {
+				return Err(ship_error("varuint32 is too long"));
+			}
+		}
+		Ok(value as u32)
+	}
+
+	pub fn bytes(&mut self) -> Result<&'a [u8], crate::Error> {
+		let len = self.varuint32()? as usize;
+		self.take(len)
+	}
+
+	pub fn checksum256(&mut self) -> Result<Checksum256, crate::Error> {
+		let mut buf = [0u8; 32];
+		buf.copy_from_slice(self.take(32)?);
+		Ok(Checksum256::from(buf))
+	}
+
+	fn optional(&mut self) -> Result<bool, crate::Error> {
+		self.bool()
+	}
+
+	// decode an eos_chain type packed at current position
+	pub(crate) fn read<T: Read>(&mut self) -> Result<T, crate::Error> {
+		let mut pos = self.pos;
+		let value = T::read(self.bytes, &mut pos).map_err(|e| ship_error(format!("{:?}", e)))?;
+		self.pos = pos;
+		Ok(value)
+	}
+
+	fn variant_v0(&mut self, what: &str) -> Result<(), crate::Error> {
+		match self.varuint32()? {
+			0 => Ok(()),
+			index => Err(ship_error(format!("unknown {} variant {}", what, index))),
+		}
+	}
+}
+
+fn pack_varuint32(bytes: &mut Vec<u8>, mut value: u32) {
+	loop {
+		let byte = (value & 0x7f) as u8;
+		value >>= 7;
+		if value == 0 {
+			bytes.push(byte);
+			break;
+		}
+		bytes.push(byte | 0x80);
+	}
+}
+
+// indices of `state_request` variant
+const GET_STATUS_REQUEST: u32 = 0;
+const GET_BLOCKS_REQUEST: u32 = 1;
+const GET_BLOCKS_ACK_REQUEST: u32 = 2;
+// indices of `state_result` variant
+const GET_STATUS_RESULT: u32 = 0;
+const GET_BLOCKS_RESULT: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlockPosition {
+	pub block_num: u32,
+	pub block_id:  Checksum256,
+}
+
+impl BlockPosition {
+	fn unpack(unpacker: &mut Unpacker) -> Result<Self, crate::Error> {
+		Ok(Self { block_num: unpacker.u32()?, block_id: unpacker.checksum256()? })
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GetBlocksRequest {
+	pub start_block_num:        u32,
+	pub end_block_num:          u32,
+	pub max_messages_in_flight: u32,
+	pub irreversible_only:      bool,
+	pub fetch_block:            bool,
+	pub fetch_traces:           bool,
+	pub fetch_deltas:           bool,
+}
+
+impl GetBlocksRequest {
+	/// Irreversible blocks with traces from `start_block_num` on.
+	pub fn irreversible_from(start_block_num: u32) -> Self {
+		Self {
+			start_block_num,
+			end_block_num: u32::max_value(),
+			max_messages_in_flight: 1,
+			irreversible_only: true,
+			fetch_block: true,
+			fetch_traces: true,
+			fetch_deltas: false,
+		}
+	}
+
+	pub fn pack(&self) -> Vec<u8> {
+		let mut bytes = Vec::with_capacity(24);
+		pack_varuint32(&mut bytes, GET_BLOCKS_REQUEST);
+		bytes.extend_from_slice(&self.start_block_num.to_le_bytes());
+		bytes.extend_from_slice(&self.end_block_num.to_le_bytes());
+		bytes.extend_from_slice(&self.max_messages_in_flight.to_le_bytes());
+		pack_varuint32(&mut bytes, 0); // have_positions
+		bytes.push(self.irreversible_only as u8);
+		bytes.push(self.fetch_block as u8);
+		bytes.push(self.fetch_traces as u8);
+		bytes.push(self.fetch_deltas as u8);
+		bytes
+	}
+}
+
+pub fn pack_status_request() -> Vec<u8> {
+	let mut bytes = Vec::new();
+	pack_varuint32(&mut bytes, GET_STATUS_REQUEST);
+	bytes
+}
+
+pub fn pack_ack(num_messages: u32) -> Vec<u8> {
+	let mut bytes = Vec::with_capacity(5);
+	pack_varuint32(&mut bytes, GET_BLOCKS_ACK_REQUEST);
+	bytes.extend_from_slice(&num_messages.to_le_bytes());
+	bytes
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GetBlocksResult {
+	pub head:              BlockPosition,
+	pub last_irreversible: BlockPosition,
+	pub this_block:        Option<BlockPosition>,
+	pub prev_block:        Option<BlockPosition>,
+	pub block:             Option<Vec<u8>>,
+	pub traces:            Option<Vec<u8>>,
+	pub deltas:            Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StateResult {
+	Status { head: BlockPosition, last_irreversible: BlockPosition },
+	Blocks(GetBlocksResult),
+}
+
+pub fn unpack_result(bytes: &[u8]) -> Result<StateResult, crate::Error> {
+	let mut unpacker = Unpacker::new(bytes);
+	match unpacker.varuint32()? {
+		GET_STATUS_RESULT => Ok(StateResult::Status {
+			head: BlockPosition::unpack(&mut unpacker)?,
+			last_irreversible: BlockPosition::unpack(&mut unpacker)?,
+		}),
+		GET_BLOCKS_RESULT => {
+			let head = BlockPosition::unpack(&mut unpacker)?;
+			let last_irreversible = BlockPosition::unpack(&mut unpacker)?;
+			let this_block = if unpacker.optional()? { Some(BlockPosition::unpack(&mut unpacker)?) } else { None };
+			let prev_block = if unpacker.optional()? { Some(BlockPosition::unpack(&mut unpacker)?) } else { None };
+			let mut optional_bytes = || -> Result<Option<Vec<u8>>, crate::Error> {
+				if unpacker.optional()? { Ok(Some(unpacker.bytes()?.to_vec())) } else { Ok(None) }
+			};
+			Ok(StateResult::Blocks(GetBlocksResult {
+				head,
+				last_irreversible,
+				this_block,
+				prev_block,
+				block: optional_bytes()?,
+				traces: optional_bytes()?,
+				deltas: optional_bytes()?,
+			}))
+		}
+		index => Err(ship_error(format!("unknown state result variant {}", index))),
+	}
+}
+
+// fc::raw packed public key: k1, r1, or webauthn with user presence and rpid
+fn skip_public_key(unpacker: &mut Unpacker) -> Result<(), crate::Error> {
+	match unpacker.varuint32()? {
+		0 | 1 => unpacker.skip(33),
+		2 => {
+			unpacker.skip(33 + 1)?;
+			unpacker.bytes().map(|_| ())
+		}
+		index => Err(ship_error(format!("unknown public key type {}", index))),
+	}
+}
+
+fn sha256(bytes: &[u8]) -> Checksum256 {
+	let mut hash = [0u8; 32];
+	hash.copy_from_slice(&Sha256::digest(bytes));
+	Checksum256::from(hash)
+}
+
+// id of `producer_schedule_change_extension` in header extensions
+const PRODUCER_SCHEDULE_CHANGE_EXTENSION: u16 = 1;
+
+/// Producer schedule proposed by a packed block or block header, by `new_producers` or
+/// the producer schedule change extension of a v2 header. The hash is what nodeos keeps
+/// as pending schedule hash, a legacy schedule is hashed before it's converted to
+/// producer_authority_schedule with threshold and key weight 1, the same as nodeos does.
+pub fn proposed_schedule(packed: &[u8]) -> Result<Option<SourceScheduleChange>, crate::Error> {
+	let mut unpacker = Unpacker::new(packed);
+	// timestamp, producer, confirmed, previous, transaction_mroot, action_mroot, schedule_version
+	unpacker.skip(4 + 8 + 2 + 32 + 32 + 32 + 4)?;
+
+	if unpacker.optional()? {
+		let start = unpacker.pos;
+		let version = unpacker.u32()?;
+		let mut authorities = Vec::new();
+		authorities.extend_from_slice(&version.to_le_bytes());
+		let producers = unpacker.varuint32()?;
+		pack_varuint32(&mut authorities, producers);
+		for _ in 0..producers {
+			authorities.extend_from_slice(&unpacker.u64()?.to_le_bytes()); // producer_name
+			let key_start = unpacker.pos;
+			skip_public_key(&mut unpacker)?;
+			authorities.push(0); // block_signing_authority_v0
+			authorities.extend_from_slice(&1u32.to_le_bytes()); // threshold
+			pack_varuint32(&mut authorities, 1);
+			authorities.extend_from_slice(&unpacker.bytes[key_start..unpacker.pos]);
+			authorities.extend_from_slice(&1u16.to_le_bytes()); // weight
+		}
+		let legacy_schedule_hash = sha256(&unpacker.bytes[start..unpacker.pos]);
+		let schedule = Unpacker::new(&authorities).read()?;
+		return Ok(Some(SourceScheduleChange { legacy_schedule_hash, schedule }));
+	}
+
+	for _ in 0..unpacker.varuint32()? {
+		let mut id = [0u8; 2];
+		id.copy_from_slice(unpacker.take(2)?);
+		let data = unpacker.bytes()?;
+		if u16::from_le_bytes(id) == PRODUCER_SCHEDULE_CHANGE_EXTENSION {
+			let schedule = Unpacker::new(data).read()?;
+			return Ok(Some(SourceScheduleChange { legacy_schedule_hash: sha256(data), schedule }));
+		}
+	}
+
+	Ok(None)
+}
+
+/// An executed action and its receipt, taken from transaction traces.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TracedAction {
+	pub action:          Action,
+	pub receipt:         ActionReceipt,
+	pub global_sequence: u64,
+	pub trx_id:          Checksum256,
+}
+
+fn skip_signature(unpacker: &mut Unpacker) -> Result<(), crate::Error> {
+	match unpacker.varuint32()? {
+		0 | 1 => unpacker.skip(65), // k1, r1
+		2 => { // webauthn
+			unpacker.skip(65)?;
+			unpacker.bytes()?;
+			unpacker.bytes().map(|_| ())
+		}
+		index => Err(ship_error(format!("unknown signature type {}", index))),
+	}
+}
+
+fn unpack_action_trace(
+	unpacker: &mut Unpacker,
+	trx_id:   Checksum256,
+	executed: bool,
+	actions:  &mut Vec<TracedAction>,
+) -> Result<(), crate::Error> {
+	unpacker.variant_v0("action_trace")?;
+	unpacker.varuint32()?; // action_ordinal
+	unpacker.varuint32()?; // creator_action_ordinal
+	let receipt = if unpacker.optional()? {
+		unpacker.variant_v0("action_receipt")?;
+		// action_receipt_v0 is packed the same as chain::action_receipt
+		let mut peek = Unpacker { bytes: unpacker.bytes, pos: unpacker.pos + 8 + 32 };
+		let global_sequence = peek.u64()?;
+		let receipt: ActionReceipt = unpacker.read()?;
+		Some((receipt, global_sequence))
+	} else {
+		None
+	};
+	unpacker.u64()?; // receiver
+	let action: Action = unpacker.read()?;
+	unpacker.bool()?; // context_free
+	unpacker.u64()?; // elapsed
+	unpacker.bytes()?; // console
+	let deltas = unpacker.varuint32()?; // account_ram_deltas
+	unpacker.skip(16 * deltas as usize)?;
+	if unpacker.optional()? { unpacker.bytes()?; } // except
+	if unpacker.optional()? { unpacker.u64()?; } // error_code
+
+	if let (true, Some((receipt, global_sequence))) = (executed, receipt) {
+		actions.push(TracedAction { action, receipt, global_sequence, trx_id });
+	}
+	Ok(())
+}
+
+// `transaction_receipt_header::status_enum`
+const EXECUTED: u8 = 0;
+const SOFT_FAIL: u8 = 1;
+
+fn unpack_transaction_trace(unpacker: &mut Unpacker, actions: &mut Vec<TracedAction>) -> Result<(), crate::Error> {
+	unpacker.variant_v0("transaction_trace")?;
+	let trx_id = unpacker.checksum256()?;
+	let status = unpacker.u8()?;
+	unpacker.u32()?; // cpu_usage_us
+	unpacker.varuint32()?; // net_usage_words
+	unpacker.u64()?; // elapsed
+	unpacker.u64()?; // net_usage
+	unpacker.bool()?; // scheduled
+	// receipts in action_mroot: actions of executed transactions, and the onerror handler of a
+	// soft failed deferred transaction. Actions of the failed one are in `failed_dtrx_trace`
+	// with hard_fail status, they're rolled back.
+	let executed = status == EXECUTED || status == SOFT_FAIL;
+	for _ in 0..unpacker.varuint32()? {
+		unpack_action_trace(unpacker, trx_id, executed, actions)?;
+	}
+	if unpacker.optional()? { unpacker.skip(16)?; } // account_ram_delta
+	if unpacker.optional()? { unpacker.bytes()?; } // except
+	if unpacker.optional()? { unpacker.u64()?; } // error_code
+	if unpacker.optional()? { // failed_dtrx_trace
+		unpack_transaction_trace(unpacker, actions)?;
+	}
+	if unpacker.optional()? { // partial
+		unpacker.variant_v0("partial_transaction")?;
+		unpacker.skip(4 + 2 + 4)?; // expiration, ref_block_num, ref_block_prefix
+		unpacker.varuint32()?; // max_net_usage_words
+		unpacker.u8()?; // max_cpu_usage_ms
+		unpacker.varuint32()?; // delay_sec
+		for _ in 0..unpacker.varuint32()? { // transaction_extensions
+			unpacker.skip(2)?;
+			unpacker.bytes()?;
+		}
+		for _ in 0..unpacker.varuint32()? {
+			skip_signature(unpacker)?;
+		}
+		for _ in 0..unpacker.varuint32()? { // context_free_data
+			unpacker.bytes()?;
+		}
+	}
+	Ok(())
+}
+
+/// Executed actions with receipts of a block, in execution order.
+pub fn unpack_traces(bytes: &[u8]) -> Result<Vec<TracedAction>, crate::Error> {
+	let mut unpacker = Unpacker::new(bytes);
+	let mut actions = Vec::new();
+	for _ in 0..unpacker.varuint32()? {
+		unpack_transaction_trace(&mut unpacker, &mut actions)?;
+	}
+	actions.sort_by_key(|act| act.global_sequence);
+	Ok(actions)
+}
+
+/// Block of a get_blocks result in the shape backfill works on, `blockroot_merkle` is
+/// the merkle of ids of all blocks before it. A producer schedule proposed by the block
+/// header is given as well, backfill finds the block after which it becomes active.
+pub fn to_source_block(
+	result:           &GetBlocksResult,
+	blockroot_merkle: IncrementalMerkle,
+) -> Result<Option<SourceBlock>, crate::Error> {
+	let position = match result.this_block {
+		Some(position) => position,
+		None => return Ok(None),
+	};
+	let block_bytes = result.block.as_ref().ok_or_else(|| ship_error("block is not fetched"))?;
+	let block: SignedBlock = Unpacker::new(block_bytes).read()?;
+	let proposed_schedule = proposed_schedule(block_bytes)?;
+	let traced = match result.traces {
+		Some(ref traces) => unpack_traces(traces)?,
+		None => Vec::new(),
+	};
+
+	let mut action_receipts = Vec::with_capacity(traced.len());
+	let mut actions = Vec::with_capacity(traced.len());
+	for (receipt_index, act) in traced.into_iter().enumerate() {
+		action_receipts.push(act.receipt);
+		actions.push(SourceAction { action: act.action, receipt_index, trx_id: act.trx_id });
+	}
+
+	Ok(Some(SourceBlock {
+		block_num: position.block_num,
+		id: position.block_id,
+		header: block.signed_block_header,
+		blockroot_merkle,
+		action_receipts,
+		actions,
+		schedule_change: None,
+		proposed_schedule,
+	}))
+}
+
+// nothing proven by a forked block could be verified, neither its cached block nor queued proofs
+fn drop_forked(forked: &[(u32, Checksum256)]) -> Result<(), crate::Error> {
+	let dropped = crate::queue::drop_forked(forked)?;
+	if !dropped.is_empty() {
+		println!("[!] Drop submissions {:?} of forked blocks\n", dropped);
+	}
+	crate::block_cache::drop_forked(forked)?;
+
+	Ok(())
+}
+
+/// Blocks streamed from a state-history node, as a `BlockSource` that only reads forward.
+/// Only final blocks are given out, either irreversible ones from the node, or head blocks
+/// that went through a fork database until LIB passed them. Recent blocks are kept so
+/// overlapping ranges could be read again.
+pub struct ShipSource<T: Transport> {
+	transport:   T,
+	start:       u32,
+	started:     bool,
+	finished:    bool,
+	next_num:    u32,
+	// merkle of ids before `next_num`, blocks after it get theirs from fork db when following head
+	merkle:      IncrementalMerkle,
+	fork_db:     Option<ForkDb>,
+	buffer:      BTreeMap<u32, SourceBlock>,
+	pub head:    Option<BlockPosition>,
+	pub lib:     Option<BlockPosition>,
+}
+
+impl<T: Transport> ShipSource<T> {
+	/// Stream irreversible blocks from `start`, `merkle` is the blockroot merkle of block `start`.
+	pub fn new(transport: T, start: u32, merkle: IncrementalMerkle) -> Self {
+		Self {
+			transport,
+			start,
+			started: false,
+			finished: false,
+			next_num: start,
+			merkle,
+			fork_db: None,
+			buffer: BTreeMap::new(),
+			head: None,
+			lib: None,
+		}
+	}
+
+	/// Like `new`, but follow head blocks and keep forks until they're resolved by LIB.
+	pub fn following_head(transport: T, start: u32, merkle: IncrementalMerkle) -> Self {
+		Self { fork_db: Some(ForkDb::new()), ..Self::new(transport, start, merkle) }
+	}
+
+	pub fn transport(&self) -> &T {
+		&self.transport
+	}
+
+	/// Whether the node has closed the session.
+	pub fn is_finished(&self) -> bool {
+		self.finished
+	}
+
+	fn start(&mut self) -> Result<(), crate::Error> {
+		// the node sends its abi before anything else
+		match self.transport.recv()? {
+			Some(Frame::Text(_)) => (),
+			Some(Frame::Binary(_)) => return Err(ship_error("expect abi from state history node")),
+			None => return Err(ship_error("state history node closed the connection")),
+		}
+		let mut request = GetBlocksRequest::irreversible_from(self.start);
+		request.irreversible_only = self.fork_db.is_none();
+		self.transport.send(request.pack())?;
+		self.started = true;
+		Ok(())
+	}
+
+	// next get_blocks result from the node, `None` once the session ends
+	fn next_result(&mut self) -> Result<Option<GetBlocksResult>, crate::Error> {
+		if !self.started {
+			self.start()?;
+		}
+		loop {
+			let frame = match self.transport.recv()? {
+				Some(Frame::Binary(bytes)) => bytes,
+				Some(Frame::Text(_)) => continue,
+				None => return Ok(None),
+			};
+			if let StateResult::Blocks(result) = unpack_result(&frame)? {
+				self.transport.send(pack_ack(1))?;
+				self.head = Some(result.head);
+				self.lib = Some(result.last_irreversible);
+				return Ok(Some(result));
+			}
+		}
+	}
+
+	// blocks that became final by the next result, `None` once the session ends
+	fn read_next(&mut self) -> Result<Option<Vec<SourceBlock>>, crate::Error> {
+		let result = match self.next_result()? {
+			Some(result) => result,
+			None => return Ok(None),
+		};
+		let block = to_source_block(&result, self.merkle.clone())?;
+
+		let fork_db = match self.fork_db {
+			Some(ref mut fork_db) => fork_db,
+			None => {
+				let block = match block {
+					Some(block) => block,
+					None => return Ok(Some(Vec::new())),
+				};
+				if block.block_num != self.next_num {
+					return Err(ship_error(format!("expect block {}, but got block {}", self.next_num, block.block_num)));
+				}
+				self.merkle.append(block.id).map_err(|e| ship_error(format!("{:?}", e)))?;
+				return Ok(Some(vec![block]));
+			}
+		};
+
+		let mut released = Vec::new();
+		if let Some(block) = block {
+			let (block_num, id) = (block.block_num, block.id);
+			let (outcome, changes) = fork_db.add(block)?;
+			if outcome == Outcome::Unlinkable {
+				println!("[!] Block {} {:?} doesn't link to any known block\n", block_num, id);
+			}
+			if changes.is_fork_switch() {
+				let forked: Vec<(u32, Checksum256)> = changes.rolled_back.iter().map(|r| (r.block_num, r.id)).collect();
+				let nums: Vec<u32> = forked.iter().map(|(num, _)| *num).collect();
+				println!("[!] Fork switch at block {}, blocks {:?} are rolled back\n", block_num, nums);
+				drop_forked(&forked)?;
+			}
+			released.extend(changes.released);
+		}
+		released.extend(fork_db.set_lib(result.last_irreversible.block_num).released);
+
+		Ok(Some(released))
+	}
+}
+
+impl<T: Transport> BlockSource for ShipSource<T> {
+	fn block(&mut self, block_num: u32) -> Result<Option<SourceBlock>, crate::Error> {
+		while !self.finished && block_num >= self.next_num {
+			match self.read_next()? {
+				Some(blocks) => {
+					for block in blocks.into_iter() {
+						self.next_num = block.block_num + 1;
+						self.buffer.insert(block.block_num, block);
+					}
+					// a range read again starts at most a lookahead behind
+					let keep_from = self.next_num.saturating_sub(2 * LOOKAHEAD + 2);
+					self.buffer = self.buffer.split_off(&keep_from);
+				}
+				None => self.finished = true,
+			}
+		}
+
+		Ok(self.buffer.get(&block_num).cloned())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use codec::Encode;
+	use eos_chain::Digest;
+
+	fn position(bytes: &mut Vec<u8>, block_num: u32) {
+		bytes.extend_from_slice(&block_num.to_le_bytes());
+		bytes.extend_from_slice(&[block_num as u8; 32]);
+	}
+
+	// a get_blocks_result_v0 with positions, and block and traces if any
+	fn blocks_result(block_num: u32, block: Option<&[u8]>, traces: Option<&[u8]>) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		pack_varuint32(&mut bytes, GET_BLOCKS_RESULT);
+		position(&mut bytes, block_num + 10); // head
+		position(&mut bytes, block_num); // last_irreversible
+		bytes.push(1);
+		position(&mut bytes, block_num); // this_block
+		bytes.push(1);
+		position(&mut bytes, block_num - 1); // prev_block
+		match block {
+			Some(block) => {
+				bytes.push(1);
+				pack_varuint32(&mut bytes, block.len() as u32);
+				bytes.extend_from_slice(block);
+			}
+			None => bytes.push(0),
+		}
+		match traces {
+			Some(traces) => {
+				bytes.push(1);
+				pack_varuint32(&mut bytes, traces.len() as u32);
+				bytes.extend_from_slice(traces);
+			}
+			None => bytes.push(0),
+		}
+		bytes.push(0); // deltas
+		bytes
+	}
+
+	fn action_trace(bytes: &mut Vec<u8>, global_sequence: u64, with_receipt: bool) {
+		pack_varuint32(bytes, 0); // action_trace_v0
+		pack_varuint32(bytes, 1); // action_ordinal
+		pack_varuint32(bytes, 0); // creator_action_ordinal
+		if with_receipt {
+			bytes.push(1);
+			pack_varuint32(bytes, 0); // action_receipt_v0
+			bytes.extend_from_slice(&1u64.to_le_bytes()); // receiver
+			bytes.extend_from_slice(&[9u8; 32]); // act_digest
+			bytes.extend_from_slice(&global_sequence.to_le_bytes());
+			bytes.extend_from_slice(&7u64.to_le_bytes()); // recv_sequence
+			pack_varuint32(bytes, 1); // auth_sequence
+			bytes.extend_from_slice(&2u64.to_le_bytes());
+			bytes.extend_from_slice(&3u64.to_le_bytes());
+			pack_varuint32(bytes, 1); // code_sequence
+			pack_varuint32(bytes, 1); // abi_sequence
+		} else {
+			bytes.push(0);
+		}
+		bytes.extend_from_slice(&1u64.to_le_bytes()); // receiver
+		// action
+		bytes.extend_from_slice(&1u64.to_le_bytes());
+		bytes.extend_from_slice(&2u64.to_le_bytes());
+		pack_varuint32(bytes, 1);
+		bytes.extend_from_slice(&3u64.to_le_bytes());
+		bytes.extend_from_slice(&4u64.to_le_bytes());
+		pack_varuint32(bytes, 2);
+		bytes.extend_from_slice(&[0xaa, 0xbb]);
+		bytes.push(0); // context_free
+		bytes.extend_from_slice(&5i64.to_le_bytes()); // elapsed
+		pack_varuint32(bytes, 0); // console
+		pack_varuint32(bytes, 1); // account_ram_deltas
+		bytes.extend_from_slice(&[0u8; 16]);
+		bytes.push(0); // except
+		bytes.push(0); // error_code
+	}
+
+	// packed signed_block without transactions, `previous` tells its block number
+	fn signed_block(block_num: u32, action_mroot: &str, new_producers: Option<&[u8]>) -> Vec<u8> {
+		let mut previous = [0u8; 32];
+		previous[..4].copy_from_slice(&(block_num - 1).to_be_bytes());
+		signed_block_after(previous, action_mroot, new_producers)
+	}
+
+	fn signed_block_after(previous: [u8; 32], action_mroot: &str, new_producers: Option<&[u8]>) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		bytes.extend_from_slice(&[0x80, 0x2b, 0x70, 0x4d]); // timestamp
+		bytes.extend_from_slice(&0x3b97_7a63_2000_0000u64.to_le_bytes()); // producer, bifrost
+		bytes.extend_from_slice(&[0, 0]); // confirmed
+		bytes.extend_from_slice(&previous);
+		bytes.extend_from_slice(&[0x11; 32]); // transaction_mroot
+		bytes.extend_from_slice(&hex::decode(action_mroot).unwrap());
+		bytes.extend_from_slice(&1u32.to_le_bytes()); // schedule_version
+		match new_producers {
+			Some(schedule) => {
+				bytes.push(1);
+				bytes.extend_from_slice(schedule);
+			}
+			None => bytes.push(0),
+		}
+		pack_varuint32(&mut bytes, 0); // header_extensions
+		pack_varuint32(&mut bytes, 0); // k1 signature
+		bytes.extend_from_slice(&[0u8; 65]);
+		pack_varuint32(&mut bytes, 0); // transactions
+		pack_varuint32(&mut bytes, 0); // block_extensions
+		bytes
+	}
+
+	fn block_id(block: &[u8]) -> [u8; 32] {
+		let block: SignedBlock = Unpacker::new(block).read().unwrap();
+		let mut id = [0u8; 32];
+		id.copy_from_slice(&block.signed_block_header.block_header.id().unwrap().encode()[..32]);
+		id
+	}
+
+	// a get_blocks_result_v0 of a head block, its position is its real id
+	fn head_result(block: &[u8], lib: u32) -> Vec<u8> {
+		let header: SignedBlock = Unpacker::new(block).read().unwrap();
+		let block_num = header.signed_block_header.block_header.block_num();
+		let mut bytes = Vec::new();
+		pack_varuint32(&mut bytes, GET_BLOCKS_RESULT);
+		position(&mut bytes, block_num); // head
+		bytes.extend_from_slice(&lib.to_le_bytes()); // last_irreversible
+		bytes.extend_from_slice(&[0u8; 32]);
+		bytes.push(1); // this_block
+		bytes.extend_from_slice(&block_num.to_le_bytes());
+		bytes.extend_from_slice(&block_id(block));
+		bytes.push(0); // prev_block
+		bytes.push(1);
+		pack_varuint32(&mut bytes, block.len() as u32);
+		bytes.extend_from_slice(block);
+		bytes.push(0); // traces
+		bytes.push(0); // deltas
+		bytes
+	}
+
+	fn transaction_trace(bytes: &mut Vec<u8>, trx: u8, status: u8, global_sequences: &[u64]) {
+		transaction_trace_with_failed(bytes, trx, status, global_sequences, None);
+	}
+
+	// `failed` are actions of the failed deferred transaction of a soft failed one
+	fn transaction_trace_with_failed(
+		bytes:            &mut Vec<u8>,
+		trx:              u8,
+		status:           u8,
+		global_sequences: &[u64],
+		failed:           Option<&[u64]>,
+	) {
+		pack_varuint32(bytes, 0); // transaction_trace_v0
+		bytes.extend_from_slice(&[trx; 32]);
+		bytes.push(status);
+		bytes.extend_from_slice(&100u32.to_le_bytes()); // cpu_usage_us
+		pack_varuint32(bytes, 12); // net_usage_words
+		bytes.extend_from_slice(&5i64.to_le_bytes()); // elapsed
+		bytes.extend_from_slice(&96u64.to_le_bytes()); // net_usage
+		bytes.push(0); // scheduled
+		pack_varuint32(bytes, global_sequences.len() as u32);
+		for seq in global_sequences {
+			action_trace(bytes, *seq, true);
+		}
+		bytes.push(0); // account_ram_delta
+		bytes.push(0); // except
+		bytes.push(0); // error_code
+		match failed {
+			Some(failed) => {
+				bytes.push(1); // failed_dtrx_trace
+				transaction_trace(bytes, trx, 2, failed);
+			}
+			None => bytes.push(0),
+		}
+		bytes.push(1); // partial
+		pack_varuint32(bytes, 0);
+		bytes.extend_from_slice(&[0u8; 10]);
+		pack_varuint32(bytes, 0);
+		bytes.push(0);
+		pack_varuint32(bytes, 0);
+		pack_varuint32(bytes, 0); // transaction_extensions
+		pack_varuint32(bytes, 1); // signatures
+		pack_varuint32(bytes, 0);
+		bytes.extend_from_slice(&[1u8; 65]);
+		pack_varuint32(bytes, 0); // context_free_data
+	}
+
+	#[test]
+	fn get_blocks_request_should_be_packed() {
+		let request = GetBlocksRequest::irreversible_from(0x0102);
+		let bytes = request.pack();
+		assert_eq!(bytes[0], 1);
+		assert_eq!(&bytes[1..5], &0x0102u32.to_le_bytes());
+		assert_eq!(&bytes[5..9], &u32::max_value().to_le_bytes());
+		assert_eq!(&bytes[9..13], &1u32.to_le_bytes());
+		assert_eq!(&bytes[13..], &[0, 1, 1, 1, 0]);
+
+		assert_eq!(pack_ack(3), vec![2, 3, 0, 0, 0]);
+		assert_eq!(pack_status_request(), vec![0]);
+	}
+
+	#[test]
+	fn varuint32_should_round_trip() {
+		for value in [0u32, 1, 127, 128, 300, 16384, u32::max_value()].iter() {
+			let mut bytes = Vec::new();
+			pack_varuint32(&mut bytes, *value);
+			assert_eq!(Unpacker::new(&bytes).varuint32().unwrap(), *value);
+		}
+	}
+
+	#[test]
+	fn blocks_result_should_be_unpacked() {
+		let bytes = blocks_result(100, None, None);
+		match unpack_result(&bytes).unwrap() {
+			StateResult::Blocks(result) => {
+				assert_eq!(result.head.block_num, 110);
+				assert_eq!(result.last_irreversible.block_num, 100);
+				assert_eq!(result.this_block.map(|p| p.block_num), Some(100));
+				assert_eq!(result.prev_block.map(|p| p.block_num), Some(99));
+				assert!(result.block.is_none());
+				assert!(result.traces.is_none());
+			}
+			_ => panic!("expect a get_blocks_result"),
+		}
+		assert!(unpack_result(&bytes[..bytes.len() - 3]).is_err());
+	}
+
+	#[test]
+	fn traces_should_keep_executed_actions_in_order() {
+		let mut traces = Vec::new();
+		pack_varuint32(&mut traces, 3);
+		transaction_trace(&mut traces, 1, 0, &[12, 10]);
+		transaction_trace(&mut traces, 2, 3, &[11]); // hard failed
+		transaction_trace(&mut traces, 3, 0, &[13]);
+
+		let actions = unpack_traces(&traces).unwrap();
+		let sequences: Vec<u64> = actions.iter().map(|act| act.global_sequence).collect();
+		assert_eq!(sequences, vec![10, 12, 13]);
+		assert_eq!(actions[0].trx_id, Checksum256::from([1u8; 32]));
+		assert_eq!(actions[2].trx_id, Checksum256::from([3u8; 32]));
+		assert_eq!(actions[0].action.data, vec![0xaa, 0xbb]);
+	}
+
+	#[test]
+	fn traces_should_keep_onerror_of_soft_failed_transaction() {
+		let mut traces = Vec::new();
+		pack_varuint32(&mut traces, 2);
+		transaction_trace(&mut traces, 1, 0, &[10]);
+		transaction_trace_with_failed(&mut traces, 2, 1, &[11], Some(&[9]));
+
+		let actions = unpack_traces(&traces).unwrap();
+		let sequences: Vec<u64> = actions.iter().map(|act| act.global_sequence).collect();
+		assert_eq!(sequences, vec![10, 11]);
+		assert_eq!(actions[1].trx_id, Checksum256::from([2u8; 32]));
+	}
+
+	#[test]
+	fn recorded_session_should_prove_actions_by_action_mroot() {
+		// executed 10 and 12, onerror 11 of a soft failed transaction, 14 is rolled back.
+		// the root is computed by python hashlib over the packed receipts of `action_trace`.
+		const ACTION_MROOT: &str = "7ca8d1f7d145a1af0e7d101252091959f5f303949667f30f0a64d7ce8558cd56";
+		let mut traces = Vec::new();
+		pack_varuint32(&mut traces, 2);
+		transaction_trace(&mut traces, 1, 0, &[12, 10]);
+		transaction_trace_with_failed(&mut traces, 2, 1, &[11], Some(&[14]));
+		let block = signed_block(5, ACTION_MROOT, None);
+
+		let session = RecordedSession::new("{}".to_owned(), vec![blocks_result(5, Some(&block), Some(&traces))]);
+		let mut source = ShipSource::new(session, 5, IncrementalMerkle::new(4, Vec::new()));
+		let block = source.block(5).unwrap().unwrap();
+		assert_eq!(block.actions.len(), 3);
+		assert_eq!(block.proposed_schedule, None);
+
+		let action_mroot = block.header.block_header.action_mroot;
+		let digests: Vec<Checksum256> = block.action_receipts.iter().map(|r| r.digest().unwrap()).collect();
+		assert_eq!(crate::merkle::merkle_root(digests.clone()), action_mroot);
+		for act in block.actions.iter() {
+			let paths = crate::merkle::get_proof(act.receipt_index, digests.clone());
+			assert_eq!(crate::merkle::proof_root(digests[act.receipt_index], &paths), action_mroot);
+		}
+	}
+
+	#[test]
+	fn recorded_session_should_give_proposed_schedule() {
+		// producer_schedule { 2, [{ bifrost, EOS6MRyAjQq8ud7hVNYcfnVPJqcVpscN5So8BhtHuGYqET5GDW5CV }] }
+		let legacy = hex::decode(concat!(
+			"02000000", "01", "00000020637a973b",
+			"00", "02c0ded2bc1f1305fb0faac5e6c03ee3a1924234985427b6167ca569d13df435cf",
+		)).unwrap();
+		let block = signed_block(5, &"2".repeat(64), Some(&legacy));
+
+		let session = RecordedSession::new("{}".to_owned(), vec![blocks_result(5, Some(&block), None)]);
+		let mut source = ShipSource::new(session, 5, IncrementalMerkle::new(4, Vec::new()));
+		let proposed = source.block(5).unwrap().unwrap().proposed_schedule.unwrap();
+
+		let mut hash = [0u8; 32];
+		hash.copy_from_slice(&Sha256::digest(&legacy));
+		assert_eq!(proposed.legacy_schedule_hash, Checksum256::from(hash));
+		assert_eq!(proposed.schedule.version, 2);
+		assert_eq!(proposed.schedule.producers[0].producer_name.to_string(), "bifrost");
+		assert_eq!(proposed.schedule.producers[0].authority.1.threshold, 1);
+	}
+
+	#[test]
+	fn fork_switch_should_drop_proofs_of_forked_blocks() {
+		let _lock = crate::store::TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+		// 6a is forked out by 6b and 7b, nothing is final yet
+		let block_5 = signed_block(5, &"2".repeat(64), None);
+		let block_6a = signed_block_after(block_id(&block_5), &"a".repeat(64), None);
+		let block_6b = signed_block_after(block_id(&block_5), &"b".repeat(64), None);
+		let block_7b = signed_block_after(block_id(&block_6b), &"b".repeat(64), None);
+
+		let proof = |block: &[u8]| {
+			let mut traces = Vec::new();
+			pack_varuint32(&mut traces, 1);
+			transaction_trace(&mut traces, 1, 0, &[10]);
+			let traced = unpack_traces(&traces).unwrap().remove(0);
+			let header: SignedBlock = Unpacker::new(block).read().unwrap();
+			crate::queue::Submission::ProveAction(crate::rpc_calls::ProveActionCall {
+				action: traced.action,
+				action_receipt: traced.receipt,
+				action_merkle_paths: vec![],
+				merkle: IncrementalMerkle::new(5, vec![]),
+				block_headers: vec![header.signed_block_header],
+				block_ids_list: vec![vec![]],
+				trx_id: traced.trx_id,
+				_runtime: core::marker::PhantomData,
+			})
+		};
+		let forked = crate::queue::enqueue(proof(&block_6a)).unwrap();
+		let kept = crate::queue::enqueue(proof(&block_6b)).unwrap();
+
+		let frames = [&block_5, &block_6a, &block_6b, &block_7b].iter().map(|block| head_result(block, 4)).collect();
+		let session = RecordedSession::new("{}".to_owned(), frames);
+		let mut source = ShipSource::following_head(session, 5, IncrementalMerkle::new(4, Vec::new()));
+		assert!(source.block(5).unwrap().is_none());
+
+		let pending = crate::queue::pending_ids();
+		assert!(!pending.contains(&forked));
+		assert!(pending.contains(&kept));
+		crate::queue::drop_forked(&[(6, Checksum256::from(block_id(&block_6b)))]).unwrap();
+	}
+
+	#[test]
+	fn recorded_session_should_be_acked() {
+		let session = RecordedSession::new("{}".to_owned(), vec![blocks_result(5, None, None)]);
+		let mut source = ShipSource::new(session, 5, IncrementalMerkle::new(4, Vec::new()));
+
+		// no block is fetched in this recording, the node misbehaves
+		assert!(source.block(5).is_err());
+		let sent = &source.transport().sent;
+		assert_eq!(sent[0], GetBlocksRequest::irreversible_from(5).pack());
+		assert_eq!(sent[1], pack_ack(1));
+		assert_eq!(source.lib.map(|p| p.block_num), Some(5));
+	}
+
+	#[test]
+	fn recorded_session_should_end() {
+		let session = RecordedSession::new("{}".to_owned(), Vec::new());
+		let mut source = ShipSource::new(session, 5, IncrementalMerkle::new(4, Vec::new()));
+		assert_eq!(source.block(5).unwrap().map(|b| b.block_num), None);
+		assert_eq!(source.block(6).unwrap().map(|b| b.block_num), None);
+	}
+
+	#[test]
+	fn session_must_start_with_abi() {
+		let mut session = RecordedSession::default();
+		session.frames.push_back(Frame::Binary(blocks_result(5, None, None)));
+		let mut source = ShipSource::new(session, 5, IncrementalMerkle::new(4, Vec::new()));
+		assert!(source.block(5).is_err());
+	}
+}
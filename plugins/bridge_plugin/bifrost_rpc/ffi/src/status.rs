@@ -0,0 +1,61 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static RELAY_STATUS: Lazy<Mutex<RelayStatus>> = Lazy::new(|| Mutex::new(RelayStatus::default()));
+
+/// Snapshot of what the relayer knows about itself, reported through `relay_status`.
+#[derive(Clone, Debug, Default)]
+pub struct RelayStatus {
+	pub signer_free_balance: Option<u128>,
+	pub balance_checked_at: u64, // unix timestamp in seconds
+	pub low_balance: bool,
+	pub payload_cache: crate::payload_cache::PayloadCacheStats,
+}
+
+impl RelayStatus {
+	pub fn to_json(&self) -> Value {
+		json!({
+			// u128 doesn't fit in a json number on the c++ side, so keep it as string
+			"signer_free_balance": self.signer_free_balance.map(|b| b.to_string()),
+			"balance_checked_at": self.balance_checked_at,
+			"low_balance": self.low_balance,
+			"payload_cache": self.payload_cache.to_json(),
+		})
+	}
+}
+
+pub fn update_signer_balance(free: u128, low_balance: bool) {
+	if let Ok(mut status) = RELAY_STATUS.lock() {
+		status.signer_free_balance = Some(free);
+		status.balance_checked_at = now();
+		status.low_balance = low_balance;
+	}
+}
+
+pub fn relay_status() -> RelayStatus {
+	let mut status = RELAY_STATUS.lock().map(|status| status.clone()).unwrap_or_default();
+	status.payload_cache = crate::payload_cache::stats();
+	status
+}
+
+pub(crate) fn now() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
@@ -0,0 +1,102 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Crash-safe relay state. Every change is written and flushed to disk right away,
+//! so nothing collected or pending is lost if nodeos doesn't shut down cleanly.
+//! Values are opaque bytes, c++ side stores its fc::raw packed objects as they are.
+
+use once_cell::sync::Lazy;
+use std::path::Path;
+use std::sync::RwLock;
+
+static RELAY_STORE: Lazy<RwLock<Option<sled::Db>>> = Lazy::new(|| RwLock::new(None));
+
+// relay store is global, tests that open it or expect nothing else written into it take turns
+#[cfg(test)]
+pub(crate) static TEST_LOCK: Lazy<std::sync::Mutex<()>> = Lazy::new(|| std::sync::Mutex::new(()));
+
+// trees used by rust side itself
+pub const SUBMISSIONS_TREE: &str = "submissions";
+
+fn store_error(e: impl ToString) -> crate::Error {
+	crate::Error::StoreError(e.to_string())
+}
+
+pub fn open(path: impl AsRef<Path>) -> Result<(), crate::Error> {
+	let db = sled::open(path).map_err(store_error)?;
+	let mut store = RELAY_STORE.write().map_err(|_| store_error("relay store is poisoned"))?;
+	*store = Some(db);
+
+	Ok(())
+}
+
+pub fn is_open() -> bool {
+	RELAY_STORE.read().map(|store| store.is_some()).unwrap_or(false)
+}
+
+fn with_tree<R>(tree: &str, f: impl FnOnce(&sled::Tree) -> Result<R, crate::Error>) -> Result<R, crate::Error> {
+	let store = RELAY_STORE.read().map_err(|_| store_error("relay store is poisoned"))?;
+	let db = store.as_ref().ok_or_else(|| store_error("relay store is not opened"))?;
+	let tree = db.open_tree(tree).map_err(store_error)?;
+
+	f(&tree)
+}
+
+/// Insert or replace a value, it's on disk once this returns.
+pub fn put(tree: &str, key: &[u8], value: &[u8]) -> Result<(), crate::Error> {
+	with_tree(tree, |tree| {
+		tree.insert(key, value).map_err(store_error)?;
+		tree.flush().map_err(store_error)?;
+		Ok(())
+	})
+}
+
+pub fn get(tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, crate::Error> {
+	with_tree(tree, |tree| {
+		let value = tree.get(key).map_err(store_error)?;
+		Ok(value.map(|v| v.to_vec()))
+	})
+}
+
+pub fn remove(tree: &str, key: &[u8]) -> Result<(), crate::Error> {
+	with_tree(tree, |tree| {
+		tree.remove(key).map_err(store_error)?;
+		tree.flush().map_err(store_error)?;
+		Ok(())
+	})
+}
+
+/// Visit every entry of a tree in key order, returns how many entries there're.
+pub fn for_each(tree: &str, mut f: impl FnMut(&[u8], &[u8])) -> Result<usize, crate::Error> {
+	with_tree(tree, |tree| {
+		let mut count = 0;
+		for entry in tree.iter() {
+			let (key, value) = entry.map_err(store_error)?;
+			f(&key, &value);
+			count += 1;
+		}
+		Ok(count)
+	})
+}
+
+pub fn close() -> Result<(), crate::Error> {
+	let mut store = RELAY_STORE.write().map_err(|_| store_error("relay store is poisoned"))?;
+	if let Some(db) = store.take() {
+		db.flush().map_err(store_error)?;
+	}
+
+	Ok(())
+}
@@ -0,0 +1,121 @@
+// Copyright 2019-2020 Liebi Technologies.
+// This file is part of Bifrost.
+
+// Bifrost is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Bifrost is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Bifrost.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Block ids, headers and calls the unit tests are built from.
+
+use crate::rpc_calls::{BifrostRuntime, ChangeScheduleCall, ProveActionCall};
+use core::marker::PhantomData;
+use eos_chain::{
+	AccountName, Action, ActionName, ActionReceipt, Checksum256, Digest, IncrementalMerkle,
+	ProducerAuthoritySchedule, SignedBlockHeader, UnsignedInt, utils::flat_map::FlatMap,
+};
+use std::str::FromStr;
+
+const SIGNATURE: &str = "SIG_K1_111111111111111111111111111111111111111111111111111111111111111116uk5ne";
+
+/// Block ids carry block number and fork, 0 is the main branch.
+pub fn id_bytes(block_num: u32, fork: u8) -> [u8; 32] {
+	let mut bytes = [0u8; 32];
+	bytes[..4].copy_from_slice(&block_num.to_be_bytes());
+	bytes[4] = fork;
+	bytes
+}
+
+pub fn id(block_num: u32, fork: u8) -> Checksum256 {
+	Checksum256::from(id_bytes(block_num, fork))
+}
+
+pub fn checksum(byte: u8) -> Checksum256 {
+	Checksum256::from([byte; 32])
+}
+
+/// Header following `previous` in JSON, transaction and action mroots are filled with 0x11 and 0x22.
+pub fn header_json(previous: [u8; 32], schedule_version: u32) -> String {
+	format!(r#"{{
+		"timestamp": "2020-08-01T12:00:00.000",
+		"producer": "bifrost",
+		"confirmed": 0,
+		"previous": "{}",
+		"transaction_mroot": "{}",
+		"action_mroot": "{}",
+		"schedule_version": {},
+		"new_producers": null,
+		"header_extensions": [],
+		"producer_signature": "{}"
+	}}"#, hex::encode(previous), "1".repeat(64), "2".repeat(64), schedule_version, SIGNATURE)
+}
+
+pub fn header_after(previous: [u8; 32], schedule_version: u32) -> SignedBlockHeader {
+	serde_json::from_str(&header_json(previous, schedule_version)).expect("valid block header")
+}
+
+/// Header of `block_num` on the main branch.
+pub fn header(block_num: u32, schedule_version: u32) -> SignedBlockHeader {
+	header_after(id_bytes(block_num - 1, 0), schedule_version)
+}
+
+pub fn action(account: &str, name: &str) -> Action {
+	Action {
+		account: AccountName::from_str(account).unwrap(),
+		name: ActionName::from_str(name).unwrap(),
+		authorization: vec![],
+		data: vec![],
+	}
+}
+
+pub fn receipt(action: &Action, seq: u64) -> ActionReceipt {
+	ActionReceipt {
+		receiver: action.account,
+		act_digest: action.digest().unwrap(),
+		global_sequence: seq,
+		recv_sequence: seq,
+		auth_sequence: FlatMap::assign(vec![]),
+		code_sequence: UnsignedInt::from(1u32),
+		abi_sequence: UnsignedInt::from(1u32),
+	}
+}
+
+/// Change to `version`, proven by the header of `block_num`, the last block of the version before.
+pub fn change_call(
+	block_num:            u32,
+	version:              u32,
+	legacy_schedule_hash: Checksum256,
+) -> ChangeScheduleCall<BifrostRuntime> {
+	ChangeScheduleCall {
+		legacy_schedule_hash,
+		schedule: ProducerAuthoritySchedule { version, producers: vec![] },
+		merkle: IncrementalMerkle::new(block_num as u64, vec![]),
+		block_headers: vec![header(block_num, version.saturating_sub(1))],
+		block_ids_list: vec![vec![]],
+		_runtime: PhantomData,
+	}
+}
+
+/// Transfer executed in `block_num`, proven by the header of that block only.
+pub fn prove_call(block_num: u32, schedule_version: u32, seq: u64) -> ProveActionCall<BifrostRuntime> {
+	let action = action("eosio.token", "transfer");
+	let action_receipt = receipt(&action, seq);
+	ProveActionCall {
+		action,
+		action_receipt,
+		action_merkle_paths: vec![],
+		merkle: IncrementalMerkle::new(block_num as u64, vec![]),
+		block_headers: vec![header(block_num, schedule_version)],
+		block_ids_list: vec![vec![]],
+		trx_id: checksum(seq as u8),
+		_runtime: PhantomData,
+	}
+}
@@ -1,3 +1,5 @@
+#include <eosio/chain/abi_serializer.hpp>
+#include <eosio/chain/account_object.hpp>
 #include <eosio/chain/exceptions.hpp>
 #include <eosio/chain/merkle.hpp>
 #include <eosio/chain/types.hpp>
@@ -8,7 +10,9 @@
 #include <fstream>
 #include <fc/log/logger_config.hpp>
 #include <fc/io/json.hpp>
+#include <deque>
 #include <mutex>
+#include <set>
 #include <thread>
 
 #include "bifrost_rpc.h"
@@ -25,13 +29,93 @@ namespace eosio {
    digest_type digest(const action &act) { return digest_type::hash(act); }
    std::mutex mtx;
 
+   // results of bifrost_rpc are allocated by rust side, hand them back once they're read
+   struct rpc_result_deleter {
+      void operator()(rpc_result *result) const { rpc_result_free(result); }
+   };
+   using rpc_result_ptr = std::unique_ptr<rpc_result, rpc_result_deleter>;
+
+   // requests delivered by rust side from its own thread, guarded by mtx
+   std::deque<cross_out_request> cross_out_requests;
+   const size_t max_cross_out_requests = 1024;
+
+   void on_cross_out_request(const cross_out_request_ffi *ffi) {
+      if (!ffi) return;
+
+      auto request = cross_out_request {
+         ffi->request_id,
+         std::string(ffi->bifrost_account),
+         ffi->eos_recipient,
+         std::string(ffi->asset),
+         std::string(ffi->amount)
+      };
+      ilog("cross out request from bifrost: ${r}", ("r", request));
+
+      std::lock_guard<std::mutex> lock(mtx);
+      if (cross_out_requests.size() >= max_cross_out_requests) {
+         wlog("too many cross out requests waiting, drop request ${id}.", ("id", cross_out_requests.front().request_id));
+         cross_out_requests.pop_front();
+      }
+      cross_out_requests.push_back(request);
+   }
+
+   // trees of relay store, it's owned by rust side and flushed on every write
+   const char *blocks_tree = "blocks"; // written by older versions, blocks are cached by rust side now
+   const char *change_schedules_tree = "change_schedules";
+   const char *prove_actions_tree = "prove_actions";
+
+   template<typename K, typename V>
+   void store_put(const char *tree, const K &key, const V &value) {
+      auto k = fc::raw::pack(key);
+      auto v = fc::raw::pack(value);
+      rpc_result_ptr result(relay_store_put(tree, k.data(), k.size(), v.data(), v.size()));
+      if (result && !result->success) {
+         elog("failed to write ${tree} into relay store due to: ${err}.", ("tree", tree)("err", std::string(result->msg)));
+      }
+   }
+
+   template<typename K>
+   void store_remove(const char *tree, const K &key) {
+      auto k = fc::raw::pack(key);
+      rpc_result_ptr result(relay_store_remove(tree, k.data(), k.size()));
+      if (result && !result->success) {
+         elog("failed to remove ${tree} from relay store due to: ${err}.", ("tree", tree)("err", std::string(result->msg)));
+      }
+   }
+
+   template<typename T, typename Index>
+   void store_load(const char *tree, Index &index) {
+      rpc_result_ptr result(relay_store_load(tree, [](void *ctx, const char *, size_t, const char *value, size_t value_size) {
+         try {
+            T entry;
+            fc::datastream<const char *> ds(value, value_size);
+            fc::raw::unpack(ds, entry);
+            static_cast<Index *>(ctx)->insert(entry);
+         } catch (...) {
+            elog("skip a broken entry in relay store.");
+         }
+      }, &index));
+
+      if (result && result->success) {
+         ilog("loaded ${n} entries of ${tree} from relay store.", ("n", std::string(result->msg))("tree", tree));
+      } else if (result) {
+         elog("failed to load ${tree} from relay store due to: ${err}.", ("tree", tree)("err", std::string(result->msg)));
+      }
+   }
+
+   void persist(const bridge_change_schedule &entry) { store_put(change_schedules_tree, entry.block_num, entry); }
+   void persist(const bridge_prove_action &entry) { store_put(prove_actions_tree, entry.act_receipt_digest, entry); }
+
+   void unpersist(const bridge_change_schedule &entry) { store_remove(change_schedules_tree, entry.block_num); }
+   void unpersist(const bridge_prove_action &entry) { store_remove(prove_actions_tree, entry.act_receipt_digest); }
+
    typedef multi_index_container<
            bridge_blocks,
            indexed_by<
               ordered_unique<tag<by_id>,
               member<bridge_blocks, block_id_type, &bridge_blocks::id>>
            >
-   > bridge_block_index;
+   > bridge_block_index; // only for moving blocks of older versions into block cache
 
    typedef multi_index_container<
            bridge_change_schedule,
@@ -65,6 +149,7 @@ namespace eosio {
       std::string bifrost_addr;
       std::string bifrost_crossaccount;
       std::string bifrost_signer;
+      std::string relay_config = "{}"; // json passed to init_relay_config
    };
 
    class bridge_plugin_impl {
@@ -77,7 +162,6 @@ namespace eosio {
       boost::asio::steady_timer::duration change_schedule_timeout{std::chrono::milliseconds{1000}};
       boost::asio::steady_timer::duration prove_action_timeout{std::chrono::milliseconds{1000}};
 
-      bridge_block_index            block_index;
       bridge_change_schedule_index  change_schedule_index;
       bridge_prove_action_index     prove_action_index;
 
@@ -91,176 +175,57 @@ namespace eosio {
       void collect_blocks_timer_tick();
 
       void irreversible_block(const chain::block_state_ptr &);
+      void cache_irreversible_block(const block_state &);
+      template<typename Index>
+      void migrate_legacy_blocks(Index &);
+      template<typename Index>
+      void update_pending(Index &, uint32_t, const char *);
+      bool rebuild_blocks(uint32_t);
+      void update_block_cache_floor();
       void apply_action_receipt(std::tuple<const transaction_trace_ptr &, const std::vector<action_receipt>&>);
 
       void open_db();
       void close_db();
 
-      std::atomic<bool>                     in_shutdown{false};
+      void process_submission_queue();
+      void settle_submitted(const fc::variant &);
+      void requeue_lost_entries();
+      void confirm_redeem_requests();
+      void settle_irreversible_refunds(const block_state &bls);
 
-      std::tuple<std::vector<signed_block_header>, std::vector<std::vector<block_id_type>>, bool> collect_incremental_merkle_and_blocks(bridge_change_schedule_index::iterator &);
-      std::tuple<std::vector<signed_block_header>, std::vector<std::vector<block_id_type>>, bool> collect_incremental_merkle_and_blocks(bridge_prove_action_index::iterator &);
+      void register_abi(const account_name &contract);
+
+      std::atomic<bool>                     in_shutdown{false};
 
       void filter_action(const std::string &contract, const std::vector<action_trace> &, const std::vector<action_receipt> &, transaction_id_type&);
    };
 
-   std::tuple<std::vector<signed_block_header>, std::vector<std::vector<block_id_type>>, bool> bridge_plugin_impl::collect_incremental_merkle_and_blocks(bridge_prove_action_index::iterator &ti) {
-      auto bl_state = block_state();
-      std::vector<signed_block_header> block_headers; // can reserve a buffer to store id
-      block_headers.reserve(15);
-
-      std::vector<std::vector<block_id_type>> block_id_lists; // can reserve a buffer to store id
-      block_id_lists.reserve(15);
-
-      bool block_found = false;
-      for (auto bls: ti->bs) {
-         if (ti->block_num == bls.block_num) { // which block is need to be verified
-            block_headers.push_back(bls.header);
-            bl_state = bls;
-            block_found = true;
-            break;
-         }
-      }
-      if (!block_found) {
-         return std::make_tuple(block_headers, block_id_lists, false);
-      }
-
-      auto reserved = std::vector<block_id_type>();
-      reserved.reserve(10);
-      block_id_lists.push_back(std::vector<block_id_type>());
-      block_id_lists.push_back(reserved);
-      for (auto bls: ti->bs) {
-         if (bls.block_num <= ti->block_num) continue;
-         if (bls.block_num - block_headers.back().block_num() == 12) {
-            block_headers.push_back(bls.header);
-            if (block_headers.size() >= 15) break;
-            block_id_lists.push_back(std::vector<block_id_type>());
-         } else {
-            auto block_ids = block_id_lists.back();
-            if (block_ids.size() < 10) block_id_lists.back().push_back(bls.id);
-         }
-         if (block_id_lists.size() >= 15 && block_id_lists.back().size() >= 10 && block_headers.size() >= 15) break;
-      }
-
-      return std::make_tuple(block_headers, block_id_lists, true);
-   }
-
-   std::tuple<std::vector<signed_block_header>, std::vector<std::vector<block_id_type>>, bool> bridge_plugin_impl::collect_incremental_merkle_and_blocks(bridge_change_schedule_index::iterator &ti) {
-      auto bl_state = block_state();
-      std::vector<signed_block_header> block_headers; // can reserve a buffer to store id
-      block_headers.reserve(15);
-
-      std::vector<std::vector<block_id_type>>  block_id_lists; // can reserve a buffer to store id
-      block_id_lists.reserve(15);
-
-      bool block_found = false;
-      for (auto bls: ti->bs) {
-         if (ti->block_num == bls.block_num) {
-            block_headers.push_back(bls.header);
-            bl_state = bls;
-            block_found = true;
-            break;
-         }
-      }
-
-      // get incremental_merkle
-      auto pre_block_state = block_index.find(bl_state.header.previous);
-      auto blockroot_merkle = pre_block_state->bls.blockroot_merkle;
-      if (blockroot_merkle._node_count != 0)  {
-         change_schedule_index.modify(ti, [&](auto &entry) {
-            entry.imcre_merkle = blockroot_merkle;
-            block_found = true;
-            ilog("bl_state blockroot_merkle ${times}.", ("times", blockroot_merkle));
-         });
-      }
-
-      if (!block_found) {
-         return std::make_tuple(block_headers, block_id_lists, false);
-      }
-
-      auto reserved = std::vector<block_id_type>();
-      reserved.reserve(10);
-      block_id_lists.push_back(std::vector<block_id_type>());
-      block_id_lists.push_back(reserved);
-      for (auto bls: ti->bs) {
-         if (bls.block_num <= ti->block_num) continue;
-         if (bls.block_num - block_headers.back().block_num() == 12) {
-            block_headers.push_back(bls.header);
-            if (block_headers.size() >= 15) break;
-            block_id_lists.push_back(std::vector<block_id_type>());
-         } else {
-            auto block_ids = block_id_lists.back();
-            if (block_ids.size() < 10) block_id_lists.back().push_back(bls.id);
-         }
-         if (block_id_lists.size() >= 15 && block_id_lists.back().size() >= 10 && block_headers.size() >= 15) break;
-      }
-
-      return std::make_tuple(block_headers, block_id_lists, true);
-   }
-
    void bridge_plugin_impl::change_schedule_timer_tick() {
       change_schedule_timer->expires_from_now(change_schedule_timeout);
       change_schedule_timer->async_wait([&](boost::system::error_code ec) {
          for (auto ti = change_schedule_index.begin(); ti != change_schedule_index.end(); ++ti) {
             if (ti->status != 1) continue;
 
-            auto tuple = collect_incremental_merkle_and_blocks(ti);
-            incremental_merkle blockroot_merkle = ti->imcre_merkle;
-
-            string new_schedule = fc::json::to_pretty_string(ti->schedule);
-
-            auto block_headers = std::get<0>(tuple);
-            auto block_id_lists = std::get<1>(tuple);
-            auto found = std::get<2>(tuple);
-
-            if (!found) {
-               ilog("It doesn't finish collecting related blocks, cotinue");
-               continue;
-            }
-
-            string blocks_json = fc::json::to_pretty_string(block_headers);
-            string ids_json = fc::json::to_pretty_string(block_id_lists);
-            string mroot_json = fc::json::to_pretty_string(blockroot_merkle);
-
-//            signed_block_header_ffi *blocks_ffi = new signed_block_header_ffi[block_headers.size()];
-//            for (size_t i = 0; i < block_headers.size(); ++i) {
-//               auto p = new signed_block_header_ffi(block_headers[i]);
-//               blocks_ffi[i] = *p;
-//            }
+            auto schedule_ffi = producer_authority_schedule_ffi(ti->schedule);
 
-//            auto merkle_ptr = convert_ffi(blockroot_merkle);
-
-//            block_id_type_list *ids_list = new block_id_type_list[block_id_lists.size()];
-//            for (size_t i = 0; i < block_id_lists.size(); ++i) {
-//               ids_list[i] = convert_ffi(block_id_lists[i]);
-//            }
-
-            rpc_result *result = change_schedule(
-               config.bifrost_addr.data(),
-               config.bifrost_signer.data(),
+            // headers, ids and blockroot merkle are taken from block cache
+            rpc_result_ptr result(enqueue_change_schedule_cached(
                ti->legacy_schedule_hash,
-               new_schedule.data(),
-               mroot_json.data(),
-               blocks_json.data(),
-               block_headers.size(),
-               ids_json.data(),
-               block_id_lists.size()
-            );
+               &schedule_ffi,
+               ti->block_num
+            ));
 
             if (result) { // not null
                if (result->success) {
                   change_schedule_index.modify(ti, [&](auto &entry) {
-                     entry.status = 2; // sent successfully
+                     entry.status = 3; // queued in rust side, kept until it's sent
                   });
-                  ilog("sent data to bifrost for changing schedule.");
-                  ilog("Transaction got finalized. Hash: ${hash}.", ("hash", std::string(result->msg)));
+                  persist(*ti);
+                  ilog("queued changing schedule, id: ${id}.", ("id", std::string(result->msg)));
                } else {
-                  ilog("failed to send data to bifrost for changing schedule due to: ${err}.", ("err", std::string(result->msg)));
+                  ilog("failed to queue changing schedule due to: ${err}.", ("err", std::string(result->msg)));
                }
             }
-
-//            if (blocks_ffi) delete []blocks_ffi;
-//            if (ids_list) delete []ids_list;
          }
 
          change_schedule_timer_tick();
@@ -281,26 +246,8 @@ namespace eosio {
             for (auto ti = prove_action_index.begin(); ti != prove_action_index.end(); ++ti) {
                if (ti->status != 1) continue;
 
-               auto tuple = collect_incremental_merkle_and_blocks(ti);
-               incremental_merkle blockroot_merkle = ti->imcre_merkle;
-               auto block_headers = std::get<0>(tuple);
-               auto block_id_lists = std::get<1>(tuple);
-               auto found = std::get<2>(tuple);
-
-               if (!found) {
-                  ilog("It doesn't finish collecting related blocks, cotinue");
-                  continue;
-               }
-
-               signed_block_header_ffi *blocks_ffi = new signed_block_header_ffi[block_headers.size()];
-               for (size_t i = 0; i < block_headers.size(); ++i) {
-                  auto p = new signed_block_header_ffi(block_headers[i]);
-                  blocks_ffi[i] = *p;
-               }
-
                auto receipts = action_receipt_ffi(ti->receipt);
                auto act_ffi = action_ffi(ti->act);
-               auto merkle_ptr = convert_ffi(blockroot_merkle);
 
                std::vector<block_id_type> act_receipts_digs;
                int j = -1;
@@ -314,93 +261,267 @@ namespace eosio {
                   ilog("all receipts: ${to}", ("to", ti->act_receipts));
                   ilog("all receipts hash: ${to}", ("to", ti->act_receipt_digest));
                   ilog("act_receipt_digest: ${to}", ("to", ti->act_receipt_digest));
-                  ilog("imcre_merkle: ${to}", ("to", ti->imcre_merkle));
                   ilog("receipt: ${to}", ("to", ti->receipt));
                   continue;
                }
                auto paths = get_proof(j, act_receipts_digs);
                auto merkle_paths = convert_ffi(paths);
 
-               block_id_type_list *ids_list = new block_id_type_list[block_id_lists.size()];
-               for (size_t i = 0; i < block_id_lists.size(); ++i) {
-                  ids_list[i] = convert_ffi(block_id_lists[i]);
-               }
-
-               rpc_result *result = prove_action(
-                 config.bifrost_addr.data(),
-                 config.bifrost_signer.data(),
+               // headers, ids and blockroot merkle are taken from block cache
+               rpc_result_ptr result(enqueue_prove_action_cached(
                  &act_ffi,
-                 &merkle_ptr,
                  &receipts,
                  &merkle_paths,
-                 blocks_ffi,
-                 block_headers.size(),
-                 ids_list,
-                 block_id_lists.size(),
-                 ti->trx_id
-               );
+                 ti->trx_id,
+                 ti->block_num
+               ));
 
                if (result) { // not null
                   if (result->success) {
                      prove_action_index.modify(ti, [&](auto &entry) {
-                        entry.status = 2; // sent successfully
+                        entry.status = 3; // queued in rust side, kept until it's sent
                      });
-                     ilog("sent data to bifrost for proving action.");
-                     ilog("Transaction got finalized. Hash: ${hash}.", ("hash", std::string(result->msg)));
+                     persist(*ti);
+                     ilog("queued proving action, id: ${id}.", ("id", std::string(result->msg)));
                   } else {
-                     ilog("failed to send data to bifrost for proving action due to: ${err}.",
-                          ("err", std::string(result->msg)));
+                     ilog("failed to queue proving action due to: ${err}.", ("err", std::string(result->msg)));
                   }
                }
-
-               if (blocks_ffi) delete []blocks_ffi;
-               if (ids_list) delete []ids_list;
             }
+
+            process_submission_queue();
+            confirm_redeem_requests();
          }
 
          prove_action_timer_tick();
       });
    }
 
-   // listen and retrieve block headers, collecting block headers for verifying
-   void bridge_plugin_impl::irreversible_block(const chain::block_state_ptr &block) {
-      // flush buffer
-      uint64_t block_index_max_size = 512; // How many transaction will be stored.
-      if (prove_action_index.size() >= block_index_max_size) {
-         if (prove_action_index.begin()->status == 2) prove_action_index.erase(prove_action_index.begin());
+   // send queued schedule changes and action proofs, schedule changes always go first
+   void bridge_plugin_impl::process_submission_queue() {
+      if (submission_queue_pending() == 0) return;
+
+      // signer balance is checked by rust side before anything is sent
+      rpc_result_ptr result(::process_submission_queue(config.bifrost_addr.data(), config.bifrost_signer.data()));
+      if (!result) return;
+      if (!result->success && result->code == rpc_insufficient_balance) {
+         wlog("refuse to submit transactions to bifrost: ${err}.", ("err", std::string(result->msg)));
+         return;
+      }
+      if (!result->success) {
+         ilog("failed to process submission queue due to: ${err}.", ("err", std::string(result->msg)));
+         return;
+      }
+
+      auto results = fc::json::from_string(std::string(result->msg)).get_array();
+      for (auto &r: results) {
+         settle_submitted(r);
+         if (r["success"].as_bool()) {
+            ilog("sent ${kind} of block ${num} to bifrost: ${msg}.",
+                 ("kind", r["kind"].as_string())("num", r["block_num"].as_uint64())("msg", r["msg"].as_string()));
+         } else {
+            ilog("failed to send ${kind} of block ${num} to bifrost due to: ${err}.",
+                 ("kind", r["kind"].as_string())("num", r["block_num"].as_uint64())("err", r["msg"].as_string()));
+         }
       }
+   }
 
-      if (change_schedule_index.size() >= block_index_max_size && change_schedule_index.begin()->status == 2) {
-         change_schedule_index.erase(change_schedule_index.begin());
+   // bifrost requests whose redeem transfers are proven get confirmed and settled
+   void bridge_plugin_impl::confirm_redeem_requests() {
+      rpc_result_ptr result(::confirm_redeem_requests(config.bifrost_addr.data(), config.bifrost_signer.data()));
+      if (!result) return;
+      if (!result->success) {
+         ilog("failed to confirm redeem requests due to: ${err}.", ("err", std::string(result->msg)));
+         return;
       }
 
-      auto bb = bridge_blocks{ block->id, *block };
-      if (block_index.size() >= block_index_max_size) {
-         block_index.erase(block_index.begin());
+      auto settled = fc::json::from_string(std::string(result->msg)).get_array();
+      if (!settled.empty()) {
+         ilog("settled redeem requests: ${ids}.", ("ids", settled));
       }
-      block_index.insert(bb);
+   }
 
-      // collect blocks for prove_action
-      for (auto iter = prove_action_index.begin(); iter !=prove_action_index.end(); ++iter) {
-         if (iter->status == 0 && iter->bs.size() <= 12 * 16) {
-            prove_action_index.modify(iter, [=](auto &entry) {
-               if (entry.block_num <= block->block_num) {
-                  entry.bs.push_back(*block);
-               }
-               if (entry.block_num - 1 == block->block_num) { // need previous block blockroot_merkle
-                  entry.imcre_merkle = block->blockroot_merkle;
+   // refunds seen in the block are settled, the host stops sending them again
+   void bridge_plugin_impl::settle_irreversible_refunds(const block_state &bls) {
+      std::vector<transaction_id_type> trx_ids;
+      for (const auto &receipt: bls.block->transactions) {
+         if (receipt.trx.contains<transaction_id_type>()) {
+            trx_ids.push_back(receipt.trx.get<transaction_id_type>());
+         } else {
+            trx_ids.push_back(receipt.trx.get<packed_transaction>().id());
+         }
+      }
+
+      auto settled = settle_refunds(bls.block_num, trx_ids.data(), trx_ids.size());
+      if (settled > 0) {
+         ilog("settled ${n} refunds in block ${num}.", ("n", settled)("num", bls.block_num));
+      }
+   }
+
+   // an entry is done once rust side sent it, or found it could never be sent
+   void bridge_plugin_impl::settle_submitted(const fc::variant &r) {
+      if (!r["success"].as_bool() && !r["permanent"].as_bool()) return; // still queued for next pass
+
+      auto done = [](auto &entry) {
+         entry.status = 2; // sent, dropped by update_pending
+      };
+      if (r["kind"].as_string() == "change_schedule") {
+         auto itr = change_schedule_index.find(r["block_num"].as_uint64());
+         if (itr != change_schedule_index.end() && itr->status == 3) {
+            change_schedule_index.modify(itr, done);
+            persist(*itr);
+         }
+      } else if (r["receipt_digest"].is_string()) {
+         auto itr = prove_action_index.find(block_id_type(r["receipt_digest"].as_string()));
+         if (itr != prove_action_index.end() && itr->status == 3) {
+            prove_action_index.modify(itr, done);
+            persist(*itr);
+         }
+      }
+   }
+
+   // entries queued before a restart whose submissions are lost, like a deleted relay store, are queued again
+   void bridge_plugin_impl::requeue_lost_entries() {
+      rpc_result_ptr status(submission_queue_status());
+      if (!status || !status->success) return;
+
+      std::set<uint64_t> schedule_blocks;
+      std::set<block_id_type> receipt_digests;
+      for (auto &item: fc::json::from_string(std::string(status->msg))["items"].get_array()) {
+         if (item["kind"].as_string() == "change_schedule") {
+            schedule_blocks.insert(item["block_num"].as_uint64());
+         } else if (item["receipt_digest"].is_string()) {
+            receipt_digests.insert(block_id_type(item["receipt_digest"].as_string()));
+         }
+      }
+
+      auto requeue = [](auto &entry) {
+         entry.status = 1; // full, blocks are kept by block cache floor
+      };
+      for (auto itr = change_schedule_index.begin(); itr != change_schedule_index.end(); ++itr) {
+         if (itr->status == 3 && !schedule_blocks.count(itr->block_num)) {
+            change_schedule_index.modify(itr, requeue);
+            persist(*itr);
+         }
+      }
+      for (auto itr = prove_action_index.begin(); itr != prove_action_index.end(); ++itr) {
+         if (itr->status == 3 && !receipt_digests.count(itr->act_receipt_digest)) {
+            prove_action_index.modify(itr, requeue);
+            persist(*itr);
+         }
+      }
+   }
+
+   // header, id and blockroot merkle are all a proof needs from a block
+   void bridge_plugin_impl::cache_irreversible_block(const block_state &bls) {
+      auto header_ffi = signed_block_header_ffi(bls.header);
+      auto merkle_ffi = convert_ffi(bls.blockroot_merkle);
+      if (!cache_block(bls.block_num, bls.id, &header_ffi, &merkle_ffi)) {
+         elog("failed to cache block ${num}.", ("num", bls.block_num));
+      }
+   }
+
+   // entries stored by older versions carry their blocks from the target block on, and the blockroot
+   // merkle of the block before it. move them into block cache, the entries are built from there now
+   template<typename Index>
+   void bridge_plugin_impl::migrate_legacy_blocks(Index &index) {
+      const auto &chain = chain_plug->chain();
+      for (auto iter = index.begin(); iter != index.end(); ++iter) {
+         if (iter->bs.empty() && iter->imcre_merkle._node_count == 0) continue;
+
+         if (iter->status != 2) {
+            // only the blockroot merkle of the block before was kept, its header is in block log
+            auto previous = iter->imcre_merkle._node_count > 0 ? chain.fetch_block_by_number(iter->block_num - 1) : nullptr;
+            if (previous) {
+               auto header_ffi = signed_block_header_ffi(*previous);
+               auto merkle_ffi = convert_ffi(iter->imcre_merkle);
+               if (!cache_block(iter->block_num - 1, previous->id(), &header_ffi, &merkle_ffi)) {
+                  elog("failed to cache block ${num}.", ("num", iter->block_num - 1));
                }
-            });
+            }
+            for (const auto &bls: iter->bs) {
+               cache_irreversible_block(bls);
+            }
+            ilog("moved ${n} blocks of entry at block ${num} into block cache.", ("n", iter->bs.size())("num", iter->block_num));
+         }
+
+         index.modify(iter, [](auto &entry) {
+            entry.bs.clear();
+            entry.imcre_merkle = incremental_merkle();
+         });
+         persist(*iter);
+      }
+   }
+
+   // drop entries sent by submission queue, mark entries whose blocks are all cached
+   template<typename Index>
+   void bridge_plugin_impl::update_pending(Index &index, uint32_t block_num, const char *kind) {
+      for (auto iter = index.begin(); iter != index.end();) {
+         if (iter->status == 2) {
+            unpersist(*iter);
+            iter = index.erase(iter);
+            continue;
+         }
+         if (iter->status != 0) {
+            ++iter;
+            continue;
+         }
+
+         bool ready = block_cache_ready(iter->block_num);
+         if (!ready && iter->block_num + 12 * 16 < block_num) {
+            // its blocks were irreversible while nodeos was down, read them back from block log
+            ready = rebuild_blocks(iter->block_num);
+            if (!ready) {
+               elog("give up ${kind} of block ${num}, its blocks can't be rebuilt from block log.", ("kind", kind)("num", iter->block_num));
+               unpersist(*iter);
+               iter = index.erase(iter);
+               continue;
+            }
          }
-         if (iter->status == 0 && iter->block_num != 0 && iter->bs.size() >= 12 * 16) {
-            prove_action_index.modify(iter, [=](auto &entry) {
-               ilog("collected blocks for proving action: ${to}", ("to", block->block_num));
+         if (ready) {
+            index.modify(iter, [=](auto &entry) {
+               ilog("collected blocks for ${kind}: ${to}", ("kind", kind)("to", block_num));
                entry.status = 1; // full
             });
-            // trigger cross trade
-            // prove_action_timer_tick();
+            persist(*iter);
          }
+         ++iter;
+      }
+   }
+
+   // a block's blockroot merkle follows from the block before, so a gap is rebuilt from the last cached block on
+   bool bridge_plugin_impl::rebuild_blocks(uint32_t block_num) {
+      const auto &chain = chain_plug->chain();
+      for (auto missing = block_cache_next_missing(block_num); missing != 0; missing = block_cache_next_missing(block_num)) {
+         auto block = chain.fetch_block_by_number(missing);
+         if (!block) return false;
+         auto header_ffi = signed_block_header_ffi(*block);
+         if (!rebuild_cached_block(missing, block->id(), &header_ffi)) return false;
+      }
+      return true;
+   }
+
+   // block cache keeps blocks from the oldest entry not sent yet, a lost submission is built from them again
+   void bridge_plugin_impl::update_block_cache_floor() {
+      uint32_t floor = 0;
+      auto lower = [&](uint32_t block_num) {
+         if (floor == 0 || block_num < floor) floor = block_num;
+      };
+      for (const auto &entry: prove_action_index) {
+         if (entry.status != 2) lower(entry.block_num);
+      }
+      for (const auto &entry: change_schedule_index) {
+         if (entry.status != 2) lower(entry.block_num);
+      }
+
+      if (!set_block_cache_floor(floor)) {
+         elog("failed to set block cache floor to ${floor}.", ("floor", floor));
       }
+   }
+
+   // listen and retrieve block headers, collecting block headers for verifying
+   void bridge_plugin_impl::irreversible_block(const chain::block_state_ptr &block) {
+      cache_irreversible_block(*block);
 
       // check if block has new producers, and collect blocks for change_schedule
       auto blk = block->block;
@@ -410,7 +531,6 @@ namespace eosio {
       if (block->header.schedule_version + 1 == block->active_schedule.version) {
          // insert blocks
          ilog("new producers list coming: ${to}", ("to", block->active_schedule));
-         // ilog("new producers list coming: ${to}", ("to", block->active_schedule));
 
          auto trace = bridge_change_schedule {
             block->block_num,
@@ -421,25 +541,32 @@ namespace eosio {
             block->active_schedule // this is new producer schedule
          };
          change_schedule_index.insert(trace);
+         persist(trace);
       }
 
-      for (auto iter = change_schedule_index.begin(); iter !=change_schedule_index.end(); ++iter) {
-         if (iter->status == 0 && iter->bs.size() <= 12 * 16) {
-            change_schedule_index.modify(iter, [=](auto &entry) {
-               if (entry.block_num <= block->block_num) {
-                  entry.bs.push_back(*block);
-               }
-               if (entry.block_num - 1 == block->block_num) { // need previous block blockroot_merkle
-                  entry.imcre_merkle = block->blockroot_merkle;
-               }
-            });
-         }
-         if (iter->status == 0 && iter->block_num != 0 && iter->bs.size() >= 12 * 16) {
-            change_schedule_index.modify(iter, [=](auto &entry) {
-               ilog("collected blocks for changing schedule: ${to}", ("to", block->block_num));
-               entry.status = 1; // full
-            });
-         }
+      settle_irreversible_refunds(*block);
+
+      update_pending(prove_action_index, block->block_num, "proving action");
+      update_pending(change_schedule_index, block->block_num, "changing schedule");
+      update_block_cache_floor();
+   }
+
+   // hand contract abi over to rust side once it's changed, for decoding and filtering action data
+   void bridge_plugin_impl::register_abi(const account_name &contract) {
+      const auto &db = chain_plug->chain().db();
+      const auto *meta = db.find<account_metadata_object, by_name>(contract);
+      const auto *account = db.find<account_object, by_name>(contract);
+      if (!meta || !account) return;
+
+      if (is_contract_abi_cached(contract, meta->abi_sequence)) return;
+
+      abi_def abi;
+      if (!abi_serializer::to_abi(account->abi, abi)) return;
+
+      auto abi_json = fc::json::to_string(abi, fc::time_point::maximum());
+      rpc_result_ptr result(register_contract_abi(contract, meta->abi_sequence, abi_json.data()));
+      if (result && !result->success) {
+         ilog("failed to register abi of ${c} due to: ${err}.", ("c", contract)("err", std::string(result->msg)));
       }
    }
 
@@ -465,9 +592,30 @@ namespace eosio {
          auto act = action_traces[i].act;
          auto act_dig = digest(act);
          auto receiver = action_traces[i].receiver;
-         if (act.account == name("eosio.token") && act.name == name("transfer") && receiver == name("eosio.token")) {
+         register_abi(act.account);
+         auto act_ffi = action_ffi(act);
+         auto matched = match_action_rules(&act_ffi, receiver);
+         if (matched == rule_action) {
+            ilog("skip action ${act} matched by rules, only token transfers are relayed.", ("act", act));
+            continue;
+         }
+         // only tokens in asset registry could be relayed, other contracts' transfers may have other layouts
+         if (matched == rule_transfer && is_asset_contract(act.account)) {
             action_transfer der_act;
-            fc::raw::unpack<action_transfer>(act.data, der_act);
+            try {
+               fc::raw::unpack<action_transfer>(act.data, der_act);
+            } catch (const fc::exception &e) {
+               wlog("skip action ${act} which is not a token transfer: ${err}.", ("act", act)("err", e.to_detail_string()));
+               continue;
+            } catch (...) {
+               wlog("skip action ${act} which is not a token transfer.", ("act", act));
+               continue;
+            }
+
+            // and only symbols of the contract that bifrost maps to an asset
+            auto quantity = der_act.quantity.to_string();
+            rpc_result_ptr classified(classify_transfer(act.account, quantity.data()));
+            if (!classified || !classified->success) continue;
             ilog("money from: ${from}", ("from", der_act.from));
             ilog("money to: ${to}", ("to", der_act.to));
             ilog("action_transfer: ${to}", ("to", der_act));
@@ -480,13 +628,35 @@ namespace eosio {
                // 2. directly redeem assets by cleos command, we may not need this transaction id
                // ["bifrostcross", "jim", "43.0000 EOS", "5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY@bifrost:vEOS"]
                current_trx_id = trx_id;
+
+               // refunds are settled on EOS once they're irreversible, bifrost has nothing to do with them
+               if (der_act.memo.rfind("refund:", 0) == 0) {
+                  if (!observe_refund_transfer(der_act.memo.data(), act.account, der_act.to, quantity.data(), trx_id, action_traces[i].block_num)) {
+                     wlog("transfer ${trx} refunds nothing queued: ${memo}.", ("trx", trx_id)("memo", der_act.memo));
+                  }
+                  continue;
+               }
+
+               rpc_result_ptr matched(match_redeem_transfer(der_act.memo.data(), trx_id));
+               if (matched && matched->success) {
+                  ilog("redeem transfer ${trx} matches bifrost request ${id}.", ("trx", trx_id)("id", std::string(matched->msg)));
+               } else if (matched) {
+                  wlog("redeem transfer ${trx} matches nothing: ${err}.", ("trx", trx_id)("err", std::string(matched->msg)));
+               }
             }
             if (der_act.to == name(contract)) {
                // EOS => Bifrost, doesn't need to transaction id
                current_trx_id = transaction_id_type();
+
+               // nobody could be credited by a bad memo, it goes to refund queue instead of being proven
+               rpc_result_ptr valid(validate_transfer_memo(der_act.memo.data(), act.account, der_act.from, quantity.data(), trx_id));
+               if (valid && !valid->success) {
+                  wlog("transfer ${trx} is queued for refunding: ${err}.", ("trx", trx_id)("err", std::string(valid->msg)));
+                  continue;
+               }
             }
 
-            if (der_act.to != name(contract) && der_act.from != name(contract)) return;
+            if (der_act.to != name(contract) && der_act.from != name(contract)) continue;
 
             if (!action_traces[i].receipt) {
                ilog("action traces is exception.");
@@ -526,6 +696,7 @@ namespace eosio {
          current_trx_id
       };
       prove_action_index.insert(bt);
+      persist(bt);
    }
 
    void bridge_plugin_impl::apply_action_receipt(std::tuple<const transaction_trace_ptr&, const std::vector<action_receipt>&> t) {
@@ -544,6 +715,16 @@ namespace eosio {
       if (!fc::is_directory(datadir))
          fc::create_directories(datadir);
 
+      auto store_dir = datadir / "relay_store";
+      rpc_result_ptr result(open_relay_store(store_dir.generic_string().c_str()));
+      EOS_ASSERT(result && result->success, plugin_config_exception, "failed to open relay store: ${err}",
+                 ("err", result ? std::string(result->msg) : std::string("null result")));
+      ilog("relay store is opened, ${msg}.", ("msg", std::string(result->msg)));
+
+      change_schedule_index.clear();
+      prove_action_index.clear();
+
+      // state written by older versions on clean shutdown, move it into relay store
       auto bridge_db_dat = datadir / config::bridgedb_filename;
       if (fc::exists(bridge_db_dat)) {
          try {
@@ -551,16 +732,12 @@ namespace eosio {
             fc::read_file_contents(bridge_db_dat, content);
             fc::datastream<const char *> ds(content.data(), content.size());
 
-            block_index.clear();
-            change_schedule_index.clear();
-            prove_action_index.clear();
-
             unsigned_int block_index_size;
             fc::raw::unpack(ds, block_index_size);
             for (uint32_t i = 0, n = block_index_size.value; i < n; ++i) {
                bridge_blocks bb;
                fc::raw::unpack(ds, bb);
-               block_index.insert(bb);
+               store_put(blocks_tree, bb.id, bb);
             }
 
             unsigned_int change_schedule_index_size;
@@ -568,7 +745,7 @@ namespace eosio {
             for (uint32_t i = 0, n = change_schedule_index_size.value; i < n; ++i) {
                bridge_change_schedule bcs;
                fc::raw::unpack(ds, bcs);
-               change_schedule_index.insert(bcs);
+               persist(bcs);
             }
 
             unsigned_int prove_action_index_size;
@@ -576,46 +753,40 @@ namespace eosio {
             for (uint32_t i = 0, n = prove_action_index_size.value; i < n; ++i) {
                bridge_prove_action bpa;
                fc::raw::unpack(ds, bpa);
-               prove_action_index.insert(bpa);
+               persist(bpa);
             }
 
          } FC_CAPTURE_AND_RETHROW((bridge_db_dat))
 
          fc::remove(bridge_db_dat);
       }
+
+      store_load<bridge_change_schedule>(change_schedules_tree, change_schedule_index);
+      store_load<bridge_prove_action>(prove_actions_tree, prove_action_index);
+      requeue_lost_entries();
+
+      // blocks kept by older versions in relay store, move them into block cache. floor goes
+      // first, or blocks needed by pending entries would be dropped as soon as they're cached
+      update_block_cache_floor();
+      bridge_block_index legacy_blocks;
+      store_load<bridge_blocks>(blocks_tree, legacy_blocks);
+      for (const auto &bb: legacy_blocks) {
+         cache_irreversible_block(bb.bls);
+         store_remove(blocks_tree, bb.id);
+      }
+      migrate_legacy_blocks(change_schedule_index);
+      migrate_legacy_blocks(prove_action_index);
    }
 
+   // every change is written through relay store already, nothing to dump here
    void bridge_plugin_impl::close_db() {
       ilog("bridge_plugin_impl::close_db()");
-      auto bridge_db_dat = datadir / config::bridgedb_filename;
-
-      std::ofstream out(bridge_db_dat.generic_string().c_str(), std::ios::out | std::ios::binary | std::ofstream::trunc);
-
-      uint32_t block_index_size = block_index.size();
-      fc::raw::pack(out, unsigned_int{block_index_size});
-      auto block_iter = block_index.get<by_id>().begin();
-      auto blk_it = block_index.project<0>(block_iter);
-      for (; blk_it != block_index.end(); ++blk_it) {
-         fc::raw::pack(out, *blk_it);
-      }
-
-      uint32_t change_schedule_index_size = change_schedule_index.size();
-      fc::raw::pack(out, unsigned_int{change_schedule_index_size});
-      auto cs_iter = change_schedule_index.get<by_id>().begin();
-      auto cs_it = change_schedule_index.project<0>(cs_iter);
-      for (; cs_it != change_schedule_index.end(); ++cs_it) {
-         fc::raw::pack(out, *cs_it);
-      }
 
-      uint32_t prove_action_index_size = prove_action_index.size();
-      fc::raw::pack(out, unsigned_int{prove_action_index_size});
-      auto pa_iter = prove_action_index.get<by_id>().begin();
-      auto pa_it = prove_action_index.project<0>(pa_iter);
-      for (; pa_it != prove_action_index.end(); ++pa_it) {
-         fc::raw::pack(out, *pa_it);
+      rpc_result_ptr result(close_relay_store());
+      if (result && !result->success) {
+         elog("failed to close relay store due to: ${err}.", ("err", std::string(result->msg)));
       }
 
-      block_index.clear();
       change_schedule_index.clear();
       prove_action_index.clear();
    }
@@ -634,6 +805,9 @@ namespace eosio {
       cfg.add_options()
               ("bifrost-signer", bpo::value<string>()->default_value("//Alice"),
                "This is sopposed to be a bifrost crossaccount like: alice or bob");
+      cfg.add_options()
+              ("bifrost-relay-config", bpo::value<string>(),
+               "Path to a json file with relayer settings, like: {\"min_balance_warning\": 1000000000000}");
       cfg.add_options()
               ("delete-relay-history", bpo::bool_switch()->default_value(false),
                "This is sopposed to delete all realy data history");
@@ -661,19 +835,31 @@ namespace eosio {
             my->config.bifrost_signer = "//Alice";
          }
 
+         if (options.count("bifrost-relay-config")) {
+            auto config_path = options.at("bifrost-relay-config").as<std::string>();
+            fc::read_file_contents(config_path, my->config.relay_config);
+         }
+         rpc_result_ptr config_result(init_relay_config(my->config.relay_config.data()));
+         if (config_result && !config_result->success) {
+            elog("invalid bifrost relay config: ${err}.", ("err", std::string(config_result->msg)));
+         }
+
          if (options.at("delete-relay-history").as<bool>()) {
             // Todo, delete relay data
             ilog("delete relay data history. ${h}", ("h", my->datadir));
             boost::filesystem::remove_all(my->datadir);
          }
 
+         // legacy entries are moved into block cache on open, headers they lack come from block log
+         my->chain_plug = app().find_plugin<chain_plugin>();
          my->open_db();
 
-         my->chain_plug = app().find_plugin<chain_plugin>();
          chain::controller &cc = my->chain_plug->chain();
          cc.irreversible_block.connect(boost::bind(&bridge_plugin_impl::irreversible_block, my.get(), _1));
          cc.apply_action_receipt.connect(boost::bind(&bridge_plugin_impl::apply_action_receipt, my.get(), _1));
 
+         register_cross_out_callback(&on_cross_out_request);
+
          // init timer tick
          my->change_schedule_timer = std::make_unique<boost::asio::steady_timer>(app().get_io_service());
          my->prove_action_timer = std::make_unique<boost::asio::steady_timer>(app().get_io_service());
@@ -686,9 +872,22 @@ namespace eosio {
       // Make the magic happen
       ilog("bridge_plugin::plugin_startup.");
 
+      // find out what happened to extrinsics sent right before last shutdown, before sending anything
+      rpc_result_ptr reconciled(reconcile_outbox(my->config.bifrost_addr.data()));
+      if (reconciled && reconciled->success) {
+         ilog("reconciled outbox with bifrost: ${r}.", ("r", std::string(reconciled->msg)));
+      } else if (reconciled) {
+         elog("failed to reconcile outbox due to: ${err}.", ("err", std::string(reconciled->msg)));
+      }
+
       // start timer tick
       my->change_schedule_timer_tick();
       my->prove_action_timer_tick();
+
+      rpc_result_ptr result(subscribe_cross_out_requests(my->config.bifrost_addr.data()));
+      if (result && !result->success) {
+         elog("failed to subscribe cross out requests due to: ${err}.", ("err", std::string(result->msg)));
+      }
    }
 
    void bridge_plugin::plugin_shutdown() {
@@ -696,6 +895,7 @@ namespace eosio {
       ilog("bridge_plugin::plugin_shutdown.");
 
       my->in_shutdown = true;
+      unsubscribe_cross_out_requests();
 
       my->close_db();
    }
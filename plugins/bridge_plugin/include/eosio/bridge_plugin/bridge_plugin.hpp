@@ -39,8 +39,8 @@ struct bridge_blocks {
 
 struct bridge_change_schedule {
    uint32_t                                 block_num = 0; // the block has new producer schedule
-   incremental_merkle                       imcre_merkle;
-   std::vector<block_state>                 bs;
+   incremental_merkle                       imcre_merkle;  // unused, blocks are in rust block cache
+   std::vector<block_state>                 bs;            // unused, kept for entries stored by older versions
    uint8_t                                  status = 0;
    digest_type                              legacy_schedule_hash;
    producer_authority_schedule              schedule;
@@ -52,12 +52,21 @@ struct bridge_prove_action {
    action_receipt                           receipt;
    std::vector<action_receipt>              act_receipts;
    block_id_type                            act_receipt_digest;
-   incremental_merkle                       imcre_merkle;
-   std::vector<block_state>                 bs;
+   incremental_merkle                       imcre_merkle;  // unused, blocks are in rust block cache
+   std::vector<block_state>                 bs;            // unused, kept for entries stored by older versions
    uint8_t                                  status = 0;
    transaction_id_type                      trx_id;
 };
 
+// Bifrost => EOS, asked by bridge-eos pallet
+struct cross_out_request {
+   uint64_t                                 request_id = 0;
+   std::string                              bifrost_account;
+   account_name                             eos_recipient;
+   std::string                              asset;
+   std::string                              amount;
+};
+
 struct action_transfer {
    account_name                             from;
    account_name                             to;
@@ -68,6 +77,7 @@ struct action_transfer {
 }
 
 FC_REFLECT( eosio::bridge_blocks, (id)(bls) )
+FC_REFLECT( eosio::cross_out_request, (request_id)(bifrost_account)(eos_recipient)(asset)(amount) )
 FC_REFLECT( eosio::action_transfer, (from)(to)(quantity)(memo) )
 FC_REFLECT( eosio::bridge_change_schedule, (block_num)(bs)(status) )
 FC_REFLECT( eosio::bridge_prove_action, (block_num)(act)(receipt)(act_receipts)(act_receipt_digest)(imcre_merkle)(bs)(status) )
@@ -1,5 +1,6 @@
 #pragma once
 #include <appbase/application.hpp>
+#include <cstddef>
 #include <eosio/chain_plugin/chain_plugin.hpp>
 #include <eosio/chain/producer_schedule.hpp>
 #include <eosio/chain/types.hpp>
@@ -9,9 +10,47 @@ namespace eosio {
 using namespace appbase;
 using namespace chain;
 
+// keep in sync with ERROR_CODE_* in bifrost_rpc/ffi/src/lib.rs
+enum rpc_error_code : uint32_t {
+   rpc_success              = 0,
+   rpc_generic_error        = 1,
+   rpc_insufficient_balance = 2,
+   rpc_fee_too_high         = 3,
+   rpc_invalid_call         = 4,
+   rpc_already_proven       = 5,
+   rpc_invalid_memo         = 6,
+   rpc_unknown_asset        = 7,
+   rpc_abi_error            = 8,
+   rpc_batch_interrupted    = 9,
+   rpc_store_error          = 10,
+   rpc_already_submitted    = 11,
+   rpc_submission_in_flight = 12,
+   rpc_block_log_error      = 13,
+   rpc_ship_error           = 14,
+   rpc_unpack_error         = 15,
+   rpc_dry_run              = 16,
+};
+
+// keep in sync with RuleMatch in bifrost_rpc/ffi/src/rules.rs
+enum rule_match : uint8_t {
+   rule_unmatched = 0,
+   rule_action    = 1,
+   rule_transfer  = 2,
+};
+
 struct rpc_result {
    bool success;
    char* msg;
+   uint32_t code;
+};
+
+// cross out request from bifrost, only valid during the callback
+struct cross_out_request_ffi {
+   uint64_t                         request_id;
+   const char                       *bifrost_account;
+   account_name                     eos_recipient;
+   const char                       *asset;
+   const char                       *amount;
 };
 
 struct action_ffi {
@@ -257,6 +296,7 @@ struct block_signing_authority_v0_ffi {
       for (size_t i = 0; i < keys_size; ++i) {
          key_weight_ffi ffi = key_weight_ffi(v0.keys[i]);
          memcpy(&keys[i], &ffi, sizeof(ffi));
+         ffi.key = nullptr; // owned by keys[i] now
       }
    }
 
@@ -267,7 +307,7 @@ struct block_signing_authority_v0_ffi {
 
 struct producer_authority_ffi {
    account_name producer_name;
-   int tag;
+   uint32_t tag;
    block_signing_authority_v0_ffi *v0_ffi;
 
    producer_authority_ffi() {
@@ -290,7 +330,7 @@ struct producer_authority_ffi {
 };
 
 struct producer_authority_schedule_ffi {
-   int version;
+   uint32_t version;
    producer_authority_ffi *producers_ffi;
    size_t producers_size;
 
@@ -307,6 +347,7 @@ struct producer_authority_schedule_ffi {
       for (size_t i = 0; i < producers_size; ++i) {
          auto ffi = producer_authority_ffi(schedule.producers[i]);
          memcpy(&producers_ffi[i], &ffi, sizeof(ffi));
+         ffi.v0_ffi = nullptr; // owned by producers_ffi[i] now
       }
    }
 
@@ -315,4 +356,30 @@ struct producer_authority_schedule_ffi {
    }
 };
 
+// layouts are shared with the #[repr(C)] structs in ffi_types.rs, whose tests check the same sizes and offsets
+static_assert(sizeof(key_weight_ffi) == 16, "key_weight_ffi layout");
+static_assert(offsetof(key_weight_ffi, weight) == 8, "key_weight_ffi layout");
+static_assert(sizeof(block_signing_authority_v0_ffi) == 24, "block_signing_authority_v0_ffi layout");
+static_assert(offsetof(block_signing_authority_v0_ffi, keys) == 8, "block_signing_authority_v0_ffi layout");
+static_assert(offsetof(block_signing_authority_v0_ffi, keys_size) == 16, "block_signing_authority_v0_ffi layout");
+static_assert(sizeof(producer_authority_ffi) == 24, "producer_authority_ffi layout");
+static_assert(offsetof(producer_authority_ffi, tag) == 8, "producer_authority_ffi layout");
+static_assert(offsetof(producer_authority_ffi, v0_ffi) == 16, "producer_authority_ffi layout");
+static_assert(sizeof(producer_authority_schedule_ffi) == 24, "producer_authority_schedule_ffi layout");
+static_assert(offsetof(producer_authority_schedule_ffi, producers_ffi) == 8, "producer_authority_schedule_ffi layout");
+static_assert(offsetof(producer_authority_schedule_ffi, producers_size) == 16, "producer_authority_schedule_ffi layout");
+
+// all arguments of prove_action, used by prove_actions_batch
+struct prove_action_ffi {
+   const action_ffi                 *act_ffi;
+   const incremental_merkle_ffi     *imcre_merkle;
+   const action_receipt_ffi         *act_receipt;
+   const block_id_type_list         *action_merkle_paths;
+   const signed_block_header_ffi    *blocks_ffi;
+   size_t                           blocks_ffi_size;
+   const block_id_type_list         *ids_list;
+   size_t                           ids_list_size;
+   transaction_id_type              trx_id;
+};
+
 }